@@ -0,0 +1,499 @@
+use criterion::{
+    black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput,
+};
+use toktrie::{
+    recognizer::{FunctionalRecognizer, StackRecognizer},
+    rng::Rng,
+    testing::{synthetic_text, synthetic_vocab},
+    BiasStats, Recognizer, SimpleVob, SpecialToken, TokRxInfo, TokTrie, TokenId,
+};
+
+/// Whether a byte is allowed after `len` bytes.
+type AllowFn = fn(usize, u8) -> bool;
+
+/// Allows bytes while the function holds; EOS is always allowed.
+#[derive(Clone)]
+struct ByteFilter(AllowFn);
+
+impl FunctionalRecognizer<usize> for ByteFilter {
+    fn initial(&self) -> usize {
+        0
+    }
+
+    fn try_append(&self, state: usize, byte: u8) -> Option<usize> {
+        if (self.0)(state, byte) {
+            Some(state + 1)
+        } else {
+            None
+        }
+    }
+
+    fn special_allowed(&self, _state: usize, tok: SpecialToken) -> bool {
+        tok == SpecialToken::EndOfSentence
+    }
+}
+
+const RECOGNIZERS: [(&str, AllowFn); 3] = [
+    ("accept_all", |_, _| true),
+    ("ascii_only", |_, b| b < 0x80),
+    ("max_3_bytes", |len, _| len < 3),
+];
+
+fn trie(size: usize) -> TokTrie {
+    let words = synthetic_vocab(size, 1);
+    TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words)
+}
+
+fn compute_bias(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compute_bias");
+    for size in [32_000, 128_000] {
+        let trie = trie(size);
+        let mut logits = trie.alloc_token_set();
+        for (name, f) in RECOGNIZERS {
+            let mut r = StackRecognizer::from(ByteFilter(f));
+            group.bench_function(BenchmarkId::new(name, size), |b| {
+                b.iter(|| trie.compute_bias(&mut r, &mut logits))
+            });
+        }
+    }
+    group.finish();
+}
+
+/// The cost of counting; compare with the `compute_bias` group.
+fn compute_bias_with_stats(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compute_bias_with_stats");
+    for size in [32_000, 128_000] {
+        let trie = trie(size);
+        let mut logits = trie.alloc_token_set();
+        let mut stats = BiasStats::default();
+        for (name, f) in RECOGNIZERS {
+            let mut r = StackRecognizer::from(ByteFilter(f));
+            group.bench_function(BenchmarkId::new(name, size), |b| {
+                b.iter(|| trie.compute_bias_with_stats(&mut r, &mut logits, &mut stats))
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Compare with the `compute_bias` group.
+#[cfg(feature = "rayon")]
+fn compute_bias_parallel(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compute_bias_parallel");
+    for size in [32_000, 128_000] {
+        let trie = trie(size);
+        let mut logits = trie.alloc_token_set();
+        for (name, f) in RECOGNIZERS {
+            let r = StackRecognizer::from(ByteFilter(f));
+            group.bench_function(BenchmarkId::new(name, size), |b| {
+                b.iter(|| trie.compute_bias_parallel(&r, &mut logits))
+            });
+        }
+    }
+    group.finish();
+}
+
+#[cfg(not(feature = "rayon"))]
+fn compute_bias_parallel(_c: &mut Criterion) {}
+
+/// `TokTrie::compute_bias_with_index()` with 0.5% of the tokens as candidates;
+/// compare with the `compute_bias` group.
+fn compute_bias_within(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compute_bias_within");
+    for size in [32_000, 128_000] {
+        let trie = trie(size);
+        let mut rng = Rng::new(1);
+        let mut candidates = trie.alloc_token_set();
+        for _ in 0..size / 200 {
+            candidates.allow_token(rng.gen_up_to(size - 1) as u32);
+        }
+        let index = trie.index_candidates(&candidates);
+        let mut logits = trie.alloc_token_set();
+        for (name, f) in RECOGNIZERS {
+            let mut r = StackRecognizer::from(ByteFilter(f));
+            group.bench_function(BenchmarkId::new(name, size), |b| {
+                b.iter(|| trie.compute_bias_with_index(&mut r, &index, &mut logits))
+            });
+        }
+    }
+    group.finish();
+}
+
+/// With about 30% of the multi-byte tokens being duplicates of other tokens.
+fn apply_duplicates(c: &mut Criterion) {
+    let mut words = synthetic_vocab(128_000, 1);
+    let mut rng = Rng::new(1);
+    for t in 256..words.len() {
+        if rng.gen_up_to(9) < 3 {
+            words[t] = words[rng.gen_up_to(words.len() - 1)].clone();
+        }
+    }
+    let trie = TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words);
+    let mut group = c.benchmark_group("apply_duplicates");
+    for (name, f) in RECOGNIZERS {
+        let mut r = StackRecognizer::from(ByteFilter(f));
+        let mut base = trie.alloc_token_set();
+        trie.compute_bias(&mut r, &mut base);
+        let mut logits = base.clone();
+        group.bench_function(BenchmarkId::new(name, 128_000), |b| {
+            b.iter(|| {
+                logits.set_all(false);
+                logits.or(&base);
+                trie.apply_duplicates(&mut logits)
+            })
+        });
+    }
+    group.finish();
+}
+
+/// A sparse mask, as from a recognizer allowing few tokens.
+fn iter_set_bits(c: &mut Criterion) {
+    let mut mask = SimpleVob::alloc(128_000);
+    for i in 0..50 {
+        mask.allow_token(i * 2551);
+    }
+    let mut group = c.benchmark_group("iter_set_bits");
+    group.bench_function("50_of_128k", |b| {
+        b.iter(|| black_box(&mask).iter_set_bits().sum::<u32>())
+    });
+    group.bench_function("50_of_128k_is_allowed", |b| {
+        b.iter(|| {
+            let mask = black_box(&mask);
+            (0..mask.len() as u32)
+                .filter(|&t| mask.is_allowed(t))
+                .sum::<u32>()
+        })
+    });
+    group.finish();
+}
+
+/// The length table against `token()` for every token.
+fn tokens_with_len_at_most(c: &mut Criterion) {
+    let trie = trie(128_000);
+    let naive = |n: usize| {
+        let mut res = trie.alloc_token_set();
+        for t in 0..trie.vocab_size() as TokenId {
+            if trie.token(t).len() <= n {
+                res.allow_token(t);
+            }
+        }
+        res
+    };
+    assert_eq!(trie.tokens_with_len_at_most(4), naive(4));
+    let mut group = c.benchmark_group("tokens_with_len_at_most");
+    group.bench_function("naive", |b| b.iter(|| naive(black_box(4))));
+    group.bench_function("table", |b| {
+        b.iter(|| trie.tokens_with_len_at_most(black_box(4)))
+    });
+    group.finish();
+}
+
+/// `sorted_tokens()` as it was, walking the node array by hand.
+fn sorted_tokens_by_hand(trie: &TokTrie) -> Vec<(u32, Vec<u8>)> {
+    let mut res = vec![];
+    let endp = trie.root().subtree_size();
+    let mut next_pop = 0;
+    let mut bytes = vec![];
+    for p in 1..endp {
+        bytes.truncate(bytes.len() - next_pop);
+        let n = trie.node_at_offset(p);
+        bytes.push(n.byte());
+        if let Some(t) = n.token_id() {
+            res.push((t, bytes.clone()));
+        }
+        next_pop = if n.subtree_size() == 1 {
+            n.num_parents()
+        } else {
+            0
+        };
+    }
+    res
+}
+
+fn sorted_tokens(c: &mut Criterion) {
+    let trie = trie(128_000);
+    assert_eq!(trie.sorted_tokens(), sorted_tokens_by_hand(&trie));
+    let mut group = c.benchmark_group("sorted_tokens");
+    group.sample_size(20);
+    group.bench_function("by_hand", |b| {
+        b.iter(|| sorted_tokens_by_hand(black_box(&trie)))
+    });
+    group.bench_function("walk", |b| b.iter(|| black_box(&trie).sorted_tokens()));
+    group.finish();
+}
+
+fn greedy_tokenize(c: &mut Criterion) {
+    let trie = trie(32_000);
+    let text = synthetic_text(4 << 20, 2);
+    let mut group = c.benchmark_group("greedy_tokenize");
+    group.throughput(Throughput::Bytes(text.len() as u64));
+    group.sample_size(10);
+    group.bench_function("4MB", |b| b.iter(|| trie.greedy_tokenize(black_box(&text))));
+    group.finish();
+}
+
+/// `decode()` as it was, a `Vec` per token and a `retain()`.
+fn decode_flat_map(trie: &TokTrie, tokens: &[TokenId]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = tokens
+        .iter()
+        .flat_map(|t| trie.token(*t).to_vec())
+        .collect();
+    if bytes.contains(&TokTrie::SPECIAL_TOKEN_PREFIX_BYTE) {
+        bytes.retain(|&b| b != TokTrie::SPECIAL_TOKEN_PREFIX_BYTE);
+    }
+    bytes
+}
+
+fn decode(c: &mut Criterion) {
+    let trie = trie(32_000);
+    let mut tokens = trie.greedy_tokenize(&synthetic_text(100_000, 5));
+    tokens.truncate(10_000);
+    assert_eq!(tokens.len(), 10_000);
+    assert_eq!(trie.decode(&tokens), decode_flat_map(&trie, &tokens));
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Elements(tokens.len() as u64));
+    group.bench_function("flat_map", |b| {
+        b.iter(|| decode_flat_map(&trie, black_box(&tokens)))
+    });
+    group.bench_function("decode", |b| b.iter(|| trie.decode(black_box(&tokens))));
+    group.bench_function("decode_raw", |b| {
+        b.iter(|| trie.decode_raw(black_box(&tokens)))
+    });
+    let mut buf = Vec::new();
+    group.bench_function("decode_into", |b| {
+        b.iter(|| {
+            buf.clear();
+            trie.decode_into(black_box(&tokens), &mut buf)
+        })
+    });
+    group.bench_function("decode_raw_into", |b| {
+        b.iter(|| {
+            buf.clear();
+            trie.decode_raw_into(black_box(&tokens), &mut buf)
+        })
+    });
+    group.finish();
+}
+
+/// Accepts everything; the stack is just its depth.
+#[derive(Default)]
+struct AcceptAll {
+    len: usize,
+    base: usize,
+}
+
+impl Recognizer for AcceptAll {
+    fn pop_bytes(&mut self, num: usize) {
+        self.len -= num;
+    }
+    fn collapse(&mut self) {
+        self.base = self.len;
+    }
+    fn special_allowed(&mut self, _tok: SpecialToken) -> bool {
+        true
+    }
+    fn trie_finished(&mut self) {}
+    fn try_push_byte(&mut self, _byte: u8) -> bool {
+        self.len += 1;
+        true
+    }
+    fn try_push_bytes(&mut self, bytes: &[u8]) -> usize {
+        self.len += bytes.len();
+        bytes.len()
+    }
+}
+
+/// Forwards to the inner recognizer, but with the default `try_push_bytes()`,
+/// which calls it for every byte.
+struct ByteAtATime<R>(R);
+
+impl<R: Recognizer> Recognizer for ByteAtATime<R> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.0.pop_bytes(num)
+    }
+    fn collapse(&mut self) {
+        self.0.collapse()
+    }
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        self.0.special_allowed(tok)
+    }
+    fn trie_finished(&mut self) {
+        self.0.trie_finished()
+    }
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        self.0.try_push_byte(byte)
+    }
+}
+
+/// Tokens of 32 to 64 bytes, through `dyn Recognizer`, as for recognizers picked at run time.
+fn append_tokens(c: &mut Criterion) {
+    let mut words = synthetic_vocab(32_000, 1);
+    let text = synthetic_text(100_000, 7);
+    let mut rng = Rng::new(7);
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while tokens.len() < 2000 {
+        let len = 32 + rng.gen_up_to(32);
+        tokens.push(words.len() as TokenId);
+        words.push(text[pos..pos + len].to_vec());
+        pos += len;
+    }
+    let trie = TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words);
+    let num_bytes = pos;
+    let mut group = c.benchmark_group("append_tokens");
+    group.throughput(Throughput::Bytes(num_bytes as u64));
+    group.bench_function("try_push_bytes", |b| {
+        b.iter(|| {
+            let mut r = AcceptAll::default();
+            trie.append_tokens(&mut (&mut r as &mut dyn Recognizer), black_box(&tokens))
+                .unwrap();
+            assert_eq!(r.base, num_bytes);
+        })
+    });
+    group.bench_function("try_push_byte", |b| {
+        b.iter(|| {
+            let mut r = AcceptAll::default();
+            let mut by_byte = ByteAtATime(&mut r as &mut dyn Recognizer);
+            trie.append_tokens(&mut by_byte, black_box(&tokens))
+                .unwrap();
+            assert_eq!(r.base, num_bytes);
+        })
+    });
+    group.finish();
+}
+
+fn from_bytes(c: &mut Criterion) {
+    let bytes = trie(128_000).serialize();
+    let mut group = c.benchmark_group("from_bytes");
+    group.sample_size(20);
+    group.bench_function("128k", |b| {
+        b.iter(|| TokTrie::from_bytes(black_box(&bytes)))
+    });
+    group.finish();
+}
+
+/// Adding 64 tokens to a 128k trie, against building it again with all of them.
+fn extend(c: &mut Criterion) {
+    let mut words = synthetic_vocab(128_000, 1);
+    let trie = TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words);
+    let text = synthetic_text(10_000, 6);
+    let new_tokens = (0..64)
+        .map(|i| {
+            let w = text[i * 100..i * 100 + 4 + i % 8].to_vec();
+            ((words.len() + i) as TokenId, w)
+        })
+        .collect::<Vec<_>>();
+    words.extend(new_tokens.iter().map(|(_, w)| w.clone()));
+    let info = TokRxInfo::new(words.len() as u32, 0);
+    let mut extended = trie.clone();
+    extended.extend(&new_tokens).unwrap();
+    assert_eq!(
+        extended.serialize(),
+        TokTrie::from(&info, &words).serialize()
+    );
+
+    let mut group = c.benchmark_group("extend");
+    group.sample_size(10);
+    group.bench_function("rebuild", |b| {
+        b.iter(|| TokTrie::from(&info, black_box(&words)))
+    });
+    group.bench_function("extend", |b| {
+        b.iter_batched(
+            || trie.clone(),
+            |mut t| t.extend(black_box(&new_tokens)).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn chop_tokens(c: &mut Criterion) {
+    let trie = trie(32_000);
+    let tokens = trie.greedy_tokenize(&synthetic_text(1000, 3));
+    let mut group = c.benchmark_group("chop_tokens");
+    for (name, f) in RECOGNIZERS {
+        let mut r = StackRecognizer::from(ByteFilter(f));
+        group.bench_function(name, |b| {
+            b.iter(|| trie.chop_tokens(&mut r, black_box(&tokens)))
+        });
+        let mut scratch = trie.new_scratch();
+        group.bench_function(format!("{}_with_scratch", name), |b| {
+            b.iter(|| trie.chop_tokens_with_scratch(&mut r, black_box(&tokens), &mut scratch))
+        });
+    }
+    group.finish();
+}
+
+/// `chop_tokens()` as it was, growing the suffix at the front, and checking all of them.
+fn chop_tokens_splice(
+    trie: &TokTrie,
+    r: &mut impl Recognizer,
+    tokens: &[TokenId],
+) -> (usize, usize) {
+    let mut suff = Vec::new();
+    let mut chop_tokens = 0;
+    let mut chop_bytes = 0;
+    for (idx, t) in tokens.iter().rev().enumerate() {
+        suff.splice(0..0, trie.token(*t).iter().cloned());
+        if suff.len() > trie.max_token_len() {
+            break;
+        }
+        if trie.has_valid_extensions(r, &suff) {
+            chop_tokens = idx + 1;
+            chop_bytes = suff.len();
+        }
+    }
+    (chop_tokens, chop_bytes)
+}
+
+/// With 2000 tokens of 16 to 64 bytes of text added, so that `max_token_len()` is 64;
+/// `chop_tokens()` against the version above.
+fn chop_tokens_64(c: &mut Criterion) {
+    let mut words = synthetic_vocab(32_000, 1);
+    let text = synthetic_text(200_000, 4);
+    let mut rng = Rng::new(4);
+    let mut pos = 0;
+    for len in (0..2000).map(|i| if i == 0 { 64 } else { 16 + rng.gen_up_to(48) }) {
+        words.push(text[pos..pos + len].to_vec());
+        pos += len;
+    }
+    let trie = TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words);
+    assert_eq!(trie.max_token_len(), 64);
+    // ending in the middle of a long token
+    let tokens = trie.greedy_tokenize(&text[..pos - 10]);
+    let mut group = c.benchmark_group("chop_tokens_64");
+    for (name, f) in RECOGNIZERS {
+        let mut r = StackRecognizer::from(ByteFilter(f));
+        assert_eq!(
+            trie.chop_tokens(&mut r, &tokens),
+            chop_tokens_splice(&trie, &mut r, &tokens)
+        );
+        group.bench_function(BenchmarkId::new(name, "splice"), |b| {
+            b.iter(|| chop_tokens_splice(&trie, &mut r, black_box(&tokens)))
+        });
+        group.bench_function(BenchmarkId::new(name, "chop_tokens"), |b| {
+            b.iter(|| trie.chop_tokens(&mut r, black_box(&tokens)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    compute_bias,
+    compute_bias_with_stats,
+    compute_bias_parallel,
+    compute_bias_within,
+    apply_duplicates,
+    iter_set_bits,
+    tokens_with_len_at_most,
+    sorted_tokens,
+    greedy_tokenize,
+    decode,
+    append_tokens,
+    from_bytes,
+    extend,
+    chop_tokens,
+    chop_tokens_64
+);
+criterion_main!(benches);
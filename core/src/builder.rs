@@ -0,0 +1,454 @@
+use alloc::{
+    collections::BTreeSet,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::ops::Bound;
+
+use anyhow::{bail, ensure, Result};
+
+use crate::{
+    toktree::{push_token, TokRxInfo, TokTrie, TokenId, NO_TOKEN},
+    FxHashMap, FxHashSet,
+};
+
+/// What to do when two tokens have identical bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum DuplicatePolicy {
+    /// The trie node gets the token added last; others become its duplicates.
+    /// This is what `TokTrie::from()` does.
+    #[default]
+    KeepLast,
+    /// The trie node gets the token added first; others become its duplicates.
+    KeepFirst,
+    /// Fail in `build()`.
+    Reject,
+}
+
+/// Incrementally assembles a vocabulary and produces a `TokTrie`.
+///
+/// Token ids are assigned sequentially, in the order tokens are added.
+/// Special tokens are added by name, and get `TokTrie::SPECIAL_TOKEN_PREFIX_BYTE`
+/// prepended automatically. Only these are special tokens in the trie;
+/// see `TokTrie::from_ext()`.
+#[derive(Clone, Debug, Default)]
+pub struct TokTrieBuilder {
+    words: Vec<Vec<u8>>,
+    special: FxHashMap<String, TokenId>,
+    vocab_size: Option<u32>,
+    duplicates: DuplicatePolicy,
+    tok_eos: Option<TokenId>,
+    tok_bos: Option<TokenId>,
+    tok_pad: Option<TokenId>,
+    tok_unk: Option<TokenId>,
+    tok_end_of_turn: Option<TokenId>,
+}
+
+impl TokTrieBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of tokens added so far; this is also the id of the next token.
+    pub fn num_tokens(&self) -> usize {
+        self.words.len()
+    }
+
+    /// Add a regular token and return its id.
+    /// Empty tokens are allowed; they occupy an id but are not present in the trie.
+    pub fn add_token(&mut self, bytes: &[u8]) -> Result<TokenId> {
+        let id = self.words.len() as TokenId;
+        self.words.push(bytes.to_vec());
+        Ok(id)
+    }
+
+    pub fn add_tokens(&mut self, words: &[Vec<u8>]) -> Result<()> {
+        for w in words {
+            self.add_token(w)?;
+        }
+        Ok(())
+    }
+
+    /// Add a special token, like `<|endoftext|>`, and return its id.
+    pub fn add_special_token(&mut self, name: &str) -> Result<TokenId> {
+        ensure!(!name.is_empty(), "empty special token name");
+        if let Some(id) = self.special.get(name) {
+            bail!("duplicate special token {:?} (already id {})", name, id);
+        }
+        let mut bytes = Vec::with_capacity(name.len() + 1);
+        bytes.push(TokTrie::SPECIAL_TOKEN_PREFIX_BYTE);
+        bytes.extend_from_slice(name.as_bytes());
+        let id = self.add_token(&bytes)?;
+        self.special.insert(name.to_string(), id);
+        Ok(id)
+    }
+
+    /// Id of a special token previously added with `add_special_token()`.
+    pub fn special_token(&self, name: &str) -> Option<TokenId> {
+        self.special.get(name).copied()
+    }
+
+    /// Expected vocabulary size; `build()` fails if a different number of tokens was added.
+    pub fn vocab_size(&mut self, vocab_size: u32) -> &mut Self {
+        self.vocab_size = Some(vocab_size);
+        self
+    }
+
+    pub fn duplicate_policy(&mut self, policy: DuplicatePolicy) -> &mut Self {
+        self.duplicates = policy;
+        self
+    }
+
+    pub fn tok_eos(&mut self, tok: TokenId) -> &mut Self {
+        self.tok_eos = Some(tok);
+        self
+    }
+
+    pub fn tok_bos(&mut self, tok: TokenId) -> &mut Self {
+        self.tok_bos = Some(tok);
+        self
+    }
+
+    pub fn tok_pad(&mut self, tok: TokenId) -> &mut Self {
+        self.tok_pad = Some(tok);
+        self
+    }
+
+    pub fn tok_unk(&mut self, tok: TokenId) -> &mut Self {
+        self.tok_unk = Some(tok);
+        self
+    }
+
+    pub fn tok_end_of_turn(&mut self, tok: TokenId) -> &mut Self {
+        self.tok_end_of_turn = Some(tok);
+        self
+    }
+
+    pub fn build(&self) -> Result<TokTrie> {
+        let num_tokens = self.words.len() as u32;
+        if let Some(vocab_size) = self.vocab_size {
+            ensure!(
+                vocab_size == num_tokens,
+                "vocab size mismatch: expected {}, got {} tokens",
+                vocab_size,
+                num_tokens
+            );
+        }
+
+        let tok_eos = match self.tok_eos {
+            Some(t) => t,
+            None => bail!("EOS token not set"),
+        };
+        for (name, tok) in [
+            ("eos", Some(tok_eos)),
+            ("bos", self.tok_bos),
+            ("pad", self.tok_pad),
+            ("unk", self.tok_unk),
+            ("end_of_turn", self.tok_end_of_turn),
+        ] {
+            if let Some(tok) = tok {
+                ensure!(
+                    tok < num_tokens,
+                    "{} token {} out of range (vocab size {})",
+                    name,
+                    tok,
+                    num_tokens
+                );
+            }
+        }
+
+        if self.duplicates == DuplicatePolicy::Reject {
+            let mut seen = FxHashSet::default();
+            for (idx, w) in self.words.iter().enumerate() {
+                if !w.is_empty() && !seen.insert(w.as_slice()) {
+                    bail!("token {} duplicates an earlier token", idx);
+                }
+            }
+        }
+
+        let info = TokRxInfo {
+            vocab_size: num_tokens,
+            tok_eos,
+            tok_bos: self.tok_bos,
+            tok_pad: self.tok_pad,
+            tok_unk: self.tok_unk,
+            tok_end_of_turn: self.tok_end_of_turn,
+        };
+        // tokens added with add_token() are never special, whatever their bytes
+        let mut special_tokens = self.special.values().copied().collect::<Vec<_>>();
+        special_tokens.sort_unstable();
+        TokTrie::try_from_words(
+            &info,
+            &self.words,
+            self.duplicates == DuplicatePolicy::KeepFirst,
+            Some(&special_tokens),
+        )
+    }
+}
+
+/// How far a `TokTrieBuilderIncremental` got.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct BuildProgress {
+    /// Tokens given to `feed()`.
+    pub tokens_inserted: usize,
+    /// Trie nodes for these tokens, the root included. The trie can end up with
+    /// a few more, as nodes with over 250 children get all 256.
+    pub nodes_so_far: usize,
+    /// Tokens done by `finish_step()`, out of the vocab size.
+    pub tokens_finished: usize,
+}
+
+/// Builds a `TokTrie` a bit at a time, for applications that report progress or keep
+/// an event loop going while a large vocabulary is built: tokens are given to `feed()`
+/// in batches, and then `finish_step()` is called until it returns the trie.
+///
+/// The trie is the same as `TokTrie::from()` with all the tokens in order of their ids
+/// (or `TokTrie::from_sparse()`), whatever order they were fed in.
+/// Dropping the builder at any point is fine.
+#[derive(Clone, Debug, Default)]
+pub struct TokTrieBuilderIncremental {
+    // indexed by token id; ids not given are empty
+    words: Vec<Vec<u8>>,
+    given: Vec<bool>,
+    // the distinct non-empty tokens, to count nodes; dropped once finishing
+    sorted: BTreeSet<Vec<u8>>,
+    num_prefixes: usize,
+    num_inserted: usize,
+    // as given to the first finish_step()
+    info: Option<TokRxInfo>,
+    token_offsets: Vec<u32>,
+    token_data: Vec<u8>,
+    finished: bool,
+}
+
+impl TokTrieBuilderIncremental {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add tokens, given with their ids, in any order. Ids that are never given
+    /// get empty tokens. Giving an id twice is an error, as is calling this after
+    /// `finish_step()`; the tokens of the batch before the failing one are kept.
+    pub fn feed(&mut self, words: &[(TokenId, &[u8])]) -> Result<()> {
+        ensure!(self.info.is_none(), "feed() called after finish_step()");
+        for &(tok, bytes) in words {
+            ensure!(tok < NO_TOKEN - 1, "token id {} too large", tok);
+            let idx = tok as usize;
+            if idx >= self.words.len() {
+                self.words.resize(idx + 1, Vec::new());
+                self.given.resize(idx + 1, false);
+            }
+            ensure!(!self.given[idx], "token {} given more than once", tok);
+            self.given[idx] = true;
+            self.words[idx] = bytes.to_vec();
+            self.num_inserted += 1;
+
+            // a new token adds a node for every byte past what it shares
+            // with its neighbors in byte order
+            if !bytes.is_empty() && !self.sorted.contains(bytes) {
+                let common = |w: Option<&Vec<u8>>| {
+                    w.map_or(0, |w| {
+                        w.iter().zip(bytes).take_while(|(a, b)| a == b).count()
+                    })
+                };
+                let before = self
+                    .sorted
+                    .range::<[u8], _>((Bound::Unbounded, Bound::Excluded(bytes)))
+                    .next_back();
+                let after = self
+                    .sorted
+                    .range::<[u8], _>((Bound::Excluded(bytes), Bound::Unbounded))
+                    .next();
+                self.num_prefixes += bytes.len() - common(before).max(common(after));
+                self.sorted.insert(bytes.to_vec());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn progress(&self) -> BuildProgress {
+        BuildProgress {
+            tokens_inserted: self.num_inserted,
+            nodes_so_far: self.num_prefixes + 1,
+            tokens_finished: if self.finished {
+                self.words.len()
+            } else {
+                self.token_offsets.len()
+            },
+        }
+    }
+
+    /// Build the trie; see `finish_step()` for `info`.
+    pub fn finish(mut self, info: &TokRxInfo) -> Result<TokTrie> {
+        loop {
+            if let Some(trie) = self.finish_step(info, usize::MAX)? {
+                return Ok(trie);
+            }
+        }
+    }
+
+    /// Do the work of `finish()` for up to `max_tokens` more tokens, returning the trie
+    /// once done; the last step, which builds the nodes and checks the trie, is not split.
+    /// When `info.vocab_size` is 0, it's taken to be the largest id plus one.
+    /// All the calls have to give the same `info`.
+    pub fn finish_step(&mut self, info: &TokRxInfo, max_tokens: usize) -> Result<Option<TokTrie>> {
+        ensure!(
+            !self.finished,
+            "finish_step() called after the trie was built"
+        );
+        match self.info {
+            Some(prev) => ensure!(prev == *info, "finish_step() called with another info"),
+            None => {
+                let vocab_size = match info.vocab_size {
+                    0 => self.words.len(),
+                    n => n as usize,
+                };
+                ensure!(
+                    self.words.len() <= vocab_size,
+                    "token {} out of range (vocab size {})",
+                    self.words.len() - 1,
+                    vocab_size
+                );
+                self.words.resize(vocab_size, Vec::new());
+                self.given = Vec::new();
+                self.sorted = BTreeSet::new();
+                self.info = Some(*info);
+            }
+        }
+
+        let start = self.token_offsets.len();
+        let end = self
+            .words
+            .len()
+            .min(start.saturating_add(max_tokens.max(1)));
+        for w in &self.words[start..end] {
+            push_token(&mut self.token_offsets, &mut self.token_data, w)?;
+        }
+        if end < self.words.len() {
+            return Ok(None);
+        }
+
+        self.finished = true;
+        let info = TokRxInfo {
+            vocab_size: self.words.len() as u32,
+            ..*info
+        };
+        let trie = TokTrie::from_encoded_words(
+            &info,
+            &self.words,
+            core::mem::take(&mut self.token_offsets),
+            core::mem::take(&mut self.token_data),
+            false,
+            None,
+        )?;
+        Ok(Some(trie))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::{rng::Rng, testing::synthetic_vocab};
+
+    #[test]
+    fn incremental_matches_from() {
+        let size = 5000;
+        let words = synthetic_vocab(size, 3);
+        let info = TokRxInfo::new(size as u32, 0);
+        let expected = TokTrie::from(&info, &words);
+
+        // shuffled, in batches of varying size
+        let mut order = (0..size).collect::<Vec<_>>();
+        let mut rng = Rng::new(7);
+        for i in (1..size).rev() {
+            order.swap(i, rng.gen_up_to(i));
+        }
+        let mut b = TokTrieBuilderIncremental::new();
+        let mut fed = 0;
+        while fed < size {
+            let n = (1 + rng.gen_up_to(300)).min(size - fed);
+            let batch = order[fed..fed + n]
+                .iter()
+                .map(|&t| (t as TokenId, words[t].as_slice()))
+                .collect::<Vec<_>>();
+            b.feed(&batch).unwrap();
+            fed += n;
+            assert_eq!(b.progress().tokens_inserted, fed);
+        }
+        // the root has every byte but 0, and gets an empty leaf for it
+        assert_eq!(b.progress().nodes_so_far + 1, expected.stats().num_nodes);
+
+        let mut steps = 0;
+        let trie = loop {
+            if let Some(trie) = b.finish_step(&info, 700).unwrap() {
+                break trie;
+            }
+            steps += 1;
+            assert_eq!(b.progress().tokens_finished, steps * 700);
+        };
+        assert_eq!(steps, size / 700);
+        assert_eq!(b.progress().tokens_finished, size);
+        assert_eq!(trie.serialize(), expected.serialize());
+    }
+
+    #[test]
+    fn progress_and_vocab_size() {
+        let mut b = TokTrieBuilderIncremental::new();
+        assert_eq!(
+            b.progress(),
+            BuildProgress {
+                nodes_so_far: 1,
+                ..BuildProgress::default()
+            }
+        );
+        // "ab" and "abc" share a node with "a"; "b" is a duplicate
+        b.feed(&[(4, b"abc"), (1, b"a")]).unwrap();
+        b.feed(&[(2, b"ab"), (3, b"b"), (0, b"b")]).unwrap();
+        assert_eq!(
+            b.progress(),
+            BuildProgress {
+                tokens_inserted: 5,
+                nodes_so_far: 5,
+                tokens_finished: 0,
+            }
+        );
+        // id 5 is never given
+        let info = TokRxInfo::new(6, 1);
+        let trie = b.clone().finish(&info).unwrap();
+        let words = [&b"b"[..], b"a", b"ab", b"b", b"abc", b""]
+            .iter()
+            .map(|w| w.to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(trie.serialize(), TokTrie::from(&info, &words).serialize());
+
+        // the vocab size is taken from the ids
+        let trie = b.finish(&TokRxInfo::new(0, 1)).unwrap();
+        assert_eq!(trie.vocab_size(), 5);
+    }
+
+    #[test]
+    fn incremental_errors() {
+        let mut b = TokTrieBuilderIncremental::new();
+        b.feed(&[(0, b"a"), (1, b"b")]).unwrap();
+        let err = b.feed(&[(2, b"c"), (1, b"d")]).unwrap_err();
+        assert_eq!(err.to_string(), "token 1 given more than once");
+        // the batch is kept up to the failing token
+        assert_eq!(b.progress().tokens_inserted, 3);
+        assert!(b.feed(&[(NO_TOKEN, b"x")]).is_err());
+
+        assert!(b.clone().finish(&TokRxInfo::new(2, 0)).is_err());
+        let info = TokRxInfo::new(10, 0);
+        assert!(b.finish_step(&info, 1).unwrap().is_none());
+        assert_eq!(
+            b.feed(&[(3, b"d")]).unwrap_err().to_string(),
+            "feed() called after finish_step()"
+        );
+        assert!(b.finish_step(&TokRxInfo::new(10, 1), 1).is_err());
+        // dropping a partly finished builder is fine
+        drop(b.clone());
+        assert!(b.finish_step(&info, 100).unwrap().is_some());
+        assert!(b.finish_step(&info, 100).is_err());
+    }
+}
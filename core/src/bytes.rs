@@ -1,6 +1,13 @@
-use std::mem::size_of;
+use alloc::{
+    borrow::Cow,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::mem::size_of;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, ensure, Result};
 use bytemuck::{NoUninit, Pod};
 use bytemuck_derive::{Pod, Zeroable};
 
@@ -12,7 +19,13 @@ pub fn clone_vec_as_bytes<T: NoUninit>(input: &[T]) -> Vec<u8> {
     bytemuck::cast_slice(input).to_vec()
 }
 
+/// `bytes` don't need to be aligned.
 pub fn vec_from_bytes<T: Pod>(bytes: &[u8]) -> Vec<T> {
+    vec_or_slice_from_bytes(bytes).into_owned()
+}
+
+/// Reinterpret `bytes` in place if they are suitably aligned, otherwise copy them.
+pub fn vec_or_slice_from_bytes<T: Pod>(bytes: &[u8]) -> Cow<'_, [T]> {
     if bytes.len() % size_of::<T>() != 0 {
         panic!(
             "vecT: got {} bytes, needed multiple of {}",
@@ -20,7 +33,22 @@ pub fn vec_from_bytes<T: Pod>(bytes: &[u8]) -> Vec<T> {
             size_of::<T>()
         );
     }
-    bytemuck::cast_slice(bytes).to_vec()
+    match bytemuck::try_cast_slice(bytes) {
+        Ok(slice) => Cow::Borrowed(slice),
+        Err(_) => {
+            let mut r = vec![T::zeroed(); bytes.len() / size_of::<T>()];
+            bytemuck::cast_slice_mut(&mut r).copy_from_slice(bytes);
+            Cow::Owned(r)
+        }
+    }
+}
+
+/// Reverse the byte order of every u32 word in `bytes`.
+pub fn swap_u32_bytes(bytes: &mut [u8]) {
+    assert!(bytes.len() % 4 == 0);
+    for w in bytes.chunks_exact_mut(4) {
+        w.reverse();
+    }
 }
 
 pub fn limit_str(s: &str, max_len: usize) -> String {
@@ -55,3 +83,30 @@ pub fn from_hex_string(s: &str) -> Result<Vec<u8>> {
     }
     Ok(result)
 }
+
+/// Decodes standard base64 (`+` and `/`), with or without `=` padding.
+pub fn from_base64(s: &str) -> Result<Vec<u8>> {
+    let s = s.trim_end_matches('=');
+    let mut result = Vec::with_capacity(s.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut num_bits = 0;
+    for c in s.bytes() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => bail!("invalid base64 char {:?}", c as char),
+        };
+        acc = (acc << 6) | v as u32;
+        num_bits += 6;
+        if num_bits >= 8 {
+            num_bits -= 8;
+            result.push((acc >> num_bits) as u8);
+            acc &= (1 << num_bits) - 1;
+        }
+    }
+    ensure!(num_bits < 6, "invalid base64 length {}", s.len());
+    Ok(result)
+}
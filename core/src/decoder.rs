@@ -0,0 +1,176 @@
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use crate::toktree::{TokTrie, TokenId};
+
+type SpecialCallback<'a> = Box<dyn FnMut(TokenId, &str) + 'a>;
+
+/// Decodes tokens one at a time, only emitting complete UTF-8 characters;
+/// see `TokTrie::decoder()`.
+///
+/// The concatenation of all the outputs, including `flush()`,
+/// is the same as `TokTrie::decode_str()` of all the tokens.
+pub struct StreamDecoder<'a> {
+    trie: &'a TokTrie,
+    pending: Vec<u8>,
+    on_special: Option<SpecialCallback<'a>>,
+}
+
+impl<'a> StreamDecoder<'a> {
+    pub(crate) fn new(trie: &'a TokTrie) -> Self {
+        StreamDecoder {
+            trie,
+            pending: Vec::new(),
+            on_special: None,
+        }
+    }
+
+    /// Call `f` with the token id and name (without the prefix byte)
+    /// whenever a special token is pushed.
+    pub fn with_special_callback(mut self, f: impl FnMut(TokenId, &str) + 'a) -> Self {
+        self.on_special = Some(Box::new(f));
+        self
+    }
+
+    /// Add a token, and return the text that is now complete.
+    pub fn push_token(&mut self, t: TokenId) -> String {
+        let bytes = self.trie.token(t);
+        if self.trie.is_special_token(t) {
+            if let Some(f) = self.on_special.as_mut() {
+                f(t, &String::from_utf8_lossy(&bytes[1..]));
+            }
+            self.pending.extend_from_slice(&bytes[1..]);
+        } else {
+            self.pending.extend_from_slice(bytes);
+        }
+        self.take_complete()
+    }
+
+    /// Bytes of an incomplete UTF-8 character, waiting for the next token.
+    pub fn pending_bytes(&self) -> &[u8] {
+        &self.pending
+    }
+
+    /// Return whatever is left, with incomplete characters replaced by U+FFFD.
+    pub fn flush(&mut self) -> String {
+        let r = String::from_utf8_lossy(&self.pending).to_string();
+        self.pending.clear();
+        r
+    }
+
+    // like from_utf8_lossy(), but keeps a possibly incomplete character at the end
+    fn take_complete(&mut self) -> String {
+        let mut res = String::new();
+        let mut buf = &self.pending[..];
+        loop {
+            match core::str::from_utf8(buf) {
+                Ok(s) => {
+                    res.push_str(s);
+                    buf = &[];
+                    break;
+                }
+                Err(e) => {
+                    let (valid, rest) = buf.split_at(e.valid_up_to());
+                    res.push_str(core::str::from_utf8(valid).unwrap());
+                    match e.error_len() {
+                        Some(n) => {
+                            res.push('\u{fffd}');
+                            buf = &rest[n..];
+                        }
+                        None => {
+                            buf = rest;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        self.pending = buf.to_vec();
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::String, vec, vec::Vec};
+    use core::cell::RefCell;
+
+    use crate::{TokRxInfo, TokTrie, TokenId};
+
+    fn trie() -> TokTrie {
+        let words = [
+            &b"\xff<eos>"[..],
+            b"a",
+            b"\xe2",
+            b"\x9d",
+            b"\xa4",
+            b"\xf0",
+            b"\x9f\x98",
+            b"\x80",
+            b"\xff<sep>",
+            b" b",
+        ]
+        .iter()
+        .map(|w| w.to_vec())
+        .collect::<Vec<_>>();
+        TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words)
+    }
+
+    // the output of each push_token(), and of flush()
+    fn outputs(trie: &TokTrie, tokens: &[TokenId]) -> Vec<String> {
+        let mut d = trie.decoder();
+        let mut res = tokens.iter().map(|&t| d.push_token(t)).collect::<Vec<_>>();
+        res.push(d.flush());
+        assert_eq!(res.concat(), trie.decode_str(tokens));
+        res
+    }
+
+    #[test]
+    fn split_characters() {
+        let trie = trie();
+        // U+2764, one byte per token
+        assert_eq!(
+            outputs(&trie, &[1, 2, 3, 4, 1]),
+            ["a", "", "", "\u{2764}", "a", ""]
+        );
+        // U+1F600 in three tokens
+        assert_eq!(outputs(&trie, &[5, 6, 7]), ["", "", "\u{1f600}", ""]);
+        let mut d = trie.decoder();
+        d.push_token(5);
+        d.push_token(6);
+        assert_eq!(d.pending_bytes(), b"\xf0\x9f\x98");
+        // cut off
+        assert_eq!(d.flush(), "\u{fffd}");
+        assert_eq!(d.pending_bytes(), b"");
+        // a byte that can't start a character
+        assert_eq!(outputs(&trie, &[4, 1]), ["\u{fffd}", "a", ""]);
+    }
+
+    #[test]
+    fn special_tokens_mid_stream() {
+        let trie = trie();
+        let seen = RefCell::new(vec![]);
+        let mut d = trie
+            .decoder()
+            .with_special_callback(|t, name| seen.borrow_mut().push((t, String::from(name))));
+        let res = [1, 8, 9, 0]
+            .iter()
+            .map(|&t| d.push_token(t))
+            .collect::<Vec<_>>();
+        assert_eq!(res, ["a", "<sep>", " b", "<eos>"]);
+        drop(d);
+        assert_eq!(
+            *seen.borrow(),
+            [(8, String::from("<sep>")), (0, String::from("<eos>"))]
+        );
+
+        // in the middle of a character, which is then incomplete
+        assert_eq!(
+            outputs(&trie, &[2, 3, 8, 4]),
+            ["", "", "\u{fffd}<sep>", "\u{fffd}", ""]
+        );
+    }
+}
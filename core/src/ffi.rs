@@ -0,0 +1,275 @@
+//! C API for loading a trie and computing token masks, declared in `toktrie.h`
+//! (keep the two in sync). See the `cffi` feature in `Cargo.toml` for building
+//! the C libraries.
+//!
+//! Functions return `TOKTRIE_OK` or one of the negative `TOKTRIE_ERR_*` codes;
+//! panics are caught and reported as `TOKTRIE_ERR_PANIC`.
+
+use core::ffi::c_void;
+use std::{
+    boxed::Box,
+    panic::{catch_unwind, AssertUnwindSafe},
+};
+
+use crate::{
+    toktree::{Recognizer, SpecialToken},
+    TokTrie, TokenId,
+};
+
+pub const TOKTRIE_OK: i32 = 0;
+/// A required pointer (or callback) was null.
+pub const TOKTRIE_ERR_NULL: i32 = -1;
+/// An output buffer is too small; the required size is reported where possible.
+pub const TOKTRIE_ERR_SIZE: i32 = -2;
+/// A token id is out of range, or bytes could not be tokenized.
+pub const TOKTRIE_ERR_INVALID: i32 = -3;
+pub const TOKTRIE_ERR_PANIC: i32 = -4;
+
+// values passed to TokTrieRecognizer::special_allowed
+pub const TOKTRIE_SPECIAL_UNKNOWN: u32 = 0;
+pub const TOKTRIE_SPECIAL_PADDING: u32 = 1;
+pub const TOKTRIE_SPECIAL_SEPARATOR: u32 = 2;
+pub const TOKTRIE_SPECIAL_BEGINNING_OF_SENTENCE: u32 = 3;
+pub const TOKTRIE_SPECIAL_END_OF_SENTENCE: u32 = 4;
+pub const TOKTRIE_SPECIAL_END_OF_TURN: u32 = 5;
+
+/// A `Recognizer` implemented in C; see the `Recognizer` trait for the semantics.
+/// `trie_started` and `trie_finished` may be null; the other callbacks are required.
+/// Every callback gets `user_data` as the first argument.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct TokTrieRecognizer {
+    pub user_data: *mut c_void,
+    pub try_push_byte: Option<unsafe extern "C" fn(*mut c_void, u8) -> bool>,
+    pub pop_bytes: Option<unsafe extern "C" fn(*mut c_void, usize)>,
+    pub collapse: Option<unsafe extern "C" fn(*mut c_void)>,
+    pub special_allowed: Option<unsafe extern "C" fn(*mut c_void, u32) -> bool>,
+    pub trie_started: Option<unsafe extern "C" fn(*mut c_void)>,
+    pub trie_finished: Option<unsafe extern "C" fn(*mut c_void)>,
+}
+
+struct CRecognizer {
+    user_data: *mut c_void,
+    try_push_byte: unsafe extern "C" fn(*mut c_void, u8) -> bool,
+    pop_bytes: unsafe extern "C" fn(*mut c_void, usize),
+    collapse: unsafe extern "C" fn(*mut c_void),
+    special_allowed: unsafe extern "C" fn(*mut c_void, u32) -> bool,
+    trie_started: Option<unsafe extern "C" fn(*mut c_void)>,
+    trie_finished: Option<unsafe extern "C" fn(*mut c_void)>,
+}
+
+impl CRecognizer {
+    fn new(cb: &TokTrieRecognizer) -> Option<Self> {
+        Some(CRecognizer {
+            user_data: cb.user_data,
+            try_push_byte: cb.try_push_byte?,
+            pop_bytes: cb.pop_bytes?,
+            collapse: cb.collapse?,
+            special_allowed: cb.special_allowed?,
+            trie_started: cb.trie_started,
+            trie_finished: cb.trie_finished,
+        })
+    }
+}
+
+fn special_token_code(tok: SpecialToken) -> u32 {
+    match tok {
+        SpecialToken::Unknown => TOKTRIE_SPECIAL_UNKNOWN,
+        SpecialToken::Padding => TOKTRIE_SPECIAL_PADDING,
+        SpecialToken::Separator => TOKTRIE_SPECIAL_SEPARATOR,
+        SpecialToken::BeginningOfSentence => TOKTRIE_SPECIAL_BEGINNING_OF_SENTENCE,
+        SpecialToken::EndOfSentence => TOKTRIE_SPECIAL_END_OF_SENTENCE,
+        SpecialToken::EndOfTurn => TOKTRIE_SPECIAL_END_OF_TURN,
+    }
+}
+
+// The callbacks are trusted to follow the contract of toktrie_compute_bias().
+impl Recognizer for CRecognizer {
+    fn pop_bytes(&mut self, num: usize) {
+        unsafe { (self.pop_bytes)(self.user_data, num) }
+    }
+
+    fn collapse(&mut self) {
+        unsafe { (self.collapse)(self.user_data) }
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        unsafe { (self.special_allowed)(self.user_data, special_token_code(tok)) }
+    }
+
+    fn trie_finished(&mut self) {
+        if let Some(f) = self.trie_finished {
+            unsafe { f(self.user_data) }
+        }
+    }
+
+    fn trie_started(&mut self) {
+        if let Some(f) = self.trie_started {
+            unsafe { f(self.user_data) }
+        }
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        unsafe { (self.try_push_byte)(self.user_data, byte) }
+    }
+}
+
+fn guard(f: impl FnOnce() -> i32) -> i32 {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or(TOKTRIE_ERR_PANIC)
+}
+
+/// Number of `u32` words needed for a token mask of `trie`.
+fn mask_words_for(trie: &TokTrie) -> usize {
+    trie.vocab_size().div_ceil(32)
+}
+
+/// Load a trie serialized with `TokTrie::serialize()`.
+/// Returns null if `ptr` is null, or the bytes are not a valid trie.
+///
+/// # Safety
+/// `ptr` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn toktrie_new_from_bytes(ptr: *const u8, len: usize) -> *mut TokTrie {
+    if ptr.is_null() {
+        return core::ptr::null_mut();
+    }
+    let bytes = core::slice::from_raw_parts(ptr, len);
+    match catch_unwind(|| TokTrie::try_from_bytes(bytes)) {
+        Ok(Ok(trie)) => Box::into_raw(Box::new(trie)),
+        _ => core::ptr::null_mut(),
+    }
+}
+
+/// Free a trie returned by `toktrie_new_from_bytes()`; null is ignored.
+///
+/// # Safety
+/// `trie` must come from `toktrie_new_from_bytes()`, and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn toktrie_free(trie: *mut TokTrie) {
+    if !trie.is_null() {
+        drop(Box::from_raw(trie));
+    }
+}
+
+/// Number of tokens in the trie, or 0 if `trie` is null.
+///
+/// # Safety
+/// `trie` must be null or come from `toktrie_new_from_bytes()`.
+#[no_mangle]
+pub unsafe extern "C" fn toktrie_vocab_size(trie: *const TokTrie) -> usize {
+    match trie.as_ref() {
+        Some(trie) => trie.vocab_size(),
+        None => 0,
+    }
+}
+
+/// Set `*out_ptr` and `*out_len` to the bytes of token `id`.
+/// The bytes are owned by the trie.
+///
+/// # Safety
+/// `trie` must be null or come from `toktrie_new_from_bytes()`;
+/// `out_ptr` and `out_len` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn toktrie_token(
+    trie: *const TokTrie,
+    id: TokenId,
+    out_ptr: *mut *const u8,
+    out_len: *mut usize,
+) -> i32 {
+    let trie = match trie.as_ref() {
+        Some(trie) => trie,
+        None => return TOKTRIE_ERR_NULL,
+    };
+    if out_ptr.is_null() || out_len.is_null() {
+        return TOKTRIE_ERR_NULL;
+    }
+    if id as usize >= trie.vocab_size() {
+        return TOKTRIE_ERR_INVALID;
+    }
+    guard(|| {
+        let bytes = trie.token(id);
+        *out_ptr = bytes.as_ptr();
+        *out_len = bytes.len();
+        TOKTRIE_OK
+    })
+}
+
+/// Tokenize `len` bytes at `bytes` with `TokTrie::try_greedy_tokenize()`,
+/// writing the tokens to `out_tokens` and their number to `*out_len`.
+/// If `out_cap` is too small, returns `TOKTRIE_ERR_SIZE` with the required size in `*out_len`.
+///
+/// # Safety
+/// `trie` must be null or come from `toktrie_new_from_bytes()`;
+/// `bytes` must point to `len` readable bytes;
+/// `out_tokens` must point to `out_cap` writable tokens; `out_len` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn toktrie_greedy_tokenize(
+    trie: *const TokTrie,
+    bytes: *const u8,
+    len: usize,
+    out_tokens: *mut TokenId,
+    out_cap: usize,
+    out_len: *mut usize,
+) -> i32 {
+    let trie = match trie.as_ref() {
+        Some(trie) => trie,
+        None => return TOKTRIE_ERR_NULL,
+    };
+    if bytes.is_null() || out_len.is_null() || (out_tokens.is_null() && out_cap > 0) {
+        return TOKTRIE_ERR_NULL;
+    }
+    let bytes = core::slice::from_raw_parts(bytes, len);
+    guard(|| {
+        let tokens = match trie.try_greedy_tokenize(bytes) {
+            Ok(tokens) => tokens,
+            Err(_) => return TOKTRIE_ERR_INVALID,
+        };
+        *out_len = tokens.len();
+        if tokens.len() > out_cap {
+            return TOKTRIE_ERR_SIZE;
+        }
+        core::ptr::copy_nonoverlapping(tokens.as_ptr(), out_tokens, tokens.len());
+        TOKTRIE_OK
+    })
+}
+
+/// Compute the set of tokens allowed by the recognizer, see `TokTrie::compute_bias()`.
+/// The mask is written to `mask_ptr` in the layout of `SimpleVob`:
+/// token `t` is allowed if bit `t % 32` of word `t / 32` is set.
+/// `mask_words` must be at least `(vocab_size + 31) / 32`; extra words are left unchanged.
+///
+/// # Safety
+/// `trie` must be null or come from `toktrie_new_from_bytes()`;
+/// `rec` must be null or point to a valid `TokTrieRecognizer`,
+/// whose callbacks must not unwind;
+/// `mask_ptr` must point to `mask_words` writable words.
+#[no_mangle]
+pub unsafe extern "C" fn toktrie_compute_bias(
+    trie: *const TokTrie,
+    rec: *const TokTrieRecognizer,
+    mask_ptr: *mut u32,
+    mask_words: usize,
+) -> i32 {
+    let (trie, rec) = match (trie.as_ref(), rec.as_ref()) {
+        (Some(trie), Some(rec)) => (trie, rec),
+        _ => return TOKTRIE_ERR_NULL,
+    };
+    if mask_ptr.is_null() {
+        return TOKTRIE_ERR_NULL;
+    }
+    let mut rec = match CRecognizer::new(rec) {
+        Some(rec) => rec,
+        None => return TOKTRIE_ERR_NULL,
+    };
+    let num_words = mask_words_for(trie);
+    if mask_words < num_words {
+        return TOKTRIE_ERR_SIZE;
+    }
+    guard(|| {
+        let mut logits = trie.alloc_token_set();
+        trie.compute_bias(&mut rec, &mut logits);
+        let src = &logits.as_slice()[0..num_words];
+        core::ptr::copy_nonoverlapping(src.as_ptr(), mask_ptr, num_words);
+        TOKTRIE_OK
+    })
+}
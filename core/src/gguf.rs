@@ -0,0 +1,327 @@
+//! Reading the tokenizer from the metadata of a llama.cpp GGUF file.
+//! Only the key-value section at the start of the file is read, not the tensors.
+
+use alloc::{string::String, vec, vec::Vec};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+
+use anyhow::{anyhow, bail, ensure, Result};
+
+use crate::{
+    huggingface::{build_char_map, byte_fallback_token},
+    toktree::{TokRxInfo, TokTrie, TokenId},
+};
+
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+
+// value types of the metadata
+const TYPE_UINT8: u32 = 0;
+const TYPE_INT8: u32 = 1;
+const TYPE_UINT16: u32 = 2;
+const TYPE_INT16: u32 = 3;
+const TYPE_UINT32: u32 = 4;
+const TYPE_INT32: u32 = 5;
+const TYPE_FLOAT32: u32 = 6;
+const TYPE_BOOL: u32 = 7;
+const TYPE_STRING: u32 = 8;
+const TYPE_ARRAY: u32 = 9;
+const TYPE_UINT64: u32 = 10;
+const TYPE_INT64: u32 = 11;
+const TYPE_FLOAT64: u32 = 12;
+
+// values of tokenizer.ggml.token_type
+const TOKEN_NORMAL: i32 = 1;
+const TOKEN_UNKNOWN: i32 = 2;
+const TOKEN_CONTROL: i32 = 3;
+const TOKEN_USER_DEFINED: i32 = 4;
+const TOKEN_UNUSED: i32 = 5;
+const TOKEN_BYTE: i32 = 6;
+
+/// Reads the metadata, checking every length against what's left of the file,
+/// so that a corrupt one fails before allocating much.
+struct MetadataReader<R> {
+    r: BufReader<R>,
+    remaining: u64,
+}
+
+impl<R: Read + Seek> MetadataReader<R> {
+    fn new(mut r: R) -> Result<Self> {
+        let start = r.stream_position()?;
+        let end = r.seek(SeekFrom::End(0))?;
+        r.seek(SeekFrom::Start(start))?;
+        Ok(MetadataReader {
+            r: BufReader::new(r),
+            remaining: end.saturating_sub(start),
+        })
+    }
+
+    fn reserve(&mut self, num_bytes: u64) -> Result<()> {
+        ensure!(num_bytes <= self.remaining, "GGUF: metadata truncated");
+        self.remaining -= num_bytes;
+        Ok(())
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N]> {
+        self.reserve(N as u64)?;
+        let mut buf = [0; N];
+        self.r.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes()?))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u64()?;
+        self.reserve(len)?;
+        let mut buf = vec![0; len as usize];
+        self.r.read_exact(&mut buf)?;
+        String::from_utf8(buf).map_err(|_| anyhow!("GGUF: string is not valid UTF-8"))
+    }
+
+    fn skip(&mut self, num_bytes: u64) -> Result<()> {
+        self.reserve(num_bytes)?;
+        let num_bytes =
+            i64::try_from(num_bytes).map_err(|_| anyhow!("GGUF: metadata truncated"))?;
+        self.r.seek_relative(num_bytes)?;
+        Ok(())
+    }
+
+    /// An integer value of any of the integer types.
+    fn int(&mut self, tp: u32) -> Result<i64> {
+        Ok(match tp {
+            TYPE_UINT8 => u8::from_le_bytes(self.bytes()?) as i64,
+            TYPE_INT8 => i8::from_le_bytes(self.bytes()?) as i64,
+            TYPE_UINT16 => u16::from_le_bytes(self.bytes()?) as i64,
+            TYPE_INT16 => i16::from_le_bytes(self.bytes()?) as i64,
+            TYPE_UINT32 => self.u32()? as i64,
+            TYPE_INT32 => i32::from_le_bytes(self.bytes()?) as i64,
+            TYPE_UINT64 => i64::try_from(self.u64()?)?,
+            TYPE_INT64 => i64::from_le_bytes(self.bytes()?),
+            _ => bail!("GGUF: expecting an integer, got value type {}", tp),
+        })
+    }
+
+    /// Array header: element type and number of elements; the elements are checked
+    /// to fit in the file, when their size is known.
+    fn array_header(&mut self) -> Result<(u32, u64)> {
+        let tp = self.u32()?;
+        let len = self.u64()?;
+        // a string is at least its length
+        let elt_size = fixed_size(tp).unwrap_or(8);
+        ensure!(
+            len.checked_mul(elt_size)
+                .is_some_and(|n| n <= self.remaining),
+            "GGUF: metadata truncated"
+        );
+        Ok((tp, len))
+    }
+
+    fn skip_value(&mut self, tp: u32) -> Result<()> {
+        match tp {
+            TYPE_STRING => {
+                let len = self.u64()?;
+                self.skip(len)
+            }
+            TYPE_ARRAY => {
+                let (elt_tp, len) = self.array_header()?;
+                match fixed_size(elt_tp) {
+                    Some(size) => self.skip(len * size),
+                    None => {
+                        for _ in 0..len {
+                            self.skip_value(elt_tp)?;
+                        }
+                        Ok(())
+                    }
+                }
+            }
+            _ => match fixed_size(tp) {
+                Some(size) => self.skip(size),
+                None => bail!("GGUF: unknown value type {}", tp),
+            },
+        }
+    }
+}
+
+fn fixed_size(tp: u32) -> Option<u64> {
+    match tp {
+        TYPE_UINT8 | TYPE_INT8 | TYPE_BOOL => Some(1),
+        TYPE_UINT16 | TYPE_INT16 => Some(2),
+        TYPE_UINT32 | TYPE_INT32 | TYPE_FLOAT32 => Some(4),
+        TYPE_UINT64 | TYPE_INT64 | TYPE_FLOAT64 => Some(8),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+struct TokenizerMetadata {
+    model: Option<String>,
+    tokens: Option<Vec<String>>,
+    token_types: Option<Vec<i32>>,
+    eos: Option<i64>,
+    bos: Option<i64>,
+    unk: Option<i64>,
+    pad: Option<i64>,
+    eot: Option<i64>,
+}
+
+fn read_metadata(r: impl Read + Seek) -> Result<TokenizerMetadata> {
+    let mut r = MetadataReader::new(r)?;
+    ensure!(&r.bytes::<4>()? == GGUF_MAGIC, "GGUF: not a GGUF file");
+    let version = r.u32()?;
+    ensure!(
+        version == 2 || version == 3,
+        "GGUF: unsupported version {}",
+        version
+    );
+    let _num_tensors = r.u64()?;
+    let num_kv = r.u64()?;
+
+    let mut md = TokenizerMetadata::default();
+    for _ in 0..num_kv {
+        let key = r.string()?;
+        let tp = r.u32()?;
+        let id = match key.as_str() {
+            "tokenizer.ggml.eos_token_id" => &mut md.eos,
+            "tokenizer.ggml.bos_token_id" => &mut md.bos,
+            "tokenizer.ggml.unknown_token_id" => &mut md.unk,
+            "tokenizer.ggml.padding_token_id" => &mut md.pad,
+            "tokenizer.ggml.eot_token_id" => &mut md.eot,
+            "tokenizer.ggml.model" => {
+                ensure!(tp == TYPE_STRING, "GGUF: {} is not a string", key);
+                md.model = Some(r.string()?);
+                continue;
+            }
+            "tokenizer.ggml.tokens" => {
+                ensure!(tp == TYPE_ARRAY, "GGUF: {} is not an array", key);
+                let (elt_tp, len) = r.array_header()?;
+                ensure!(elt_tp == TYPE_STRING, "GGUF: {} is not of strings", key);
+                md.tokens = Some((0..len).map(|_| r.string()).collect::<Result<_>>()?);
+                continue;
+            }
+            "tokenizer.ggml.token_type" => {
+                ensure!(tp == TYPE_ARRAY, "GGUF: {} is not an array", key);
+                let (elt_tp, len) = r.array_header()?;
+                md.token_types = Some(
+                    (0..len)
+                        .map(|_| Ok(r.int(elt_tp)? as i32))
+                        .collect::<Result<_>>()?,
+                );
+                continue;
+            }
+            _ => {
+                r.skip_value(tp)?;
+                continue;
+            }
+        };
+        *id = Some(r.int(tp)?);
+    }
+    Ok(md)
+}
+
+/// Parse the tokenizer in the metadata of a GGUF file (versions 2 and 3), starting
+/// at the current position of `reader`. Returns token info, from `tokenizer.ggml.eos_token_id`
+/// and similar keys, and the bytes of every token.
+/// Only the `llama` (sentencepiece) and `gpt2` (byte-level BPE) tokenizers are supported.
+/// Control and unknown tokens get `TokTrie::SPECIAL_TOKEN_PREFIX_BYTE`, and unused ones
+/// are empty. Without an EOS id, token 0 is used, as in `token_bytes_from_hf_json()`.
+pub fn token_bytes_from_gguf(reader: impl Read + Seek) -> Result<(TokRxInfo, Vec<Vec<u8>>)> {
+    let (info, token_bytes, _) = parse_gguf(reader)?;
+    Ok((info, token_bytes))
+}
+
+/// `token_bytes_from_gguf()`, with the special tokens.
+fn parse_gguf(reader: impl Read + Seek) -> Result<(TokRxInfo, Vec<Vec<u8>>, Vec<TokenId>)> {
+    let md = read_metadata(reader)?;
+    let model = md
+        .model
+        .ok_or_else(|| anyhow!("GGUF: tokenizer.ggml.model missing"))?;
+    let tokens = md
+        .tokens
+        .ok_or_else(|| anyhow!("GGUF: tokenizer.ggml.tokens missing"))?;
+    ensure!(!tokens.is_empty(), "GGUF: empty vocabulary");
+    ensure!(tokens.len() < u32::MAX as usize, "GGUF: too many tokens");
+    let token_types = match md.token_types {
+        Some(tt) => {
+            ensure!(
+                tt.len() == tokens.len(),
+                "GGUF: {} token types for {} tokens",
+                tt.len(),
+                tokens.len()
+            );
+            tt
+        }
+        None => vec![TOKEN_NORMAL; tokens.len()],
+    };
+
+    let vocab_size = tokens.len() as u32;
+    let token_id = |name: &str, id: Option<i64>| -> Result<Option<TokenId>> {
+        match id {
+            Some(id) if id < 0 || id >= vocab_size as i64 => {
+                bail!(
+                    "GGUF: {} token {} out of range (vocab size {})",
+                    name,
+                    id,
+                    vocab_size
+                )
+            }
+            id => Ok(id.map(|id| id as TokenId)),
+        }
+    };
+    let mut info = TokRxInfo::new(vocab_size, token_id("EOS", md.eos)?.unwrap_or(0));
+    info.tok_bos = token_id("BOS", md.bos)?;
+    info.tok_unk = token_id("unknown", md.unk)?;
+    info.tok_pad = token_id("padding", md.pad)?;
+    info.tok_end_of_turn = token_id("end-of-turn", md.eot)?;
+
+    let char_map = match model.as_str() {
+        "llama" => None,
+        "gpt2" => Some(build_char_map()),
+        _ => bail!("GGUF: unsupported tokenizer model {:?}", model),
+    };
+    let mut token_bytes = Vec::with_capacity(tokens.len());
+    let mut special_tokens = Vec::new();
+    for (name, tp) in tokens.iter().zip(token_types) {
+        let bytes = match tp {
+            TOKEN_CONTROL | TOKEN_UNKNOWN => {
+                special_tokens.push(token_bytes.len() as TokenId);
+                let mut bytes = Vec::with_capacity(name.len() + 1);
+                bytes.push(TokTrie::SPECIAL_TOKEN_PREFIX_BYTE);
+                bytes.extend_from_slice(name.as_bytes());
+                bytes
+            }
+            TOKEN_UNUSED => Vec::new(),
+            TOKEN_USER_DEFINED => name.as_bytes().to_vec(),
+            TOKEN_BYTE if char_map.is_none() => vec![byte_fallback_token(name)
+                .ok_or_else(|| anyhow!("GGUF: invalid byte token {:?}", name))?],
+            TOKEN_NORMAL | TOKEN_BYTE => match &char_map {
+                Some(char_map) => name
+                    .chars()
+                    .map(|c| {
+                        char_map.get(&c).copied().ok_or_else(|| {
+                            anyhow!("GGUF: missing char {:?} in token {:?}", c, name)
+                        })
+                    })
+                    .collect::<Result<Vec<u8>>>()?,
+                None => name.replace('\u{2581}', " ").into_bytes(),
+            },
+            _ => bail!("GGUF: invalid type {} of token {:?}", tp, name),
+        };
+        token_bytes.push(bytes);
+    }
+
+    Ok((info, token_bytes, special_tokens))
+}
+
+/// Build a trie from the tokenizer in the metadata of a GGUF file;
+/// see `token_bytes_from_gguf()`. Only control and unknown tokens are special tokens;
+/// see `TokTrie::from_ext()`.
+pub fn trie_from_gguf(reader: impl Read + Seek) -> Result<(TokTrie, TokRxInfo)> {
+    let (info, token_bytes, special_tokens) = parse_gguf(reader)?;
+    let trie = TokTrie::try_from_words(&info, &token_bytes, false, Some(&special_tokens))?;
+    Ok((trie, info))
+}
@@ -0,0 +1,215 @@
+use alloc::{vec, vec::Vec};
+
+use anyhow::{anyhow, bail, ensure, Result};
+use serde_json::Value;
+
+use crate::{
+    toktree::{TokRxInfo, TokTrie, TokenId, NO_TOKEN},
+    FxHashMap,
+};
+
+// Maps the vocabulary of a HuggingFace tokenizer.json to token bytes.
+// This is the same logic as in the hf_tokenizers crate, but working
+// directly on the JSON, without the tokenizers library.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum VocabKind {
+    // GPT-2 style: bytes are mapped to printable characters
+    ByteLevel,
+    // sentencepiece style: <0xAB> tokens for bytes, and a special char for space
+    ByteFallback { space_ch: char },
+}
+
+fn is_self_mapped(c: char) -> bool {
+    matches!(c, '!'..='~' | '\u{00A1}'..='\u{00AC}' | '\u{00AE}'..='\u{00FF}')
+}
+
+pub(crate) fn build_char_map() -> FxHashMap<char, u8> {
+    let mut res = FxHashMap::default();
+    let mut k = 0x100u32;
+    for byte in 0..=255u8 {
+        let c = byte as char;
+        if is_self_mapped(c) {
+            res.insert(c, byte);
+        } else {
+            res.insert(char::from_u32(k).unwrap(), byte);
+            k += 1;
+        }
+    }
+    res
+}
+
+fn single_char(s: Option<&str>) -> Option<char> {
+    let mut chars = s?.chars();
+    let c = chars.next()?;
+    if chars.next().is_none() {
+        Some(c)
+    } else {
+        None
+    }
+}
+
+fn vocab_kind(json: &Value) -> Result<VocabKind> {
+    let decoder = &json["decoder"];
+    match decoder["type"].as_str() {
+        Some("ByteLevel") => return Ok(VocabKind::ByteLevel),
+        Some("Metaspace") => {
+            let space_ch = single_char(decoder["replacement"].as_str()).unwrap_or('\u{2581}');
+            return Ok(VocabKind::ByteFallback { space_ch });
+        }
+        Some("Sequence") => {
+            let mut byte_fallback = false;
+            let mut space_ch = '\u{2581}';
+            for d in decoder["decoders"].as_array().into_iter().flatten() {
+                match d["type"].as_str() {
+                    Some("ByteLevel") => return Ok(VocabKind::ByteLevel),
+                    Some("ByteFallback") => byte_fallback = true,
+                    Some("Replace") if d["content"].as_str() == Some(" ") => {
+                        if let Some(c) = single_char(d["pattern"]["String"].as_str()) {
+                            space_ch = c;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            if byte_fallback {
+                return Ok(VocabKind::ByteFallback { space_ch });
+            }
+        }
+        _ => {}
+    }
+    if json["model"]["byte_fallback"].as_bool() == Some(true) {
+        return Ok(VocabKind::ByteFallback {
+            space_ch: '\u{2581}',
+        });
+    }
+    bail!("can't determine decoder type: {}", decoder)
+}
+
+// Token ids can have gaps, but a vocabulary this much larger than the number
+// of tokens listed is taken as a corrupt file, rather than allocated.
+const MAX_VOCAB_PER_TOKEN: usize = 2;
+
+fn token_id(v: &Value) -> Option<TokenId> {
+    v.as_u64()
+        .and_then(|id| TokenId::try_from(id).ok())
+        .filter(|&id| id < NO_TOKEN)
+}
+
+pub(crate) fn byte_fallback_token(name: &str) -> Option<u8> {
+    if name.len() == 6 && name.starts_with("<0x") && name.ends_with('>') {
+        u8::from_str_radix(&name[3..5], 16).ok()
+    } else {
+        None
+    }
+}
+
+/// Parse the vocabulary of a HuggingFace `tokenizer.json`.
+/// Returns token info (EOS, BOS, etc. are recognized by their names) and the bytes of every token.
+/// Tokens from `added_tokens` marked as `special` get `TokTrie::SPECIAL_TOKEN_PREFIX_BYTE`;
+/// ids not present in the file get empty tokens.
+/// If no EOS token is found, token 0 is used, as in the hf_tokenizers crate.
+pub fn token_bytes_from_hf_json(json: &str) -> Result<(TokRxInfo, Vec<Vec<u8>>)> {
+    let (info, token_bytes, _) = parse_hf_json(json)?;
+    Ok((info, token_bytes))
+}
+
+/// `token_bytes_from_hf_json()`, with the special tokens.
+fn parse_hf_json(json: &str) -> Result<(TokRxInfo, Vec<Vec<u8>>, Vec<TokenId>)> {
+    let json: Value = serde_json::from_str(json)?;
+    let kind = vocab_kind(&json)?;
+
+    let vocab = json["model"]["vocab"]
+        .as_object()
+        .ok_or_else(|| anyhow!("model.vocab missing or not an object"))?;
+    let mut entries = Vec::with_capacity(vocab.len());
+    for (name, id) in vocab {
+        let id = token_id(id).ok_or_else(|| anyhow!("invalid id for token {:?}", name))?;
+        entries.push((id, name.as_str()));
+    }
+
+    // (id, content, special)
+    let mut added = Vec::new();
+    for t in json["added_tokens"].as_array().into_iter().flatten() {
+        let id = token_id(&t["id"]).ok_or_else(|| anyhow!("invalid added token: {}", t))?;
+        let content = t["content"]
+            .as_str()
+            .ok_or_else(|| anyhow!("invalid added token: {}", t))?;
+        added.push((id, content, t["special"].as_bool() == Some(true)));
+    }
+
+    let vocab_size = entries
+        .iter()
+        .map(|e| e.0)
+        .chain(added.iter().map(|a| a.0))
+        .max()
+        .map_or(0, |m| m + 1);
+    ensure!(vocab_size > 0, "empty vocabulary");
+    let num_listed = entries.len() + added.len();
+    ensure!(
+        vocab_size as usize <= MAX_VOCAB_PER_TOKEN * num_listed,
+        "token id {} is too large for {} tokens",
+        vocab_size - 1,
+        num_listed
+    );
+    let mut info = TokRxInfo::new(vocab_size, 0);
+    let mut token_bytes: Vec<Vec<u8>> = vec![Vec::new(); vocab_size as usize];
+
+    let char_map = build_char_map();
+    for (id, name) in entries {
+        let bytes = match kind {
+            VocabKind::ByteLevel => name
+                .chars()
+                .map(|c| {
+                    char_map
+                        .get(&c)
+                        .copied()
+                        .ok_or_else(|| anyhow!("missing char {:?} in token {:?}", c, name))
+                })
+                .collect::<Result<Vec<u8>>>()?,
+            VocabKind::ByteFallback { space_ch } => match byte_fallback_token(name) {
+                Some(b) => vec![b],
+                None => name.replace(space_ch, " ").into_bytes(),
+            },
+        };
+        token_bytes[id as usize] = bytes;
+    }
+
+    // added tokens override the vocab entries with the same id
+    let mut is_special = vec![false; vocab_size as usize];
+    for (id, content, special) in added {
+        is_special[id as usize] = special;
+        if special {
+            match content {
+                "</s>" | "<|endoftext|>" | "<|end_of_text|>" => info.tok_eos = id,
+                "<s>" | "<|begin_of_text|>" | "<|startoftext|>" => info.tok_bos = Some(id),
+                "<|end|>" | "<|eot_id|>" => info.tok_end_of_turn = Some(id),
+                "<unk>" | "<|unk|>" => info.tok_unk = Some(id),
+                "<pad>" | "<|pad|>" => info.tok_pad = Some(id),
+                _ => {}
+            }
+            let mut bytes = Vec::with_capacity(content.len() + 1);
+            bytes.push(TokTrie::SPECIAL_TOKEN_PREFIX_BYTE);
+            bytes.extend_from_slice(content.as_bytes());
+            token_bytes[id as usize] = bytes;
+        } else {
+            token_bytes[id as usize] = content.as_bytes().to_vec();
+        }
+    }
+
+    let special_tokens = (0..vocab_size)
+        .filter(|&t| is_special[t as usize])
+        .collect();
+    Ok((info, token_bytes, special_tokens))
+}
+
+impl TokTrie {
+    /// Build a trie from the vocabulary of a HuggingFace `tokenizer.json`;
+    /// see `token_bytes_from_hf_json()`. Only the added tokens marked as `special`
+    /// are special tokens; see `TokTrie::from_ext()`.
+    pub fn from_hf_json(json: &str) -> Result<(TokTrie, TokRxInfo)> {
+        let (info, token_bytes, special_tokens) = parse_hf_json(json)?;
+        let trie = TokTrie::try_from_words(&info, &token_bytes, false, Some(&special_tokens))?;
+        Ok((trie, info))
+    }
+}
@@ -1,16 +1,66 @@
+#![no_std]
+
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+use alloc::{vec, vec::Vec};
 use serde::{Deserialize, Serialize};
 
+mod builder;
 pub mod bytes;
+mod decoder;
+#[cfg(feature = "cffi")]
+pub mod ffi;
+#[cfg(feature = "gguf")]
+pub mod gguf;
+#[cfg(feature = "hf")]
+pub mod huggingface;
+#[cfg(feature = "serde_json")]
+pub mod logit_bias;
 pub mod recognizer;
+#[cfg(any(test, feature = "testing"))]
+pub mod recognizer_check;
 pub mod rng;
+mod stop;
 mod svob;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(feature = "std")]
+mod text_format;
+#[cfg(feature = "tiktoken")]
+pub mod tiktoken;
 mod toktree;
+#[cfg(feature = "std")]
+pub mod trace;
+mod translate;
+#[cfg(feature = "std")]
+pub mod trie_cache;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub(crate) type FxHashMap<K, V> = hashbrown::HashMap<K, V, rustc_hash::FxBuildHasher>;
+pub(crate) type FxHashSet<K> = hashbrown::HashSet<K, rustc_hash::FxBuildHasher>;
 
+pub use builder::{BuildProgress, DuplicatePolicy, TokTrieBuilder, TokTrieBuilderIncremental};
+pub use decoder::StreamDecoder;
+pub use stop::{StopController, StopResult};
 pub use svob::{SimpleVob, SimpleVobIter};
+#[cfg(feature = "metrics")]
+pub use toktree::TrieCounters;
+pub use toktree::{
+    AndRecognizer, AppendTokenError, BiasStats, ByteBias, ByteCoverage, CandidateIndex,
+    ConstraintStepper, DbgOptions, EosMode, FingerprintMismatch, GapPolicy, HealResult, MaybeSend,
+    MemoryUsage, NodeRef, OrRecognizer, Recognizer, SpecialToken, StepOutcome, TokRxInfo, TokTrie,
+    TokTrieRef, TokenId, TokenProps, TrieDiff, TrieNode, TrieScratch, TrieStats, TrieWalker,
+    ValidationError, WalkEvent,
+};
+#[cfg(feature = "std")]
 pub use toktree::{
-    Recognizer, SpecialToken, TokEnv, TokEnvWithTrie, TokRxInfo, TokTrie, TokenId, TokenizerEnv,
-    TrieNode,
+    EosSource, RetokenizeResult, TokEnv, TokEnvWithTrie, TokenizerEnv, TrieTokenizerEnv,
+    VocabAnalysis, WalkBudget, WalkOutcome,
 };
+pub use translate::TokenTranslator;
 
 /// Defines what is allowed in Branch
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
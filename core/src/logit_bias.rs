@@ -0,0 +1,242 @@
+//! OpenAI-style `logit_bias` maps, from token ids or strings to a bias;
+//! see `TokTrie::logit_bias_from_json_map()`.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+
+use anyhow::{anyhow, bail, ensure, Result};
+use serde_json::{Map, Value};
+
+use crate::{FxHashMap, TokTrie, TokenId, TokenizerEnv};
+
+/// Biases are clamped to `-MAX_LOGIT_BIAS..=MAX_LOGIT_BIAS`, as in the OpenAI API.
+pub const MAX_LOGIT_BIAS: f32 = 100.0;
+
+impl TokTrie {
+    /// Resolve a `logit_bias` map to the bias of each token, sorted by token id.
+    ///
+    /// Keys made of decimal digits are token ids. Other keys are the bytes of a token
+    /// (see `token_id()`), or, failing that and with `env` given, text that's tokenized
+    /// with it, the bias then going to each of the tokens (once, even if a token
+    /// appears several times). Biases of a token from several keys are added up;
+    /// the sums are clamped to `MAX_LOGIT_BIAS`.
+    ///
+    /// Errors on ids out of range, values that are not numbers, and keys that don't
+    /// resolve to any token; the message has the key.
+    pub fn logit_bias_from_json_map(
+        &self,
+        map: &Map<String, Value>,
+        env: Option<&dyn TokenizerEnv>,
+    ) -> Result<Vec<(TokenId, f32)>> {
+        let mut sums: FxHashMap<TokenId, f32> = FxHashMap::default();
+        for (key, value) in map {
+            let bias = value.as_f64().ok_or_else(|| {
+                anyhow!("logit_bias: key {:?}: value {} is not a number", key, value)
+            })? as f32;
+            let mut tokens = self.logit_bias_key_tokens(key, env)?;
+            tokens.sort_unstable();
+            tokens.dedup();
+            for t in tokens {
+                *sums.entry(t).or_default() += bias;
+            }
+        }
+        let mut res = sums
+            .into_iter()
+            .map(|(t, bias)| (t, bias.clamp(-MAX_LOGIT_BIAS, MAX_LOGIT_BIAS)))
+            .collect::<Vec<_>>();
+        res.sort_unstable_by_key(|e| e.0);
+        Ok(res)
+    }
+
+    fn logit_bias_key_tokens(
+        &self,
+        key: &str,
+        env: Option<&dyn TokenizerEnv>,
+    ) -> Result<Vec<TokenId>> {
+        if !key.is_empty() && key.bytes().all(|b| b.is_ascii_digit()) {
+            let tok = key
+                .parse::<TokenId>()
+                .ok()
+                .filter(|&t| self.is_valid_token(t))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "logit_bias: key {:?}: token out of range (vocab size {})",
+                        key,
+                        self.vocab_size()
+                    )
+                })?;
+            return Ok(vec![tok]);
+        }
+        if let Some(tok) = self.token_id(key.as_bytes()) {
+            return Ok(vec![tok]);
+        }
+        match env {
+            Some(env) => {
+                let tokens = env.tokenize_bytes(key.as_bytes());
+                ensure!(
+                    !tokens.is_empty(),
+                    "logit_bias: key {:?}: tokenizes to nothing",
+                    key
+                );
+                if let Some(&t) = tokens.iter().find(|&&t| !self.is_valid_token(t)) {
+                    bail!(
+                        "logit_bias: key {:?}: tokenizer gave token {} out of range (vocab size {})",
+                        key,
+                        t,
+                        self.vocab_size()
+                    );
+                }
+                Ok(tokens)
+            }
+            None => bail!("logit_bias: key {:?}: not a token", key),
+        }
+    }
+
+    /// The reverse of `logit_bias_from_json_map()`: a map with token ids as keys.
+    /// Biases of a token given more than once are added up, and clamped
+    /// to `MAX_LOGIT_BIAS`. Errors on tokens out of range, and on NaN and infinite biases.
+    pub fn logit_bias_to_json_map(&self, bias: &[(TokenId, f32)]) -> Result<Map<String, Value>> {
+        let mut sums: FxHashMap<TokenId, f32> = FxHashMap::default();
+        for &(t, b) in bias {
+            ensure!(
+                self.is_valid_token(t),
+                "logit_bias: token {} out of range (vocab size {})",
+                t,
+                self.vocab_size()
+            );
+            ensure!(
+                b.is_finite(),
+                "logit_bias: bias {} of token {} is not finite",
+                b,
+                t
+            );
+            *sums.entry(t).or_default() += b;
+        }
+        let mut sums = sums.into_iter().collect::<Vec<_>>();
+        sums.sort_unstable_by_key(|e| e.0);
+        let mut res = Map::new();
+        for (t, b) in sums {
+            let b = b.clamp(-MAX_LOGIT_BIAS, MAX_LOGIT_BIAS);
+            // through the shortest decimal form of the f32, so 0.1 stays 0.1
+            let b = format!("{}", b).parse::<f64>().unwrap();
+            res.insert(t.to_string(), Value::from(b));
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{TokRxInfo, TrieTokenizerEnv};
+
+    fn env() -> TrieTokenizerEnv {
+        let words = [
+            &b"\xff<eos>"[..],
+            b"a",
+            b"b",
+            b"ab",
+            b"c",
+            b"hello",
+            b" world",
+        ]
+        .iter()
+        .map(|w| w.to_vec())
+        .collect::<Vec<_>>();
+        TrieTokenizerEnv::from_words(&TokRxInfo::new(words.len() as u32, 0), &words).unwrap()
+    }
+
+    fn map(v: Value) -> Map<String, Value> {
+        v.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn from_json_map() {
+        let env = env();
+        let trie = env.tok_trie();
+        let m = map(json!({
+            "1": 5.0,
+            "ab": 2.0,
+            "3": 99,
+            "hello world": -3.0,
+            "ca": 1.5,
+            "aa": 1.0,
+        }));
+        // "aa" gives its bias to token 1 once; token 3 is clamped
+        assert_eq!(
+            trie.logit_bias_from_json_map(&m, Some(&env)).unwrap(),
+            vec![(1, 7.5), (3, 100.0), (4, 1.5), (5, -3.0), (6, -3.0)]
+        );
+        let m = map(json!({"1": -150, "a": -1}));
+        assert_eq!(
+            trie.logit_bias_from_json_map(&m, None).unwrap(),
+            vec![(1, -100.0)]
+        );
+        assert_eq!(
+            trie.logit_bias_from_json_map(&Map::new(), None).unwrap(),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn from_json_map_errors() {
+        let env = env();
+        let trie = env.tok_trie();
+        let err = |v: Value, env: Option<&dyn TokenizerEnv>| {
+            trie.logit_bias_from_json_map(&map(v), env)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            err(json!({"1": 1, "7": 1}), Some(&env)),
+            "logit_bias: key \"7\": token out of range (vocab size 7)"
+        );
+        assert_eq!(
+            err(json!({"99999999999": 1}), None),
+            "logit_bias: key \"99999999999\": token out of range (vocab size 7)"
+        );
+        assert_eq!(
+            err(json!({"hello world": 1}), None),
+            "logit_bias: key \"hello world\": not a token"
+        );
+        assert_eq!(
+            err(json!({"": 1}), Some(&env)),
+            "logit_bias: key \"\": tokenizes to nothing"
+        );
+        assert_eq!(
+            err(json!({"a": "x"}), None),
+            "logit_bias: key \"a\": value \"x\" is not a number"
+        );
+    }
+
+    #[test]
+    fn to_json_map() {
+        let trie = env().tok_trie().clone();
+        let bias = [(3, 0.25), (1, 2.0), (3, 0.5), (2, 150.0), (4, 0.1)];
+        let m = trie.logit_bias_to_json_map(&bias).unwrap();
+        assert_eq!(m, map(json!({"1": 2.0, "2": 100.0, "3": 0.75, "4": 0.1})));
+        assert_eq!(
+            trie.logit_bias_from_json_map(&m, None).unwrap(),
+            vec![(1, 2.0), (2, 100.0), (3, 0.75), (4, 0.1)]
+        );
+
+        assert_eq!(
+            trie.logit_bias_to_json_map(&[(7, 1.0)])
+                .unwrap_err()
+                .to_string(),
+            "logit_bias: token 7 out of range (vocab size 7)"
+        );
+        assert_eq!(
+            trie.logit_bias_to_json_map(&[(1, f32::NAN)])
+                .unwrap_err()
+                .to_string(),
+            "logit_bias: bias NaN of token 1 is not finite"
+        );
+    }
+}
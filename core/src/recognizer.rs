@@ -1,5 +1,6 @@
 use crate::toktree::{Recognizer, SpecialToken};
-use std::fmt::Debug;
+use alloc::{string::String, vec, vec::Vec};
+use core::{fmt::Debug, ops::RangeInclusive};
 
 pub trait FunctionalRecognizer<S: Copy> {
     /// Initial state
@@ -46,7 +47,7 @@ impl<S: Copy, R: FunctionalRecognizer<S>> StackRecognizer<S, R> {
 }
 
 impl<S: Copy + Debug, R: FunctionalRecognizer<S>> Debug for StackRecognizer<S, R> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("StackRecognizer")
             .field("top", &self.stack[self.stack_ptr])
             .finish()
@@ -61,7 +62,8 @@ impl<S: Copy + Debug, R: FunctionalRecognizer<S>> Recognizer for StackRecognizer
 
     fn trie_finished(&mut self) {
         // println!("{:?}", &self.stack[0..=self.stack_ptr]);
-        assert!(self.stack_ptr == 0);
+        // the trie walk started from the collapsed state, and popped all it pushed
+        self.stack_ptr = 0;
     }
 
     fn collapse(&mut self) {
@@ -77,12 +79,25 @@ impl<S: Copy + Debug, R: FunctionalRecognizer<S>> Recognizer for StackRecognizer
         self.rec.get_error(self.stack[self.stack_ptr])
     }
 
+    fn peek_byte(&self, byte: u8) -> Option<bool> {
+        Some(
+            self.rec
+                .try_append(self.stack[self.stack_ptr], byte)
+                .is_some(),
+        )
+    }
+
     #[inline(always)]
     fn try_push_byte(&mut self, byte: u8) -> bool {
         match self.rec.try_append(self.stack[self.stack_ptr], byte) {
             Some(state) => {
                 self.stack_ptr += 1;
-                self.stack[self.stack_ptr] = state;
+                if self.stack_ptr == self.stack.len() {
+                    // only for tokens longer than the initial stack
+                    self.stack.push(state);
+                } else {
+                    self.stack[self.stack_ptr] = state;
+                }
                 true
             }
             None => false,
@@ -106,3 +121,716 @@ impl FunctionalRecognizer<()> for AnythingGoes {
         true
     }
 }
+
+/// The bytes accepted by a recognizer, with the stack discipline of `Recognizer`.
+#[derive(Clone, Debug, Default)]
+struct ByteStack {
+    bytes: Vec<u8>,
+    // length at trie_started() or collapse()
+    base: usize,
+}
+
+impl ByteStack {
+    fn pop_bytes(&mut self, num: usize) {
+        self.bytes.truncate(self.bytes.len() - num);
+    }
+
+    fn collapse(&mut self) {
+        self.base = self.bytes.len();
+    }
+
+    fn trie_started(&mut self) {
+        self.base = self.bytes.len();
+    }
+
+    fn trie_finished(&mut self) {
+        self.bytes.truncate(self.base);
+    }
+}
+
+/// Accepts a byte when `allow_byte(bytes, byte)` says so, where `bytes` are the ones
+/// accepted so far (since construction); EOS is allowed when `allow_eos(bytes)` is true,
+/// which is always unless set with `with_eos()`.
+#[derive(Clone)]
+pub struct FnRecognizer<F, E = fn(&[u8]) -> bool> {
+    allow_byte: F,
+    allow_eos: E,
+    stack: ByteStack,
+}
+
+impl<F: Fn(&[u8], u8) -> bool> FnRecognizer<F> {
+    pub fn new(allow_byte: F) -> Self {
+        FnRecognizer {
+            allow_byte,
+            allow_eos: |_| true,
+            stack: ByteStack::default(),
+        }
+    }
+}
+
+impl<F: Fn(&[u8], u8) -> bool, E: Fn(&[u8]) -> bool> FnRecognizer<F, E> {
+    pub fn with_eos<E2: Fn(&[u8]) -> bool>(self, allow_eos: E2) -> FnRecognizer<F, E2> {
+        FnRecognizer {
+            allow_byte: self.allow_byte,
+            allow_eos,
+            stack: self.stack,
+        }
+    }
+
+    /// The bytes accepted so far.
+    pub fn bytes(&self) -> &[u8] {
+        &self.stack.bytes
+    }
+}
+
+impl<F, E> Debug for FnRecognizer<F, E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("FnRecognizer")
+            .field("bytes", &self.stack.bytes)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<F: Fn(&[u8], u8) -> bool, E: Fn(&[u8]) -> bool> Recognizer for FnRecognizer<F, E> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.stack.pop_bytes(num);
+    }
+
+    fn collapse(&mut self) {
+        self.stack.collapse();
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        tok == SpecialToken::EndOfSentence && (self.allow_eos)(&self.stack.bytes)
+    }
+
+    fn trie_started(&mut self) {
+        self.stack.trie_started();
+    }
+
+    fn trie_finished(&mut self) {
+        self.stack.trie_finished();
+    }
+
+    fn peek_byte(&self, byte: u8) -> Option<bool> {
+        Some((self.allow_byte)(&self.stack.bytes, byte))
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        if (self.allow_byte)(&self.stack.bytes, byte) {
+            self.stack.bytes.push(byte);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Accepts strings of bytes from a set, like `[0-9]{1,5}`, with EOS allowed
+/// once there are at least `min_len` bytes (and `with_eos_when()` agrees).
+#[derive(Clone, Debug)]
+pub struct CharClassRecognizer {
+    allowed: [bool; 256],
+    min_len: usize,
+    max_len: usize,
+    allow_eos_when: Option<fn(&[u8]) -> bool>,
+    stack: ByteStack,
+}
+
+impl CharClassRecognizer {
+    /// Bytes in any of `ranges`, any number of them.
+    pub fn new(ranges: &[RangeInclusive<u8>]) -> Self {
+        let mut allowed = [false; 256];
+        for r in ranges {
+            for b in r.clone() {
+                allowed[b as usize] = true;
+            }
+        }
+        CharClassRecognizer {
+            allowed,
+            min_len: 0,
+            max_len: usize::MAX,
+            allow_eos_when: None,
+            stack: ByteStack::default(),
+        }
+    }
+
+    /// ASCII digits only.
+    pub fn digits() -> Self {
+        Self::new(&[b'0'..=b'9'])
+    }
+
+    /// Also allow the given bytes.
+    pub fn with_bytes(mut self, bytes: &[u8]) -> Self {
+        for &b in bytes {
+            self.allowed[b as usize] = true;
+        }
+        self
+    }
+
+    pub fn with_len(self, min_len: usize, max_len: usize) -> Self {
+        CharClassRecognizer {
+            min_len,
+            max_len,
+            ..self
+        }
+    }
+
+    /// Only allow EOS when `f` returns true for the bytes accepted so far.
+    pub fn with_eos_when(self, f: fn(&[u8]) -> bool) -> Self {
+        CharClassRecognizer {
+            allow_eos_when: Some(f),
+            ..self
+        }
+    }
+
+    /// The bytes accepted so far.
+    pub fn bytes(&self) -> &[u8] {
+        &self.stack.bytes
+    }
+}
+
+impl Recognizer for CharClassRecognizer {
+    fn pop_bytes(&mut self, num: usize) {
+        self.stack.pop_bytes(num);
+    }
+
+    fn collapse(&mut self) {
+        self.stack.collapse();
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        let bytes = &self.stack.bytes;
+        tok == SpecialToken::EndOfSentence
+            && bytes.len() >= self.min_len
+            && self.allow_eos_when.map_or(true, |f| f(bytes))
+    }
+
+    fn trie_started(&mut self) {
+        self.stack.trie_started();
+    }
+
+    fn trie_finished(&mut self) {
+        self.stack.trie_finished();
+    }
+
+    fn peek_byte(&self, byte: u8) -> Option<bool> {
+        Some(self.allowed[byte as usize] && self.stack.bytes.len() < self.max_len)
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        if self.allowed[byte as usize] && self.stack.bytes.len() < self.max_len {
+            self.stack.bytes.push(byte);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Accepts exactly the prefixes of a fixed set of byte strings, like the values
+/// of an enum field, and allows EOS after any of the strings.
+#[derive(Clone, Debug)]
+pub struct AnyOfRecognizer {
+    // sorted, without duplicates
+    allowed: Vec<Vec<u8>>,
+    // (start, end, len): allowed[start..end] are the strings starting with
+    // the len bytes pushed so far
+    stack: Vec<(usize, usize, usize)>,
+    // stack length at trie_started()
+    base: usize,
+}
+
+impl AnyOfRecognizer {
+    pub fn new<T: AsRef<[u8]>>(allowed: impl IntoIterator<Item = T>) -> Self {
+        let mut allowed: Vec<Vec<u8>> = allowed.into_iter().map(|s| s.as_ref().to_vec()).collect();
+        allowed.sort();
+        allowed.dedup();
+        let stack = vec![(0, allowed.len(), 0)];
+        AnyOfRecognizer {
+            allowed,
+            stack,
+            base: 1,
+        }
+    }
+
+    /// Number of bytes consumed since construction.
+    pub fn num_bytes(&self) -> usize {
+        self.stack.last().unwrap().2
+    }
+
+    fn top(&self) -> &[Vec<u8>] {
+        let (start, end, _) = *self.stack.last().unwrap();
+        &self.allowed[start..end]
+    }
+
+    /// The strings in `top()` continuing with `byte`.
+    fn byte_range(&self, byte: u8) -> (usize, usize) {
+        let len = self.num_bytes();
+        // strings of length len (at most one) sort first, and the rest are sorted by byte len
+        let strs = self.top();
+        let lo = strs.partition_point(|s| s.len() <= len || s[len] < byte);
+        let hi = strs.partition_point(|s| s.len() <= len || s[len] <= byte);
+        (lo, hi)
+    }
+}
+
+impl Recognizer for AnyOfRecognizer {
+    fn pop_bytes(&mut self, num: usize) {
+        self.stack.truncate(self.stack.len() - num);
+    }
+
+    fn collapse(&mut self) {
+        let top = *self.stack.last().unwrap();
+        self.stack.clear();
+        self.stack.push(top);
+        self.base = 1;
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        // an exact match sorts first among the strings with this prefix
+        let len = self.num_bytes();
+        tok == SpecialToken::EndOfSentence && self.top().first().is_some_and(|s| s.len() == len)
+    }
+
+    fn trie_started(&mut self) {
+        self.base = self.stack.len();
+    }
+
+    fn trie_finished(&mut self) {
+        self.stack.truncate(self.base);
+    }
+
+    fn peek_byte(&self, byte: u8) -> Option<bool> {
+        let (lo, hi) = self.byte_range(byte);
+        Some(lo < hi)
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        let (start, _, len) = *self.stack.last().unwrap();
+        let (lo, hi) = self.byte_range(byte);
+        if lo == hi {
+            return false;
+        }
+        self.stack.push((start + lo, start + hi, len + 1));
+        true
+    }
+}
+
+/// A recognizer of chars rather than bytes, with the stack discipline of `Recognizer`
+/// in chars; see `Utf8Recognizer`.
+pub trait CharRecognizer {
+    /// for _ in 0..num { stack.pop() }
+    fn pop_chars(&mut self, num: usize);
+    /// X = stack.top(); stack.empty(); stack.push(X)
+    fn collapse(&mut self);
+    /// check if stack.top() transitions via tok to a viable state
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool;
+    /// Push the char if it's allowed.
+    fn try_push_char(&mut self, c: char) -> bool;
+    /// Whether some char in `range` may be allowed next; used to reject the first bytes
+    /// of a UTF-8 sequence, before the char is known. The default says yes, which lets
+    /// a token end with the start of a char that then turns out not to be allowed;
+    /// override it to rule such tokens out.
+    fn char_in_range_allowed(&mut self, _range: RangeInclusive<char>) -> bool {
+        true
+    }
+    /// Check if there are any errors to be reported to the user.
+    fn get_error(&mut self) -> Option<String> {
+        None
+    }
+}
+
+/// The state after a byte pushed to `Utf8Recognizer`.
+#[derive(Clone, Copy, Debug, Default)]
+struct Utf8Entry {
+    // bits of the char so far, when in the middle of one
+    value: u32,
+    // bytes of the char so far, and how many it has; len == need between chars
+    len: u8,
+    need: u8,
+    // the byte completed a char, which was pushed to the inner recognizer
+    pushed_char: bool,
+}
+
+/// Adapts a `CharRecognizer` to `Recognizer`, by decoding the bytes as UTF-8.
+/// Invalid UTF-8 is rejected, and the bytes of a char are only accepted while some
+/// char they could be the start of may be allowed (see `char_in_range_allowed()`),
+/// so `TokTrie::compute_bias()` only allows tokens keeping the output valid UTF-8,
+/// though they may end in the middle of a char. EOS and other special tokens are
+/// not allowed there.
+#[derive(Clone, Debug)]
+pub struct Utf8Recognizer<R> {
+    inner: R,
+    // one entry per byte, after the initial one
+    stack: Vec<Utf8Entry>,
+    // stack length at trie_started()
+    base: usize,
+}
+
+impl<R: CharRecognizer> Utf8Recognizer<R> {
+    pub fn new(inner: R) -> Self {
+        Utf8Recognizer {
+            inner,
+            stack: vec![Utf8Entry::default()],
+            base: 1,
+        }
+    }
+
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Whether the bytes pushed so far end in the middle of a char.
+    pub fn in_char(&self) -> bool {
+        let top = self.stack.last().unwrap();
+        top.len < top.need
+    }
+}
+
+/// The chars starting with the `len` bytes with `value` bits of a `need`-byte UTF-8
+/// sequence, or None when there are none (overlong encodings, surrogates, past U+10FFFF).
+fn utf8_char_range(value: u32, len: u8, need: u8) -> Option<RangeInclusive<char>> {
+    let rest_bits = 6 * (need - len) as u32;
+    let min_value = [0, 0, 0x80, 0x800, 0x10000][need as usize];
+    let mut lo = core::cmp::max(value << rest_bits, min_value);
+    let mut hi = core::cmp::min((value << rest_bits) | ((1 << rest_bits) - 1), 0x10FFFF);
+    if (0xD800..=0xDFFF).contains(&lo) {
+        lo = 0xE000;
+    }
+    if (0xD800..=0xDFFF).contains(&hi) {
+        hi = 0xD7FF;
+    }
+    if lo > hi {
+        return None;
+    }
+    Some(char::from_u32(lo)?..=char::from_u32(hi)?)
+}
+
+impl<R: CharRecognizer> Recognizer for Utf8Recognizer<R> {
+    fn pop_bytes(&mut self, num: usize) {
+        let new_len = self.stack.len() - num;
+        let num_chars = self.stack[new_len..]
+            .iter()
+            .filter(|e| e.pushed_char)
+            .count();
+        self.stack.truncate(new_len);
+        if num_chars > 0 {
+            self.inner.pop_chars(num_chars);
+        }
+    }
+
+    fn collapse(&mut self) {
+        let top = Utf8Entry {
+            pushed_char: false,
+            ..*self.stack.last().unwrap()
+        };
+        self.stack.clear();
+        self.stack.push(top);
+        self.base = 1;
+        self.inner.collapse();
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        !self.in_char() && self.inner.special_allowed(tok)
+    }
+
+    fn trie_started(&mut self) {
+        self.base = self.stack.len();
+    }
+
+    fn trie_finished(&mut self) {
+        self.pop_bytes(self.stack.len() - self.base);
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        let top = *self.stack.last().unwrap();
+        let (value, len, need) = if top.len < top.need {
+            if byte & 0xC0 != 0x80 {
+                return false;
+            }
+            (top.value << 6 | (byte & 0x3F) as u32, top.len + 1, top.need)
+        } else {
+            match byte.leading_ones() {
+                0 => (byte as u32, 1, 1),
+                2 => ((byte & 0x1F) as u32, 1, 2),
+                3 => ((byte & 0x0F) as u32, 1, 3),
+                4 => ((byte & 0x07) as u32, 1, 4),
+                _ => return false,
+            }
+        };
+        let range = match utf8_char_range(value, len, need) {
+            Some(range) => range,
+            None => return false,
+        };
+        let pushed_char = len == need;
+        let allowed = if pushed_char {
+            self.inner.try_push_char(*range.start())
+        } else {
+            self.inner.char_in_range_allowed(range)
+        };
+        if allowed {
+            self.stack.push(Utf8Entry {
+                value,
+                len,
+                need,
+                pushed_char,
+            });
+        }
+        allowed
+    }
+
+    fn get_error(&mut self) -> Option<String> {
+        self.inner.get_error()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::*;
+    use crate::{rng::Rng, TokRxInfo, TokTrie, TokenId};
+
+    // chars for which `allow` holds, EOS anywhere
+    struct Chars {
+        chars: Vec<char>,
+        allow: fn(char) -> bool,
+        allow_range: fn(RangeInclusive<char>) -> bool,
+    }
+
+    impl Chars {
+        fn any() -> Self {
+            Chars {
+                chars: Vec::new(),
+                allow: |_| true,
+                allow_range: |_| true,
+            }
+        }
+
+        fn greek() -> Self {
+            Chars {
+                chars: Vec::new(),
+                allow: |c| ('\u{370}'..='\u{3ff}').contains(&c),
+                allow_range: |r| *r.start() <= '\u{3ff}' && *r.end() >= '\u{370}',
+            }
+        }
+    }
+
+    impl CharRecognizer for Chars {
+        fn pop_chars(&mut self, num: usize) {
+            self.chars.truncate(self.chars.len() - num);
+        }
+
+        fn collapse(&mut self) {}
+
+        fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+            tok == SpecialToken::EndOfSentence
+        }
+
+        fn try_push_char(&mut self, c: char) -> bool {
+            if (self.allow)(c) {
+                self.chars.push(c);
+                true
+            } else {
+                false
+            }
+        }
+
+        fn char_in_range_allowed(&mut self, range: RangeInclusive<char>) -> bool {
+            (self.allow_range)(range)
+        }
+    }
+
+    fn trie(words: &[&[u8]]) -> TokTrie {
+        let words = words.iter().map(|w| w.to_vec()).collect::<Vec<_>>();
+        TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words)
+    }
+
+    fn allowed(trie: &TokTrie, r: &mut impl Recognizer) -> Vec<TokenId> {
+        let mut logits = trie.alloc_token_set();
+        trie.compute_bias(r, &mut logits);
+        logits.iter().collect()
+    }
+
+    #[test]
+    fn greek_letters() {
+        let trie = trie(&[
+            b"\xff<eos>",
+            "α".as_bytes(),
+            "β".as_bytes(),
+            "αβ".as_bytes(),
+            b"\xce",
+            b"\xb1",
+            b"a",
+            "é".as_bytes(),
+            b"\xff",
+            "αa".as_bytes(),
+        ]);
+        let mut r = Utf8Recognizer::new(Chars::greek());
+        // "\xce" starts U+0380..U+03BF, but "\xb1" starts no char
+        assert_eq!(allowed(&trie, &mut r), vec![0, 1, 2, 3, 4]);
+        assert!(r.inner().chars.is_empty());
+
+        trie.append_token(&mut r, 4).unwrap();
+        assert!(r.in_char());
+        // only "\xb1" completes a char, α; no EOS in the middle of a char
+        assert_eq!(allowed(&trie, &mut r), vec![5]);
+        trie.append_token(&mut r, 5).unwrap();
+        assert!(!r.in_char());
+        assert_eq!(r.inner().chars, vec!['α']);
+        assert_eq!(allowed(&trie, &mut r), vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn emoji_split_across_tokens() {
+        let trie = trie(&[
+            b"\xff<eos>",
+            b"\xf0\x9f",
+            b"\x98\x80",
+            b"\x98",
+            b"a",
+            b"\x80a",
+            b"\xf0\x9f\x98\x80",
+        ]);
+        let mut r = Utf8Recognizer::new(Chars::any());
+        assert_eq!(allowed(&trie, &mut r), vec![0, 1, 4, 6]);
+        trie.append_token(&mut r, 1).unwrap();
+        assert_eq!(allowed(&trie, &mut r), vec![2, 3]);
+        trie.append_token(&mut r, 3).unwrap();
+        // "\x80a" completes the emoji, and then has an "a"; "\x98" makes another one
+        assert_eq!(allowed(&trie, &mut r), vec![3, 5]);
+        assert!(r.inner().chars.is_empty());
+
+        // pops count bytes, but the inner recognizer is popped by chars
+        let mut r = Utf8Recognizer::new(Chars::any());
+        r.trie_started();
+        assert_eq!(r.try_push_bytes("😀a".as_bytes()), 5);
+        assert_eq!(r.inner().chars, vec!['😀', 'a']);
+        r.pop_bytes(1);
+        assert_eq!(r.inner().chars, vec!['😀']);
+        r.pop_bytes(2);
+        assert!(r.inner().chars.is_empty());
+        assert!(r.in_char());
+        assert!(!r.try_push_byte(b'a'));
+        assert!(r.try_push_byte(0x98));
+        r.trie_finished();
+        assert!(!r.in_char());
+        assert!(r.inner().chars.is_empty());
+
+        // invalid UTF-8 is rejected outright
+        for bytes in [
+            &b"\xff"[..],
+            b"\x80",
+            b"\xc0\xaf",
+            b"\xed\xa0\x80",
+            b"\xf4\x90",
+        ] {
+            let mut r = Utf8Recognizer::new(Chars::any());
+            assert!(r.try_push_bytes(bytes) < bytes.len(), "{:?}", bytes);
+        }
+    }
+
+    // tokens picked from the mask, one after another, make valid UTF-8
+    #[test]
+    fn random_token_sequences() {
+        let words = crate::testing::synthetic_vocab(2000, 3)
+            .into_iter()
+            .chain(["α", "😀", "é", "ü"].iter().map(|s| s.as_bytes().to_vec()))
+            .chain(
+                [&b"\xf0"[..], b"\x9f\x98", b"\x80", b"\xce", b"\xc3"]
+                    .iter()
+                    .map(|s| s.to_vec()),
+            )
+            .collect::<Vec<_>>();
+        let trie = TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words);
+        let mut rng = Rng::new(3);
+        for _ in 0..20 {
+            let mut r = Utf8Recognizer::new(Chars::any());
+            let mut bytes = Vec::new();
+            for _ in 0..30 {
+                let tokens = allowed(&trie, &mut r)
+                    .into_iter()
+                    .filter(|&t| t != 0)
+                    .collect::<Vec<_>>();
+                if tokens.is_empty() {
+                    break;
+                }
+                let t = tokens[rng.gen_up_to(tokens.len() - 1)];
+                trie.append_token(&mut r, t).unwrap();
+                bytes.extend_from_slice(trie.token(t));
+            }
+            match core::str::from_utf8(&bytes) {
+                Ok(s) => {
+                    assert!(!r.in_char());
+                    assert_eq!(r.inner().chars.iter().collect::<String>(), s);
+                }
+                // only the last char can be incomplete
+                Err(e) => {
+                    assert!(e.error_len().is_none());
+                    assert!(r.in_char());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn digits_and_enum_masks() {
+        let trie = trie(&[
+            b"\xff<eos>",
+            b"1",
+            b"12",
+            b"123456",
+            b"a",
+            b"1a",
+            b" 1",
+            b"007",
+            b"red",
+            b"re",
+            b"green",
+            b"gr",
+            b"blue",
+            b"bluey",
+            b"r",
+            b"ed",
+            b"\"red\"",
+            b"een",
+            b"d",
+            b"",
+        ]);
+
+        // [0-9]{1,5}
+        let mut r = CharClassRecognizer::digits().with_len(1, 5);
+        assert_eq!(allowed(&trie, &mut r), vec![1, 2, 7]);
+        trie.append_token(&mut r, 2).unwrap();
+        assert_eq!(allowed(&trie, &mut r), vec![0, 1, 2, 7]);
+        trie.append_token(&mut r, 7).unwrap();
+        assert_eq!(r.bytes(), b"12007");
+        assert_eq!(allowed(&trie, &mut r), vec![0]);
+
+        // "red", "green" or "blue"
+        let mut r = AnyOfRecognizer::new(["red", "green", "blue"]);
+        assert_eq!(allowed(&trie, &mut r), vec![8, 9, 10, 11, 12, 14]);
+        for (tok, next) in [(14, vec![15]), (15, vec![0])] {
+            trie.append_token(&mut r, tok).unwrap();
+            assert_eq!(allowed(&trie, &mut r), next);
+        }
+        let mut r = AnyOfRecognizer::new(["red", "green", "blue"]);
+        trie.append_token(&mut r, 11).unwrap();
+        assert_eq!(allowed(&trie, &mut r), vec![17]);
+        let mut r = AnyOfRecognizer::new(["red", "green", "blue"]);
+        trie.append_token(&mut r, 9).unwrap();
+        assert_eq!(allowed(&trie, &mut r), vec![18]);
+        let mut r = AnyOfRecognizer::new(["red", "green", "blue"]);
+        trie.append_token(&mut r, 12).unwrap();
+        assert_eq!(allowed(&trie, &mut r), vec![0]);
+    }
+}
@@ -0,0 +1,360 @@
+use alloc::{format, string::String, vec::Vec};
+
+use anyhow::{anyhow, bail, ensure, Result};
+
+use crate::{
+    rng::Rng,
+    toktree::{Recognizer, SpecialToken},
+    TokTrie, TokenId,
+};
+
+const SPECIAL_TOKENS: [SpecialToken; 6] = [
+    SpecialToken::Unknown,
+    SpecialToken::Padding,
+    SpecialToken::Separator,
+    SpecialToken::BeginningOfSentence,
+    SpecialToken::EndOfSentence,
+    SpecialToken::EndOfTurn,
+];
+
+/// A call made on a `RecordingRecognizer`, with its result.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecognizerOp {
+    TryPushByte(u8, bool),
+    PopBytes(usize),
+    Collapse,
+    SpecialAllowed(SpecialToken, bool),
+    TrieStarted,
+    /// With the number of bytes still pushed since `trie_started()`;
+    /// the walks of `TokTrie` leave none.
+    TrieFinished(usize),
+}
+
+/// Wraps a `Recognizer`, recording every call and tracking the stack depth.
+///
+/// The depth is the number of bytes pushed since the last `collapse()`.
+/// Popping more than that is not forwarded to the inner recognizer; it's reported
+/// by `violation()` instead.
+pub struct RecordingRecognizer<R: Recognizer> {
+    inner: R,
+    ops: Vec<RecognizerOp>,
+    depth: usize,
+    // depth at each trie_started()
+    started: Vec<usize>,
+    violation: Option<String>,
+}
+
+impl<R: Recognizer> RecordingRecognizer<R> {
+    pub fn new(inner: R) -> Self {
+        RecordingRecognizer {
+            inner,
+            ops: Vec::new(),
+            depth: 0,
+            started: Vec::new(),
+            violation: None,
+        }
+    }
+
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Calls recorded since the last `clear_ops()`.
+    pub fn ops(&self) -> &[RecognizerOp] {
+        &self.ops
+    }
+
+    pub fn clear_ops(&mut self) {
+        self.ops.clear();
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// First misuse of the stack seen, if any.
+    pub fn violation(&self) -> Option<&str> {
+        self.violation.as_deref()
+    }
+
+    fn violate(&mut self, msg: String) {
+        if self.violation.is_none() {
+            self.violation = Some(msg);
+        }
+    }
+}
+
+impl<R: Recognizer> Recognizer for RecordingRecognizer<R> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.ops.push(RecognizerOp::PopBytes(num));
+        if num > self.depth {
+            self.violate(format!(
+                "pop_bytes({}) with only {} bytes pushed",
+                num, self.depth
+            ));
+            return;
+        }
+        self.depth -= num;
+        self.inner.pop_bytes(num);
+    }
+
+    fn collapse(&mut self) {
+        self.ops.push(RecognizerOp::Collapse);
+        self.depth = 0;
+        self.inner.collapse();
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        let r = self.inner.special_allowed(tok);
+        self.ops.push(RecognizerOp::SpecialAllowed(tok, r));
+        r
+    }
+
+    fn trie_finished(&mut self) {
+        let start = match self.started.pop() {
+            Some(start) => start,
+            None => {
+                self.violate("trie_finished() without trie_started()".into());
+                self.depth
+            }
+        };
+        if self.depth < start {
+            self.violate(format!(
+                "{} bytes popped below the depth at trie_started()",
+                start - self.depth
+            ));
+        } else if self.depth > start {
+            self.violate(format!(
+                "{} bytes still pushed at trie_finished()",
+                self.depth - start
+            ));
+        }
+        self.ops
+            .push(RecognizerOp::TrieFinished(self.depth.saturating_sub(start)));
+        self.depth = start;
+        self.inner.trie_finished();
+    }
+
+    fn trie_started(&mut self) {
+        self.ops.push(RecognizerOp::TrieStarted);
+        self.started.push(self.depth);
+        self.inner.trie_started();
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        let r = self.inner.try_push_byte(byte);
+        self.ops.push(RecognizerOp::TryPushByte(byte, r));
+        if r {
+            self.depth += 1;
+        }
+        r
+    }
+
+    fn peek_byte(&self, byte: u8) -> Option<bool> {
+        self.inner.peek_byte(byte)
+    }
+
+    fn get_error(&mut self) -> Option<String> {
+        self.inner.get_error()
+    }
+}
+
+// What can be observed about the current state of the recognizer:
+// bytes accepted by try_push_byte() and special tokens allowed.
+// Also checks that byte_allowed() and peek_byte() agree with try_push_byte().
+fn observe(r: &mut impl Recognizer) -> Result<([bool; 256], [bool; 6])> {
+    let mut bytes = [false; 256];
+    for b in 0..=255u8 {
+        let allowed = r.byte_allowed(b);
+        let pushed = check_peek_byte_at(r, b)?;
+        if pushed {
+            r.pop_bytes(1);
+        }
+        if allowed != pushed {
+            bail!(
+                "byte_allowed(0x{:02x}) is {}, but try_push_byte() returned {}",
+                b,
+                allowed,
+                pushed
+            );
+        }
+        bytes[b as usize] = pushed;
+    }
+    let mut special = [false; 6];
+    for (idx, &tok) in SPECIAL_TOKENS.iter().enumerate() {
+        special[idx] = r.special_allowed(tok);
+    }
+    Ok((bytes, special))
+}
+
+// try_push_byte(b), checked against peek_byte(b)
+fn check_peek_byte_at(r: &mut impl Recognizer, b: u8) -> Result<bool> {
+    let peeked = r.peek_byte(b);
+    let pushed = r.try_push_byte(b);
+    if peeked.is_some_and(|p| p != pushed) {
+        if pushed {
+            r.pop_bytes(1);
+        }
+        bail!(
+            "peek_byte(0x{:02x}) is {:?}, but try_push_byte() returned {}",
+            b,
+            peeked,
+            pushed
+        );
+    }
+    Ok(pushed)
+}
+
+/// Walk `r` through `steps` random bytes it accepts (going back a byte now and then),
+/// checking in every state that `peek_byte()`, where it has an answer, agrees
+/// with `try_push_byte()` for all bytes. `r` is left in the state it was in.
+pub fn check_peek_byte(r: &mut impl Recognizer, rng: &mut Rng, steps: usize) -> Result<()> {
+    let mut path = Vec::new();
+    r.trie_started();
+    let res = peek_byte_walk(r, rng, steps, &mut path);
+    r.pop_bytes(path.len());
+    r.trie_finished();
+    res
+}
+
+fn peek_byte_walk(
+    r: &mut impl Recognizer,
+    rng: &mut Rng,
+    steps: usize,
+    path: &mut Vec<u8>,
+) -> Result<()> {
+    for step in 0..steps {
+        let mut allowed = Vec::new();
+        for b in 0..=255u8 {
+            let pushed = check_peek_byte_at(r, b)
+                .map_err(|e| anyhow!("step {}, after bytes {:?}: {}", step, path, e))?;
+            if pushed {
+                r.pop_bytes(1);
+                allowed.push(b);
+            }
+        }
+        if allowed.is_empty() || (!path.is_empty() && rng.gen_up_to(3) == 0) {
+            if path.is_empty() {
+                break;
+            }
+            r.pop_bytes(1);
+            path.pop();
+        } else {
+            let b = allowed[rng.gen_up_to(allowed.len() - 1)];
+            ensure!(
+                r.try_push_byte(b),
+                "step {}, after bytes {:?}: 0x{:02x} accepted, then rejected",
+                step,
+                path,
+                b
+            );
+            path.push(b);
+        }
+    }
+    Ok(())
+}
+
+fn random_token(trie: &TokTrie, rng: &mut Rng) -> TokenId {
+    rng.gen_up_to(trie.vocab_size() - 1) as TokenId
+}
+
+/// Run `compute_bias()`, `has_valid_extensions()`, `token_allowed()`, `chop_tokens()`
+/// and `compute_bias_from()` with random inputs from `rng` on `r`, occasionally advancing `r` by a random allowed byte.
+///
+/// Checks that the stack is never popped below its depth, that the trie walks pop
+/// all the bytes they push before `trie_finished()`, that every operation
+/// leaves the recognizer in the state it found it in (same allowed bytes and special tokens),
+/// and that `byte_allowed()` and `peek_byte()` agree with `try_push_byte()`.
+/// On failure, the error lists the recognizer calls made by the failing operation.
+pub fn check_recognizer_stack_discipline(
+    trie: &TokTrie,
+    r: &mut impl Recognizer,
+    rng: &mut Rng,
+    iterations: usize,
+) -> Result<()> {
+    let mut rec = RecordingRecognizer::new(r);
+    let mut logits = trie.alloc_token_set();
+    let mut state = observe(&mut rec.inner)?;
+    for iteration in 0..iterations {
+        rec.clear_ops();
+        let op = match rng.gen_up_to(5) {
+            0 => {
+                trie.compute_bias(&mut rec, &mut logits);
+                String::from("compute_bias()")
+            }
+            1 => {
+                let tok = trie.token(random_token(trie, rng));
+                let start = &tok[0..rng.gen_up_to(tok.len())];
+                trie.has_valid_extensions(&mut rec, start);
+                format!("has_valid_extensions(start={:?})", start)
+            }
+            2 => {
+                let tok = random_token(trie, rng);
+                trie.token_allowed(&mut rec, tok);
+                format!("token_allowed({})", tok)
+            }
+            3 => {
+                let tokens = (0..=rng.gen_up_to(3))
+                    .map(|_| random_token(trie, rng))
+                    .collect::<Vec<_>>();
+                trie.chop_tokens(&mut rec, &tokens);
+                format!("chop_tokens({:?})", tokens)
+            }
+            4 => {
+                let tok = trie.token(random_token(trie, rng));
+                let start = &tok[0..rng.gen_up_to(tok.len())];
+                let node = trie.resolve_prefix(start).unwrap();
+                trie.compute_bias_from(&mut rec, &mut logits, node);
+                format!("compute_bias_from(start={:?})", start)
+            }
+            _ => {
+                let allowed = (0..=255u8)
+                    .filter(|&b| state.0[b as usize])
+                    .collect::<Vec<_>>();
+                if allowed.is_empty() {
+                    continue;
+                }
+                let b = allowed[rng.gen_up_to(allowed.len() - 1)];
+                rec.try_push_byte(b);
+                rec.collapse();
+                state = observe(&mut rec.inner)?;
+                continue;
+            }
+        };
+
+        let err = if let Some(v) = rec.violation() {
+            Some(String::from(v))
+        } else if rec.depth() != 0 {
+            Some(format!(
+                "stack depth is {} after the operation",
+                rec.depth()
+            ))
+        } else {
+            match observe(&mut rec.inner) {
+                Ok(s) if s != state => {
+                    Some(String::from("recognizer state changed by the operation"))
+                }
+                Ok(_) => None,
+                Err(e) => Some(format!("{}", e)),
+            }
+        };
+        if let Some(err) = err {
+            bail!(
+                "iteration {}: {}: {}; recognizer calls: {:?}",
+                iteration,
+                op,
+                err,
+                rec.ops()
+            );
+        }
+    }
+    Ok(())
+}
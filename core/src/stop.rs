@@ -0,0 +1,304 @@
+use alloc::{collections::VecDeque, vec, vec::Vec};
+
+use crate::toktree::{TokTrie, TokenId};
+
+/// Result of `StopController::push_token()`.
+///
+/// Counts are of bytes of `TokTrie::decode_raw()` of the tokens pushed so far,
+/// so they include `SPECIAL_TOKEN_PREFIX_BYTE` of special tokens.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopResult {
+    /// All the bytes so far can be emitted.
+    Continue,
+    /// The last this many bytes may be the start of a stop sequence, and must be withheld;
+    /// the ones before can be emitted.
+    Hidden(usize),
+    /// Stop sequence `seq_index` was found; the last `trailing_bytes_to_trim` bytes
+    /// are the sequence and whatever followed it in the last token, and are to be dropped.
+    Stopped {
+        seq_index: usize,
+        trailing_bytes_to_trim: usize,
+    },
+}
+
+/// Finds stop sequences in the bytes of a stream of tokens, wherever the token
+/// boundaries fall; see `push_token()`.
+///
+/// The sequences are matched against `TokTrie::decode_raw()`, so a special token
+/// is matched by its name (and by its name preceded by `SPECIAL_TOKEN_PREFIX_BYTE`).
+pub struct StopController<'a> {
+    trie: &'a TokTrie,
+    automaton: StopAutomaton,
+    state: usize,
+    // the bytes matched by state, which are withheld
+    pending: Vec<u8>,
+    stopped: Option<usize>,
+}
+
+impl<'a> StopController<'a> {
+    /// Empty stop sequences are ignored.
+    pub fn new(trie: &'a TokTrie, stop_sequences: Vec<Vec<u8>>) -> Self {
+        StopController {
+            trie,
+            automaton: StopAutomaton::new(&stop_sequences),
+            state: 0,
+            pending: Vec::new(),
+            stopped: None,
+        }
+    }
+
+    /// Add a token. Once a stop sequence is found, it's reported again for every
+    /// further token, with all of its bytes to be trimmed, until `reset()`.
+    pub fn push_token(&mut self, t: TokenId) -> StopResult {
+        let bytes = self.trie.token(t);
+        if let Some(seq_index) = self.stopped {
+            return StopResult::Stopped {
+                seq_index,
+                trailing_bytes_to_trim: bytes.len(),
+            };
+        }
+        for (idx, &b) in bytes.iter().enumerate() {
+            self.state = self.automaton.next(self.state, b);
+            self.pending.push(b);
+            let excess = self.pending.len() - self.automaton.depth[self.state];
+            self.pending.drain(..excess);
+            if let Some((seq_index, len)) = self.automaton.matched[self.state] {
+                self.stopped = Some(seq_index);
+                self.state = 0;
+                self.pending.clear();
+                return StopResult::Stopped {
+                    seq_index,
+                    trailing_bytes_to_trim: len + bytes.len() - idx - 1,
+                };
+            }
+        }
+        match self.pending.len() {
+            0 => StopResult::Continue,
+            n => StopResult::Hidden(n),
+        }
+    }
+
+    /// Bytes currently withheld.
+    pub fn pending_bytes(&self) -> &[u8] {
+        &self.pending
+    }
+
+    /// Return the withheld bytes, which at the end of the stream (EOS) can be emitted,
+    /// and start matching afresh. Nothing is withheld after a stop sequence was found.
+    pub fn flush(&mut self) -> Vec<u8> {
+        self.state = 0;
+        core::mem::take(&mut self.pending)
+    }
+
+    /// Forget everything, including a stop sequence found.
+    pub fn reset(&mut self) {
+        self.flush();
+        self.stopped = None;
+    }
+}
+
+/// Aho-Corasick automaton over the bytes of the stop sequences.
+struct StopAutomaton {
+    // children of each state, sorted by byte
+    goto: Vec<Vec<(u8, usize)>>,
+    fail: Vec<usize>,
+    // length of the prefix of a sequence that the state stands for
+    depth: Vec<usize>,
+    // sequence ending at the state, the longest one if several do, with its length
+    matched: Vec<Option<(usize, usize)>>,
+}
+
+impl StopAutomaton {
+    fn new(seqs: &[Vec<u8>]) -> Self {
+        let mut a = StopAutomaton {
+            goto: vec![Vec::new()],
+            fail: vec![0],
+            depth: vec![0],
+            matched: vec![None],
+        };
+        for (seq_index, seq) in seqs.iter().enumerate() {
+            if seq.is_empty() {
+                continue;
+            }
+            let mut s = 0;
+            for &b in seq {
+                s = match a.child(s, b) {
+                    Some(c) => c,
+                    None => {
+                        let c = a.goto.len();
+                        a.goto.push(Vec::new());
+                        a.fail.push(0);
+                        a.depth.push(a.depth[s] + 1);
+                        a.matched.push(None);
+                        let pos = a.goto[s].partition_point(|e| e.0 < b);
+                        a.goto[s].insert(pos, (b, c));
+                        c
+                    }
+                };
+            }
+            // with repeated sequences, the first one wins
+            if a.matched[s].is_none() {
+                a.matched[s] = Some((seq_index, seq.len()));
+            }
+        }
+
+        // breadth-first, so that fail[] of shallower states is done first
+        let mut queue = VecDeque::from_iter(a.goto[0].iter().map(|e| e.1));
+        while let Some(s) = queue.pop_front() {
+            for idx in 0..a.goto[s].len() {
+                let (b, c) = a.goto[s][idx];
+                a.fail[c] = if s == 0 { 0 } else { a.next(a.fail[s], b) };
+                if a.matched[c].is_none() {
+                    a.matched[c] = a.matched[a.fail[c]];
+                }
+                queue.push_back(c);
+            }
+        }
+        a
+    }
+
+    fn child(&self, s: usize, b: u8) -> Option<usize> {
+        let edges = &self.goto[s];
+        edges
+            .binary_search_by_key(&b, |e| e.0)
+            .ok()
+            .map(|idx| edges[idx].1)
+    }
+
+    fn next(&self, mut s: usize, b: u8) -> usize {
+        loop {
+            if let Some(c) = self.child(s, b) {
+                return c;
+            }
+            if s == 0 {
+                return 0;
+            }
+            s = self.fail[s];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::*;
+    use crate::TokRxInfo;
+
+    fn trie() -> TokTrie {
+        let words = [
+            &b"\xff<eos>"[..],
+            b"He",
+            b"llo",
+            b" wor",
+            b"ld",
+            b"<",
+            b"/s",
+            b">",
+            b"\xff</s>",
+            b"a",
+            b"b",
+            b"c",
+            b"ab",
+            b"\xc3",
+            b"\xa9",
+        ]
+        .iter()
+        .map(|w| w.to_vec())
+        .collect::<Vec<_>>();
+        TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words)
+    }
+
+    fn results(trie: &TokTrie, stops: &[&[u8]], tokens: &[TokenId]) -> Vec<StopResult> {
+        let mut c = StopController::new(trie, stops.iter().map(|s| s.to_vec()).collect());
+        tokens.iter().map(|&t| c.push_token(t)).collect()
+    }
+
+    fn stopped(seq_index: usize, trailing_bytes_to_trim: usize) -> StopResult {
+        StopResult::Stopped {
+            seq_index,
+            trailing_bytes_to_trim,
+        }
+    }
+
+    #[test]
+    fn split_across_tokens() {
+        let trie = trie();
+        // one byte in each of "<" and ">"
+        assert_eq!(
+            results(&trie, &[b"</s>"], &[1, 5, 6, 7, 1]),
+            [
+                StopResult::Continue,
+                StopResult::Hidden(1),
+                StopResult::Hidden(3),
+                stopped(0, 4),
+                stopped(0, 2)
+            ]
+        );
+        // starting and ending inside tokens: what follows in " wor" is trimmed too
+        assert_eq!(
+            results(&trie, &[b"lo w"], &[1, 2, 3, 4]),
+            [
+                StopResult::Continue,
+                StopResult::Hidden(2),
+                stopped(0, 6),
+                stopped(0, 2)
+            ]
+        );
+        // a multi-byte character
+        assert_eq!(
+            results(&trie, &["\u{e9}".as_bytes()], &[9, 13, 14]),
+            [StopResult::Continue, StopResult::Hidden(1), stopped(0, 2)]
+        );
+
+        // withheld bytes are released at the end
+        let mut c = StopController::new(&trie, vec![b"</s>".to_vec()]);
+        c.push_token(5);
+        assert_eq!(c.push_token(6), StopResult::Hidden(3));
+        assert_eq!(c.pending_bytes(), b"</s");
+        assert_eq!(c.flush(), b"</s");
+        assert_eq!(c.push_token(7), StopResult::Continue);
+    }
+
+    #[test]
+    fn overlapping_sequences() {
+        let trie = trie();
+        // "ab" could start "abd", and its "b" "bc", which is then found
+        assert_eq!(
+            results(&trie, &[b"abd", b"bc"], &[9, 10, 11]),
+            [StopResult::Hidden(1), StopResult::Hidden(2), stopped(1, 2)]
+        );
+        // the first to end wins, even inside a longer candidate
+        assert_eq!(results(&trie, &[b"abc", b"b"], &[12]), [stopped(1, 1)]);
+        assert_eq!(
+            results(&trie, &[b"abc", b"bc"], &[12, 11]),
+            [StopResult::Hidden(2), stopped(0, 3)]
+        );
+        // a failed candidate falls back to the longest suffix that is still one
+        assert_eq!(
+            results(&trie, &[b"aab"], &[9, 9, 9, 10]),
+            [
+                StopResult::Hidden(1),
+                StopResult::Hidden(2),
+                StopResult::Hidden(2),
+                stopped(0, 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn special_token_names() {
+        let trie = trie();
+        // the special token "\xff</s>", and the same bytes as text
+        for tokens in [&[8][..], &[5, 6, 7]] {
+            let res = results(&trie, &[b"</s>"], tokens);
+            assert_eq!(res.last(), Some(&stopped(0, 4)));
+        }
+        // with the prefix byte, only the special token matches
+        assert_eq!(results(&trie, &[b"\xff</s>"], &[8]), [stopped(0, 5)]);
+        assert_eq!(
+            results(&trie, &[b"\xff</s>"], &[5, 6, 7]),
+            [StopResult::Continue; 3]
+        );
+    }
+}
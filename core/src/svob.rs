@@ -1,4 +1,7 @@
-use std::{fmt::Debug, hash::Hash, ops::Index};
+use alloc::{string::String, vec::Vec};
+use core::{fmt::Debug, hash::Hash, ops::Index};
+
+use anyhow::{bail, ensure, Result};
 
 pub type TokenId = u32;
 
@@ -9,7 +12,7 @@ pub struct SimpleVob {
 }
 
 impl Hash for SimpleVob {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
         self.size.hash(state);
         self.data.hash(state);
     }
@@ -24,7 +27,7 @@ impl PartialEq for SimpleVob {
 impl Eq for SimpleVob {}
 
 impl Debug for SimpleVob {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("SimpleVob")
             .field("len", &self.len())
             .finish()
@@ -45,6 +48,39 @@ impl Into<Vec<u32>> for SimpleVob {
 
 const BITS: usize = 32;
 
+const RLE_TAG_DENSE: u8 = 0;
+const RLE_TAG_SPARSE: u8 = 1;
+const RLE_TAG_RUNS: u8 = 2;
+
+// LEB128
+fn write_varint(buf: &mut Vec<u8>, mut v: usize) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> Result<usize> {
+    let mut v = 0usize;
+    let mut shift = 0;
+    loop {
+        ensure!(*pos < bytes.len(), "SimpleVob: truncated RLE data");
+        ensure!(shift < usize::BITS, "SimpleVob: varint too long");
+        let b = bytes[*pos];
+        *pos += 1;
+        v |= ((b & 0x7f) as usize) << shift;
+        if b & 0x80 == 0 {
+            return Ok(v);
+        }
+        shift += 7;
+    }
+}
+
+fn varint_len(v: usize) -> usize {
+    (usize::BITS - v.leading_zeros()).max(1).div_ceil(7) as usize
+}
+
 impl SimpleVob {
     pub fn new() -> Self {
         Self {
@@ -63,7 +99,7 @@ impl SimpleVob {
 
     pub fn alloc(size: usize) -> Self {
         let mut r = Self::new();
-        r.resize(size);
+        r.resize(size, false);
         r
     }
 
@@ -76,7 +112,7 @@ impl SimpleVob {
     pub fn alloc_with_capacity(size: usize, capacity: usize) -> Self {
         let mut r = Self::new();
         assert!(size <= capacity);
-        r.resize(capacity);
+        r.resize(capacity, false);
         r.size = size;
         r
     }
@@ -85,6 +121,12 @@ impl SimpleVob {
         self.size
     }
 
+    /// Number of bits that can be set, which is always more than `len()`;
+    /// `TokTrie` uses bit `vocab_size()` for its walks.
+    pub fn capacity(&self) -> usize {
+        self.data.len() * BITS
+    }
+
     pub fn num_set(&self) -> usize {
         self.data.iter().map(|x| x.count_ones() as usize).sum()
     }
@@ -212,6 +254,178 @@ impl SimpleVob {
         }
     }
 
+    /// The words in native byte order, including any spare capacity; see `from_bytes()`.
+    pub fn as_bytes(&self) -> &[u8] {
+        bytemuck::cast_slice(&self.data)
+    }
+
+    /// Inverse of `as_bytes()`: `bytes` is a whole number of native-endian words,
+    /// with room for at least `size + 1` bits, like `alloc(size)` has.
+    /// Bits at `size` and above are cleared.
+    pub fn from_bytes(size: usize, bytes: &[u8]) -> Result<Self> {
+        ensure!(
+            bytes.len() % 4 == 0,
+            "SimpleVob: {} bytes is not a whole number of words",
+            bytes.len()
+        );
+        ensure!(
+            bytes.len() / 4 > size / BITS,
+            "SimpleVob: {} bytes is too short for {} bits",
+            bytes.len(),
+            size
+        );
+        let mut r = Self::new();
+        r.data = bytes
+            .chunks_exact(4)
+            .map(|c| u32::from_ne_bytes(c.try_into().unwrap()))
+            .collect();
+        r.size = size;
+        r.clear_excessive_bits();
+        Ok(r)
+    }
+
+    /// Compact encoding of the set bits (but not the spare capacity), for sending masks around.
+    /// The first byte selects the scheme: raw words, indices of set bits, or run lengths,
+    /// whichever is estimated to be shortest given the density; see `from_rle()`.
+    pub fn to_rle(&self) -> Vec<u8> {
+        let num_words = self.size.div_ceil(BITS);
+        let num_set = self.num_set();
+        let num_runs = self.num_runs();
+        let dense_len = num_words * 4;
+        let sparse_len = num_set * varint_len(self.size / (num_set + 1));
+        let runs_len = num_runs * varint_len(self.size / num_runs);
+
+        let mut res = Vec::new();
+        if sparse_len < dense_len && sparse_len <= runs_len {
+            res.push(RLE_TAG_SPARSE);
+            write_varint(&mut res, self.size);
+            let set: Vec<usize> = self
+                .iter()
+                .map(|idx| idx as usize)
+                .take_while(|&idx| idx < self.size)
+                .collect();
+            write_varint(&mut res, set.len());
+            let mut next = 0;
+            for idx in set {
+                write_varint(&mut res, idx - next);
+                next = idx + 1;
+            }
+        } else if runs_len < dense_len {
+            res.push(RLE_TAG_RUNS);
+            write_varint(&mut res, self.size);
+            // alternating runs of unset and set bits; the last one is implied
+            let mut idx = 0;
+            let mut val = false;
+            loop {
+                let end = self.run_end(idx, val);
+                if end == self.size {
+                    break;
+                }
+                write_varint(&mut res, end - idx);
+                idx = end;
+                val = !val;
+            }
+        } else {
+            res.push(RLE_TAG_DENSE);
+            write_varint(&mut res, self.size);
+            for w in &self.data[0..num_words] {
+                res.extend_from_slice(&w.to_le_bytes());
+            }
+        }
+        res
+    }
+
+    /// Decode the output of `to_rle()`; the result has no spare capacity, as with `alloc()`.
+    pub fn from_rle(bytes: &[u8]) -> Result<Self> {
+        ensure!(!bytes.is_empty(), "SimpleVob: empty RLE data");
+        let mut pos = 1;
+        let size = read_varint(bytes, &mut pos)?;
+        ensure!(
+            size <= u32::MAX as usize,
+            "SimpleVob: RLE size {} too large",
+            size
+        );
+        let mut r = Self::alloc(size);
+        match bytes[0] {
+            RLE_TAG_DENSE => {
+                let num_words = size.div_ceil(BITS);
+                ensure!(
+                    bytes.len() - pos == num_words * 4,
+                    "SimpleVob: expected {} bytes of words, got {}",
+                    num_words * 4,
+                    bytes.len() - pos
+                );
+                for (w, c) in r.data.iter_mut().zip(bytes[pos..].chunks_exact(4)) {
+                    *w = u32::from_le_bytes(c.try_into().unwrap());
+                }
+                r.clear_excessive_bits();
+                return Ok(r);
+            }
+            RLE_TAG_SPARSE => {
+                let num_set = read_varint(bytes, &mut pos)?;
+                let mut next = 0usize;
+                for _ in 0..num_set {
+                    let idx = next.saturating_add(read_varint(bytes, &mut pos)?);
+                    ensure!(idx < size, "SimpleVob: index {} out of range", idx);
+                    r.set(idx, true);
+                    next = idx + 1;
+                }
+            }
+            RLE_TAG_RUNS => {
+                let mut idx = 0usize;
+                let mut val = false;
+                while pos < bytes.len() {
+                    let end = idx.saturating_add(read_varint(bytes, &mut pos)?);
+                    ensure!(end <= size, "SimpleVob: run past the end");
+                    if val {
+                        for i in idx..end {
+                            r.set(i, true);
+                        }
+                    }
+                    idx = end;
+                    val = !val;
+                }
+                if val {
+                    for i in idx..size {
+                        r.set(i, true);
+                    }
+                }
+            }
+            tag => bail!("SimpleVob: unknown RLE tag {}", tag),
+        }
+        ensure!(
+            pos == bytes.len(),
+            "SimpleVob: {} unexpected bytes after RLE data",
+            bytes.len() - pos
+        );
+        Ok(r)
+    }
+
+    /// Number of runs of equal bits below `len()` (an estimate if there is spare capacity).
+    fn num_runs(&self) -> usize {
+        let mut carry = 0;
+        let mut n = 1;
+        for &w in &self.data {
+            n += (w ^ ((w << 1) | carry)).count_ones() as usize;
+            carry = w >> 31;
+        }
+        n
+    }
+
+    /// First index from `start` on whose bit isn't `val`, or `len()`.
+    fn run_end(&self, start: usize, val: bool) -> usize {
+        let mut idx = start;
+        while idx < self.size {
+            let w = self.data[idx / BITS];
+            let w = if val { !w } else { w } >> (idx % BITS);
+            if w != 0 {
+                return core::cmp::min(idx + w.trailing_zeros() as usize, self.size);
+            }
+            idx = (idx / BITS + 1) * BITS;
+        }
+        self.size
+    }
+
     pub fn write_to(&self, buf: &mut [u8]) {
         assert!(buf.len() == self.data.len() * 4);
         bytemuck::cast_slice_mut(buf).copy_from_slice(&self.data);
@@ -238,11 +452,19 @@ impl SimpleVob {
         }
     }
 
-    pub fn resize(&mut self, size: usize) {
-        let new_size = size / BITS + 1;
-        assert!(new_size >= self.data.len());
-        self.data.resize(new_size, 0);
+    /// Change `len()` to `size`, growing or shrinking the capacity to match
+    /// `alloc(size)`. Bits from the old `len()` up to `size` are set to `fill`;
+    /// bits past `size` are cleared.
+    pub fn resize(&mut self, size: usize, fill: bool) {
+        let old_size = self.size;
+        self.data.resize(size / BITS + 1, 0);
         self.size = size;
+        if fill {
+            for idx in old_size..size {
+                self.set(idx, true);
+            }
+        }
+        self.clear_excessive_bits();
     }
 
     #[inline(always)]
@@ -283,6 +505,44 @@ impl SimpleVob {
         SimpleVobIter { vob: self, idx: 0 }
     }
 
+    /// Same as `iter()`: the set bits, in increasing order, skipping zero words.
+    pub fn iter_set_bits(&self) -> SimpleVobIter<'_> {
+        self.iter()
+    }
+
+    fn zip_with(&mut self, other: &SimpleVob, f: impl Fn(u32, u32) -> u32) -> Result<()> {
+        ensure!(
+            self.data.len() == other.data.len(),
+            "SimpleVob capacity mismatch: {} vs {} words",
+            self.data.len(),
+            other.data.len()
+        );
+        for (slf, oth) in self.data.iter_mut().zip(other.data.iter()) {
+            *slf = f(*slf, *oth);
+        }
+        Ok(())
+    }
+
+    /// self &= other; fails if the capacities differ.
+    pub fn and_with(&mut self, other: &SimpleVob) -> Result<()> {
+        self.zip_with(other, |a, b| a & b)
+    }
+
+    /// self |= other; fails if the capacities differ.
+    pub fn or_with(&mut self, other: &SimpleVob) -> Result<()> {
+        self.zip_with(other, |a, b| a | b)
+    }
+
+    /// self &= !other; fails if the capacities differ.
+    pub fn and_not_with(&mut self, other: &SimpleVob) -> Result<()> {
+        self.zip_with(other, |a, b| a & !b)
+    }
+
+    /// self ^= other; fails if the capacities differ.
+    pub fn xor_with(&mut self, other: &SimpleVob) -> Result<()> {
+        self.zip_with(other, |a, b| a ^ b)
+    }
+
     pub fn or(&mut self, other: &SimpleVob) {
         assert_eq!(self.size, other.size);
         for (idx, v) in self.data.iter_mut().zip(other.data.iter()) {
@@ -349,6 +609,18 @@ impl SimpleVob {
         }
         None
     }
+
+    /// First set bit below `len()`.
+    pub fn first_set(&self) -> Option<TokenId> {
+        self.first_bit_set()
+            .filter(|&idx| idx < self.size)
+            .map(|idx| idx as TokenId)
+    }
+
+    /// Number of unset bits before the first set one (`len()` if none is set).
+    pub fn count_leading_zeros(&self) -> usize {
+        self.first_set().map_or(self.size, |idx| idx as usize)
+    }
 }
 
 pub struct SimpleVobIter<'a> {
@@ -389,3 +661,154 @@ impl Index<usize> for SimpleVob {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::*;
+    use crate::rng::Rng;
+
+    // a random set of `size` bits, each set with probability about 1/density, with its bits
+    fn random_vob(rng: &mut Rng, size: usize, density: usize) -> (SimpleVob, Vec<bool>) {
+        let bits = (0..size)
+            .map(|_| rng.gen_up_to(density - 1) == 0)
+            .collect::<Vec<_>>();
+        (SimpleVob::from_slice(&bits), bits)
+    }
+
+    fn set_bits(bits: &[bool]) -> Vec<u32> {
+        (0..bits.len() as u32)
+            .filter(|&i| bits[i as usize])
+            .collect()
+    }
+
+    #[test]
+    fn iter_set_bits() {
+        let mut rng = Rng::new(1);
+        for size in [0, 1, 31, 32, 33, 100, 1000, 128_000] {
+            for density in [1, 2, 7, 1000] {
+                let (v, bits) = random_vob(&mut rng, size, density);
+                assert_eq!(v.iter_set_bits().collect::<Vec<_>>(), set_bits(&bits));
+                assert_eq!(v.num_set(), set_bits(&bits).len());
+                let first = bits.iter().position(|&b| b);
+                assert_eq!(v.first_set(), first.map(|i| i as TokenId));
+                assert_eq!(v.count_leading_zeros(), first.unwrap_or(size));
+            }
+        }
+    }
+
+    #[test]
+    fn set_algebra() {
+        let mut rng = Rng::new(2);
+        type Op = (
+            fn(&mut SimpleVob, &SimpleVob) -> Result<()>,
+            fn(bool, bool) -> bool,
+        );
+        let ops: [Op; 4] = [
+            (SimpleVob::and_with, |a, b| a && b),
+            (SimpleVob::or_with, |a, b| a || b),
+            (SimpleVob::and_not_with, |a, b| a && !b),
+            (SimpleVob::xor_with, |a, b| a != b),
+        ];
+        for size in [1, 32, 77, 5000] {
+            for (op, reference) in ops {
+                let (mut a, bits_a) = random_vob(&mut rng, size, 2);
+                let (b, bits_b) = random_vob(&mut rng, size, 3);
+                op(&mut a, &b).unwrap();
+                let expected = bits_a
+                    .iter()
+                    .zip(&bits_b)
+                    .map(|(&x, &y)| reference(x, y))
+                    .collect::<Vec<_>>();
+                assert_eq!(a, SimpleVob::from_slice(&expected));
+
+                let mut c = SimpleVob::alloc(size + 64);
+                assert!(op(&mut c, &b).is_err());
+                assert!(c.is_zero());
+            }
+        }
+    }
+
+    #[test]
+    fn byte_encodings() {
+        let mut rng = Rng::new(3);
+        for size in [0, 1, 31, 32, 33, 100, 5000] {
+            for density in [1, 2, 7, 1000] {
+                let (v, bits) = random_vob(&mut rng, size, density);
+                let w = SimpleVob::from_bytes(size, v.as_bytes()).unwrap();
+                assert_eq!(w, v);
+
+                let rle = v.to_rle();
+                let w = SimpleVob::from_rle(&rle).unwrap();
+                assert_eq!(w.len(), size);
+                assert_eq!(w.iter_set_bits().collect::<Vec<_>>(), set_bits(&bits));
+                assert!(rle.len() <= 1 + 5 + size.div_ceil(BITS) * 4);
+            }
+        }
+
+        // each scheme where it's the shortest
+        let runs = SimpleVob::from_slice(&(0..5000).map(|i| i >= 1000).collect::<Vec<_>>());
+        let sparse = SimpleVob::from_slice(&(0..5000).map(|i| i % 1000 == 7).collect::<Vec<_>>());
+        let dense = SimpleVob::from_slice(&(0..5000).map(|i| i % 3 == 0).collect::<Vec<_>>());
+        for (v, tag) in [
+            (&runs, RLE_TAG_RUNS),
+            (&sparse, RLE_TAG_SPARSE),
+            (&dense, RLE_TAG_DENSE),
+            (&SimpleVob::alloc(5000), RLE_TAG_SPARSE),
+            (&SimpleVob::alloc_ones(5000), RLE_TAG_RUNS),
+        ] {
+            let rle = v.to_rle();
+            assert_eq!(rle[0], tag);
+            assert_eq!(&SimpleVob::from_rle(&rle).unwrap(), v);
+        }
+        assert_eq!(runs.to_rle(), vec![RLE_TAG_RUNS, 0x88, 0x27, 0xe8, 0x07]);
+        // tag, size, count, then a gap of 7 and four of 999
+        assert_eq!(sparse.to_rle().len(), 1 + 2 + 1 + 1 + 4 * 2);
+
+        // the spare capacity (such as the bit at len(), used by TokTrie) isn't encoded
+        let mut v = SimpleVob::alloc_with_capacity(40, 200);
+        v.set(3, true);
+        v.data[40 / BITS] |= 1 << (40 % BITS);
+        v.data[5] = u32::MAX;
+        let w = SimpleVob::from_rle(&v.to_rle()).unwrap();
+        assert_eq!(w.len(), 40);
+        assert_eq!(w.iter_set_bits().collect::<Vec<_>>(), vec![3]);
+        let w = SimpleVob::from_bytes(40, v.as_bytes()).unwrap();
+        assert_eq!(w.iter_set_bits().collect::<Vec<_>>(), vec![3]);
+    }
+
+    #[test]
+    fn byte_encoding_errors() {
+        assert!(SimpleVob::from_bytes(32, &[0; 4]).is_err());
+        assert!(SimpleVob::from_bytes(31, &[0; 4]).is_ok());
+        assert!(SimpleVob::from_bytes(31, &[0; 6]).is_err());
+
+        let bad: [&[u8]; 10] = [
+            // empty
+            &[],
+            // unknown tag
+            &[7, 0],
+            // truncated size
+            &[RLE_TAG_RUNS, 0x80],
+            // dense: too few and too many words
+            &[RLE_TAG_DENSE, 40, 0, 0, 0, 0],
+            &[RLE_TAG_DENSE, 8, 0, 0, 0, 0, 0],
+            // sparse: index out of range, missing index, trailing bytes
+            &[RLE_TAG_SPARSE, 8, 1, 8],
+            &[RLE_TAG_SPARSE, 8, 2, 1],
+            &[RLE_TAG_SPARSE, 8, 1, 1, 0],
+            // runs: past the end, truncated run length
+            &[RLE_TAG_RUNS, 8, 4, 5],
+            &[RLE_TAG_RUNS, 8, 4, 0x80],
+        ];
+        for bytes in bad {
+            assert!(SimpleVob::from_rle(bytes).is_err(), "{:?}", bytes);
+        }
+        let ok = SimpleVob::from_rle(&[RLE_TAG_RUNS, 8, 4, 2]).unwrap();
+        assert_eq!(ok.iter_set_bits().collect::<Vec<_>>(), vec![4, 5]);
+        assert!(SimpleVob::from_rle(&[RLE_TAG_SPARSE, 8, 0])
+            .unwrap()
+            .is_zero());
+    }
+}
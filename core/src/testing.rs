@@ -0,0 +1,155 @@
+use alloc::{vec, vec::Vec};
+
+use crate::{recognizer::AnyOfRecognizer, rng::Rng, FxHashSet};
+
+/// The recognizer the crate's own checks use; same as `AnyOfRecognizer`.
+pub type PrefixSetRecognizer = AnyOfRecognizer;
+
+// lowercase letters, roughly by English frequency
+const LETTERS: &[u8] = b"eeeeeeeeeeeetttttttttaaaaaaaaoooooooiiiiiiinnnnnnnsssssshhhhhhrrrrrrddddllllcccuuummwwffggyyppbbvkjxqz";
+const CYRILLIC: &str = "абвгдеёжзийклмнопрстуфхцчшщъыьэюя";
+const CJK: &str = "的一是不了人我在有他这中大来上国个到说们为子和你地出道也时年";
+
+#[derive(Clone, Copy)]
+enum Script {
+    Latin,
+    Digits,
+    Cyrillic,
+    Cjk,
+}
+
+fn random_script(rng: &mut Rng) -> Script {
+    match rng.gen_up_to(99) {
+        0..=84 => Script::Latin,
+        85..=87 => Script::Digits,
+        88..=94 => Script::Cyrillic,
+        _ => Script::Cjk,
+    }
+}
+
+fn random_char(rng: &mut Rng, script: Script, out: &mut Vec<u8>) {
+    let mut pick = |s: &str| {
+        let n = s.chars().count();
+        let c = s.chars().nth(rng.gen_up_to(n - 1)).unwrap();
+        out.extend_from_slice(c.encode_utf8(&mut [0u8; 4]).as_bytes());
+    };
+    match script {
+        Script::Latin => out.push(LETTERS[rng.gen_up_to(LETTERS.len() - 1)]),
+        Script::Digits => out.push(b'0' + rng.gen_up_to(9) as u8),
+        Script::Cyrillic => pick(CYRILLIC),
+        Script::Cjk => pick(CJK),
+    }
+}
+
+/// Vocabulary resembling a byte-level BPE one: token 0 is `\xff<|endoftext|>` (a special token),
+/// then the 255 other single bytes, then `size - 256` distinct multi-byte tokens,
+/// mostly words with or without a leading space, with lengths peaking around 4-6 bytes.
+/// Some are Cyrillic or CJK, and many longer tokens extend shorter ones, as BPE merges do.
+pub fn synthetic_vocab(size: usize, seed: usize) -> Vec<Vec<u8>> {
+    assert!(size > 256);
+    let mut rng = Rng::new(seed);
+    let mut res: Vec<Vec<u8>> = vec![b"\xff<|endoftext|>".to_vec()];
+    res.extend((1..=255u8).map(|b| vec![b]));
+    let mut seen: FxHashSet<Vec<u8>> = res.iter().cloned().collect();
+    seen.insert(vec![0]);
+    // cumulative percentages for lengths 2, 3, ...
+    const LEN_CDF: [usize; 15] = [8, 22, 38, 53, 66, 76, 84, 90, 94, 96, 97, 98, 99, 99, 100];
+    while res.len() < size {
+        let p = rng.gen_up_to(99);
+        let len = 2 + LEN_CDF
+            .iter()
+            .position(|&c| p < c)
+            .unwrap_or(LEN_CDF.len() - 1);
+        let mut tok = Vec::new();
+        // extend an existing token half of the time
+        if rng.gen_up_to(1) == 0 && res.len() > 256 {
+            let base = &res[256 + rng.gen_up_to(res.len() - 257)];
+            if base.len() < len {
+                tok.extend_from_slice(base);
+            }
+        }
+        if tok.is_empty() && rng.gen_up_to(2) != 0 {
+            tok.push(b' ');
+        }
+        let script = random_script(&mut rng);
+        while tok.len() < len {
+            random_char(&mut rng, script, &mut tok);
+        }
+        if seen.insert(tok.clone()) {
+            res.push(tok);
+        }
+    }
+    res
+}
+
+/// About `len` bytes of space-separated words (same scripts as `synthetic_vocab()`),
+/// with some punctuation and newlines.
+pub fn synthetic_text(len: usize, seed: usize) -> Vec<u8> {
+    let mut rng = Rng::new(seed);
+    let mut res = Vec::with_capacity(len + 16);
+    while res.len() < len {
+        let script = random_script(&mut rng);
+        for _ in 0..1 + rng.gen_up_to(8) {
+            random_char(&mut rng, script, &mut res);
+        }
+        res.push(match rng.gen_up_to(19) {
+            0 => b',',
+            1 => b'.',
+            2 => b'\n',
+            _ => b' ',
+        });
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TokRxInfo, TokTrie, TokenId};
+
+    fn allowed(trie: &TokTrie, prefixes: &[&str]) -> Vec<TokenId> {
+        let mut r = PrefixSetRecognizer::new(prefixes);
+        let mut mask = trie.alloc_token_set();
+        trie.compute_bias(&mut r, &mut mask);
+        mask.iter().collect()
+    }
+
+    #[test]
+    fn prefix_set_bias() {
+        let words: Vec<Vec<u8>> = [
+            &b"\xff<eos>"[..],
+            b"a",
+            b"ab",
+            b"abc",
+            b"b",
+            b"ba",
+            b"c",
+            b"bc",
+        ]
+        .iter()
+        .map(|w| w.to_vec())
+        .collect();
+        let trie = TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words);
+
+        // EOS only once a whole string is matched
+        assert_eq!(allowed(&trie, &[]), Vec::<TokenId>::new());
+        assert_eq!(allowed(&trie, &[""]), vec![0]);
+        assert_eq!(allowed(&trie, &["a"]), vec![1]);
+        assert_eq!(allowed(&trie, &["abc"]), vec![1, 2, 3]);
+        assert_eq!(allowed(&trie, &["ab", "ba"]), vec![1, 2, 4, 5]);
+        assert_eq!(allowed(&trie, &["bcb", "cab"]), vec![4, 6, 7]);
+        assert_eq!(allowed(&trie, &["", "c", "c"]), vec![0, 6]);
+        assert_eq!(allowed(&trie, &["xyz"]), Vec::<TokenId>::new());
+
+        // after consuming bytes
+        let mut r = PrefixSetRecognizer::new(["abc", "ab", "ba"]);
+        assert_eq!(trie.consume_bytes(&mut r, b"a"), 1);
+        let mut mask = trie.alloc_token_set();
+        trie.compute_bias(&mut r, &mut mask);
+        assert_eq!(mask.iter().collect::<Vec<_>>(), vec![4, 7]);
+        assert_eq!(trie.consume_bytes(&mut r, b"b"), 1);
+        trie.compute_bias(&mut r, &mut mask);
+        assert_eq!(mask.iter().collect::<Vec<_>>(), vec![0, 6]);
+        assert_eq!(trie.consume_bytes(&mut r, b"a"), 0);
+    }
+}
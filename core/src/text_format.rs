@@ -0,0 +1,350 @@
+//! Line-oriented text form of the trie, for goldens and diffing tokenizer versions.
+//!
+//! ```text
+//! toktrie-text 1
+//! vocab_size 5
+//! tok_eos 0
+//! tok_bos none
+//! tok_pad none
+//! tok_unk none
+//! tok_end_of_turn 4
+//! stop_tokens 3 4
+//! tokens
+//! 2 61
+//! 1 6162
+//! 3 6162 dup-of 1
+//! 0 ff3c656f733e special
+//! 4 ff3c656f743e special
+//! ```
+//!
+//! Tokens are listed in trie (byte) order, each followed by its duplicates.
+//! Tokens with no bytes come first, with `-` for the bytes.
+//! Only tokens marked `special` are special tokens, whatever their bytes.
+//! The format is stable; any change needs a new version in the first line.
+
+use alloc::{
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use std::io::{self, BufRead, Write};
+
+use anyhow::{anyhow, ensure, Result};
+
+use crate::{
+    bytes::{from_hex_string, to_hex_string},
+    TokRxInfo, TokTrie, TokenId,
+};
+
+const MAGIC: &str = "toktrie-text";
+const VERSION: u32 = 1;
+
+fn opt_tok_str(tok: Option<TokenId>) -> String {
+    tok.map_or_else(|| "none".to_string(), |t| t.to_string())
+}
+
+fn parse_tok(s: &str) -> Result<TokenId> {
+    s.parse().map_err(|_| anyhow!("invalid token id {:?}", s))
+}
+
+fn parse_opt_tok(s: &str) -> Result<Option<TokenId>> {
+    if s == "none" {
+        Ok(None)
+    } else {
+        parse_tok(s).map(Some)
+    }
+}
+
+impl TokTrie {
+    /// Write the trie in the text format described in `text_format.rs`;
+    /// the output only depends on the tokens, `info()`, and `stop_tokens()`.
+    pub fn dump_text(&self, w: &mut impl Write) -> io::Result<()> {
+        let info = self.info();
+        writeln!(w, "{} {}", MAGIC, VERSION)?;
+        writeln!(w, "vocab_size {}", info.vocab_size)?;
+        writeln!(w, "tok_eos {}", info.tok_eos)?;
+        writeln!(w, "tok_bos {}", opt_tok_str(info.tok_bos))?;
+        writeln!(w, "tok_pad {}", opt_tok_str(info.tok_pad))?;
+        writeln!(w, "tok_unk {}", opt_tok_str(info.tok_unk))?;
+        writeln!(w, "tok_end_of_turn {}", opt_tok_str(info.tok_end_of_turn))?;
+        write!(w, "stop_tokens")?;
+        for t in self.stop_tokens() {
+            write!(w, " {}", t)?;
+        }
+        writeln!(w)?;
+        writeln!(w, "tokens")?;
+
+        let special = |t: TokenId| {
+            if self.is_special_token(t) {
+                " special"
+            } else {
+                ""
+            }
+        };
+        for t in 0..info.vocab_size {
+            if self.token(t).is_empty() {
+                writeln!(w, "{} -", t)?;
+            }
+        }
+        for (t, bytes) in self.sorted_tokens() {
+            let hex = to_hex_string(&bytes);
+            writeln!(w, "{} {}{}", t, hex, special(t))?;
+            let mut dups = self.duplicates_of(t).to_vec();
+            dups.sort_unstable();
+            for d in dups {
+                writeln!(w, "{} {} dup-of {}{}", d, hex, t, special(d))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the output of `dump_text()`.
+    /// The result has the same tokens, info, stop tokens, and canonical tokens.
+    pub fn from_text(r: impl BufRead) -> Result<Self> {
+        Self::from_text_inner(r).map_err(|e| anyhow!("TokTrie: text format: {}", e))
+    }
+
+    fn from_text_inner(r: impl BufRead) -> Result<Self> {
+        let mut lines = r.lines().enumerate();
+        let mut next_line = |key: &str| -> Result<String> {
+            let (idx, line) = lines
+                .next()
+                .ok_or_else(|| anyhow!("missing line {:?}", key))?;
+            let line = line?;
+            let rest = line
+                .strip_prefix(key)
+                .filter(|rest| rest.is_empty() || rest.starts_with(' '))
+                .ok_or_else(|| anyhow!("line {}: expecting {:?}", idx + 1, key))?;
+            Ok(rest.trim_start().to_string())
+        };
+
+        let version = next_line(MAGIC)?;
+        ensure!(
+            version == VERSION.to_string(),
+            "unsupported version {:?}",
+            version
+        );
+        let vocab_size = parse_tok(&next_line("vocab_size")?)?;
+        let info = TokRxInfo {
+            vocab_size,
+            tok_eos: parse_tok(&next_line("tok_eos")?)?,
+            tok_bos: parse_opt_tok(&next_line("tok_bos")?)?,
+            tok_pad: parse_opt_tok(&next_line("tok_pad")?)?,
+            tok_unk: parse_opt_tok(&next_line("tok_unk")?)?,
+            tok_end_of_turn: parse_opt_tok(&next_line("tok_end_of_turn")?)?,
+        };
+        let stop_tokens = next_line("stop_tokens")?
+            .split_whitespace()
+            .map(parse_tok)
+            .collect::<Result<Vec<_>>>()?;
+        for &t in &stop_tokens {
+            ensure!(t < vocab_size, "stop token {} out of range", t);
+        }
+        next_line("tokens")?;
+
+        let mut words: Vec<Option<Vec<u8>>> = vec![None; vocab_size as usize];
+        let mut specials = Vec::new();
+        // (duplicate, canonical)
+        let mut dups = Vec::new();
+        for (idx, line) in lines {
+            let line = line?;
+            let err = |msg: &str| anyhow!("line {}: {}: {:?}", idx + 1, msg, line);
+            let mut fields = line.split(' ');
+            let tok = fields
+                .next()
+                .and_then(|s| s.parse::<TokenId>().ok())
+                .ok_or_else(|| err("invalid token id"))?;
+            let bytes = match fields.next() {
+                Some("-") => Vec::new(),
+                Some(hex) if !hex.is_empty() => {
+                    from_hex_string(hex).map_err(|_| err("invalid hex bytes"))?
+                }
+                _ => return Err(err("missing bytes")),
+            };
+            let mut field = fields.next();
+            if field == Some("dup-of") {
+                let canonical = fields
+                    .next()
+                    .and_then(|s| s.parse::<TokenId>().ok())
+                    .ok_or_else(|| err("invalid dup-of token id"))?;
+                dups.push((tok, canonical));
+                field = fields.next();
+            }
+            if field == Some("special") {
+                specials.push(tok);
+                field = fields.next();
+            }
+            if field.is_some() {
+                return Err(err("unexpected fields"));
+            }
+            let slot = words
+                .get_mut(tok as usize)
+                .ok_or_else(|| err("token id out of range"))?;
+            ensure!(
+                slot.is_none(),
+                "line {}: token {} listed twice",
+                idx + 1,
+                tok
+            );
+            *slot = Some(bytes);
+        }
+        let words = words
+            .into_iter()
+            .enumerate()
+            .map(|(t, w)| w.ok_or_else(|| anyhow!("token {} missing", t)))
+            .collect::<Result<Vec<_>>>()?;
+
+        // the builder keeps either the first or the last of duplicate tokens
+        let keep_first = match dups.first() {
+            Some(&(d, c)) => c < d,
+            None => false,
+        };
+        // tokens starting with the prefix byte but not marked are ordinary ones
+        specials.sort_unstable();
+        let trie = TokTrie::try_from_words(&info, &words, keep_first, Some(&specials))?;
+        for &(d, c) in &dups {
+            ensure!(
+                trie.canonical_token(d) == c,
+                "token {} is a duplicate of {}, but the trie keeps {} (mixed duplicate policies?)",
+                d,
+                c,
+                trie.canonical_token(d)
+            );
+        }
+        let num_dups = (0..vocab_size)
+            .filter(|&t| trie.canonical_token(t) != t)
+            .count();
+        ensure!(
+            num_dups == dups.len(),
+            "some duplicate tokens are not marked"
+        );
+        Ok(trie.with_stop_tokens(&stop_tokens))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::testing::synthetic_vocab;
+
+    fn dump(trie: &TokTrie) -> String {
+        let mut out = Vec::new();
+        trie.dump_text(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn load(text: &str) -> Result<TokTrie> {
+        TokTrie::from_text(text.as_bytes())
+    }
+
+    // the example in the module docs
+    fn example() -> TokTrie {
+        let words: Vec<Vec<u8>> = [&b"\xff<eos>"[..], b"ab", b"a", b"ab", b"\xff<eot>"]
+            .iter()
+            .map(|w| w.to_vec())
+            .collect();
+        let info = TokRxInfo {
+            tok_end_of_turn: Some(4),
+            ..TokRxInfo::new(5, 0)
+        };
+        TokTrie::try_from_words(&info, &words, true, None)
+            .unwrap()
+            .with_stop_tokens(&[3, 4])
+    }
+
+    const EXAMPLE: &str = "\
+toktrie-text 1
+vocab_size 5
+tok_eos 0
+tok_bos none
+tok_pad none
+tok_unk none
+tok_end_of_turn 4
+stop_tokens 3 4
+tokens
+2 61
+1 6162
+3 6162 dup-of 1
+0 ff3c656f733e special
+4 ff3c656f743e special
+";
+
+    #[test]
+    fn dump_snapshot() {
+        let trie = example();
+        assert_eq!(dump(&trie), EXAMPLE);
+        let doc = include_str!("text_format.rs")
+            .lines()
+            .filter_map(|l| l.strip_prefix("//! "))
+            .skip_while(|l| !l.starts_with(MAGIC))
+            .take_while(|l| !l.starts_with("```"))
+            .map(|l| l.to_string() + "\n")
+            .collect::<String>();
+        assert_eq!(doc, EXAMPLE);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut words = synthetic_vocab(3000, 11);
+        for t in (300..3000).step_by(7) {
+            words[t] = words[t - 250].clone();
+        }
+        words[500] = Vec::new();
+        words[501] = b"\xffnot special".to_vec();
+        let info = TokRxInfo {
+            tok_bos: Some(2),
+            tok_unk: Some(500),
+            ..TokRxInfo::new(3000, 0)
+        };
+        for keep_first in [false, true] {
+            let trie = TokTrie::try_from_words(&info, &words, keep_first, Some(&[0]))
+                .unwrap()
+                .with_stop_tokens(&[7]);
+            let text = dump(&trie);
+            let loaded = load(&text).unwrap();
+            assert_eq!(dump(&loaded), text);
+            assert_eq!(loaded.info(), trie.info());
+            assert_eq!(loaded.stop_tokens(), trie.stop_tokens());
+            for t in 0..3000 {
+                assert_eq!(loaded.token(t), trie.token(t));
+                assert_eq!(loaded.canonical_token(t), trie.canonical_token(t));
+                assert_eq!(loaded.is_special_token(t), trie.is_special_token(t));
+            }
+            assert!(!loaded.is_special_token(501));
+        }
+        let trie = example();
+        assert_eq!(dump(&load(EXAMPLE).unwrap()), dump(&trie));
+    }
+
+    #[test]
+    fn errors() {
+        let header = EXAMPLE.split("tokens\n").next().unwrap().to_string() + "tokens\n";
+        let tokens = "2 61\n1 6162\n3 6162 dup-of 1\n0 ff3c656f733e special\n";
+        let bad = [
+            EXAMPLE.replace("toktrie-text 1", "toktrie-text 2"),
+            EXAMPLE.replace("toktrie-text", "toktree-text"),
+            EXAMPLE.replace("tok_bos none\n", ""),
+            EXAMPLE.replace("stop_tokens 3 4", "stop_tokens 3 5"),
+            // token 4 missing
+            header.clone() + tokens,
+            header.clone() + tokens + "4 ff3c656f743e special\n4 62\n",
+            header.clone() + tokens + "5 62\n4 ff3c656f743e special\n",
+            header.clone() + tokens + "4 zz\n",
+            header.clone() + tokens + "4\n",
+            header.clone() + tokens + "4 62 special extra\n",
+            header.clone() + tokens + "x 62\n",
+            // a duplicate not marked as such
+            header.clone() + &tokens.replace(" dup-of 1", "") + "4 ff3c656f743e special\n",
+            // the first of the duplicates isn't the one kept
+            header.clone() + &tokens.replace("dup-of 1", "dup-of 9") + "4 ff3c656f743e special\n",
+        ];
+        for text in &bad {
+            let err = load(text).unwrap_err().to_string();
+            assert!(err.starts_with("TokTrie: text format: "), "{}", err);
+        }
+        let ok = header + tokens + "4 ff3c656f743e special\n";
+        assert_eq!(dump(&load(&ok).unwrap()), EXAMPLE);
+    }
+}
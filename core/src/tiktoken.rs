@@ -0,0 +1,36 @@
+//! Reading tiktoken vocabulary files, with a token per line:
+//! the base64 of its bytes, a space, and its id (rank). See `TokTrie::from_sparse()`.
+
+use alloc::{format, vec::Vec};
+
+use anyhow::{anyhow, Result};
+
+use crate::{bytes::from_base64, TokenId};
+
+/// Panics on malformed input; see `try_parse_tiktoken()`.
+pub fn parse_tiktoken(text: &str) -> Vec<(TokenId, Vec<u8>)> {
+    try_parse_tiktoken(text).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// The `(id, bytes)` of the tokens in `text`, in the order of the lines; empty lines
+/// are skipped. The ids are not checked for gaps or repeats, which `from_sparse()` does.
+pub fn try_parse_tiktoken(text: &str) -> Result<Vec<(TokenId, Vec<u8>)>> {
+    let mut res = Vec::new();
+    for (line_idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let err = |msg: &str| anyhow!("tiktoken line {}: {}", line_idx + 1, msg);
+        let (b64, id) = line
+            .split_once(' ')
+            .ok_or_else(|| err("expecting '<base64> <id>'"))?;
+        let id = id
+            .trim()
+            .parse::<TokenId>()
+            .map_err(|_| err("invalid token id"))?;
+        let bytes = from_base64(b64).map_err(|e| err(&format!("{}", e)))?;
+        res.push((id, bytes));
+    }
+    Ok(res)
+}
@@ -1,17 +1,37 @@
 // use 8:24 encoding - num_ch:tok_id (ch_byte:ch_off)* - 8 bytes per tree node
 // special case num_ch=0xff -> num_ch=0x100
 
-use std::sync::Arc;
+use alloc::{
+    borrow::Cow,
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::ops::Range;
+#[cfg(feature = "metrics")]
+use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "std")]
+use std::{
+    io::{self, Read, Write},
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, ensure, Result};
 use bytemuck_derive::{Pod, Zeroable};
-use rustc_hash::FxHashMap;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 
 use crate::{
-    bytes::{to_hex_string, vec_from_bytes},
-    SimpleVob,
+    bytes::{swap_u32_bytes, to_hex_string, vec_from_bytes, vec_or_slice_from_bytes},
+    FxHashMap, FxHashSet, SimpleVob, StreamDecoder,
 };
 
+#[cfg(test)]
+mod tests;
+
 pub type TokenId = u32;
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Zeroable, Pod)]
@@ -21,6 +41,38 @@ pub struct BinTokRxInfo {
     pub tok_eos: TokenId,
 }
 
+/// The optional token ids of `TokRxInfo`; `BinTokRxInfoExt::NONE` stands for `None`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Zeroable, Pod)]
+#[repr(C)]
+pub struct BinTokRxInfoExt {
+    pub tok_bos: TokenId,
+    pub tok_pad: TokenId,
+    pub tok_unk: TokenId,
+    pub tok_end_of_turn: TokenId,
+}
+
+impl BinTokRxInfoExt {
+    pub const NONE: TokenId = u32::MAX;
+
+    /// All tokens absent.
+    pub fn none() -> Self {
+        BinTokRxInfoExt {
+            tok_bos: Self::NONE,
+            tok_pad: Self::NONE,
+            tok_unk: Self::NONE,
+            tok_end_of_turn: Self::NONE,
+        }
+    }
+}
+
+fn tok_from_bin(tok: TokenId) -> Option<TokenId> {
+    if tok == BinTokRxInfoExt::NONE {
+        None
+    } else {
+        Some(tok)
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct TokRxInfo {
     pub vocab_size: u32,
@@ -54,12 +106,45 @@ impl TokRxInfo {
         }
     }
 
+    /// Token id for the special token, if there is one.
+    pub fn special_token(&self, tok: SpecialToken) -> Option<TokenId> {
+        match tok {
+            SpecialToken::EndOfSentence => Some(self.tok_eos),
+            SpecialToken::BeginningOfSentence => self.tok_bos,
+            SpecialToken::Padding => self.tok_pad,
+            SpecialToken::Unknown => self.tok_unk,
+            SpecialToken::EndOfTurn => self.tok_end_of_turn,
+            SpecialToken::Separator => None,
+        }
+    }
+
+    /// Like `from_bin()`, but also sets the optional tokens.
+    pub fn from_bin_ext(info: &BinTokRxInfo, ext: &BinTokRxInfoExt) -> Self {
+        TokRxInfo {
+            tok_bos: tok_from_bin(ext.tok_bos),
+            tok_pad: tok_from_bin(ext.tok_pad),
+            tok_unk: tok_from_bin(ext.tok_unk),
+            tok_end_of_turn: tok_from_bin(ext.tok_end_of_turn),
+            ..Self::from_bin(info)
+        }
+    }
+
     pub fn to_bin(&self) -> BinTokRxInfo {
         BinTokRxInfo {
             vocab_size: self.vocab_size,
             tok_eos: self.tok_eos,
         }
     }
+
+    pub fn to_bin_ext(&self) -> BinTokRxInfoExt {
+        let f = |tok: Option<TokenId>| tok.unwrap_or(BinTokRxInfoExt::NONE);
+        BinTokRxInfoExt {
+            tok_bos: f(self.tok_bos),
+            tok_pad: f(self.tok_pad),
+            tok_unk: f(self.tok_unk),
+            tok_end_of_turn: f(self.tok_end_of_turn),
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
@@ -72,13 +157,33 @@ pub enum SpecialToken {
     EndOfTurn,
 }
 
+/// When should `compute_bias_ext_eos()` allow EOS (and the end-of-turn token, if set).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum EosMode {
+    /// If the recognizer allows it, and there are no forced `start` bytes;
+    /// this is what `compute_bias_ext()` does.
+    #[default]
+    Auto,
+    /// Never.
+    Never,
+    /// If the recognizer allows it, regardless of `start`
+    /// (the recognizer only sees the bytes after `start`).
+    /// Use this when `start` are bytes chopped off by `chop_tokens()`:
+    /// these were already generated, so the sequence could end right after them.
+    AfterPrefix,
+}
+
 pub trait Recognizer {
     /// for _ in 0..num { stack.pop() }
     fn pop_bytes(&mut self, num: usize);
     /// X = stack.top(); stack.empty(); stack.push(X)
     fn collapse(&mut self);
-    /// check if stack.top() transitions via byte to a viable state
+    /// check if stack.top() transitions via byte to a viable state;
+    /// uses `peek_byte()` when it has an answer, and pushes and pops the byte otherwise
     fn byte_allowed(&mut self, byte: u8) -> bool {
+        if let Some(allowed) = self.peek_byte(byte) {
+            return allowed;
+        }
         if self.try_push_byte(byte) {
             self.pop_bytes(1);
             true
@@ -86,23 +191,281 @@ pub trait Recognizer {
             false
         }
     }
+    /// What `try_push_byte(byte)` would return, without changing the state;
+    /// `None` when that can't be told cheaply (the default).
+    /// Override it when pushing is expensive, and checking a byte is not:
+    /// `byte_allowed()`, and through it `token_allowed()`, `has_valid_extensions()`
+    /// and `forced_bytes()`, use it. The walk of `compute_bias()` doesn't,
+    /// since it goes on from the accepted bytes anyway.
+    fn peek_byte(&self, _byte: u8) -> Option<bool> {
+        None
+    }
     /// check if stack.top() transitions via tok to a viable state
     fn special_allowed(&mut self, tok: SpecialToken) -> bool;
-    /// Called when iteration over the trie is finished
-    /// Stack has exactly one element then, except when iteration started from non-root node.
-    /// In that case, the stack may have more than one element, and trie_finished() needs to pop the excessive elements.
+    /// Called when iteration over the trie is finished.
+    /// All the bytes pushed since `trie_started()` are popped by then, also when
+    /// the iteration started from a non-root node (`compute_bias_ext()`, `add_bias_from()`),
+    /// so the stack is as it was at `trie_started()`.
     fn trie_finished(&mut self);
     /// Called when iteration over the trie is started
     fn trie_started(&mut self) {}
     /// This combines `push_byte` and `byte_allowed` into one function for performance.
     fn try_push_byte(&mut self, byte: u8) -> bool;
+    /// Push bytes while they are allowed; return how many were pushed.
+    /// Override when the recognizer can consume many bytes at once.
+    fn try_push_bytes(&mut self, bytes: &[u8]) -> usize {
+        for (idx, &byte) in bytes.iter().enumerate() {
+            if !self.try_push_byte(byte) {
+                return idx;
+            }
+        }
+        bytes.len()
+    }
     /// Check if there are any errors to be reported to the user.
+    /// The `try_*()` methods of `TokTrie` call this once, after `trie_finished()`,
+    /// and `append_token()` when a byte was rejected. A recognizer reporting an error
+    /// still has to keep its stack consistent (rejecting bytes is fine), since the walk
+    /// goes on and pops what it pushed before the error is looked at.
     fn get_error(&mut self) -> Option<String> {
         None
     }
 }
 
-pub trait TokenizerEnv: Send {
+impl<R: Recognizer + ?Sized> Recognizer for &mut R {
+    fn pop_bytes(&mut self, num: usize) {
+        (**self).pop_bytes(num)
+    }
+    fn collapse(&mut self) {
+        (**self).collapse()
+    }
+    fn byte_allowed(&mut self, byte: u8) -> bool {
+        (**self).byte_allowed(byte)
+    }
+    fn peek_byte(&self, byte: u8) -> Option<bool> {
+        (**self).peek_byte(byte)
+    }
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        (**self).special_allowed(tok)
+    }
+    fn trie_finished(&mut self) {
+        (**self).trie_finished()
+    }
+    fn trie_started(&mut self) {
+        (**self).trie_started()
+    }
+    #[inline(always)]
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        (**self).try_push_byte(byte)
+    }
+    fn try_push_bytes(&mut self, bytes: &[u8]) -> usize {
+        (**self).try_push_bytes(bytes)
+    }
+    fn get_error(&mut self) -> Option<String> {
+        (**self).get_error()
+    }
+}
+
+/// Allows a byte only if both recognizers allow it.
+#[derive(Clone)]
+pub struct AndRecognizer<A: Recognizer, B: Recognizer> {
+    a: A,
+    b: B,
+}
+
+impl<A: Recognizer, B: Recognizer> AndRecognizer<A, B> {
+    pub fn new(a: A, b: B) -> Self {
+        AndRecognizer { a, b }
+    }
+
+    pub fn into_inner(self) -> (A, B) {
+        (self.a, self.b)
+    }
+}
+
+impl<A: Recognizer, B: Recognizer> Recognizer for AndRecognizer<A, B> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.a.pop_bytes(num);
+        self.b.pop_bytes(num);
+    }
+
+    fn collapse(&mut self) {
+        self.a.collapse();
+        self.b.collapse();
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        self.a.special_allowed(tok) && self.b.special_allowed(tok)
+    }
+
+    fn peek_byte(&self, byte: u8) -> Option<bool> {
+        match (self.a.peek_byte(byte), self.b.peek_byte(byte)) {
+            (Some(false), _) | (_, Some(false)) => Some(false),
+            (Some(true), Some(true)) => Some(true),
+            _ => None,
+        }
+    }
+
+    fn trie_finished(&mut self) {
+        self.a.trie_finished();
+        self.b.trie_finished();
+    }
+
+    fn trie_started(&mut self) {
+        self.a.trie_started();
+        self.b.trie_started();
+    }
+
+    #[inline(always)]
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        if !self.a.try_push_byte(byte) {
+            return false;
+        }
+        if !self.b.try_push_byte(byte) {
+            // keep the stacks in sync
+            self.a.pop_bytes(1);
+            return false;
+        }
+        true
+    }
+
+    fn try_push_bytes(&mut self, bytes: &[u8]) -> usize {
+        let num_a = self.a.try_push_bytes(bytes);
+        let num_b = self.b.try_push_bytes(&bytes[..num_a]);
+        self.a.pop_bytes(num_a - num_b);
+        num_b
+    }
+
+    fn get_error(&mut self) -> Option<String> {
+        self.a.get_error().or_else(|| self.b.get_error())
+    }
+}
+
+/// Allows a byte if either recognizer allows it.
+///
+/// Once a recognizer rejects a byte, it is not consulted for the following bytes,
+/// until these are popped; a recognizer that is rejected at the point of `collapse()`
+/// stays rejected.
+#[derive(Clone)]
+pub struct OrRecognizer<A: Recognizer, B: Recognizer> {
+    a: A,
+    b: B,
+    // which of a, b is alive, for every byte on the stack
+    alive: Vec<(bool, bool)>,
+    // alive.len() at trie_started()
+    walk_start: usize,
+}
+
+impl<A: Recognizer, B: Recognizer> OrRecognizer<A, B> {
+    pub fn new(a: A, b: B) -> Self {
+        OrRecognizer {
+            a,
+            b,
+            alive: vec![(true, true)],
+            walk_start: 1,
+        }
+    }
+
+    pub fn into_inner(self) -> (A, B) {
+        (self.a, self.b)
+    }
+}
+
+impl<A: Recognizer, B: Recognizer> Recognizer for OrRecognizer<A, B> {
+    fn pop_bytes(&mut self, num: usize) {
+        let mut num_a = 0;
+        let mut num_b = 0;
+        for _ in 0..num {
+            let (a, b) = self.alive.pop().unwrap();
+            num_a += a as usize;
+            num_b += b as usize;
+        }
+        self.a.pop_bytes(num_a);
+        self.b.pop_bytes(num_b);
+    }
+
+    fn collapse(&mut self) {
+        let top = *self.alive.last().unwrap();
+        self.a.collapse();
+        self.b.collapse();
+        self.alive.clear();
+        self.alive.push(top);
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        let (a, b) = *self.alive.last().unwrap();
+        (a && self.a.special_allowed(tok)) || (b && self.b.special_allowed(tok))
+    }
+
+    fn peek_byte(&self, byte: u8) -> Option<bool> {
+        let (a, b) = *self.alive.last().unwrap();
+        let a = if a {
+            self.a.peek_byte(byte)
+        } else {
+            Some(false)
+        };
+        let b = if b {
+            self.b.peek_byte(byte)
+        } else {
+            Some(false)
+        };
+        match (a, b) {
+            (Some(true), _) | (_, Some(true)) => Some(true),
+            (Some(false), Some(false)) => Some(false),
+            _ => None,
+        }
+    }
+
+    fn trie_finished(&mut self) {
+        // the inner recognizers pop their own excess elements
+        self.alive.truncate(self.walk_start);
+        self.a.trie_finished();
+        self.b.trie_finished();
+    }
+
+    fn trie_started(&mut self) {
+        self.walk_start = self.alive.len();
+        self.a.trie_started();
+        self.b.trie_started();
+    }
+
+    #[inline(always)]
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        let (a, b) = *self.alive.last().unwrap();
+        let a = a && self.a.try_push_byte(byte);
+        let b = b && self.b.try_push_byte(byte);
+        if a || b {
+            self.alive.push((a, b));
+            true
+        } else {
+            false
+        }
+    }
+
+    fn get_error(&mut self) -> Option<String> {
+        // an error only if all the live recognizers report one
+        let (a, b) = *self.alive.last().unwrap();
+        let err_a = if a { Some(self.a.get_error()?) } else { None };
+        let err_b = if b { Some(self.b.get_error()?) } else { None };
+        err_a.or(err_b)
+    }
+}
+
+/// `Send` on native targets; nothing on wasm32 (or with `--cfg toktrie_local_env`),
+/// where tokenizers may hold single-threaded state, e.g., JS objects.
+#[cfg(not(any(target_arch = "wasm32", toktrie_local_env)))]
+pub trait MaybeSend: Send {}
+#[cfg(not(any(target_arch = "wasm32", toktrie_local_env)))]
+impl<T: Send + ?Sized> MaybeSend for T {}
+
+/// `Send` on native targets; nothing on wasm32 (or with `--cfg toktrie_local_env`),
+/// where tokenizers may hold single-threaded state, e.g., JS objects.
+#[cfg(any(target_arch = "wasm32", toktrie_local_env))]
+pub trait MaybeSend {}
+#[cfg(any(target_arch = "wasm32", toktrie_local_env))]
+impl<T: ?Sized> MaybeSend for T {}
+
+#[cfg(feature = "std")]
+pub trait TokenizerEnv: MaybeSend {
     /// Stop the program; not used.
     // TODO remove this
     fn stop(&self) -> !;
@@ -114,21 +477,20 @@ pub trait TokenizerEnv: Send {
     /// It may or may not interpret <|special_tokens|> as special.
     fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId>;
 
+    /// Like `tokenize_bytes()`, but appends to `out`.
+    /// Override to avoid allocating a vector for every call.
+    fn tokenize_bytes_into(&self, s: &[u8], out: &mut Vec<TokenId>) {
+        out.extend_from_slice(&self.tokenize_bytes(s));
+    }
+
     /// Tokenize a given byte sequence.
     /// It will interpret text starting with SPECIAL_TOKEN_PREFIX_BYTE as special tokens.
+    /// Prefix bytes not followed by the name of a special token in the trie are passed
+    /// to `tokenize_bytes()` with the rest of the text.
     fn tokenize_bytes_prefix(&self, s: &[u8]) -> Vec<TokenId> {
         if s.contains(&TokTrie::SPECIAL_TOKEN_PREFIX_BYTE) {
-            let copy = s
-                .iter()
-                .filter_map(|&b| {
-                    if b == TokTrie::SPECIAL_TOKEN_PREFIX_BYTE {
-                        None
-                    } else {
-                        Some(b)
-                    }
-                })
-                .collect::<Vec<_>>();
-            self.tokenize_bytes(&copy)
+            self.tok_trie()
+                .tokenize_around_specials(s, |text| self.tokenize_bytes(text))
         } else {
             self.tokenize_bytes(s)
         }
@@ -148,21 +510,138 @@ pub trait TokenizerEnv: Send {
     fn eos_token(&self) -> TokenId {
         self.tok_trie().eos_token()
     }
+
+    /// Check if tokenizing `text` after `prefix_tokens` leaves the prefix tokens unchanged;
+    /// see `TokTrie::retokenize_check()`.
+    fn retokenize_check(&self, prefix_tokens: &[TokenId], text: &[u8]) -> RetokenizeResult {
+        retokenize_with(self.tok_trie(), prefix_tokens, text, |s| {
+            self.tokenize_bytes_prefix(s)
+        })
+    }
+
+    /// Check if `tokens` is what the tokenizer produces for the bytes of `tokens`.
+    fn tokenize_is_canonical(&self, tokens: &[TokenId]) -> bool {
+        self.tokenize_bytes_prefix(&self.tok_trie().decode_raw(tokens)) == tokens
+    }
+}
+
+#[cfg(feature = "std")]
+/// Result of `TokTrie::retokenize_check()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetokenizeResult {
+    /// How many trailing prefix tokens were tokenized differently.
+    pub num_changed: usize,
+    /// Tokens that replace the last `num_changed` prefix tokens, followed by the tokens of the text.
+    pub tokens: Vec<TokenId>,
+}
+
+#[cfg(feature = "std")]
+impl RetokenizeResult {
+    /// True if the prefix tokens were kept as is.
+    pub fn is_clean(&self) -> bool {
+        self.num_changed == 0
+    }
+}
+
+/// Result of `TokTrie::heal_tokens()`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HealResult {
+    /// Number of leading tokens to keep.
+    pub keep_tokens: usize,
+    /// The bytes of `chopped_tokens`, as is (including any special prefix bytes),
+    /// to be passed as `start` to `compute_bias_ext()`.
+    pub forced_prefix: Vec<u8>,
+    /// The tokens after the first `keep_tokens`.
+    pub chopped_tokens: Vec<TokenId>,
+}
+
+/// Buffers reused by the `_with_scratch()` methods of `TokTrie`, which then don't
+/// allocate; see `TokTrie::new_scratch()`. Only for use with tries of the vocabulary size
+/// of the one that made it.
+#[derive(Clone, Debug)]
+pub struct TrieScratch {
+    // bytes of the trailing tokens in chop_tokens_ext()
+    suffix: Vec<u8>,
+    logits: SimpleVob,
+    forced: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+fn retokenize_with(
+    trie: &TokTrie,
+    prefix_tokens: &[TokenId],
+    text: &[u8],
+    tokenize: impl FnOnce(&[u8]) -> Vec<TokenId>,
+) -> RetokenizeResult {
+    // decode_raw() keeps special token prefixes, and doesn't care about
+    // partial UTF-8 at the end of the prefix
+    let mut bytes = trie.decode_raw(prefix_tokens);
+    bytes.extend_from_slice(text);
+    let mut tokens = tokenize(&bytes);
+    let num_same = prefix_tokens
+        .iter()
+        .zip(tokens.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    tokens.drain(..num_same);
+    RetokenizeResult {
+        num_changed: prefix_tokens.len() - num_same,
+        tokens,
+    }
 }
 
+#[cfg(all(feature = "std", not(any(target_arch = "wasm32", toktrie_local_env))))]
 pub type TokEnv = Arc<dyn TokenizerEnv + Sync + 'static>;
+/// Without `Sync`, as `TokenizerEnv` is not `Send` there; see `MaybeSend`.
+#[cfg(all(feature = "std", any(target_arch = "wasm32", toktrie_local_env)))]
+pub type TokEnv = Arc<dyn TokenizerEnv + 'static>;
+
+#[cfg(feature = "std")]
+/// Where `TokEnvWithTrie::eos_token()` comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum EosSource {
+    /// The overriding trie, e.g., one from `build_chat_mode_trie()`.
+    #[default]
+    Trie,
+    /// The base env.
+    Base,
+}
 
+#[cfg(feature = "std")]
+/// Replaces the trie of `base_env`; tokenization is forwarded to `base_env`.
 pub struct TokEnvWithTrie {
     base_env: TokEnv,
     tok_trie: TokTrie,
+    eos_source: EosSource,
 }
 
+#[cfg(feature = "std")]
 impl TokEnvWithTrie {
     pub fn new(base_env: TokEnv, tok_trie: TokTrie) -> Self {
-        Self { base_env, tok_trie }
+        Self::new_with_eos_source(base_env, tok_trie, EosSource::Trie)
+    }
+
+    pub fn new_with_eos_source(base_env: TokEnv, tok_trie: TokTrie, eos_source: EosSource) -> Self {
+        Self {
+            base_env,
+            tok_trie,
+            eos_source,
+        }
+    }
+
+    /// Wrap `base_env` with the trie returned by `f`, e.g.,
+    /// `TokEnvWithTrie::map_trie(env, |t| t.build_chat_mode_trie())`.
+    pub fn map_trie(base_env: TokEnv, f: impl FnOnce(&TokTrie) -> TokTrie) -> Self {
+        let tok_trie = f(base_env.tok_trie());
+        Self::new(base_env, tok_trie)
+    }
+
+    pub fn base_env(&self) -> &TokEnv {
+        &self.base_env
     }
 }
 
+#[cfg(feature = "std")]
 impl TokenizerEnv for TokEnvWithTrie {
     fn tok_trie(&self) -> &TokTrie {
         &self.tok_trie
@@ -175,957 +654,6769 @@ impl TokenizerEnv for TokEnvWithTrie {
     fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
         self.base_env.tokenize_bytes(s)
     }
-}
 
-#[derive(Clone)]
-pub struct TokTrie {
-    info: TokRxInfo,
-    token_offsets: Vec<u32>,
-    token_data: Vec<u8>,
-    nodes: Vec<TrieNode>,
-    max_token_len: usize,
-    token_duplicates: FxHashMap<TokenId, Vec<TokenId>>,
-}
+    fn tokenize_bytes_into(&self, s: &[u8], out: &mut Vec<TokenId>) {
+        self.base_env.tokenize_bytes_into(s, out)
+    }
 
-#[derive(Clone, Copy, Zeroable, Pod)]
-#[repr(C)]
-pub struct TokTrieHeader {
-    magic: u32,
-    hd_size: u32,
-    trie_bytes: u32,
-    token_offset_bytes: u32,
-    token_data_bytes: u32,
-    info: BinTokRxInfo,
-    align: [u32; 0],
-}
+    fn tokenize_bytes_prefix(&self, s: &[u8]) -> Vec<TokenId> {
+        self.base_env.tokenize_bytes_prefix(s)
+    }
 
-impl TokTrieHeader {
-    const MAGIC: u32 = 0x558b6fd3;
+    fn tokenize(&self, s: &str) -> Vec<TokenId> {
+        self.base_env.tokenize(s)
+    }
+
+    fn tokenize_special(&self, s: &str) -> Vec<TokenId> {
+        self.base_env.tokenize_special(s)
+    }
+
+    fn eos_token(&self) -> TokenId {
+        match self.eos_source {
+            EosSource::Trie => self.tok_trie.eos_token(),
+            EosSource::Base => self.base_env.eos_token(),
+        }
+    }
 }
 
-#[derive(Clone, Copy, Zeroable, Pod)]
-#[repr(C)]
-pub struct TrieNode {
-    // byte:token
-    bits: u32,
-    bits2: u32,
+#[cfg(feature = "std")]
+/// `TokenizerEnv` that only uses the trie, tokenizing greedily (longest match first).
+/// This doesn't match what the model's tokenizer would do, but is enough for tests and tools.
+pub struct TrieTokenizerEnv {
+    trie: TokTrie,
 }
 
-const NO_TOKEN: u32 = 0xffffff;
+#[cfg(feature = "std")]
+impl TrieTokenizerEnv {
+    pub fn new(trie: TokTrie) -> Self {
+        Self { trie }
+    }
 
-impl TrieNode {
-    fn new(byte: u8, token_id: u32, num_parents: u8) -> TrieNode {
-        TrieNode {
-            bits: (token_id << 8) | byte as u32,
-            bits2: num_parents as u32,
-        }
+    pub fn from_words(info: &TokRxInfo, words: &[Vec<u8>]) -> Result<Self> {
+        Ok(Self::new(TokTrie::try_from_words(
+            info, words, false, None,
+        )?))
     }
+}
 
-    #[inline(always)]
-    pub fn byte(&self) -> u8 {
-        (self.bits & 0xff) as u8
+#[cfg(feature = "std")]
+impl TokenizerEnv for TrieTokenizerEnv {
+    fn stop(&self) -> ! {
+        panic!("TrieTokenizerEnv::stop() called")
     }
 
-    #[inline(always)]
-    pub fn subtree_size(&self) -> usize {
-        (self.bits2 >> 8) as usize
+    fn tok_trie(&self) -> &TokTrie {
+        &self.trie
     }
 
-    #[inline(always)]
-    pub fn num_parents(&self) -> usize {
-        (self.bits2 & 0xff) as usize
+    fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
+        self.trie.greedy_tokenize(s)
     }
 
-    #[inline(always)]
-    pub fn token_id(&self) -> Option<u32> {
-        let r = self.bits >> 8;
-        if r == NO_TOKEN {
-            None
-        } else {
-            Some(r)
+    /// Treats `<|name|>` as a special token, if the trie has one with this name.
+    fn tokenize_special(&self, s: &str) -> Vec<TokenId> {
+        let mut res = Vec::new();
+        let mut text_start = 0;
+        let mut pos = 0;
+        while let Some(off) = s[pos..].find("<|") {
+            let start = pos + off;
+            let end = match s[start + 2..].find("|>") {
+                Some(len) => start + 2 + len + 2,
+                None => break,
+            };
+            if let Some(tok) = self.trie.get_special_token(&s[start..end]) {
+                res.extend(self.tokenize(&s[text_start..start]));
+                res.push(tok);
+                text_start = end;
+                pos = end;
+            } else {
+                pos = start + 2;
+            }
         }
+        res.extend(self.tokenize(&s[text_start..]));
+        res
     }
 }
 
-// max length of token is 1023 bytes
-const LEN_BITS: u32 = 10;
+/// Result of `ConstraintStepper::advance()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// The token was appended.
+    Continue,
+    /// EOS (or the end-of-turn token) was sampled and is allowed; generation is finished.
+    Eos,
+    /// The token was not allowed, starting at byte `byte_idx` of the token;
+    /// nothing was appended.
+    Rejected { byte_idx: usize },
+}
 
-impl TokTrie {
-    pub const SPECIAL_TOKEN_PREFIX_BYTE: u8 = 0xff;
+/// Runs the usual constrained sampling loop:
+/// `mask()`, sample a token allowed by the mask, `advance()`, repeat.
+pub struct ConstraintStepper<'a, R: Recognizer> {
+    trie: &'a TokTrie,
+    rec: R,
+    mask: SimpleVob,
+    tokens: Vec<TokenId>,
+    // number of leading entries of tokens that were not pushed into rec
+    num_prompt: usize,
+    // bytes the next token has to start with (from heal())
+    prefix: Vec<u8>,
+    // tokens removed by heal(), and their bytes
+    healed: Vec<TokenId>,
+    healed_bytes: Vec<u8>,
+}
 
-    pub fn from(info: &TokRxInfo, words: &Vec<Vec<u8>>) -> Self {
-        let mut trie = TrieHash::new(0xff);
-        let mut token_offsets = Vec::new();
-        let mut token_data = Vec::new();
-        assert!(info.vocab_size == words.len() as u32);
-        for (idx, word) in words.iter().enumerate() {
-            if word.len() > 0 {
-                trie.insert(word, idx as u32);
-            }
-            assert!(word.len() < (1 << LEN_BITS));
-            assert!(token_data.len() < (1 << (32 - LEN_BITS)));
-            let desc = (word.len() as u32) | ((token_data.len() as u32) << LEN_BITS);
-            token_offsets.push(desc);
-            token_data.extend_from_slice(word);
-        }
-        let mut nodes = Vec::new();
-        trie.serialize(&mut nodes, 0);
-        let mut r = TokTrie {
-            info: info.clone(),
-            token_offsets,
-            token_data,
-            nodes,
-            max_token_len: 0,
-            token_duplicates: FxHashMap::default(),
-        };
-        r.finalize_ctor();
-        r
+impl<'a, R: Recognizer> ConstraintStepper<'a, R> {
+    /// `prompt` is only used by `heal()`; it is not pushed into `rec`.
+    pub fn new(trie: &'a TokTrie, rec: R, prompt: &[TokenId]) -> Self {
+        ConstraintStepper {
+            trie,
+            rec,
+            mask: trie.alloc_token_set(),
+            tokens: prompt.to_vec(),
+            num_prompt: prompt.len(),
+            prefix: Vec::new(),
+            healed: Vec::new(),
+            healed_bytes: Vec::new(),
+        }
     }
 
-    pub fn with_eos_token(&self, eos_token: TokenId) -> Self {
-        self.with_info(TokRxInfo {
-            tok_eos: eos_token,
-            ..self.info.clone()
-        })
+    pub fn recognizer(&self) -> &R {
+        &self.rec
     }
 
-    pub fn with_info(&self, info: TokRxInfo) -> Self {
-        let mut r = self.clone();
-        r.info = info.clone();
-        r
+    pub fn into_recognizer(self) -> R {
+        self.rec
     }
 
-    pub fn build_chat_mode_trie(&self) -> Self {
-        self.with_eos_token(self.info.tok_end_of_turn.unwrap_or(self.info.tok_eos))
+    /// Prompt tokens (possibly shortened by `heal()`) followed by the appended tokens.
+    pub fn tokens(&self) -> &[TokenId] {
+        &self.tokens
     }
 
-    fn finalize_ctor(&mut self) {
-        for tok_id in 0..self.info.vocab_size {
-            let bytes = self.token(tok_id);
-            let tok_ids = self.greedy_tokenize(bytes);
-            self.max_token_len = std::cmp::max(self.max_token_len, bytes.len());
-            if tok_ids.len() == 1 && tok_ids[0] != tok_id {
-                self.token_duplicates
-                    .entry(tok_ids[0])
-                    .or_insert_with(Vec::new)
-                    .push(tok_id);
-            }
+    /// Compute the set of tokens allowed next; the set is reused between calls.
+    /// EOS, the stop tokens and the end-of-turn token are only allowed when
+    /// `special_allowed()` of the recognizer says so, whatever it says about their bytes.
+    pub fn mask(&mut self) -> &SimpleVob {
+        let eos_mode = if self.prefix.is_empty() {
+            EosMode::Auto
+        } else {
+            EosMode::AfterPrefix
+        };
+        self.trie
+            .compute_bias_ext_eos(&mut self.rec, &mut self.mask, &self.prefix, eos_mode);
+        // the end tokens only end the sequence, as in advance(), even if the recognizer
+        // would take their bytes
+        let (trie, mask) = (self.trie, &mut self.mask);
+        let mut disallow = |t: TokenId| {
+            if (t as usize) < trie.vocab_size() {
+                mask.disallow_token(t);
+            }
+        };
+        if !self.rec.special_allowed(SpecialToken::EndOfSentence) {
+            disallow(trie.info.tok_eos);
+            trie.stop_tokens.iter().for_each(|&t| disallow(t));
+        }
+        if !self.rec.special_allowed(SpecialToken::EndOfTurn) {
+            trie.info.tok_end_of_turn.into_iter().for_each(disallow);
+        }
+        &self.mask
+    }
+
+    /// Push the bytes of `tok` into the recognizer.
+    /// On rejection, the recognizer is left as it was.
+    pub fn advance(&mut self, tok: TokenId) -> Result<StepOutcome> {
+        ensure!(
+            (tok as usize) < self.trie.vocab_size(),
+            "token {} out of range (vocab size {})",
+            tok,
+            self.trie.vocab_size()
+        );
+
+        // the recognizer doesn't see the prefix bytes
+        let special = if self.trie.is_stop_token(tok) {
+            Some(SpecialToken::EndOfSentence)
+        } else if Some(tok) == self.trie.info().tok_end_of_turn {
+            Some(SpecialToken::EndOfTurn)
+        } else {
+            None
+        };
+        if let Some(special) = special {
+            // not allowed as text either, as mask() doesn't allow it
+            if !self.rec.special_allowed(special) {
+                return Ok(StepOutcome::Rejected { byte_idx: 0 });
+            }
+            if self.prefix == self.healed_bytes {
+                self.tokens.append(&mut self.healed);
+            } else {
+                self.tokens.extend(self.trie.greedy_tokenize(&self.prefix));
+            }
+            self.prefix.clear();
+            self.tokens.push(tok);
+            return Ok(StepOutcome::Eos);
+        }
+
+        let bytes = self.trie.token(tok);
+        let common = bytes
+            .iter()
+            .zip(self.prefix.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        if common < bytes.len().min(self.prefix.len()) {
+            return Ok(StepOutcome::Rejected { byte_idx: common });
+        }
+        if bytes.len() <= self.prefix.len() {
+            // the token is a prefix of the prefix
+            self.prefix.drain(0..bytes.len());
+            self.tokens.push(tok);
+            return Ok(StepOutcome::Continue);
+        }
+        let num = self.rec.try_push_bytes(&bytes[common..]);
+        if common + num < bytes.len() {
+            self.rec.pop_bytes(num);
+            return Ok(StepOutcome::Rejected {
+                byte_idx: common + num,
+            });
+        }
+        self.rec.collapse();
+        self.tokens.push(tok);
+        self.prefix.clear();
+        Ok(StepOutcome::Continue)
+    }
+
+    /// Remove tokens from the end of the prompt, so that the recognizer can choose
+    /// their tokenization; see `TokTrie::chop_tokens()`.
+    /// The next token then has to start with the removed bytes.
+    /// Returns the number of removed tokens and their bytes.
+    ///
+    /// If EOS is sampled before the removed bytes are produced, they are put back.
+    ///
+    /// Panics if called twice, or after `advance()`.
+    pub fn heal(&mut self) -> (usize, Vec<u8>) {
+        assert!(
+            self.tokens.len() == self.num_prompt && self.healed.is_empty(),
+            "heal() called twice, or after advance()"
+        );
+        let (num_tokens, num_bytes) = self.trie.chop_tokens(&mut self.rec, &self.tokens);
+        self.healed = self.tokens.split_off(self.tokens.len() - num_tokens);
+        self.healed_bytes = self.trie.decode_raw(&self.healed);
+        debug_assert!(self.healed_bytes.len() == num_bytes);
+        self.num_prompt = self.tokens.len();
+        self.prefix = self.healed_bytes.clone();
+        (num_tokens, self.healed_bytes.clone())
+    }
+}
+
+#[derive(Clone)]
+pub struct TokTrie {
+    info: TokRxInfo,
+    // ending the sequence like EOS; sorted, without EOS
+    stop_tokens: Vec<TokenId>,
+    // shared by clones, like the ones from with_eos_token()
+    data: Arc<TrieData>,
+    last_walk: LastWalk,
+}
+
+/// The parts of a `TokTrie` that only depend on the tokens.
+struct TrieData {
+    token_offsets: Vec<u32>,
+    token_data: Vec<u8>,
+    nodes: Vec<TrieNode>,
+    max_token_len: usize,
+    token_duplicates: FxHashMap<TokenId, Vec<TokenId>>,
+    // reverse of token_duplicates
+    token_canonical: FxHashMap<TokenId, TokenId>,
+    dup_index: DupIndex,
+    // tokens starting with SPECIAL_TOKEN_PREFIX_BYTE, or the ones given
+    // when explicit_special_tokens is set
+    special_tokens: SimpleVob,
+    explicit_special_tokens: bool,
+    // num_parents of nodes where it doesn't fit in TrieNode
+    num_parents_overflow: FxHashMap<usize, usize>,
+    // not serialized; rebuilt when loading
+    jump_tables: JumpTables,
+    depth_bounds: DepthBounds,
+    token_classes: TokenClasses,
+    // offset of the node of each token (of the canonical one for duplicates);
+    // NO_NODE for empty tokens
+    token_end_nodes: Vec<u32>,
+    // computed on first use of TokTrie::analysis()
+    #[cfg(feature = "std")]
+    analysis: OnceLock<VocabAnalysis>,
+}
+
+impl TrieData {
+    /// Validates the trie, unless `check` is false, and computes token stats,
+    /// unless they were deserialized.
+    fn new(
+        vocab_size: u32,
+        token_offsets: Vec<u32>,
+        token_data: Vec<u8>,
+        nodes: Vec<TrieNode>,
+        stats: Option<TokenStats>,
+        check: bool,
+    ) -> Result<Self> {
+        let max_depth = if check {
+            validate_token_offsets(&token_offsets, &token_data, vocab_size)?
+        } else {
+            0
+        };
+        let num_parents_overflow = validate_nodes(&nodes, vocab_size, max_depth, check)?;
+        let jump_tables = JumpTables::new(&nodes);
+        let (max_token_len, token_duplicates) = check_token_stats_in(
+            stats,
+            &nodes,
+            &jump_tables,
+            &token_offsets,
+            &token_data,
+            vocab_size,
+        )?;
+        Ok(TrieData {
+            max_token_len,
+            token_canonical: canonical_map_in(&token_duplicates),
+            dup_index: DupIndex::new(&token_duplicates, vocab_size),
+            token_end_nodes: token_end_nodes_in(&nodes, &token_duplicates, vocab_size),
+            token_duplicates,
+            special_tokens: special_tokens_in(&token_offsets, &token_data, vocab_size),
+            explicit_special_tokens: false,
+            token_classes: TokenClasses::new(&token_offsets, &token_data, vocab_size),
+            num_parents_overflow,
+            jump_tables,
+            depth_bounds: DepthBounds::new(&nodes),
+            token_offsets,
+            token_data,
+            nodes,
+            #[cfg(feature = "std")]
+            analysis: OnceLock::new(),
+        })
+    }
+
+    /// Like `new()`, for a trie with tokens appended and the nodes from `merge`.
+    /// What's derived from the nodes is copied for the subtrees that were,
+    /// and only computed for the rest. Debug builds check it against `new()`.
+    fn extended(
+        merge: NodeMerge,
+        vocab_size: u32,
+        token_offsets: Vec<u32>,
+        token_data: Vec<u8>,
+        stats: TokenStats,
+    ) -> Result<Self> {
+        validate_token_offsets(&token_offsets, &token_data, vocab_size)?;
+        let NodeMerge {
+            old,
+            nodes,
+            num_parents_overflow,
+            copied,
+            ..
+        } = merge;
+        let old_vocab_size = old.token_offsets.len() as u32;
+
+        // the nodes outside copied subtrees, in order
+        let mut touched = Vec::new();
+        let mut p = 0;
+        for &(_, off, size) in &copied {
+            touched.extend(p..off);
+            p = off + size;
+        }
+        touched.extend(p..nodes.len());
+
+        let mut old_tables = old.jump_tables.index.keys().copied().collect::<Vec<_>>();
+        old_tables.sort_unstable();
+        let mut candidates = touched.clone();
+        for &(old_off, off, size) in &copied {
+            candidates.extend(
+                offsets_in(&old_tables, old_off..old_off + size)
+                    .iter()
+                    .map(|&o| off + o - old_off),
+            );
+        }
+        candidates.sort_unstable();
+        let jump_tables = JumpTables::with_candidates(&nodes, candidates);
+
+        // with explicit special tokens, new tokens are never special
+        let mut special_tokens = old.special_tokens.clone();
+        special_tokens.resize(vocab_size as usize, false);
+        let mut token_classes = old
+            .token_classes
+            .extended(&token_offsets, &token_data, vocab_size);
+        if old.explicit_special_tokens {
+            token_classes.restrict_special(&special_tokens);
+        } else {
+            add_special_tokens_in(
+                &mut special_tokens,
+                &token_offsets,
+                &token_data,
+                old_vocab_size..vocab_size,
+            );
+        }
+
+        let (max_token_len, token_duplicates) = stats;
+        let res = TrieData {
+            max_token_len,
+            token_canonical: canonical_map_in(&token_duplicates),
+            dup_index: DupIndex::new(&token_duplicates, vocab_size),
+            token_end_nodes: token_end_nodes_in(&nodes, &token_duplicates, vocab_size),
+            token_duplicates,
+            special_tokens,
+            explicit_special_tokens: old.explicit_special_tokens,
+            token_classes,
+            num_parents_overflow,
+            jump_tables,
+            depth_bounds: old.depth_bounds.extended(&nodes, &copied, &touched),
+            token_offsets,
+            token_data,
+            nodes,
+            #[cfg(feature = "std")]
+            analysis: OnceLock::new(),
+        };
+        if cfg!(debug_assertions) {
+            let mut full = TrieData::new(
+                vocab_size,
+                res.token_offsets.clone(),
+                res.token_data.clone(),
+                res.nodes.clone(),
+                None,
+                true,
+            )?;
+            if res.explicit_special_tokens {
+                full.set_special_tokens(&res.special_token_list())?;
+            }
+            ensure!(
+                full.num_parents_overflow == res.num_parents_overflow
+                    && full.jump_tables == res.jump_tables
+                    && full.depth_bounds == res.depth_bounds
+                    && full.token_classes == res.token_classes
+                    && full.token_end_nodes == res.token_end_nodes
+                    && full.special_tokens == res.special_tokens,
+                "TokTrie: extended trie doesn't match a rebuilt one"
+            );
+        }
+        Ok(res)
+    }
+
+    /// Make only `tokens` special, instead of all the tokens starting with
+    /// `SPECIAL_TOKEN_PREFIX_BYTE`; each of them has to start with it.
+    fn set_special_tokens(&mut self, tokens: &[TokenId]) -> Result<()> {
+        self.special_tokens = explicit_special_tokens_in(
+            &self.token_offsets,
+            &self.token_data,
+            self.token_offsets.len() as u32,
+            tokens,
+        )?;
+        self.token_classes.restrict_special(&self.special_tokens);
+        self.explicit_special_tokens = true;
+        Ok(())
+    }
+
+    fn special_token_list(&self) -> Vec<TokenId> {
+        self.special_tokens.iter().collect()
+    }
+}
+
+/// Compares the tokens, nodes, info, and stop tokens; the rest is derived from these.
+impl PartialEq for TokTrie {
+    fn eq(&self, other: &Self) -> bool {
+        self.info == other.info
+            && (Arc::ptr_eq(&self.data, &other.data)
+                || (self.data.token_offsets == other.data.token_offsets
+                    && self.data.token_data == other.data.token_data
+                    && self.data.nodes == other.data.nodes
+                    && self.data.explicit_special_tokens == other.data.explicit_special_tokens
+                    && self.data.special_tokens == other.data.special_tokens))
+            && self.stop_tokens == other.stop_tokens
+    }
+}
+
+impl Eq for TokTrie {}
+
+impl core::fmt::Debug for TokTrie {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("TokTrie")
+            .field("vocab_size", &self.info.vocab_size)
+            .field("num_nodes", &self.data.nodes.len())
+            .field("tok_eos", &self.info.tok_eos)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Serialized as bytes of `TokTrie::serialize()`.
+#[cfg(feature = "serde")]
+impl serde::Serialize for TokTrie {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&TokTrie::serialize(self))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for TokTrie {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BytesVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for BytesVisitor {
+            type Value = TokTrie;
+
+            fn expecting(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                f.write_str("bytes of TokTrie::serialize()")
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<TokTrie, E> {
+                TokTrie::try_from_bytes(v).map_err(E::custom)
+            }
+
+            // formats without a bytes type, like JSON, use a sequence of numbers
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<TokTrie, A::Error> {
+                let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(b) = seq.next_element::<u8>()? {
+                    bytes.push(b);
+                }
+                self.visit_bytes(&bytes)
+            }
+        }
+
+        deserializer.deserialize_bytes(BytesVisitor)
+    }
+}
+
+#[derive(Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct TokTrieHeader {
+    magic: u32,
+    hd_size: u32,
+    trie_bytes: u32,
+    token_offset_bytes: u32,
+    token_data_bytes: u32,
+    info: BinTokRxInfo,
+    // only present with MAGIC_VERSIONED
+    version: u32,
+    // only present with MAGIC_VERSIONED and version >= 2
+    info_ext: BinTokRxInfoExt,
+    align: [u32; 0],
+}
+
+impl TokTrieHeader {
+    const MAGIC: u32 = 0x558b6fd3;
+    /// Used instead of MAGIC when the file has tokens of LEN_ESCAPE bytes or more,
+    /// or nodes with saturated num_parents; older readers would misinterpret these.
+    const MAGIC_LONG_TOKENS: u32 = 0x558b6fd4;
+    /// Written by current versions; the header has a `version` field,
+    /// and all multi-byte fields are little-endian.
+    const MAGIC_VERSIONED: u32 = 0x558b6fd5;
+    /// Version 2 added `info_ext`; version 3 added stop tokens to the stats section,
+    /// version 4 the special tokens, when they are listed (see `TokTrie::from_ext()`),
+    /// and version 5 the size of the stats section, which became required.
+    const VERSION: u32 = 5;
+    /// Starts the optional section after token data, holding max_token_len
+    /// and token duplicates, so they don't need to be recomputed on load.
+    const MAGIC_STATS: u32 = 0x558b6fe0;
+    /// Starts the optional prefix of `TokTrie::serialize_with_fingerprint()`:
+    /// this magic, a version, and the 32 bytes of the fingerprint, all little-endian,
+    /// followed by the usual header. 40 bytes, keeping the rest 8-byte aligned.
+    const MAGIC_FINGERPRINT: u32 = 0x558b6fe1;
+    const FINGERPRINT_VERSION: u32 = 1;
+    const FINGERPRINT_PREFIX_LEN: usize = 40;
+
+    /// The fingerprint at the start of `bytes`, if there's one; fails on a truncated
+    /// or unknown fingerprint prefix.
+    fn parse_fingerprint(bytes: &[u8]) -> Result<Option<[u8; 32]>> {
+        if bytes.len() < 4 || bytes[0..4] != TokTrieHeader::MAGIC_FINGERPRINT.to_le_bytes() {
+            return Ok(None);
+        }
+        ensure!(
+            bytes.len() >= TokTrieHeader::FINGERPRINT_PREFIX_LEN,
+            "TokTrie: buffer too short for fingerprint: {} bytes",
+            bytes.len()
+        );
+        let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        ensure!(
+            version == TokTrieHeader::FINGERPRINT_VERSION,
+            "TokTrie: unsupported fingerprint version {}",
+            version
+        );
+        Ok(Some(bytes[8..40].try_into().unwrap()))
+    }
+
+    fn is_known_magic(magic: u32) -> bool {
+        magic == TokTrieHeader::MAGIC
+            || magic == TokTrieHeader::MAGIC_LONG_TOKENS
+            || magic == TokTrieHeader::MAGIC_VERSIONED
+    }
+
+    /// Size of the header, from its first 8 bytes (or the whole header); fails on unknown magic.
+    /// The second result is the byte order of the file.
+    fn header_len(bytes: &[u8]) -> Result<(usize, bool)> {
+        ensure!(
+            bytes.len() >= 4,
+            "TokTrie: buffer too short for header: {} bytes",
+            bytes.len()
+        );
+        // files written by older versions on big-endian machines are big-endian
+        let magic = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+        let file_le = if TokTrieHeader::is_known_magic(magic) {
+            true
+        } else if TokTrieHeader::is_known_magic(magic.swap_bytes()) {
+            false
+        } else {
+            bail!("TokTrie: invalid magic");
+        };
+
+        let size_v1 = core::mem::offset_of!(TokTrieHeader, info_ext);
+        let pref = if magic != TokTrieHeader::MAGIC_VERSIONED
+            && magic.swap_bytes() != TokTrieHeader::MAGIC_VERSIONED
+        {
+            // no version field
+            size_v1 - 4
+        } else if bytes.len() >= 8 {
+            // version 1 headers don't have info_ext
+            let hd_size = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+            let hd_size = if file_le {
+                hd_size
+            } else {
+                hd_size.swap_bytes()
+            };
+            if hd_size as usize == size_v1 {
+                size_v1
+            } else {
+                core::mem::size_of::<TokTrieHeader>()
+            }
+        } else {
+            core::mem::size_of::<TokTrieHeader>()
+        };
+        Ok((pref, file_le))
+    }
+
+    /// Returns a copy of the header at the start of `bytes` in native byte order,
+    /// and whether the file is in non-native byte order.
+    /// Checks the header itself, but not the section sizes against `bytes`.
+    fn parse_header(bytes: &[u8]) -> Result<(TokTrieHeader, bool)> {
+        let (pref, file_le) = TokTrieHeader::header_len(bytes)?;
+        let swap = file_le != cfg!(target_endian = "little");
+        ensure!(
+            bytes.len() >= pref,
+            "TokTrie: buffer too short for header: {} bytes",
+            bytes.len()
+        );
+        let mut hd: TokTrieHeader = bytemuck::Zeroable::zeroed();
+        // stays in place for headers without info_ext; NONE is the same in both byte orders
+        hd.info_ext = BinTokRxInfoExt::none();
+        bytemuck::bytes_of_mut(&mut hd)[0..pref].copy_from_slice(&bytes[0..pref]);
+        if swap {
+            swap_u32_bytes(bytemuck::bytes_of_mut(&mut hd));
+        }
+
+        ensure!(
+            hd.hd_size as usize == pref,
+            "TokTrie: invalid header size: {}",
+            hd.hd_size
+        );
+        ensure!(
+            hd.version <= TokTrieHeader::VERSION,
+            "TokTrie: unsupported format version {} (max {})",
+            hd.version,
+            TokTrieHeader::VERSION
+        );
+        ensure!(
+            (hd.trie_bytes as usize) % core::mem::size_of::<TrieNode>() == 0,
+            "TokTrie: trie size {} is not a multiple of node size",
+            hd.trie_bytes
+        );
+        ensure!(
+            hd.token_offset_bytes % 4 == 0,
+            "TokTrie: token offsets size {} is not a multiple of 4",
+            hd.token_offset_bytes
+        );
+        Ok((hd, swap))
+    }
+
+    /// Returns a copy of the header (the buffer doesn't need to be aligned),
+    /// the byte ranges of nodes, token offsets, token data and stats (possibly empty),
+    /// and whether nodes and token offsets are stored in non-native byte order.
+    /// The returned header is always in native byte order.
+    /// A fingerprint prefix (see `MAGIC_FINGERPRINT`) is skipped.
+    fn parse(bytes: &[u8]) -> Result<(TokTrieHeader, [Range<usize>; 4], bool)> {
+        let fp_len = match TokTrieHeader::parse_fingerprint(bytes)? {
+            Some(_) => TokTrieHeader::FINGERPRINT_PREFIX_LEN,
+            None => 0,
+        };
+        let (hd, ranges, swap) = TokTrieHeader::parse_sections(&bytes[fp_len..])?;
+        Ok((hd, ranges.map(|r| r.start + fp_len..r.end + fp_len), swap))
+    }
+
+    /// `parse()` of the bytes after the fingerprint, if any.
+    fn parse_sections(bytes: &[u8]) -> Result<(TokTrieHeader, [Range<usize>; 4], bool)> {
+        let (hd, swap) = TokTrieHeader::parse_header(bytes)?;
+        let pref = hd.hd_size as usize;
+
+        let trie_end = pref + hd.trie_bytes as usize;
+        let offsets_end = trie_end + hd.token_offset_bytes as usize;
+        ensure!(
+            offsets_end <= bytes.len(),
+            "TokTrie: truncated; need at least {} bytes, got {}",
+            offsets_end,
+            bytes.len()
+        );
+        let rest = bytes.len() - offsets_end;
+        let data_len = hd.token_data_bytes as usize;
+        let data_end = if data_len == rest {
+            bytes.len()
+        } else if data_len < rest
+            && bytes[offsets_end + data_len..]
+                .starts_with(&TokTrieHeader::MAGIC_STATS.to_le_bytes())
+        {
+            offsets_end + data_len
+        } else if data_len == hd.trie_bytes as usize {
+            // files written before token_data_bytes was fixed store trie_bytes there;
+            // token data then spans the rest of the buffer
+            bytes.len()
+        } else {
+            if data_len > rest {
+                bail!(
+                    "TokTrie: truncated token data; need {} bytes, got {}",
+                    data_len,
+                    rest
+                );
+            } else {
+                bail!(
+                    "TokTrie: {} unexpected bytes after token data",
+                    rest - data_len
+                );
+            }
+        };
+
+        Ok((
+            hd,
+            [
+                pref..trie_end,
+                trie_end..offsets_end,
+                offsets_end..data_end,
+                data_end..bytes.len(),
+            ],
+            swap,
+        ))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Zeroable, Pod)]
+#[repr(C)]
+pub struct TrieNode {
+    // byte:token
+    bits: u32,
+    bits2: u32,
+}
+
+/// Index of a node in a trie's node array; unlike `&TrieNode`, it can be stored and copied
+/// freely. Only meaningful for the trie it came from; with another one, it may refer to
+/// an unrelated node, or make the lookups panic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeRef(u32);
+
+impl NodeRef {
+    /// Same as `TokTrie::node_offset()` of the node.
+    pub fn offset(self) -> usize {
+        self.0 as usize
+    }
+
+    fn at(off: usize) -> Self {
+        NodeRef(off as u32)
+    }
+}
+
+pub(crate) const NO_TOKEN: u32 = 0xffffff;
+// no node in TrieData::token_end_nodes
+const NO_NODE: u32 = u32::MAX;
+// buffer size for TokTrie::serialize_to() and deserialize_from(); a multiple of the node size
+#[cfg(feature = "std")]
+const IO_CHUNK_SIZE: usize = 1 << 16;
+// limit for TokTrie::forced_bytes(), in case the recognizer forces an infinite sequence
+const MAX_FORCED_BYTES: usize = 4096;
+// limits for TokTrie::fuzzy_token_matches(); with many mismatches allowed,
+// the search covers most of the trie
+const MAX_FUZZY_RESULTS: usize = 1000;
+const MAX_FUZZY_NODES: usize = 100_000;
+// num_parents is stored in 8 bits; larger values are stored as this,
+// and kept on the side (see num_parents_overflow)
+const NUM_PARENTS_ESCAPE: usize = 0xff;
+// subtree_size is stored in the upper 24 bits of bits2
+const MAX_SUBTREE_SIZE: usize = (1 << 24) - 1;
+
+impl TrieNode {
+    fn new(byte: u8, token_id: u32, num_parents: usize) -> TrieNode {
+        TrieNode {
+            bits: (token_id << 8) | byte as u32,
+            bits2: core::cmp::min(num_parents, NUM_PARENTS_ESCAPE) as u32,
+        }
+    }
+
+    fn set_num_parents(&mut self, num_parents: usize) {
+        self.bits2 = (self.bits2 & !0xff) | core::cmp::min(num_parents, NUM_PARENTS_ESCAPE) as u32;
+    }
+
+    #[inline(always)]
+    pub fn byte(&self) -> u8 {
+        (self.bits & 0xff) as u8
+    }
+
+    #[inline(always)]
+    pub fn subtree_size(&self) -> usize {
+        (self.bits2 >> 8) as usize
+    }
+
+    /// Number of levels to go up after this node's subtree is done.
+    /// Saturates at 255 for very long tokens; use `TrieWalker` to get exact values.
+    #[inline(always)]
+    pub fn num_parents(&self) -> usize {
+        (self.bits2 & 0xff) as usize
+    }
+
+    #[inline(always)]
+    pub fn token_id(&self) -> Option<u32> {
+        let r = self.bits >> 8;
+        if r == NO_TOKEN {
+            None
+        } else {
+            Some(r)
+        }
+    }
+}
+
+impl core::fmt::Debug for TrieNode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("TrieNode")
+            .field("byte", &format_args!("{:#04x}", self.byte()))
+            .field("token_id", &self.token_id())
+            .field("subtree_size", &self.subtree_size())
+            .field("num_parents", &self.num_parents())
+            .finish()
+    }
+}
+
+// token descriptor is len:10 offset:22
+const LEN_BITS: u32 = 10;
+// tokens of this length or more store this as len, and their actual length
+// as u32 LE in token data, just before the bytes
+const LEN_ESCAPE: u32 = (1 << LEN_BITS) - 1;
+// total size of all tokens
+const MAX_TOKEN_DATA_LEN: usize = 1 << (32 - LEN_BITS);
+// there is at most one node per byte of token data,
+// so set_subtree_size() can't fail on tries within this limit
+const _: () = assert!(MAX_TOKEN_DATA_LEN < MAX_SUBTREE_SIZE);
+
+impl TokTrie {
+    pub const SPECIAL_TOKEN_PREFIX_BYTE: u8 = 0xff;
+
+    /// Tokens starting with `SPECIAL_TOKEN_PREFIX_BYTE` (and longer than it) are special;
+    /// see `from_ext()` to list them instead.
+    pub fn from(info: &TokRxInfo, words: &Vec<Vec<u8>>) -> Self {
+        Self::try_from_words(info, words, false, None).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `from()`, but when `special_tokens` is given, only these tokens are special:
+    /// other tokens starting with `SPECIAL_TOKEN_PREFIX_BYTE` are ordinary ones,
+    /// for byte-level vocabularies that have 0xff in their tokens.
+    /// The special tokens still have to start with the prefix byte.
+    pub fn from_ext(
+        info: &TokRxInfo,
+        words: &[Vec<u8>],
+        special_tokens: Option<&[TokenId]>,
+    ) -> Self {
+        Self::try_from_ext(info, words, special_tokens).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `from_ext()`, but returns an error instead of panicking.
+    pub fn try_from_ext(
+        info: &TokRxInfo,
+        words: &[Vec<u8>],
+        special_tokens: Option<&[TokenId]>,
+    ) -> Result<Self> {
+        Self::try_from_words(info, words, false, special_tokens)
+    }
+
+    /// Build a trie from tokens given with their ids, in any order, like a tiktoken
+    /// file has them. Ids that are missing get empty tokens. When `info.vocab_size`
+    /// is 0, it's taken to be the largest id plus one; otherwise larger ids are an error,
+    /// as are ids given more than once.
+    pub fn from_sparse(
+        info: &TokRxInfo,
+        entries: impl IntoIterator<Item = (TokenId, Vec<u8>)>,
+    ) -> Result<Self> {
+        let mut entries = entries.into_iter().collect::<Vec<_>>();
+        entries.sort_unstable_by_key(|e| e.0);
+        for w in entries.windows(2) {
+            ensure!(
+                w[0].0 != w[1].0,
+                "TokTrie: token {} given more than once",
+                w[0].0
+            );
+        }
+        let mut info = *info;
+        if let Some((max_tok, _)) = entries.last() {
+            if info.vocab_size == 0 {
+                ensure!(*max_tok < NO_TOKEN - 1, "TokTrie: too many tokens");
+                info.vocab_size = max_tok + 1;
+            }
+            ensure!(
+                *max_tok < info.vocab_size,
+                "TokTrie: token {} out of range (vocab size {})",
+                max_tok,
+                info.vocab_size
+            );
+        }
+        let mut words = vec![Vec::new(); info.vocab_size as usize];
+        for (tok, bytes) in entries {
+            words[tok as usize] = bytes;
+        }
+        Self::try_from_words(&info, &words, false, None)
+    }
+
+    /// When several tokens share the same bytes, the trie node gets the last one,
+    /// unless `keep_first_duplicate` is set; the others are recorded as duplicates.
+    /// See `from_ext()` for `special_tokens`.
+    pub(crate) fn try_from_words(
+        info: &TokRxInfo,
+        words: &[Vec<u8>],
+        keep_first_duplicate: bool,
+        special_tokens: Option<&[TokenId]>,
+    ) -> Result<Self> {
+        let mut token_offsets = Vec::new();
+        let mut token_data = Vec::new();
+        ensure!(
+            info.vocab_size == words.len() as u32,
+            "TokTrie: vocab size {} doesn't match {} tokens",
+            info.vocab_size,
+            words.len()
+        );
+        for word in words.iter() {
+            push_token(&mut token_offsets, &mut token_data, word)?;
+        }
+        Self::from_encoded_words(
+            info,
+            words,
+            token_offsets,
+            token_data,
+            keep_first_duplicate,
+            special_tokens,
+        )
+    }
+
+    /// The rest of `try_from_words()`, once `push_token()` was called for every word.
+    pub(crate) fn from_encoded_words(
+        info: &TokRxInfo,
+        words: &[Vec<u8>],
+        token_offsets: Vec<u32>,
+        token_data: Vec<u8>,
+        keep_first_duplicate: bool,
+        special_tokens: Option<&[TokenId]>,
+    ) -> Result<Self> {
+        let nodes = build_nodes(words, keep_first_duplicate)?;
+        let mut data = TrieData::new(
+            info.vocab_size,
+            token_offsets,
+            token_data,
+            nodes,
+            None,
+            true,
+        )?;
+        if let Some(special_tokens) = special_tokens {
+            data.set_special_tokens(special_tokens)?;
+        }
+        Ok(TokTrie::with_data(*info, Vec::new(), data))
+    }
+
+    fn slices(&self) -> TrieSlices<'_> {
+        TrieSlices {
+            info: &self.info,
+            stop_tokens: &self.stop_tokens,
+            nodes: &self.data.nodes,
+            token_offsets: &self.data.token_offsets,
+            token_data: &self.data.token_data,
+            num_parents_overflow: &self.data.num_parents_overflow,
+            jump_tables: &self.data.jump_tables,
+            dup_index: &self.data.dup_index,
+        }
+    }
+
+    fn with_data(info: TokRxInfo, stop_tokens: Vec<TokenId>, data: TrieData) -> Self {
+        TokTrie {
+            info,
+            stop_tokens,
+            data: Arc::new(data),
+            last_walk: LastWalk::default(),
+        }
+    }
+
+    pub fn with_eos_token(&self, eos_token: TokenId) -> Self {
+        self.with_info(TokRxInfo {
+            tok_eos: eos_token,
+            ..self.info.clone()
+        })
+    }
+
+    /// Make `tokens` end the sequence like EOS does: compute_bias() allows them
+    /// whenever the recognizer allows EOS. Replaces earlier stop tokens;
+    /// `eos_token()` stays a stop token. Tokens that are not `is_valid_token()` are dropped.
+    pub fn with_stop_tokens(&self, tokens: &[TokenId]) -> Self {
+        let mut r = self.clone();
+        r.stop_tokens = tokens
+            .iter()
+            .copied()
+            .filter(|&t| t != self.info.tok_eos && self.is_valid_token(t))
+            .collect();
+        r.stop_tokens.sort_unstable();
+        r.stop_tokens.dedup();
+        r
+    }
+
+    /// EOS, and tokens set with `with_stop_tokens()`.
+    pub fn is_stop_token(&self, t: TokenId) -> bool {
+        t == self.info.tok_eos || self.stop_tokens.binary_search(&t).is_ok()
+    }
+
+    /// Tokens set with `with_stop_tokens()`, sorted, without EOS.
+    pub fn stop_tokens(&self) -> &[TokenId] {
+        &self.stop_tokens
+    }
+
+    /// Panics if `info.vocab_size` is different; use `with_vocab_size()` to change it.
+    pub fn with_info(&self, info: TokRxInfo) -> Self {
+        self.try_with_info(info).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_with_info(&self, info: TokRxInfo) -> Result<Self> {
+        ensure!(
+            info.vocab_size == self.info.vocab_size,
+            "TokTrie: can't change vocab size from {} to {} with with_info(); use with_vocab_size()",
+            self.info.vocab_size,
+            info.vocab_size
+        );
+        let mut r = self.clone();
+        r.info = info;
+        Ok(r)
+    }
+
+    /// Drop the tokens `>= vocab_size`, or add empty tokens up to `vocab_size`.
+    /// BOS, PAD, UNK, end-of-turn, and stop tokens that are dropped are unset;
+    /// dropping EOS is an error.
+    pub fn with_vocab_size(&self, vocab_size: u32) -> Result<Self> {
+        ensure!(
+            self.info.tok_eos < vocab_size,
+            "TokTrie: vocab size {} would drop EOS token {}",
+            vocab_size,
+            self.info.tok_eos
+        );
+        let keep = |tok: Option<TokenId>| tok.filter(|&t| t < vocab_size);
+        let info = TokRxInfo {
+            vocab_size,
+            tok_bos: keep(self.info.tok_bos),
+            tok_pad: keep(self.info.tok_pad),
+            tok_unk: keep(self.info.tok_unk),
+            tok_end_of_turn: keep(self.info.tok_end_of_turn),
+            ..self.info
+        };
+        // duplicates keep their canonical token, unless it's dropped
+        let tokens = (0..vocab_size)
+            .map(|t| {
+                let canonical = self.canonical_token(t);
+                (self.token(t), canonical == t || canonical >= vocab_size)
+            })
+            .collect::<Vec<_>>();
+        let stop_tokens = self
+            .stop_tokens
+            .iter()
+            .copied()
+            .filter(|&t| t < vocab_size)
+            .collect();
+        let special_tokens = self.explicit_special_tokens().map(|mut specials| {
+            specials.retain(|&t| t < vocab_size);
+            specials
+        });
+        self.with_tokens(info, stop_tokens, &tokens, special_tokens)
+    }
+
+    /// Append tokens to the vocabulary, given as bytes and whether they are special;
+    /// special tokens get `SPECIAL_TOKEN_PREFIX_BYTE` in front of their name.
+    /// A new token with the bytes of an existing one becomes its duplicate.
+    pub fn with_added_tokens(&self, extra: &[(Vec<u8>, bool)]) -> Result<Self> {
+        let extra_special = extra.iter().map(|(_, special)| *special);
+        let extra = extra
+            .iter()
+            .map(|(bytes, special)| {
+                if *special {
+                    let mut r = vec![TokTrie::SPECIAL_TOKEN_PREFIX_BYTE];
+                    r.extend_from_slice(bytes);
+                    r
+                } else {
+                    bytes.clone()
+                }
+            })
+            .collect::<Vec<_>>();
+        let vocab_size = u32::try_from(self.vocab_size() + extra.len())
+            .ok()
+            .filter(|&n| n < NO_TOKEN)
+            .ok_or_else(|| anyhow::anyhow!("TokTrie: too many tokens"))?;
+        let info = TokRxInfo {
+            vocab_size,
+            ..self.info
+        };
+        let mut tokens = (0..self.info.vocab_size)
+            .map(|t| (self.token(t), self.canonical_token(t) == t))
+            .collect::<Vec<_>>();
+        tokens.extend(
+            extra
+                .iter()
+                .map(|bytes| (bytes.as_slice(), self.token_id(bytes).is_none())),
+        );
+        let special_tokens = self.explicit_special_tokens().map(|mut specials| {
+            specials.extend(
+                (self.info.vocab_size..vocab_size)
+                    .zip(extra_special)
+                    .filter_map(|(t, special)| special.then_some(t)),
+            );
+            specials
+        });
+        self.with_tokens(info, self.stop_tokens.clone(), &tokens, special_tokens)
+    }
+
+    /// Append `new_tokens`, given with their ids, without rebuilding the whole trie.
+    /// The ids have to be `vocab_size()`, `vocab_size() + 1`, and so on;
+    /// see `extend_ext()` to allow gaps.
+    /// The result is the same as `TokTrie::from()` with all the tokens:
+    /// a new token with the bytes of an existing one gets its trie node,
+    /// and the existing one becomes a duplicate.
+    /// On error, the trie is left as it was.
+    pub fn extend(&mut self, new_tokens: &[(TokenId, Vec<u8>)]) -> Result<()> {
+        self.extend_ext(new_tokens, GapPolicy::Reject)
+    }
+
+    /// Like `extend()`, but ids only need to be increasing if `gaps` is `GapPolicy::FillEmpty`.
+    pub fn extend_ext(&mut self, new_tokens: &[(TokenId, Vec<u8>)], gaps: GapPolicy) -> Result<()> {
+        let mut vocab_size = self.info.vocab_size;
+        let mut token_offsets = self.data.token_offsets.clone();
+        let mut token_data = self.data.token_data.clone();
+        let mut max_token_len = self.data.max_token_len;
+        for (tok, bytes) in new_tokens {
+            ensure!(
+                *tok >= vocab_size,
+                "TokTrie: can't add token {}; the next token is {}",
+                tok,
+                vocab_size
+            );
+            ensure!(*tok < NO_TOKEN - 1, "TokTrie: too many tokens");
+            if *tok > vocab_size {
+                match gaps {
+                    GapPolicy::Reject => bail!(
+                        "TokTrie: can't add token {}; the next token is {} (see GapPolicy)",
+                        tok,
+                        vocab_size
+                    ),
+                    GapPolicy::FillEmpty => {
+                        while vocab_size < *tok {
+                            push_token(&mut token_offsets, &mut token_data, &[])?;
+                            vocab_size += 1;
+                        }
+                    }
+                }
+            }
+            push_token(&mut token_offsets, &mut token_data, bytes)?;
+            max_token_len = core::cmp::max(max_token_len, bytes.len());
+            vocab_size += 1;
+        }
+
+        // new tokens that get a node: like in build_nodes(), the last one for given bytes
+        let mut entries = new_tokens
+            .iter()
+            .filter(|(_, w)| !w.is_empty())
+            .map(|(tok, w)| (&w[..], *tok))
+            .collect::<Vec<_>>();
+        entries.sort_unstable();
+        entries.reverse();
+        entries.dedup_by_key(|e| e.0);
+        entries.reverse();
+
+        let mut merge = NodeMerge::new(
+            &self.data,
+            self.data.nodes.len() + token_data.len() - self.data.token_data.len(),
+        );
+        merge.merge_rec(Some(0), &entries, 0, 0xff, 0)?;
+
+        // only the duplicates of the new tokens' bytes change
+        let mut token_duplicates = self.data.token_duplicates.clone();
+        for &(bytes, tok) in &entries {
+            if let Some(prev) = self.token_id(bytes) {
+                let mut dups = token_duplicates.remove(&prev).unwrap_or_default();
+                dups.push(prev);
+                token_duplicates.insert(tok, dups);
+            }
+        }
+        for (tok, bytes) in new_tokens {
+            if bytes.is_empty() {
+                continue;
+            }
+            let idx = entries.binary_search_by(|e| e.0.cmp(bytes)).unwrap();
+            if entries[idx].1 != *tok {
+                token_duplicates
+                    .entry(entries[idx].1)
+                    .or_default()
+                    .push(*tok);
+            }
+        }
+        for (_, tok) in &entries {
+            if let Some(dups) = token_duplicates.get_mut(tok) {
+                dups.sort_unstable();
+            }
+        }
+
+        let data = TrieData::extended(
+            merge,
+            vocab_size,
+            token_offsets,
+            token_data,
+            (max_token_len, token_duplicates),
+        )?;
+        self.info.vocab_size = vocab_size;
+        self.data = Arc::new(data);
+        Ok(())
+    }
+
+    /// A trie with `info`, and the bytes of every token, with whether it gets a node;
+    /// when several tokens with the same bytes do, the last one wins.
+    /// See `from_ext()` for `special_tokens`.
+    fn with_tokens(
+        &self,
+        info: TokRxInfo,
+        stop_tokens: Vec<TokenId>,
+        tokens: &[(&[u8], bool)],
+        special_tokens: Option<Vec<TokenId>>,
+    ) -> Result<Self> {
+        let mut token_offsets = Vec::with_capacity(tokens.len());
+        let mut token_data = Vec::new();
+        let mut node_words = Vec::with_capacity(tokens.len());
+        for &(bytes, has_node) in tokens {
+            push_token(&mut token_offsets, &mut token_data, bytes)?;
+            node_words.push(if has_node { bytes.to_vec() } else { Vec::new() });
+        }
+        let nodes = build_nodes(&node_words, false)?;
+        let mut data = TrieData::new(
+            info.vocab_size,
+            token_offsets,
+            token_data,
+            nodes,
+            None,
+            true,
+        )?;
+        if let Some(special_tokens) = special_tokens {
+            data.set_special_tokens(&special_tokens)?;
+        }
+        Ok(TokTrie::with_data(info, stop_tokens, data))
+    }
+
+    pub fn build_chat_mode_trie(&self) -> Self {
+        self.with_eos_token(self.info.tok_end_of_turn.unwrap_or(self.info.tok_eos))
+    }
+
+    /// Index of the node in the trie's node array; the root is at 0.
+    ///
+    /// Like the other methods taking `&TrieNode`, this needs `n` to be borrowed from
+    /// the trie (from `root()`, `node_children()` and the like), and panics otherwise,
+    /// for example for a copy of a node. `NodeRef` and the `*_ref()` methods don't have
+    /// this requirement.
+    pub fn node_offset(&self, n: &TrieNode) -> usize {
+        node_offset_in(&self.data.nodes, n)
+    }
+
+    /// Inverse of `node_offset()`.
+    pub fn node_at_offset(&self, off: usize) -> &TrieNode {
+        &self.data.nodes[off]
+    }
+
+    /// Bytes on the path from the root to `n`.
+    pub fn node_path(&self, n: &TrieNode) -> Vec<u8> {
+        self.node_path_ref(NodeRef::at(self.node_offset(n)))
+    }
+
+    /// Like `node_path()`.
+    pub fn node_path_ref(&self, n: NodeRef) -> Vec<u8> {
+        let nodes = &self.data.nodes;
+        PathOffsets::new(nodes, n.offset())
+            .map(|p| nodes[p].byte())
+            .collect()
+    }
+
+    /// Number of bytes below `n` to the nearest and to the farthest node with a token;
+    /// both are 0 for a token leaf. Saturates at `u16::MAX`, which is also what
+    /// the root of an empty trie gets.
+    pub fn node_depth_bounds(&self, n: &TrieNode) -> (u16, u16) {
+        self.data.depth_bounds.at(self.node_offset(n))
+    }
+
+    pub fn info(&self) -> &TokRxInfo {
+        &self.info
+    }
+
+    /// Panics if the special token is not set; see `try_special_token()`.
+    pub fn special_token(&self, tok: SpecialToken) -> TokenId {
+        self.try_special_token(tok)
+            .unwrap_or_else(|| panic!("special_token(): {:?} not set", tok))
+    }
+
+    pub fn try_special_token(&self, tok: SpecialToken) -> Option<TokenId> {
+        self.info.special_token(tok)
+    }
+
+    pub fn eos_token(&self) -> TokenId {
+        self.info.tok_eos
+    }
+
+    pub fn vocab_size(&self) -> usize {
+        self.info.vocab_size as usize
+    }
+
+    /// Token id one past the vocabulary, which `add_bias()` uses as scratch space
+    /// while walking the trie (nodes without a token set it), and clears when done.
+    pub fn sentinel_token(&self) -> TokenId {
+        self.vocab_size() as TokenId
+    }
+
+    /// True for ids below `vocab_size()`; `sentinel_token()` is not a valid token.
+    ///
+    /// Methods returning `Result` (`append_token()`, `append_tokens()`, `try_append_token()`,
+    /// `singleton_token_set()`, `try_decode()` and the like) fail on other ids, except
+    /// the sentinel, which stands for no token: it has no bytes and is never in a token set.
+    /// The rest (`token()`, `decode()`, `token_props()`, ...) are lenient, and treat
+    /// other ids as tokens without any bytes or properties.
+    pub fn is_valid_token(&self, t: TokenId) -> bool {
+        (t as usize) < self.vocab_size()
+    }
+
+    fn check_token(&self, t: TokenId) -> Result<()> {
+        ensure!(
+            self.is_valid_token(t) || t == self.sentinel_token(),
+            "token {} out of range (vocab size {})",
+            t,
+            self.vocab_size()
+        );
+        Ok(())
+    }
+
+    /// Token sets passed to `compute_bias()` and friends need a length of `vocab_size()`,
+    /// and room for the `sentinel_token()` (`capacity() > vocab_size()`); this is such a set.
+    pub fn alloc_token_set(&self) -> SimpleVob {
+        SimpleVob::alloc_with_capacity(self.vocab_size(), self.vocab_size() + 1)
+    }
+
+    /// The token set with just `tok`; empty for `sentinel_token()`.
+    /// Fails if `tok` is out of range; see `is_valid_token()`.
+    pub fn singleton_token_set(&self, tok: TokenId) -> Result<SimpleVob> {
+        self.check_token(tok)?;
+        let mut r = self.alloc_token_set();
+        if self.is_valid_token(tok) {
+            r.allow_token(tok);
+        }
+        Ok(r)
+    }
+
+    /// `a &= b`, for token sets of this trie (see `alloc_token_set()`);
+    /// fails, leaving `a` unchanged, if either isn't one.
+    pub fn mask_and(&self, a: &mut SimpleVob, b: &SimpleVob) -> Result<()> {
+        try_check_token_set_in(self.vocab_size(), a)?;
+        try_check_token_set_in(self.vocab_size(), b)?;
+        a.and_with(b)
+    }
+
+    /// Disallow the special tokens (see `is_special_token()`), and tokens with the same bytes
+    /// as one, which `apply_duplicates()` would allow again otherwise.
+    pub fn mask_without_specials(&self, m: &mut SimpleVob) {
+        check_token_set_in(self.vocab_size(), m);
+        self.data.special_tokens.iter_set_entries(|idx| {
+            let t = self.canonical_token(idx as TokenId);
+            m.disallow_token(t);
+            for &dup in self.duplicates_of(t) {
+                m.disallow_token(dup);
+            }
+        });
+    }
+
+    /// The number of tokens allowed by `m`, and the first of them, if any.
+    pub fn mask_count_and_example(&self, m: &SimpleVob) -> (usize, Option<TokenId>) {
+        check_token_set_in(self.vocab_size(), m);
+        (m.num_set(), m.first_set())
+    }
+
+    /// The token forced by `m`: `Some(t)` if the tokens allowed are `t` and any of its
+    /// duplicates (as after `apply_duplicates()`), `t` being the canonical token.
+    pub fn mask_is_singleton(&self, m: &SimpleVob) -> Option<TokenId> {
+        check_token_set_in(self.vocab_size(), m);
+        let t = self.canonical_token(m.first_set()?);
+        let num_allowed = core::iter::once(&t)
+            .chain(self.duplicates_of(t))
+            .filter(|&&t| m.is_allowed(t))
+            .count();
+        (num_allowed == m.num_set()).then_some(t)
+    }
+
+    pub fn token_set_dbg(&self, ts: &SimpleVob) -> String {
+        self.token_set_dbg_ext(ts, &DbgOptions::default())
+    }
+
+    pub fn token_set_dbg_ext(&self, ts: &SimpleVob, opts: &DbgOptions) -> String {
+        check_token_set_in(self.vocab_size(), ts);
+        let ts_neg = ts.negated();
+        let use_neg = opts.allow_negation && ts_neg.num_set() * 20 < ts.num_set();
+        let ts1 = if use_neg { &ts_neg } else { &ts };
+        let num_set = ts1.num_set();
+        let max_tok = core::cmp::min(opts.max_examples, num_set);
+        let mut tokens = Vec::new();
+        // make sure we include EOS and the other stop tokens first if they're allowed
+        let eos = self.info.tok_eos;
+        let stop_tokens = self.stop_tokens.iter().filter(|&&t| t != eos);
+        for &idx in [eos].iter().chain(stop_tokens) {
+            if (idx as usize) < ts1.len() && ts1.is_allowed(idx) {
+                tokens.push(self.token_dbg_entry(idx, opts));
+            }
+        }
+        for idx in ts1.iter_set_bits() {
+            if idx as usize >= self.vocab_size() || tokens.len() >= max_tok {
+                break;
+            }
+            if !self.is_stop_token(idx) {
+                tokens.push(self.token_dbg_entry(idx, opts));
+            }
+        }
+        let truncated = tokens.len() < num_set;
+
+        if opts.json {
+            return serde_json::to_string(&TokenSetDbg {
+                num_set: ts.num_set(),
+                vocab_size: self.vocab_size(),
+                negated: use_neg,
+                tokens,
+                truncated,
+            })
+            .unwrap();
+        }
+
+        let mut token_names = tokens.iter().map(|t| t.to_text(opts)).collect::<Vec<_>>();
+        if truncated {
+            token_names.push("...".to_string());
+        }
+        format!(
+            "TokenSet: {}/{}; {}{}",
+            ts.num_set(),
+            self.vocab_size(),
+            if use_neg { "ALL EXCEPT " } else { "" },
+            token_names.join(", ")
+        )
+    }
+
+    /// Zeroed logits, one per token (without the `sentinel_token()`).
+    pub fn alloc_logits(&self) -> Vec<f32> {
+        vec![0.0; self.vocab_size()]
+    }
+
+    /// Turn `mask` into an additive logit bias: `0.0` for allowed tokens and `neg_inf`
+    /// for the rest. Both `mask` and `out` have to be of length `vocab_size()`
+    /// (like `alloc_token_set()` and `alloc_logits()`); the `sentinel_token()` is ignored.
+    pub fn mask_to_logit_bias(
+        &self,
+        mask: &SimpleVob,
+        neg_inf: f32,
+        out: &mut [f32],
+    ) -> Result<()> {
+        let vocab_size = self.vocab_size();
+        if mask.len() != vocab_size {
+            bail!(
+                "TokTrie: mask of size {} used with vocab size {}",
+                mask.len(),
+                vocab_size
+            );
+        }
+        if out.len() != vocab_size {
+            bail!(
+                "TokTrie: logit bias of size {} used with vocab size {}",
+                out.len(),
+                vocab_size
+            );
+        }
+        out.fill(neg_inf);
+        mask.iter_set_entries(|idx| {
+            if idx < vocab_size {
+                out[idx] = 0.0;
+            }
+        });
+        Ok(())
+    }
+
+    pub fn test_trace_tokens(&self, toks: &[u32]) -> String {
+        toks.iter()
+            .map(|t| {
+                let s = self.token_dbg(*t);
+                if s.starts_with("\"") {
+                    self.token_str(*t)
+                } else {
+                    format!("≺{}≻", s)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("‧")
+    }
+
+    pub fn tokens_dbg(&self, toks: &[u32]) -> String {
+        self.tokens_dbg_ext(toks, &DbgOptions::default())
+    }
+
+    pub fn tokens_dbg_ext(&self, toks: &[u32], opts: &DbgOptions) -> String {
+        let tokens = toks
+            .iter()
+            .map(|&t| self.token_dbg_entry(t, opts))
+            .collect::<Vec<_>>();
+        if opts.json {
+            return serde_json::to_string(&tokens).unwrap();
+        }
+
+        let joined = tokens
+            .iter()
+            .map(|t| {
+                let s = if t.quoted {
+                    let s = format!("{:?}", t.repr);
+                    s[1..s.len() - 1].to_string()
+                } else {
+                    format!("≺{}≻", t.repr)
+                };
+                if opts.show_ids {
+                    format!("{}[{}]", s, t.id)
+                } else {
+                    s
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("‧");
+
+        format!("\"{}\"", joined)
+    }
+
+    /// `OOB[idx]` for out-of-range tokens.
+    pub fn token_dbg(&self, idx: u32) -> String {
+        self.token_dbg_entry(idx, &DbgOptions::default())
+            .to_text(&DbgOptions::default())
+    }
+
+    fn token_dbg_entry(&self, idx: u32, opts: &DbgOptions) -> TokenDbg {
+        let (repr, special, quoted) = if idx == self.info.tok_eos {
+            ("EOS".to_string(), true, false)
+        } else if idx as usize >= self.vocab_size() {
+            (format!("OOB[{}]", idx), false, false)
+        } else {
+            let bytes = self.token(idx);
+            if self.is_special_token(idx) {
+                (
+                    String::from_utf8_lossy(&bytes[1..]).to_string(),
+                    true,
+                    false,
+                )
+            } else {
+                let s = String::from_utf8_lossy(bytes);
+                if s.len() == 0 {
+                    (format!("EMPTY[{}]", idx), false, false)
+                } else if s.matches('\u{fffd}').count() <= opts.hex_threshold {
+                    (s.to_string(), false, true)
+                } else {
+                    (format!("HEX[{}]", to_hex_string(bytes)), false, false)
+                }
+            }
+        };
+        TokenDbg {
+            id: idx,
+            repr,
+            special,
+            quoted,
+        }
+    }
+
+    pub fn token_str(&self, idx: u32) -> String {
+        String::from_utf8_lossy(self.token(idx)).to_string()
+    }
+
+    /// Bytes of the token; empty for out-of-range tokens.
+    pub fn token(&self, idx: u32) -> &[u8] {
+        self.slices().token(idx)
+    }
+
+    /// Bytes of the tokens, without the `SPECIAL_TOKEN_PREFIX_BYTE` of special tokens;
+    /// other tokens are given as they are, 0xff bytes included.
+    /// Out-of-range tokens are skipped; see `try_decode()`.
+    pub fn decode(&self, tokens: &[TokenId]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.decode_into(tokens, &mut bytes);
+        bytes
+    }
+
+    /// Like `decode()`, but fails on out-of-range tokens; see `is_valid_token()`.
+    pub fn try_decode(&self, tokens: &[TokenId]) -> Result<Vec<u8>> {
+        for &t in tokens {
+            self.check_token(t)?;
+        }
+        Ok(self.decode(tokens))
+    }
+
+    /// Like `decode()`, but appends to `buf`.
+    pub fn decode_into(&self, tokens: &[TokenId], buf: &mut Vec<u8>) {
+        for &t in tokens {
+            let bytes = self.token(t);
+            if self.is_special_token(t) {
+                buf.extend_from_slice(&bytes[1..]);
+            } else {
+                buf.extend_from_slice(bytes);
+            }
+        }
+    }
+
+    pub fn decode_raw(&self, tokens: &[TokenId]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.decode_raw_into(tokens, &mut bytes);
+        bytes
+    }
+
+    /// Like `decode_raw()`, but appends to `buf`.
+    pub fn decode_raw_into(&self, tokens: &[TokenId], buf: &mut Vec<u8>) {
+        for t in tokens {
+            buf.extend_from_slice(self.token(*t));
+        }
+    }
+
+    pub fn decode_str(&self, tokens: &[TokenId]) -> String {
+        String::from_utf8_lossy(&self.decode(tokens)).to_string()
+    }
+
+    /// Incremental version of `decode_str()`, for streaming output.
+    pub fn decoder(&self) -> StreamDecoder<'_> {
+        StreamDecoder::new(self)
+    }
+
+    pub fn get_special_token(&self, name: &str) -> Option<TokenId> {
+        self.child_at_byte(self.root(), TokTrie::SPECIAL_TOKEN_PREFIX_BYTE)
+            .and_then(|n| {
+                self.child_at_bytes(n, name.as_bytes())
+                    .and_then(|n| n.token_id())
+                    .and_then(|tok| self.special_token_or_dup(tok))
+            })
+    }
+
+    /// `tok` if it's special, or else a special duplicate of it.
+    fn special_token_or_dup(&self, tok: TokenId) -> Option<TokenId> {
+        let dups = self.data.token_duplicates.get(&tok);
+        [tok]
+            .into_iter()
+            .chain(dups.into_iter().flatten().copied())
+            .find(|&t| self.is_special_token(t))
+    }
+
+    /// True if the token starts with `SPECIAL_TOKEN_PREFIX_BYTE` (and isn't just that byte),
+    /// or, for tries built with the special tokens listed, if it's one of them;
+    /// see `from_ext()`.
+    #[inline(always)]
+    pub fn is_special_token(&self, t: TokenId) -> bool {
+        (t as usize) < self.data.special_tokens.len() && self.data.special_tokens.is_allowed(t)
+    }
+
+    /// The special tokens, if they were listed when building the trie,
+    /// rather than going by the prefix byte.
+    pub fn explicit_special_tokens(&self) -> Option<Vec<TokenId>> {
+        self.data
+            .explicit_special_tokens
+            .then(|| self.data.special_token_list())
+    }
+
+    /// Flags describing the bytes of the token; empty for out-of-range tokens.
+    #[inline(always)]
+    pub fn token_props(&self, t: TokenId) -> TokenProps {
+        self.data
+            .token_classes
+            .props
+            .get(t as usize)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Tokens with `TokenProps::WHITESPACE_ONLY`.
+    pub fn whitespace_tokens(&self) -> SimpleVob {
+        self.data.token_classes.whitespace.clone()
+    }
+
+    /// Tokens with `TokenProps::ASCII_ONLY`.
+    pub fn ascii_tokens(&self) -> SimpleVob {
+        self.data.token_classes.ascii.clone()
+    }
+
+    /// Tokens without any bytes, typically unused slots in the vocabulary.
+    /// They are not in the trie, so `compute_bias()` never allows them.
+    pub fn empty_tokens(&self) -> Vec<TokenId> {
+        self.data.token_classes.empty.clone()
+    }
+
+    /// Byte length of every token, indexed by id; lengths over `u16::MAX` are
+    /// given as `u16::MAX`.
+    pub fn token_lengths(&self) -> &[u16] {
+        &self.data.token_classes.lengths
+    }
+
+    /// First byte of every token, indexed by id; 0 for empty tokens.
+    pub fn token_first_bytes(&self) -> &[u8] {
+        &self.data.token_classes.first_bytes
+    }
+
+    /// Last byte of every token, indexed by id; 0 for empty tokens.
+    pub fn token_last_bytes(&self) -> &[u8] {
+        &self.data.token_classes.last_bytes
+    }
+
+    /// Non-empty tokens whose first byte is `b`. Special tokens start with
+    /// `SPECIAL_TOKEN_PREFIX_BYTE`.
+    pub fn tokens_starting_with_byte(&self, b: u8) -> SimpleVob {
+        let mut res = self.alloc_token_set();
+        let classes = &self.data.token_classes;
+        for (tok, (&fb, &len)) in classes.first_bytes.iter().zip(&classes.lengths).enumerate() {
+            if fb == b && len > 0 {
+                res.allow_token(tok as TokenId);
+            }
+        }
+        res
+    }
+
+    /// Tokens of at most `n` bytes, including empty ones.
+    pub fn tokens_with_len_at_most(&self, n: usize) -> SimpleVob {
+        let mut res = self.alloc_token_set();
+        for (tok, &len) in self.data.token_classes.lengths.iter().enumerate() {
+            // saturated lengths need the real one
+            let fits = if len == u16::MAX {
+                self.token(tok as TokenId).len() <= n
+            } else {
+                len as usize <= n
+            };
+            if fits {
+                res.allow_token(tok as TokenId);
+            }
+        }
+        res
+    }
+
+    /// Tokens that have all flags in `props` set.
+    pub fn tokens_with_props(&self, props: TokenProps) -> SimpleVob {
+        let mut res = self.alloc_token_set();
+        for (tok, p) in self.data.token_classes.props.iter().enumerate() {
+            if p.contains(props) {
+                res.allow_token(tok as TokenId);
+            }
+        }
+        res
+    }
+
+    /// Tokens to start output that has to be one of `literals`. With `partial`, these are
+    /// the tokens whose bytes are a prefix of a literal, the whole literal included,
+    /// so the literal can be finished with more tokens; otherwise only the tokens equal
+    /// to a literal. Tokens going past the end of every literal they start are never
+    /// included, as they can't be part of the output. Duplicates are included;
+    /// empty literals match no token. See `advance_literals()` for the next steps.
+    pub fn mask_for_literals(&self, literals: &[&[u8]], partial: bool) -> SimpleVob {
+        let mut res = self.alloc_token_set();
+        for lit in literals {
+            self.add_literal_tokens(&mut res, lit, partial);
+        }
+        self.apply_duplicates(&mut res);
+        res
+    }
+
+    /// Like `mask_for_literals()` with `partial`, once `consumed` bytes were output:
+    /// the tokens whose bytes are a prefix of the rest of a literal that starts with
+    /// `consumed`. Also returns true if `consumed` is one of the literals, when
+    /// the output can end there (the mask then only has tokens for longer literals).
+    pub fn advance_literals(&self, literals: &[&[u8]], consumed: &[u8]) -> (SimpleVob, bool) {
+        let mut res = self.alloc_token_set();
+        let mut matched = false;
+        for lit in literals {
+            if let Some(rest) = lit.strip_prefix(consumed) {
+                matched |= rest.is_empty();
+                self.add_literal_tokens(&mut res, rest, true);
+            }
+        }
+        self.apply_duplicates(&mut res);
+        (res, matched)
+    }
+
+    /// Allow the tokens that are a prefix of `lit` (only `lit` itself unless `partial`),
+    /// without their duplicates.
+    fn add_literal_tokens(&self, res: &mut SimpleVob, lit: &[u8], partial: bool) {
+        let mut n = self.root();
+        for (idx, &b) in lit.iter().enumerate() {
+            n = match self.child_at_byte(n, b) {
+                Some(n) => n,
+                None => return,
+            };
+            if let Some(tok) = n.token_id() {
+                if partial || idx + 1 == lit.len() {
+                    res.allow_token(tok);
+                }
+            }
+        }
+    }
+
+    /// All special tokens, in byte order of their names; empty if there are none.
+    /// Duplicates of special tokens are not included.
+    pub fn get_special_tokens(&self) -> Vec<TokenId> {
+        self.get_special_tokens_with_names()
+            .into_iter()
+            .map(|(_, tok)| tok)
+            .collect()
+    }
+
+    /// All special tokens with their names (without the prefix byte), in byte order.
+    pub fn get_special_tokens_with_names(&self) -> Vec<(String, TokenId)> {
+        let mut res = Vec::new();
+        // walk() skips pref_node, which would be a token consisting of just the prefix byte
+        let pref_node = match self.child_at_byte(self.root(), TokTrie::SPECIAL_TOKEN_PREFIX_BYTE) {
+            Some(n) => n,
+            None => return res,
+        };
+        let mut name = vec![];
+        for ev in self.walk(pref_node) {
+            match ev {
+                WalkEvent::Push(b, tok) => {
+                    name.push(b);
+                    if let Some(tok) = tok.and_then(|t| self.special_token_or_dup(t)) {
+                        res.push((String::from_utf8_lossy(&name).to_string(), tok));
+                    }
+                }
+                WalkEvent::Pop(num) => name.truncate(name.len() - num),
+            }
+        }
+        res
+    }
+
+    /// Tokenize by repeatedly taking the longest token that matches.
+    /// Bytes that don't start any token are mapped to `tok_unk` if it's set,
+    /// and skipped otherwise.
+    pub fn greedy_tokenize(&self, bytes: &[u8]) -> Vec<TokenId> {
+        self.greedy_tokenize_ext(bytes, |_, res| {
+            if let Some(unk) = self.info.tok_unk {
+                res.push(unk);
+            }
+            Ok(())
+        })
+        .unwrap()
+    }
+
+    /// Like `greedy_tokenize()`, but fails on bytes that don't start any token.
+    pub fn try_greedy_tokenize(&self, bytes: &[u8]) -> Result<Vec<TokenId>> {
+        self.greedy_tokenize_ext(bytes, |idx, _| {
+            bail!("no token for byte 0x{:02x} at offset {}", bytes[idx], idx)
+        })
+    }
+
+    fn greedy_tokenize_ext(
+        &self,
+        bytes: &[u8],
+        mut on_unknown: impl FnMut(usize, &mut Vec<TokenId>) -> Result<()>,
+    ) -> Result<Vec<TokenId>> {
+        let mut r = Vec::new();
+        let mut idx = 0;
+        while idx < bytes.len() {
+            let (tok, len) = self.prefix_token_id(&bytes[idx..]);
+            if len == 0 {
+                on_unknown(idx, &mut r)?;
+                idx += 1;
+            } else {
+                r.push(tok);
+                idx += len;
+            }
+        }
+        Ok(r)
+    }
+
+    /// Like `greedy_tokenize()`, but `SPECIAL_TOKEN_PREFIX_BYTE` followed by the name
+    /// of a special token (the longest one, if several match) always yields that token,
+    /// and text is never merged across it.
+    /// Prefix bytes not starting a special token are tokenized like other bytes.
+    pub fn greedy_tokenize_with_special(&self, bytes: &[u8]) -> Vec<TokenId> {
+        self.tokenize_around_specials(bytes, |text| self.greedy_tokenize(text))
+    }
+
+    /// The longest special token (including the prefix byte) that `bytes` starts with,
+    /// and its length.
+    pub fn prefix_special_token(&self, bytes: &[u8]) -> Option<(TokenId, usize)> {
+        let (&first, rest) = bytes.split_first()?;
+        if first != TokTrie::SPECIAL_TOKEN_PREFIX_BYTE {
+            return None;
+        }
+        let mut n = self.child_at_byte(self.root(), first)?;
+        let mut last = None;
+        for (idx, &byte) in rest.iter().enumerate() {
+            n = match self.child_at_byte(n, byte) {
+                Some(n) => n,
+                None => break,
+            };
+            if let Some(tok) = n.token_id().and_then(|t| self.special_token_or_dup(t)) {
+                last = Some((tok, idx + 2));
+            }
+        }
+        last
+    }
+
+    /// Split `bytes` at special tokens, passing the text between them to `tokenize_text`,
+    /// prefix bytes that don't start a special token included.
+    fn tokenize_around_specials(
+        &self,
+        bytes: &[u8],
+        mut tokenize_text: impl FnMut(&[u8]) -> Vec<TokenId>,
+    ) -> Vec<TokenId> {
+        let mut res = Vec::new();
+        let mut text = Vec::new();
+        let mut idx = 0;
+        while idx < bytes.len() {
+            if bytes[idx] != TokTrie::SPECIAL_TOKEN_PREFIX_BYTE {
+                text.push(bytes[idx]);
+                idx += 1;
+            } else if let Some((tok, len)) = self.prefix_special_token(&bytes[idx..]) {
+                if !text.is_empty() {
+                    res.extend(tokenize_text(&text));
+                    text.clear();
+                }
+                res.push(tok);
+                idx += len;
+            } else {
+                text.push(bytes[idx]);
+                idx += 1;
+            }
+        }
+        if !text.is_empty() {
+            res.extend(tokenize_text(&text));
+        }
+        res
+    }
+
+    pub fn tokenize_with_greedy_fallback(
+        &self,
+        s: &[u8],
+        str_tokenize: impl FnOnce(&str) -> Vec<TokenId>,
+    ) -> Vec<TokenId> {
+        let utf8_str = String::from_utf8_lossy(s);
+        // if the string ends with a replacement character, remove them
+        let to_tokenize = if utf8_str.ends_with('\u{FFFD}') {
+            utf8_str.trim_end_matches('\u{FFFD}')
+        } else {
+            &utf8_str
+        };
+        let mut r = str_tokenize(to_tokenize);
+        // if we didn't tokenize everything (because of the replacement character)
+        // we tokenize the suffix using greedy tokenizer that is happy with bytes
+        let last_tokenized = to_tokenize.len();
+        if last_tokenized < s.len() {
+            let mut added = self.greedy_tokenize(&s[last_tokenized..]);
+            r.append(&mut added);
+        }
+        r
+    }
+
+    /// Which bytes tokens start with, and which are tokens by themselves.
+    /// Special tokens count like the others.
+    pub fn byte_coverage(&self) -> ByteCoverage {
+        let mut starts_token = [false; 256];
+        let mut single_byte_token = [false; 256];
+        for n in self.node_children(self.root()) {
+            starts_token[n.byte() as usize] = true;
+            single_byte_token[n.byte() as usize] = n.token_id().is_some();
+        }
+        ByteCoverage {
+            starts_token,
+            single_byte_token,
+            covers_all_bytes: single_byte_token.iter().all(|&b| b),
+        }
+    }
+
+    /// Whether `bytes` can be split into tokens, with any tokens, not only the ones
+    /// a tokenizer would pick; `greedy_tokenize()` can fail to find a split that exists.
+    /// Always true for empty `bytes`, and with `byte_coverage().covers_all_bytes`.
+    ///
+    /// Walks the trie from every offset reachable by a split of the bytes before it,
+    /// so it takes `O(bytes.len() * max_token_len())` child lookups at worst.
+    pub fn can_represent(&self, bytes: &[u8]) -> bool {
+        // reachable[i]: bytes[..i] can be split into tokens
+        let mut reachable = vec![false; bytes.len() + 1];
+        reachable[0] = true;
+        for start in 0..bytes.len() {
+            if reachable[start] {
+                self.for_each_prefix_token(&bytes[start..], |len, _| {
+                    reachable[start + len] = true;
+                });
+            }
+        }
+        reachable[bytes.len()]
+    }
+
+    /// A split of `bytes` into as few tokens as possible, `None` if there's no split.
+    /// Of the shortest splits, the one with the longest first token is picked, then
+    /// the longest second token, and so on. Tokens are canonical, as from `token_id()`.
+    ///
+    /// Takes `O(bytes.len() * max_token_len())` child lookups at worst.
+    pub fn shortest_tokenization(&self, bytes: &[u8]) -> Option<Vec<TokenId>> {
+        let splits = self.shortest_splits(bytes);
+        if splits[0].0 == usize::MAX {
+            return None;
+        }
+        let mut res = Vec::with_capacity(splits[0].0);
+        let mut pos = 0;
+        while pos < bytes.len() {
+            let (_, len, tok) = splits[pos];
+            res.push(tok);
+            pos += len;
+        }
+        Some(res)
+    }
+
+    /// Splits of `bytes` into at most `max_len` tokens, at most `max_results` of them;
+    /// empty if there are none. Tokens are canonical, as from `token_id()`.
+    ///
+    /// The splits are listed in order of the length of the first token, longest first,
+    /// then of the second token, and so on. Only splits that can be completed within
+    /// `max_len` tokens are followed, so the work is bounded by
+    /// `O(max_results * max_len * max_token_len())` child lookups, on top of
+    /// the `shortest_tokenization()` pass.
+    pub fn tokenizations_bounded(
+        &self,
+        bytes: &[u8],
+        max_results: usize,
+        max_len: usize,
+    ) -> Vec<Vec<TokenId>> {
+        let mut res = Vec::new();
+        let splits = self.shortest_splits(bytes);
+        if max_results == 0 || splits[0].0 > max_len {
+            return res;
+        }
+        if bytes.is_empty() {
+            res.push(Vec::new());
+            return res;
+        }
+
+        // tokens at pos that leave a split of the rest, within max_len with `depth` tokens before;
+        // shortest first, as they are popped
+        let matches_at = |pos: usize, depth: usize| {
+            let mut m = Vec::new();
+            self.for_each_prefix_token(&bytes[pos..], |len, tok| {
+                if splits[pos + len].0.saturating_add(depth + 1) <= max_len {
+                    m.push((len, tok));
+                }
+            });
+            m
+        };
+        let mut path = Vec::new();
+        let mut stack = vec![(0, matches_at(0, 0))];
+        while let Some((pos, matches)) = stack.last_mut() {
+            let pos = *pos;
+            match matches.pop() {
+                Some((len, tok)) => {
+                    path.push(tok);
+                    if pos + len == bytes.len() {
+                        res.push(path.clone());
+                        if res.len() == max_results {
+                            break;
+                        }
+                        path.pop();
+                    } else {
+                        stack.push((pos + len, matches_at(pos + len, path.len())));
+                    }
+                }
+                None => {
+                    stack.pop();
+                    path.pop();
+                }
+            }
+        }
+        res
+    }
+
+    /// For each offset `i`, the number of tokens in the shortest split of `bytes[i..]`
+    /// (`usize::MAX` if there's none), and the length and id of the first token of
+    /// the split `shortest_tokenization()` picks.
+    fn shortest_splits(&self, bytes: &[u8]) -> Vec<(usize, usize, TokenId)> {
+        let mut splits = vec![(usize::MAX, 0, 0); bytes.len() + 1];
+        splits[bytes.len()].0 = 0;
+        for pos in (0..bytes.len()).rev() {
+            self.for_each_prefix_token(&bytes[pos..], |len, tok| {
+                let rest = splits[pos + len].0;
+                // longer tokens come later, and win ties
+                if rest < splits[pos].0 {
+                    splits[pos] = (rest + 1, len, tok);
+                }
+            });
+        }
+        splits
+    }
+
+    /// Calls `f(len, tok)` for the tokens `bytes` starts with, shortest first;
+    /// `tok` is the canonical token.
+    fn for_each_prefix_token(&self, bytes: &[u8], mut f: impl FnMut(usize, TokenId)) {
+        let mut off = 0;
+        for (idx, &byte) in bytes.iter().enumerate() {
+            off = match child_offset_at_byte_in(&self.data.nodes, &self.data.jump_tables, off, byte)
+            {
+                Some(off) => off,
+                None => break,
+            };
+            if let Some(tok) = self.data.nodes[off].token_id() {
+                f(idx + 1, tok);
+            }
+        }
+    }
+
+    pub fn has_extensions(&self, bytes: &[u8]) -> bool {
+        match self.child_at_bytes(self.root(), bytes) {
+            None => false,
+            Some(n) => n.subtree_size() > 1,
+        }
+    }
+
+    /// The token with exactly `bytes`; `None` for empty `bytes`, see `empty_tokens()`.
+    pub fn token_id(&self, bytes: &[u8]) -> Option<TokenId> {
+        let (tok, len) = self.prefix_token_id(bytes);
+        // println!("tok_id {:?} {:?} {:?} ", bytes, tok, len);
+        if len > 0 && len == bytes.len() {
+            Some(tok)
+        } else {
+            None
+        }
+    }
+
+    /// The node where the bytes of `t` end, the same for all duplicates of a token;
+    /// `None` for empty and out-of-range tokens.
+    pub fn token_end_node(&self, t: TokenId) -> Option<NodeRef> {
+        self.data
+            .token_end_nodes
+            .get(t as usize)
+            .filter(|&&off| off != NO_NODE)
+            .map(|&off| NodeRef::at(off as usize))
+    }
+
+    /// The token with the bytes of `a` followed by the bytes of `b` (the canonical one,
+    /// as `token_id()` gives), found by walking `b` from `token_end_node(a)`.
+    /// `None` if there's no such token, or `a` or `b` are empty or out of range.
+    pub fn merge_token(&self, a: TokenId, b: TokenId) -> Option<TokenId> {
+        let mut off = self.token_end_node(a)?.offset();
+        let bytes = self.token(b);
+        if bytes.is_empty() {
+            return None;
+        }
+        for &byte in bytes {
+            off = child_offset_at_byte_in(&self.data.nodes, &self.data.jump_tables, off, byte)?;
+        }
+        self.data.nodes[off].token_id()
+    }
+
+    /// Indices `i` where `tokens[i]` and `tokens[i + 1]` can be merged into one token;
+    /// see `merge_token()`.
+    pub fn find_mergeable_pairs(&self, tokens: &[TokenId]) -> Vec<usize> {
+        tokens
+            .windows(2)
+            .enumerate()
+            .filter(|(_, w)| self.merge_token(w[0], w[1]).is_some())
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// The longest token that is a prefix of `bytes`, and its length.
+    /// The length is 0 if there is no such token (in particular when `bytes` is empty),
+    /// and then the token is meaningless; empty tokens are never returned.
+    pub fn prefix_token_id(&self, bytes: &[u8]) -> (TokenId, usize) {
+        let mut last = (0, 0);
+        let mut n = self.root();
+        for (idx, byte) in bytes.iter().enumerate() {
+            n = match self.child_at_byte(n, *byte) {
+                Some(n) => n,
+                None => break,
+            };
+            if let Some(tok) = n.token_id() {
+                last = (tok, idx + 1);
+            }
+        }
+        return last;
+    }
+
+    /// Like `from_bytes()`, but uses the buffer in place instead of copying it, where possible.
+    pub fn from_bytes_borrowed(bytes: &[u8]) -> TokTrieRef<'_> {
+        TokTrieRef::from_bytes(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        Self::try_from_bytes(bytes).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `from_bytes()`, but returns an error on malformed input instead of panicking.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::from_bytes_ext(bytes, true)
+    }
+
+    /// Like `try_from_bytes()`, but without validating the tokens and nodes, which saves
+    /// time loading large tries from a trusted source, like a cache written by
+    /// `serialize()`. The header is still checked, and so are the subtree sizes and
+    /// token ids of the nodes, without which loading could loop or panic.
+    /// A malformed trie loaded this way can give wrong results or panic later on;
+    /// `validate()` checks it.
+    pub fn from_bytes_unchecked(bytes: &[u8]) -> Result<Self> {
+        Self::from_bytes_ext(bytes, false)
+    }
+
+    /// Check the tokens and nodes, as `try_from_bytes()` does; tries not loaded with
+    /// `from_bytes_unchecked()` are always valid.
+    pub fn validate(&self) -> core::result::Result<(), ValidationError> {
+        let max_depth = validate_token_offsets(
+            &self.data.token_offsets,
+            &self.data.token_data,
+            self.vocab_size() as u32,
+        )?;
+        validate_nodes(&self.data.nodes, self.vocab_size() as u32, max_depth, true)?;
+        Ok(())
+    }
+
+    fn from_bytes_ext(bytes: &[u8], check: bool) -> Result<Self> {
+        let (hd, [nodes, token_offsets, token_data, stats], swap) = TokTrieHeader::parse(bytes)?;
+        Self::from_sections(
+            &hd,
+            vec_from_bytes(&bytes[nodes]),
+            vec_from_bytes(&bytes[token_offsets]),
+            vec_from_bytes(&bytes[token_data]),
+            &bytes[stats],
+            swap,
+            check,
+        )
+    }
+
+    /// Reads what `serialize()` (or `serialize_to()`) wrote from `r`, like `try_from_bytes()`.
+    /// The header is read first, then exactly the section sizes it has; the memory used
+    /// grows with the data actually read, not with the sizes claimed in the header.
+    /// Nothing past the trie is read, so it can be followed by other data in `r`;
+    /// except for files older than format version 5, whose stats section
+    /// has no size, and spans the rest of `r`.
+    /// Files written before `token_data_bytes` in the header was fixed can't be read
+    /// this way; `try_from_bytes()` handles these.
+    #[cfg(feature = "std")]
+    pub fn deserialize_from(r: &mut impl Read) -> Result<Self> {
+        let mut hd_bytes = vec![0u8; 8];
+        read_section(r, &mut hd_bytes, "header")?;
+        if hd_bytes[0..4] == TokTrieHeader::MAGIC_FINGERPRINT.to_le_bytes() {
+            let mut fp = vec![0u8; TokTrieHeader::FINGERPRINT_PREFIX_LEN];
+            fp[0..8].copy_from_slice(&hd_bytes);
+            read_section(r, &mut fp[8..], "fingerprint")?;
+            TokTrieHeader::parse_fingerprint(&fp)?;
+            read_section(r, &mut hd_bytes, "header")?;
+        }
+        let (hd_len, _) = TokTrieHeader::header_len(&hd_bytes)?;
+        hd_bytes.resize(hd_len, 0);
+        read_section(r, &mut hd_bytes[8..], "header")?;
+        let (hd, swap) = TokTrieHeader::parse_header(&hd_bytes)?;
+
+        let nodes = read_pod_vec(r, hd.trie_bytes as usize, "nodes")?;
+        let token_offsets = read_pod_vec(r, hd.token_offset_bytes as usize, "token offsets")?;
+        let token_data = read_pod_vec(r, hd.token_data_bytes as usize, "token data")?;
+        let stats = if hd.version >= 5 {
+            let mut stats = vec![0u8; 8];
+            read_section(r, &mut stats, "stats")?;
+            let size = u32::from_le_bytes(stats[4..8].try_into().unwrap()) as usize;
+            ensure!(
+                stats.starts_with(&TokTrieHeader::MAGIC_STATS.to_le_bytes()) && size >= 8,
+                "TokTrie: invalid stats section"
+            );
+            stats.extend(read_pod_vec::<u8>(r, size - 8, "stats")?);
+            stats
+        } else {
+            let mut stats = Vec::new();
+            r.read_to_end(&mut stats)
+                .map_err(|e| anyhow!("TokTrie: reading stats: {}", e))?;
+            ensure!(
+                stats.is_empty() || stats.starts_with(&TokTrieHeader::MAGIC_STATS.to_le_bytes()),
+                "TokTrie: {} unexpected bytes after token data",
+                stats.len()
+            );
+            stats
+        };
+        Self::from_sections(&hd, nodes, token_offsets, token_data, &stats, swap, true)
+    }
+
+    /// The sections as stored, with nodes and token offsets in the file's byte order.
+    fn from_sections(
+        hd: &TokTrieHeader,
+        mut nodes: Vec<TrieNode>,
+        mut token_offsets: Vec<u32>,
+        mut token_data: Vec<u8>,
+        stats: &[u8],
+        swap: bool,
+        check: bool,
+    ) -> Result<Self> {
+        let info = TokRxInfo::from_bin_ext(&hd.info, &hd.info_ext);
+        let (stats, stop_tokens, special_tokens) =
+            parse_token_stats(stats, info.vocab_size, hd.version)?;
+        if swap {
+            swap_u32_bytes(bytemuck::cast_slice_mut(&mut nodes));
+            swap_u32_bytes(bytemuck::cast_slice_mut(&mut token_offsets));
+        }
+        if hd.magic == TokTrieHeader::MAGIC {
+            if let Some((offs, data)) = upgrade_legacy_tokens(&token_offsets, &token_data)? {
+                token_offsets = offs;
+                token_data = data;
+            }
+        }
+
+        let mut data = TrieData::new(
+            info.vocab_size,
+            token_offsets,
+            token_data,
+            nodes,
+            stats,
+            check,
+        )?;
+        if let Some(special_tokens) = special_tokens {
+            data.set_special_tokens(&special_tokens)?;
+        }
+        Ok(TokTrie::with_data(info, stop_tokens, data))
+    }
+
+    pub fn max_token_len(&self) -> usize {
+        self.data.max_token_len
+    }
+
+    /// Header, nodes and token offsets are always written little-endian.
+    pub fn serialize(&self) -> Vec<u8> {
+        let trie_data: &[u8] = bytemuck::cast_slice(&self.data.nodes);
+        let token_offsets: &[u8] = bytemuck::cast_slice(&self.data.token_offsets);
+        let token_data: &[u8] = bytemuck::cast_slice(&self.data.token_data);
+
+        let mut bytes = bytemuck::bytes_of(&self.serialized_header()).to_vec();
+        bytes.extend_from_slice(trie_data);
+        bytes.extend_from_slice(token_offsets);
+        if cfg!(target_endian = "big") {
+            // header, nodes and token offsets are all u32 words
+            swap_u32_bytes(&mut bytes);
+        }
+        bytes.extend_from_slice(token_data);
+        self.serialize_stats(&mut bytes);
+        bytes
+    }
+
+    /// `serialize()`, prefixed with `fingerprint`, for instance a hash of the tokenizer file
+    /// the trie was built from; `from_bytes_checked()` compares it with the expected one.
+    /// The other ways of loading a trie accept the output too, ignoring the fingerprint.
+    pub fn serialize_with_fingerprint(&self, fingerprint: &[u8; 32]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&TokTrieHeader::MAGIC_FINGERPRINT.to_le_bytes());
+        bytes.extend_from_slice(&TokTrieHeader::FINGERPRINT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(fingerprint);
+        bytes.extend_from_slice(&self.serialize());
+        bytes
+    }
+
+    /// The fingerprint of `serialize_with_fingerprint()` output, without loading the trie;
+    /// `None` for the output of `serialize()`, and for what isn't a serialized trie.
+    pub fn serialized_fingerprint(bytes: &[u8]) -> Option<[u8; 32]> {
+        TokTrieHeader::parse_fingerprint(bytes).ok().flatten()
+    }
+
+    /// Like `try_from_bytes()`, but with `expected_fingerprint` given, fails with
+    /// `FingerprintMismatch` unless `bytes` were written by `serialize_with_fingerprint()`
+    /// with that fingerprint. This is checked before loading the trie.
+    pub fn from_bytes_checked(
+        bytes: &[u8],
+        expected_fingerprint: Option<&[u8; 32]>,
+    ) -> Result<Self> {
+        if let Some(expected) = expected_fingerprint {
+            let found = TokTrieHeader::parse_fingerprint(bytes)?;
+            if found.as_ref() != Some(expected) {
+                return Err(FingerprintMismatch {
+                    expected: *expected,
+                    found,
+                }
+                .into());
+            }
+        }
+        Self::try_from_bytes(bytes)
+    }
+
+    /// SHA-256 of the tokens (as in `serialize()`), nodes, info, stop tokens and special tokens,
+    /// which is the same for tries that are `==`, however they were built.
+    pub fn content_hash(&self) -> [u8; 32] {
+        let info = self.info.to_bin();
+        let ext = self.info.to_bin_ext();
+        let info = [
+            info.vocab_size,
+            info.tok_eos,
+            ext.tok_bos,
+            ext.tok_pad,
+            ext.tok_unk,
+            ext.tok_end_of_turn,
+        ];
+        let specials = [
+            &[self.data.explicit_special_tokens as u32][..],
+            &self.data.special_token_list(),
+        ]
+        .concat();
+        let mut h = Sha256::new();
+        h.update(b"toktrie content 2");
+        for words in [
+            &info[..],
+            &self.stop_tokens,
+            &specials,
+            bytemuck::cast_slice(&self.data.nodes),
+            &self.data.token_offsets,
+        ] {
+            h.update((words.len() as u64).to_le_bytes());
+            for w in words {
+                h.update(w.to_le_bytes());
+            }
+        }
+        h.update((self.data.token_data.len() as u64).to_le_bytes());
+        h.update(&self.data.token_data);
+        h.finalize().into()
+    }
+
+    /// Like `serialize()`, but writes to `w` directly, instead of building the output
+    /// in memory; the bytes are the same.
+    #[cfg(feature = "std")]
+    pub fn serialize_to(&self, w: &mut impl Write) -> io::Result<()> {
+        write_u32_words(w, bytemuck::bytes_of(&self.serialized_header()))?;
+        write_u32_words(w, bytemuck::cast_slice(&self.data.nodes))?;
+        write_u32_words(w, bytemuck::cast_slice(&self.data.token_offsets))?;
+        w.write_all(&self.data.token_data)?;
+        let mut stats = Vec::new();
+        self.serialize_stats(&mut stats);
+        w.write_all(&stats)
+    }
+
+    /// In native byte order.
+    fn serialized_header(&self) -> TokTrieHeader {
+        TokTrieHeader {
+            magic: TokTrieHeader::MAGIC_VERSIONED,
+            hd_size: core::mem::size_of::<TokTrieHeader>() as u32,
+            trie_bytes: core::mem::size_of_val(&self.data.nodes[..]) as u32,
+            token_offset_bytes: core::mem::size_of_val(&self.data.token_offsets[..]) as u32,
+            token_data_bytes: self.data.token_data.len() as u32,
+            info: self.info.to_bin(),
+            version: TokTrieHeader::VERSION,
+            info_ext: self.info.to_bin_ext(),
+            align: [],
+        }
+    }
+
+    fn serialize_stats(&self, bytes: &mut Vec<u8>) {
+        // older readers see this as part of token data
+        serialize_token_stats(
+            bytes,
+            self.data.max_token_len,
+            &self.data.token_duplicates,
+            &self.stop_tokens,
+            self.explicit_special_tokens().as_deref(),
+        );
+    }
+
+    pub fn root(&self) -> &TrieNode {
+        &self.data.nodes[0]
+    }
+
+    pub fn root_ref(&self) -> NodeRef {
+        NodeRef::at(0)
+    }
+
+    /// Panics if `n` is out of range, which can only happen for a `NodeRef` of another trie.
+    pub fn node(&self, n: NodeRef) -> &TrieNode {
+        &self.data.nodes[n.offset()]
+    }
+
+    pub fn token_id_of(&self, n: NodeRef) -> Option<TokenId> {
+        self.node(n).token_id()
+    }
+
+    pub fn child_at_byte_ref(&self, n: NodeRef, byte: u8) -> Option<NodeRef> {
+        child_offset_at_byte_in(&self.data.nodes, &self.data.jump_tables, n.offset(), byte)
+            .map(NodeRef::at)
+    }
+
+    /// The node at `bytes` below the root, if any; the root for empty `bytes`.
+    /// Resolve a prefix once with this, to start `add_bias_from()` and `compute_bias_from()`
+    /// from it many times.
+    pub fn resolve_prefix(&self, bytes: &[u8]) -> Option<NodeRef> {
+        child_at_bytes_in(&self.data.nodes, &self.data.jump_tables, self.root(), bytes)
+            .map(|n| NodeRef::at(self.node_offset(n)))
+    }
+
+    /// Like `node_children()`.
+    pub fn children_refs(&self, n: NodeRef) -> impl Iterator<Item = NodeRef> + '_ {
+        ChildOffsets::new(&self.data.nodes, n.offset()).map(NodeRef::at)
+    }
+
+    /// Checks that `tokens[i]` are the bytes of token `i` and that the trie finds them;
+    /// `tokens` may be shorter than the vocabulary.
+    pub fn check_against(&self, tokens: &[Vec<u8>]) -> Result<()> {
+        let tokens = tokens.iter().map(|t| t.as_slice()).collect::<Vec<_>>();
+        let diff = TrieDiff::from_tokens(&self.token_bytes_vec(), &tokens);
+        ensure!(
+            diff.ids_only_in_other.is_empty() && diff.changed_ids.is_empty(),
+            "TokTrie: tokens don't match: {}",
+            diff
+        );
+        for (idx, bytes) in tokens.iter().enumerate() {
+            if bytes.is_empty() {
+                continue;
+            }
+            let tid = idx as TokenId;
+            let tid2 = self
+                .child_at_bytes(self.root(), bytes)
+                .and_then(|n| n.token_id());
+            ensure!(
+                tid2 == Some(self.canonical_token(tid)),
+                "TokTrie: token {} not found in the trie",
+                self.token_dbg(tid)
+            );
+        }
+        Ok(())
+    }
+
+    /// Compares the vocabularies and special tokens of the two tries;
+    /// see `TrieDiff::mask_compatible` for whether token sets can be shared.
+    pub fn compatibility(&self, other: &TokTrie) -> TrieDiff {
+        let mut diff = TrieDiff::from_tokens(&self.token_bytes_vec(), &other.token_bytes_vec());
+        let specials = [
+            ("eos", Some(self.info.tok_eos), Some(other.info.tok_eos)),
+            ("bos", self.info.tok_bos, other.info.tok_bos),
+            ("pad", self.info.tok_pad, other.info.tok_pad),
+            ("unk", self.info.tok_unk, other.info.tok_unk),
+            (
+                "end_of_turn",
+                self.info.tok_end_of_turn,
+                other.info.tok_end_of_turn,
+            ),
+        ];
+        diff.special_diffs = specials.into_iter().filter(|d| d.1 != d.2).collect();
+        diff
+    }
+
+    fn token_bytes_vec(&self) -> Vec<&[u8]> {
+        (0..self.vocab_size() as u32)
+            .map(|t| self.token(t))
+            .collect()
+    }
+
+    pub fn child_at_byte<'a>(&'a self, n: &'a TrieNode, byte: u8) -> Option<&'a TrieNode> {
+        self.slices().child_at_byte(n, byte)
+    }
+
+    pub fn all_subtokens(&self, bytes: &[u8]) -> Vec<TokenId> {
+        let mut r = Vec::new();
+        for i in 0..bytes.len() {
+            let mut n = self.root();
+            for j in i..bytes.len() {
+                n = match self.child_at_byte(n, bytes[j]) {
+                    Some(n) => n,
+                    None => break,
+                };
+                if let Some(tok) = n.token_id() {
+                    r.push(tok);
+                }
+            }
+        }
+        r
+    }
+
+    pub fn node_children(&self, n: &TrieNode) -> NodeChildren {
+        NodeChildren::new(&self.data.nodes, n)
+    }
+
+    pub fn child_at_bytes<'a>(&'a self, n: &'a TrieNode, bytes: &[u8]) -> Option<&'a TrieNode> {
+        self.slices().child_at_bytes(n, bytes)
+    }
+
+    pub fn compute_bias(&self, r: &mut impl Recognizer, logits: &mut SimpleVob) {
+        self.compute_bias_ext(r, logits, &[]);
+    }
+
+    /// Like `compute_bias()`, but fails if `r` reports an error; `logits` are then
+    /// what the walk found, which is not to be relied on.
+    pub fn try_compute_bias(&self, r: &mut impl Recognizer, logits: &mut SimpleVob) -> Result<()> {
+        self.compute_bias(r, logits);
+        check_recognizer_error(r)
+    }
+
+    pub fn compute_bias_ext(&self, r: &mut impl Recognizer, logits: &mut SimpleVob, start: &[u8]) {
+        self.compute_bias_ext_eos(r, logits, start, EosMode::Auto);
+    }
+
+    pub fn compute_bias_ext_eos(
+        &self,
+        r: &mut impl Recognizer,
+        logits: &mut SimpleVob,
+        start: &[u8],
+        eos_mode: EosMode,
+    ) {
+        let counters = self
+            .slices()
+            .compute_bias_ext_eos(r, logits, start, eos_mode);
+        self.last_walk.store(&counters);
+    }
+
+    /// Same as `compute_bias_ext()` with `start` being the bytes of `node`
+    /// (see `resolve_prefix()`), without looking them up again.
+    pub fn compute_bias_from(
+        &self,
+        r: &mut impl Recognizer,
+        logits: &mut SimpleVob,
+        node: NodeRef,
+    ) {
+        check_token_set_in(self.vocab_size(), logits);
+        logits.set_all(false);
+        // the root is the only node with no bytes
+        let at_root = node == self.root_ref();
+        allow_end_tokens_in(
+            &self.info,
+            &self.stop_tokens,
+            r,
+            logits,
+            at_root,
+            EosMode::Auto,
+        );
+        self.add_bias_from(r, logits, node);
+        self.apply_duplicates(logits);
+    }
+
+    /// Like `compute_bias()`, but with `include_empty` also allows `empty_tokens()`.
+    /// These are off by default, since sampling one makes no progress, and the model
+    /// may keep doing so.
+    pub fn compute_bias_with_empty_tokens(
+        &self,
+        r: &mut impl Recognizer,
+        logits: &mut SimpleVob,
+        include_empty: bool,
+    ) {
+        self.compute_bias(r, logits);
+        if include_empty {
+            for &tok in &self.data.token_classes.empty {
+                logits.allow_token(tok);
+            }
+        }
+    }
+
+    /// Like `compute_bias()`, but also fills in `stats`, overwriting what was there.
+    pub fn compute_bias_with_stats(
+        &self,
+        r: &mut impl Recognizer,
+        logits: &mut SimpleVob,
+        stats: &mut BiasStats,
+    ) {
+        self.compute_bias_ext_with_stats(r, logits, &[], stats);
+    }
+
+    pub fn compute_bias_ext_with_stats(
+        &self,
+        r: &mut impl Recognizer,
+        logits: &mut SimpleVob,
+        start: &[u8],
+        stats: &mut BiasStats,
+    ) {
+        check_token_set_in(self.vocab_size(), logits);
+        *stats = BiasStats::default();
+        logits.set_all(false);
+        allow_end_tokens_in(
+            &self.info,
+            &self.stop_tokens,
+            r,
+            logits,
+            start.is_empty(),
+            EosMode::Auto,
+        );
+        let counters = add_bias_in(
+            &self.data.nodes,
+            &self.data.jump_tables,
+            &self.data.num_parents_overflow,
+            self.vocab_size() as u32,
+            r,
+            logits,
+            start,
+            None,
+            Some(stats),
+        );
+        self.last_walk.store(&counters);
+        self.apply_duplicates(logits);
+        stats.tokens_allowed = logits.num_set();
+    }
+
+    /// Like `compute_bias()`, but only allows tokens of at most `max_bytes` bytes.
+    /// When `max_bytes` is 0, EOS is always allowed.
+    pub fn compute_bias_with_limit(
+        &self,
+        r: &mut impl Recognizer,
+        logits: &mut SimpleVob,
+        max_bytes: usize,
+    ) {
+        self.compute_bias_ext_with_limit(r, logits, &[], max_bytes);
+    }
+
+    /// Like `compute_bias_ext()`, but only allows tokens of at most `max_bytes` bytes
+    /// (including `start`).
+    pub fn compute_bias_ext_with_limit(
+        &self,
+        r: &mut impl Recognizer,
+        logits: &mut SimpleVob,
+        start: &[u8],
+        max_bytes: usize,
+    ) {
+        check_token_set_in(self.vocab_size(), logits);
+        logits.set_all(false);
+        allow_end_tokens_in(
+            &self.info,
+            &self.stop_tokens,
+            r,
+            logits,
+            start.is_empty(),
+            EosMode::Auto,
+        );
+        if start.is_empty() && max_bytes == 0 {
+            if self.is_valid_token(self.info.tok_eos) {
+                logits.allow_token(self.info.tok_eos);
+            }
+            for &tok in &self.stop_tokens {
+                logits.allow_token(tok);
+            }
+        }
+        let counters = add_bias_in(
+            &self.data.nodes,
+            &self.data.jump_tables,
+            &self.data.num_parents_overflow,
+            self.vocab_size() as u32,
+            r,
+            logits,
+            start,
+            Some((max_bytes, &self.data.depth_bounds)),
+            None,
+        );
+        self.last_walk.store(&counters);
+        self.apply_duplicates(logits);
+    }
+
+    /// Like `compute_bias()`, but stops walking the trie once `budget` is exhausted.
+    /// On truncation, `logits` only has the tokens found so far (a subset of what
+    /// `compute_bias()` would allow); the recognizer is unwound as usual either way.
+    #[cfg(feature = "std")]
+    pub fn compute_bias_with_budget(
+        &self,
+        r: &mut impl Recognizer,
+        logits: &mut SimpleVob,
+        budget: WalkBudget,
+    ) -> WalkOutcome {
+        check_token_set_in(self.vocab_size(), logits);
+        logits.set_all(false);
+        allow_end_tokens_in(
+            &self.info,
+            &self.stop_tokens,
+            r,
+            logits,
+            true,
+            EosMode::Auto,
+        );
+        r.trie_started();
+        let (next_pop, counters, outcome) = add_bias_budget_in(
+            &self.data.nodes,
+            &self.data.num_parents_overflow,
+            self.vocab_size() as u32,
+            r,
+            logits,
+            1..self.data.nodes.len(),
+            &budget,
+        );
+        r.pop_bytes(next_pop);
+        r.trie_finished();
+        // revert the fake token
+        logits.disallow_token(self.vocab_size() as u32);
+        self.last_walk.store(&counters);
+        self.apply_duplicates(logits);
+        outcome
+    }
+
+    /// Which subtrees have tokens from `candidates`, computed in one pass over the nodes;
+    /// a token with the bytes of a candidate, but which is not one, still marks its node.
+    /// Panics if `candidates` are not of `vocab_size()`.
+    pub fn index_candidates(&self, candidates: &SimpleVob) -> CandidateIndex {
+        assert!(
+            candidates.len() == self.vocab_size(),
+            "TokTrie: candidate set of size {} used with vocab size {}",
+            candidates.len(),
+            self.vocab_size()
+        );
+        let nodes = &self.data.nodes;
+        let mut subtree_has_candidate = SimpleVob::alloc(nodes.len());
+        // the nodes of a subtree follow its root, so going backwards,
+        // the nearest candidate seen so far is in the subtree if it's within its size
+        let mut nearest = usize::MAX;
+        for p in (0..nodes.len()).rev() {
+            if let Some(t) = nodes[p].token_id() {
+                if candidates.is_allowed(t)
+                    || self
+                        .duplicates_of(t)
+                        .iter()
+                        .any(|&d| candidates.is_allowed(d))
+                {
+                    nearest = p;
+                }
+            }
+            if nearest < p + nodes[p].subtree_size() {
+                subtree_has_candidate.set(p, true);
+            }
+        }
+        CandidateIndex {
+            candidates: candidates.clone(),
+            subtree_has_candidate,
+        }
+    }
+
+    /// Same as `compute_bias()` followed by and-ing `logits` with `candidates`, but
+    /// subtrees without candidates are not walked. This builds a `CandidateIndex` every
+    /// time, which costs a pass over all the nodes; when `candidates` don't change
+    /// between calls, use `index_candidates()` once and `compute_bias_with_index()`.
+    pub fn compute_bias_within(
+        &self,
+        r: &mut impl Recognizer,
+        candidates: &SimpleVob,
+        logits: &mut SimpleVob,
+    ) {
+        let index = self.index_candidates(candidates);
+        self.compute_bias_with_index(r, &index, logits);
+    }
+
+    /// Same as `compute_bias_within()`, with the candidates indexed by `index_candidates()`
+    /// of this trie.
+    pub fn compute_bias_with_index(
+        &self,
+        r: &mut impl Recognizer,
+        index: &CandidateIndex,
+        logits: &mut SimpleVob,
+    ) {
+        assert!(
+            index.subtree_has_candidate.len() == self.data.nodes.len()
+                && index.candidates.len() == self.vocab_size(),
+            "TokTrie: CandidateIndex is from another trie"
+        );
+        check_token_set_in(self.vocab_size(), logits);
+        logits.set_all(false);
+        allow_end_tokens_in(
+            &self.info,
+            &self.stop_tokens,
+            r,
+            logits,
+            true,
+            EosMode::Auto,
+        );
+        r.trie_started();
+        let (next_pop, counters) = add_bias_within_in(
+            &self.data.nodes,
+            &self.data.num_parents_overflow,
+            self.vocab_size() as u32,
+            r,
+            logits,
+            &index.subtree_has_candidate,
+        );
+        r.pop_bytes(next_pop);
+        r.trie_finished();
+        // revert the fake token
+        logits.disallow_token(self.vocab_size() as u32);
+        self.last_walk.store(&counters);
+        self.apply_duplicates(logits);
+        logits.and(&index.candidates);
+    }
+
+    /// Same as `compute_bias()`, but the root's subtrees are split between threads,
+    /// each with its own clone of the recognizer.
+    #[cfg(feature = "rayon")]
+    pub fn compute_bias_parallel<R: Recognizer + Clone + Send>(
+        &self,
+        r: &R,
+        logits: &mut SimpleVob,
+    ) {
+        use rayon::prelude::*;
+
+        check_token_set_in(self.vocab_size(), logits);
+        logits.set_all(false);
+        allow_end_tokens_in(
+            &self.info,
+            &self.stop_tokens,
+            &mut r.clone(),
+            logits,
+            true,
+            EosMode::Auto,
+        );
+
+        // group the root's children into ranges of roughly equal number of nodes
+        let num_chunks = rayon::current_num_threads() * 4;
+        let chunk_size = core::cmp::max(1, self.data.nodes.len() / num_chunks);
+        let mut chunks = Vec::new();
+        let mut start = 1;
+        for ch in ChildOffsets::new(&self.data.nodes, 0) {
+            let end = ch + self.data.nodes[ch].subtree_size();
+            if end - start >= chunk_size {
+                chunks.push(start..end);
+                start = end;
+            }
+        }
+        if start < self.data.nodes.len() {
+            chunks.push(start..self.data.nodes.len());
+        }
+
+        let vocab_size = self.vocab_size() as u32;
+        let merged = chunks
+            .into_iter()
+            .map(|range| (range, r.clone()))
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .map(|(range, mut r)| {
+                let mut toks = self.alloc_token_set();
+                r.trie_started();
+                let (next_pop, counters) = add_bias_inner_in::<false, false>(
+                    &self.data.nodes,
+                    &self.data.num_parents_overflow,
+                    &[],
+                    vocab_size,
+                    &mut r,
+                    &mut toks,
+                    range,
+                    0,
+                    &mut BiasStats::default(),
+                );
+                r.pop_bytes(next_pop);
+                r.trie_finished();
+                (toks, counters)
+            })
+            .reduce_with(|(mut a, mut ca), (b, cb)| {
+                a.or(&b);
+                ca.add(&cb);
+                (a, ca)
+            });
+        if let Some((toks, counters)) = merged {
+            logits.or(&toks);
+            self.last_walk.store(&counters);
+        }
+        // revert the fake token
+        logits.disallow_token(vocab_size);
+        self.apply_duplicates(logits);
+    }
+
+    pub fn apply_duplicates(&self, logits: &mut SimpleVob) {
+        self.slices().apply_duplicates(logits)
+    }
+
+    /// The token that the trie has for the bytes of `t`; this is `t` itself,
+    /// unless `t` is a duplicate of another token.
+    pub fn canonical_token(&self, t: TokenId) -> TokenId {
+        self.data.token_canonical.get(&t).copied().unwrap_or(t)
+    }
+
+    /// Tokens with the same bytes as `t`, other than `canonical_token(t)`.
+    pub fn duplicates_of(&self, t: TokenId) -> &[TokenId] {
+        self.data
+            .token_duplicates
+            .get(&self.canonical_token(t))
+            .map_or(&[], |v| v.as_slice())
+    }
+
+    /// Replace duplicate tokens with their canonical versions; decoding is unaffected.
+    pub fn canonicalize_tokens(&self, ts: &[TokenId]) -> Vec<TokenId> {
+        ts.iter().map(|&t| self.canonical_token(t)).collect()
+    }
+
+    /// Tokens `t` that are not `canonical_token(t)`, and for which `greedy_tokenize(token(t))`
+    /// isn't `[t]`; this means all the duplicates, as the trie only has the canonical token
+    /// for their bytes. Empty tokens are not included; see `empty_tokens()`.
+    /// Part of `analysis()`.
+    #[cfg(feature = "std")]
+    pub fn greedy_unreachable_tokens(&self) -> SimpleVob {
+        self.analysis().greedy_unreachable.clone()
+    }
+
+    /// Tokens that no other token extends: their node is a leaf (`subtree_size() == 1`).
+    /// Duplicates of such tokens are included. Part of `analysis()`.
+    #[cfg(feature = "std")]
+    pub fn tokens_never_extending(&self) -> SimpleVob {
+        self.analysis().never_extending.clone()
+    }
+
+    /// Computed on first use, and shared by clones of the trie (as from `with_info()`).
+    #[cfg(feature = "std")]
+    pub fn analysis(&self) -> &VocabAnalysis {
+        self.data.analysis.get_or_init(|| self.compute_analysis())
+    }
+
+    #[cfg(feature = "std")]
+    fn compute_analysis(&self) -> VocabAnalysis {
+        let mut greedy_unreachable = self.alloc_token_set();
+        for &t in self.data.token_canonical.keys() {
+            let bytes = self.token(t);
+            // greedy_tokenize(bytes) == [t] iff the longest match of bytes is all of t
+            let (tok, len) = self.prefix_token_id(bytes);
+            if bytes.is_empty() || tok != t || len != bytes.len() {
+                greedy_unreachable.allow_token(t);
+            }
+        }
+
+        let mut never_extending = self.alloc_token_set();
+        for n in &self.data.nodes {
+            if n.subtree_size() == 1 {
+                if let Some(tok) = n.token_id() {
+                    never_extending.allow_token(tok);
+                    for &dup in self.duplicates_of(tok) {
+                        never_extending.allow_token(dup);
+                    }
+                }
+            }
+        }
+
+        let dups = &self.data.token_duplicates;
+        VocabAnalysis {
+            greedy_unreachable,
+            never_extending,
+            num_duplicate_groups: dups.len(),
+            num_duplicates: dups.values().map(|v| v.len()).sum(),
+            max_duplicate_group_size: dups.values().map(|v| v.len() + 1).max().unwrap_or(0),
+        }
+    }
+
+    /// Push the bytes of each token into `r`, collapsing it after each one, as
+    /// `append_token()` does. Fails without touching `r` if any token is out of range.
+    /// When a byte is rejected, the error is an `AppendTokenError`, saying where;
+    /// the tokens before it stay in `r`, and the bytes of the rejected token are popped
+    /// without collapsing, as in `try_append_token()`.
+    pub fn append_tokens(&self, r: &mut impl Recognizer, ts: &[TokenId]) -> Result<()> {
+        for &t in ts {
+            self.check_token(t)?;
+        }
+        let mut byte_offset = 0;
+        for (token_index, &t) in ts.iter().enumerate() {
+            let bytes = self.token(t);
+            if bytes.is_empty() {
+                continue;
+            }
+            let num = r.try_push_bytes(bytes);
+            if num < bytes.len() {
+                r.pop_bytes(num);
+                return Err(AppendTokenError {
+                    token_index,
+                    token: t,
+                    byte_offset_in_token: num,
+                    byte: bytes[num],
+                    byte_offset: byte_offset + num,
+                    recognizer_error: r.get_error(),
+                }
+                .into());
+            }
+            r.collapse();
+            byte_offset += bytes.len();
+        }
+        Ok(())
+    }
+
+    /// Push the bytes of `t` into `r` and collapse it; an empty token leaves `r` untouched.
+    /// Fails if `t` is out of range; see `is_valid_token()`.
+    pub fn append_token(&self, r: &mut impl Recognizer, t: TokenId) -> Result<()> {
+        // println!("append_token: {}", self.token_dbg(t));
+        self.check_token(t)?;
+        self.append_token_bytes(r, t)
+            .map_err(|num| byte_not_allowed(r, self.token(t)[num]))
+    }
+
+    // append_token() for a valid token; on failure, the offset of the rejected byte in t
+    fn append_token_bytes(
+        &self,
+        r: &mut impl Recognizer,
+        t: TokenId,
+    ) -> core::result::Result<(), usize> {
+        let bytes = self.token(t);
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        let num = r.try_push_bytes(bytes);
+        r.collapse();
+        if num < bytes.len() {
+            return Err(num);
+        }
+        Ok(())
+    }
+
+    /// Like `append_token()`, but if `t` is rejected, the bytes pushed so far are popped
+    /// without collapsing, leaving `r` exactly as it was before the call.
+    /// `r` is collapsed only once all the bytes of `t` were accepted (and not at all
+    /// for an empty token).
+    pub fn try_append_token(&self, r: &mut impl Recognizer, t: TokenId) -> Result<()> {
+        self.check_token(t)?;
+        let bytes = self.token(t);
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        let num = r.try_push_bytes(bytes);
+        if num < bytes.len() {
+            r.pop_bytes(num);
+            return Err(byte_not_allowed(r, bytes[num]));
+        }
+        r.collapse();
+        Ok(())
+    }
+
+    /// Apply tokens with `try_append_token()` until one is rejected, and return
+    /// the number applied. Each applied token is collapsed into `r`, so it can't be
+    /// rolled back; the rejected one leaves no trace.
+    /// Fails without touching `r` if any token is out of range.
+    pub fn append_tokens_transactional(
+        &self,
+        r: &mut impl Recognizer,
+        ts: &[TokenId],
+    ) -> Result<usize> {
+        for &t in ts {
+            self.check_token(t)?;
+        }
+        for (idx, &t) in ts.iter().enumerate() {
+            if self.try_append_token(r, t).is_err() {
+                return Ok(idx);
+            }
+        }
+        Ok(ts.len())
+    }
+
+    /// False for out-of-range tokens.
+    pub fn token_allowed(&self, r: &mut impl Recognizer, t: TokenId) -> bool {
+        if !self.is_valid_token(t) {
+            return false;
+        }
+        let bytes = self.token(t);
+        r.trie_started();
+        // the last byte is only checked, with byte_allowed()
+        let res = match bytes.split_last() {
+            Some((&last, init)) => {
+                let num = r.try_push_bytes(init);
+                let res = num == init.len() && r.byte_allowed(last);
+                r.pop_bytes(num);
+                res
+            }
+            None => true,
+        };
+        r.trie_finished();
+        res
+    }
+
+    /// Like `token_allowed()`, but fails if `r` reports an error.
+    pub fn try_token_allowed(&self, r: &mut impl Recognizer, t: TokenId) -> Result<bool> {
+        let res = self.token_allowed(r, t);
+        check_recognizer_error(r)?;
+        Ok(res)
+    }
+
+    /// Push `bytes` into `r` and return how many were accepted.
+    /// If all are accepted, they stay pushed and `r` is collapsed, as in `append_token()`;
+    /// otherwise the accepted prefix is popped again, leaving `r` unchanged.
+    pub fn consume_bytes(&self, r: &mut impl Recognizer, bytes: &[u8]) -> usize {
+        let num = r.try_push_bytes(bytes);
+        if num == bytes.len() {
+            r.collapse();
+        } else {
+            r.pop_bytes(num);
+        }
+        num
+    }
+
+    /// Return the length of the longest prefix of `bytes` accepted by `r`; `r` is left unchanged.
+    pub fn check_bytes(&self, r: &mut impl Recognizer, bytes: &[u8]) -> usize {
+        r.trie_started();
+        let num = r.try_push_bytes(bytes);
+        r.pop_bytes(num);
+        r.trie_finished();
+        num
+    }
+
+    #[cfg(feature = "std")]
+    /// Tokenize the bytes of `prefix_tokens` followed by `text` with `env`,
+    /// and report how many of the trailing prefix tokens changed, and what replaces them.
+    /// Unlike `chop_tokens()`, this uses the real tokenizer, not a recognizer.
+    pub fn retokenize_check(
+        &self,
+        env: &dyn TokenizerEnv,
+        prefix_tokens: &[TokenId],
+        text: &[u8],
+    ) -> RetokenizeResult {
+        retokenize_with(self, prefix_tokens, text, |s| env.tokenize_bytes_prefix(s))
+    }
+
+    /// Return how many tokens and bytes need to chopped off tokens,
+    /// so that we do not limit all possible future tokenizations matching the recognizer.
+    /// The chopped bytes can then be passed as `start` to `compute_bias_ext_eos()`,
+    /// with `EosMode::AfterPrefix`.
+    pub fn chop_tokens(&self, r: &mut impl Recognizer, tokens: &[TokenId]) -> (usize, usize) {
+        self.chop_tokens_ext(r, tokens, true, &mut Vec::new())
+    }
+
+    /// Buffers for the `_with_scratch()` methods; one per thread (or per sequence).
+    pub fn new_scratch(&self) -> TrieScratch {
+        TrieScratch {
+            suffix: Vec::with_capacity(self.max_token_len()),
+            logits: self.alloc_token_set(),
+            forced: Vec::with_capacity(MAX_FORCED_BYTES),
+        }
+    }
+
+    /// Like `chop_tokens()`, but doesn't allocate.
+    pub fn chop_tokens_with_scratch(
+        &self,
+        r: &mut impl Recognizer,
+        tokens: &[TokenId],
+        scratch: &mut TrieScratch,
+    ) -> (usize, usize) {
+        self.chop_tokens_ext(r, tokens, true, &mut scratch.suffix)
+    }
+
+    /// Like `heal_tokens()`, but doesn't allocate: returns `keep_tokens` and `forced_prefix`
+    /// (in `scratch`); `chopped_tokens` are `&tokens[keep_tokens..]`.
+    pub fn heal_tokens_with_scratch<'a>(
+        &self,
+        r: &mut impl Recognizer,
+        tokens: &[TokenId],
+        scratch: &'a mut TrieScratch,
+    ) -> (usize, &'a [u8]) {
+        let (num_tokens, num_bytes) = self.chop_tokens_ext(r, tokens, false, &mut scratch.suffix);
+        // the suffix ends with the bytes of the chopped tokens
+        let suffix = &scratch.suffix[scratch.suffix.len() - num_bytes..];
+        (tokens.len() - num_tokens, suffix)
+    }
+
+    /// Like `compute_bias()`, but fills the token set of `scratch`, and returns it.
+    pub fn compute_bias_with_scratch<'a>(
+        &self,
+        r: &mut impl Recognizer,
+        scratch: &'a mut TrieScratch,
+    ) -> &'a SimpleVob {
+        self.compute_bias(r, &mut scratch.logits);
+        &scratch.logits
+    }
+
+    /// Like `forced_bytes()`, but the bytes are kept in `scratch`.
+    pub fn forced_bytes_with_scratch<'a>(
+        &self,
+        r: &mut impl Recognizer,
+        scratch: &'a mut TrieScratch,
+    ) -> &'a [u8] {
+        self.forced_bytes_into(r, &mut scratch.forced);
+        &scratch.forced
+    }
+
+    /// Token healing: chop off the tokens that `chop_tokens()` says to, and return
+    /// the bytes to force with `compute_bias_ext()` instead.
+    /// Special tokens (and anything before them) are never chopped; see `heal_tokens_ext()`.
+    pub fn heal_tokens(&self, r: &mut impl Recognizer, tokens: &[TokenId]) -> HealResult {
+        self.heal_tokens_ext(r, tokens, false)
+    }
+
+    /// Like `heal_tokens()`, but with `allow_special`, special tokens can be chopped too,
+    /// as for any token when the recognizer allows a longer token starting with their bytes
+    /// (`SPECIAL_TOKEN_PREFIX_BYTE` included), as `chop_tokens()` does.
+    /// This is rarely useful: no token continues past a special token, so
+    /// `compute_bias_ext()` with such a prefix allows little beyond its prefixes.
+    pub fn heal_tokens_ext(
+        &self,
+        r: &mut impl Recognizer,
+        tokens: &[TokenId],
+        allow_special: bool,
+    ) -> HealResult {
+        let (num_tokens, _) = self.chop_tokens_ext(r, tokens, allow_special, &mut Vec::new());
+        let keep_tokens = tokens.len() - num_tokens;
+        let chopped_tokens = tokens[keep_tokens..].to_vec();
+        HealResult {
+            keep_tokens,
+            forced_prefix: self.decode_raw(&chopped_tokens),
+            chopped_tokens,
+        }
+    }
+
+    fn chop_tokens_ext(
+        &self,
+        r: &mut impl Recognizer,
+        tokens: &[TokenId],
+        allow_special: bool,
+        suff: &mut Vec<u8>,
+    ) -> (usize, usize) {
+        // only suffixes up to max_token_len can have extensions
+        let mut num_bytes = 0;
+        let mut first = tokens.len();
+        while first > 0 {
+            if !allow_special && self.is_special_token(tokens[first - 1]) {
+                break;
+            }
+            let len = self.token(tokens[first - 1]).len();
+            if num_bytes + len > self.max_token_len() {
+                break;
+            }
+            num_bytes += len;
+            first -= 1;
+        }
+        let tokens = &tokens[first..];
+
+        suff.clear();
+        suff.reserve(num_bytes);
+        for t in tokens {
+            suff.extend_from_slice(self.token(*t));
+        }
+
+        // The suffixes start at different nodes below the root, so there is no walk to share
+        // between them; but the longest one with extensions is the answer, so try the longest
+        // first, and stop there. Most long suffixes are not a path of the trie, which
+        // has_valid_extensions() finds without asking the recognizer.
+        let mut start = 0;
+        for (idx, t) in tokens.iter().enumerate() {
+            if self.has_valid_extensions(r, &suff[start..]) {
+                return (tokens.len() - idx, suff.len() - start);
+            }
+            start += self.token(*t).len();
+        }
+        (0, 0)
+    }
+
+    /// Bytes that the recognizer forces: at each step, exactly one of the 256 bytes is allowed,
+    /// and EOS is not. Stops after 4096 bytes.
+    /// The recognizer is left unchanged.
+    pub fn forced_bytes(&self, r: &mut impl Recognizer) -> Vec<u8> {
+        let mut forced = Vec::new();
+        self.forced_bytes_into(r, &mut forced);
+        forced
+    }
+
+    fn forced_bytes_into(&self, r: &mut impl Recognizer, forced: &mut Vec<u8>) {
+        forced.clear();
+        r.trie_started();
+        while forced.len() < MAX_FORCED_BYTES && !r.special_allowed(SpecialToken::EndOfSentence) {
+            let mut allowed = None;
+            for b in 0..=255u8 {
+                if r.byte_allowed(b) {
+                    if allowed.is_some() {
+                        allowed = None;
+                        break;
+                    }
+                    allowed = Some(b);
+                }
+            }
+            match allowed {
+                Some(b) => {
+                    let ok = r.try_push_byte(b);
+                    assert!(ok);
+                    forced.push(b);
+                }
+                None => break,
+            }
+        }
+        r.pop_bytes(forced.len());
+        r.trie_finished();
+    }
+
+    /// `forced_bytes()` greedily split into tokens, as far as the trie has tokens for them.
+    pub fn forced_tokens(&self, r: &mut impl Recognizer) -> Vec<TokenId> {
+        let bytes = self.forced_bytes(r);
+        let mut tokens = Vec::new();
+        let mut idx = 0;
+        while idx < bytes.len() {
+            let (tok, len) = self.prefix_token_id(&bytes[idx..]);
+            if len == 0 {
+                break;
+            }
+            tokens.push(tok);
+            idx += len;
+        }
+        tokens
+    }
+
+    /// Which single bytes `r` accepts in its current state, and whether it allows EOS.
+    /// This doesn't look at the vocabulary; `r` is left unchanged.
+    pub fn compute_byte_bias(&self, r: &mut impl Recognizer) -> ByteBias {
+        let mut res = ByteBias::new();
+        r.trie_started();
+        for b in 0..=255u8 {
+            res.bytes[b as usize] = r.byte_allowed(b);
+        }
+        res.eos_allowed = r.special_allowed(SpecialToken::EndOfSentence);
+        r.trie_finished();
+        res
+    }
+
+    /// Like `compute_byte_bias()`, but only checks the bytes that start some token.
+    /// If none are allowed, `compute_bias()` can only allow EOS.
+    pub fn allowed_first_bytes_of_tokens(&self, r: &mut impl Recognizer) -> ByteBias {
+        let mut res = ByteBias::new();
+        r.trie_started();
+        for n in self.node_children(self.root()) {
+            let b = n.byte();
+            res.bytes[b as usize] = r.byte_allowed(b);
+        }
+        res.eos_allowed = r.special_allowed(SpecialToken::EndOfSentence);
+        r.trie_finished();
+        res
+    }
+
+    /// Number of children of the node at `start` (the root, if empty) whose byte
+    /// the recognizer allows, ignoring the empty leaves that don't lead to tokens.
+    /// Like in `compute_bias_ext()`, the recognizer has to be past `start` already.
+    pub fn num_allowed_children(&self, r: &mut impl Recognizer, start: &[u8]) -> usize {
+        let n = match self.child_at_bytes(self.root(), start) {
+            Some(n) => n,
+            None => return 0,
+        };
+        r.trie_started();
+        let res = self
+            .node_children(n)
+            .filter(|c| {
+                (c.token_id().is_some() || c.subtree_size() > 1) && r.byte_allowed(c.byte())
+            })
+            .count();
+        r.trie_finished();
+        res
+    }
+
+    /// Check if add_bias() would have returned any tokens.
+    pub fn has_valid_extensions(&self, r: &mut impl Recognizer, start: &[u8]) -> bool {
+        self.first_valid_extension(r, start).is_some()
+    }
+
+    /// Like `has_valid_extensions()`, but fails if `r` reports an error.
+    pub fn try_has_valid_extensions(&self, r: &mut impl Recognizer, start: &[u8]) -> Result<bool> {
+        let res = self.has_valid_extensions(r, start);
+        check_recognizer_error(r)?;
+        Ok(res)
+    }
+
+    /// Like `has_valid_extensions()`, but returns the first token (in trie order)
+    /// that add_bias() would have allowed.
+    /// All bytes pushed are popped before `trie_finished()`, whatever `start` is.
+    #[inline(never)]
+    pub fn first_valid_extension(&self, r: &mut impl Recognizer, start: &[u8]) -> Option<TokenId> {
+        let n = self.child_at_bytes(self.root(), start)?;
+        r.trie_started();
+        let off = self.node_offset(n);
+        let mut p = off + 1;
+        let endp = off + n.subtree_size();
+        let mut found = None;
+        let mut next_pop = 0;
+        // bytes currently pushed
+        let mut depth = 0;
+        while p < endp {
+            r.pop_bytes(next_pop);
+            depth -= next_pop;
+            let n = &self.data.nodes[p];
+            let b = n.byte();
+            let pushed = match n.token_id() {
+                // the walk ends at the first token, so its byte is only checked
+                Some(tok) => {
+                    if r.byte_allowed(b) {
+                        found = Some(tok);
+                        break;
+                    }
+                    false
+                }
+                None => r.try_push_byte(b),
+            };
+            if pushed {
+                depth += 1;
+                next_pop = if n.subtree_size() == 1 {
+                    num_parents_in(&self.data.nodes, &self.data.num_parents_overflow, p)
+                } else {
+                    0
+                };
+                p += 1;
+            } else {
+                next_pop = num_parents_in(&self.data.nodes, &self.data.num_parents_overflow, p) - 1;
+                p += n.subtree_size();
+            }
+        }
+        // next_pop of the last node may reach above `start`
+        r.pop_bytes(depth);
+        r.trie_finished();
+        found
+    }
+
+    pub fn add_bias(&self, r: &mut impl Recognizer, toks: &mut SimpleVob, start: &[u8]) {
+        let counters = self.slices().add_bias(r, toks, start);
+        self.last_walk.store(&counters);
+    }
+
+    /// Same as `add_bias()` with `start` being the bytes of `node` (see `resolve_prefix()`):
+    /// allows the tokens on the path to `node`, and the ones below it the recognizer accepts,
+    /// the recognizer being past the bytes of `node` already.
+    pub fn add_bias_from(&self, r: &mut impl Recognizer, toks: &mut SimpleVob, node: NodeRef) {
+        check_token_set_in(self.vocab_size(), toks);
+        let nodes = &self.data.nodes;
+        let mut start_len = 0;
+        for p in PathOffsets::new(nodes, node.offset()) {
+            start_len += 1;
+            if let Some(tok) = nodes[p].token_id() {
+                toks.allow_token(tok);
+            }
+        }
+        let counters = add_bias_from_in(
+            nodes,
+            &self.data.num_parents_overflow,
+            self.vocab_size() as u32,
+            r,
+            toks,
+            node.offset(),
+            start_len,
+            None,
+            None,
+        );
+        self.last_walk.store(&counters);
+    }
+
+    /// Counters of the last `add_bias()` walk, including the ones done by
+    /// `compute_bias()` and its variants. With concurrent walks, it's one of them.
+    #[cfg(feature = "metrics")]
+    pub fn last_walk_counters(&self) -> TrieCounters {
+        self.last_walk.load()
+    }
+
+    /// Depth-first walk over the descendants of `from` (excluding `from` itself).
+    pub fn walk(&self, from: &TrieNode) -> TrieWalker<'_> {
+        TrieWalker::new(&self.data.nodes, &self.data.num_parents_overflow, from)
+    }
+
+    /// Calls `f` for every token whose bytes start with `prefix`, in trie order,
+    /// including duplicates of such tokens.
+    /// An empty prefix enumerates all non-empty tokens.
+    pub fn for_each_token_with_prefix(&self, prefix: &[u8], mut f: impl FnMut(TokenId, &[u8])) {
+        let n = match self.child_at_bytes(self.root(), prefix) {
+            Some(n) => n,
+            None => return,
+        };
+        let mut report = |tok: TokenId| {
+            f(tok, self.token(tok));
+            if let Some(dups) = self.data.token_duplicates.get(&tok) {
+                for &dup in dups {
+                    f(dup, self.token(dup));
+                }
+            }
+        };
+        if let Some(tok) = n.token_id() {
+            report(tok);
+        }
+        for ev in self.walk(n) {
+            if let WalkEvent::Push(_, Some(tok)) = ev {
+                report(tok);
+            }
+        }
+    }
+
+    pub fn tokens_with_prefix(&self, prefix: &[u8]) -> Vec<TokenId> {
+        let mut res = Vec::new();
+        self.for_each_token_with_prefix(prefix, |tok, _| res.push(tok));
+        res
+    }
+
+    /// Tokens matching a prefix of `bytes` with at most `max_mismatches` substituted bytes,
+    /// with the number of substitutions; inserted or deleted bytes are not considered.
+    /// Duplicates of matching tokens are included.
+    ///
+    /// Sorted longest token first, then by mismatches and id, so the first one is
+    /// the longest fuzzy match, much like `prefix_token_id()` gives the longest exact one.
+    /// Tokens matching all of `bytes` are the ones with length `bytes.len()`.
+    /// Special tokens only match if `bytes` starts with `SPECIAL_TOKEN_PREFIX_BYTE`.
+    ///
+    /// Stops at 1000 results, or after visiting 100000 nodes; see `fuzzy_token_matches_ext()`.
+    pub fn fuzzy_token_matches(
+        &self,
+        bytes: &[u8],
+        max_mismatches: usize,
+    ) -> Vec<(TokenId, usize)> {
+        self.fuzzy_token_matches_ext(bytes, max_mismatches, MAX_FUZZY_RESULTS, MAX_FUZZY_NODES)
+            .0
+    }
+
+    /// Like `fuzzy_token_matches()`, but with the given limits on the number of results
+    /// and of trie nodes visited. Also returns true if a limit was hit, in which case
+    /// some matches are missing (not necessarily the worst ones).
+    pub fn fuzzy_token_matches_ext(
+        &self,
+        bytes: &[u8],
+        max_mismatches: usize,
+        max_results: usize,
+        max_nodes: usize,
+    ) -> (Vec<(TokenId, usize)>, bool) {
+        let mut res = Vec::new();
+        let mut truncated = false;
+        let mut num_nodes = 0;
+        // node, its depth, and mismatches so far
+        let mut stack = vec![(self.root(), 0, 0)];
+        'search: while let Some((n, depth, mismatches)) = stack.pop() {
+            if depth == bytes.len() {
+                continue;
+            }
+            for c in self.node_children(n) {
+                if num_nodes >= max_nodes {
+                    truncated = true;
+                    break 'search;
+                }
+                num_nodes += 1;
+                let b = c.byte();
+                if depth == 0
+                    && (b == TokTrie::SPECIAL_TOKEN_PREFIX_BYTE)
+                        != (bytes[0] == TokTrie::SPECIAL_TOKEN_PREFIX_BYTE)
+                {
+                    continue;
+                }
+                let mismatches = mismatches + (b != bytes[depth]) as usize;
+                if mismatches > max_mismatches {
+                    continue;
+                }
+                if let Some(tok) = c.token_id() {
+                    let dups = self.data.token_duplicates.get(&tok);
+                    for &t in [tok].iter().chain(dups.into_iter().flatten()) {
+                        if res.len() >= max_results {
+                            truncated = true;
+                            break 'search;
+                        }
+                        res.push((t, mismatches));
+                    }
+                }
+                if c.subtree_size() > 1 {
+                    stack.push((c, depth + 1, mismatches));
+                }
+            }
+        }
+        res.sort_by_key(|&(t, mismatches)| {
+            (core::cmp::Reverse(self.token(t).len()), mismatches, t)
+        });
+        (res, truncated)
+    }
+
+    /// Build a standalone trie of the tokens strictly extending `prefix`, with `prefix`
+    /// removed from their bytes. Also returns the old id of every new token.
+    /// Returns `None` if no token extends `prefix`.
+    ///
+    /// Token 0 is EOS, and token 1 is end-of-turn (if set); they keep their bytes
+    /// (minus `prefix`) if they extend `prefix`, and are empty otherwise.
+    /// The remaining tokens follow in the order of old ids; BOS, PAD and UNK
+    /// are only kept if they extend `prefix`. Tokens that are a prefix of `prefix`
+    /// (including `prefix` itself) are dropped.
+    ///
+    /// Mapped back through the id table, `compute_bias()` on the subtrie gives
+    /// the result of `compute_bias_ext_eos(r, logits, prefix, EosMode::AfterPrefix)` here,
+    /// except for the tokens that are a prefix of `prefix`.
+    pub fn subtrie(&self, prefix: &[u8]) -> Option<(TokTrie, Vec<TokenId>)> {
+        let mut old_ids = Vec::new();
+        self.for_each_token_with_prefix(prefix, |tok, bytes| {
+            if bytes.len() > prefix.len() {
+                old_ids.push(tok);
+            }
+        });
+        if old_ids.is_empty() {
+            return None;
+        }
+        old_ids.sort_unstable();
+
+        let mut mapping = vec![self.info.tok_eos];
+        if let Some(tok) = self.info.tok_end_of_turn {
+            mapping.push(tok);
+        }
+        let num_end = mapping.len();
+        old_ids.retain(|t| !mapping.contains(t));
+        mapping.extend_from_slice(&old_ids);
+
+        let words = mapping
+            .iter()
+            .map(|&t| {
+                let bytes = self.token(t);
+                if bytes.len() > prefix.len() && bytes.starts_with(prefix) {
+                    bytes[prefix.len()..].to_vec()
+                } else {
+                    Vec::new()
+                }
+            })
+            .collect::<Vec<_>>();
+        let new_id = |tok: Option<TokenId>| {
+            let tok = tok?;
+            mapping[num_end..]
+                .binary_search(&tok)
+                .ok()
+                .map(|idx| (num_end + idx) as TokenId)
+        };
+        let info = TokRxInfo {
+            vocab_size: mapping.len() as u32,
+            tok_eos: 0,
+            tok_bos: new_id(self.info.tok_bos),
+            tok_pad: new_id(self.info.tok_pad),
+            tok_unk: new_id(self.info.tok_unk),
+            tok_end_of_turn: self.info.tok_end_of_turn.map(|_| 1),
+        };
+        // the subtrie is smaller than self, so this can't fail
+        let trie = TokTrie::try_from_words(&info, &words, false, None).unwrap();
+        Some((trie, mapping))
+    }
+
+    /// A trie with only the tokens in `keep`; token ids and `info()` stay the same,
+    /// and the other tokens have no node, so walks over the trie skip them.
+    /// `compute_bias()` on the result gives the result here ANDed with `keep`.
+    ///
+    /// The exception are EOS, the end-of-turn and stop tokens: when the recognizer
+    /// allows them as special tokens (see `compute_bias_ext_eos()`), they are allowed
+    /// even if not in `keep`. Include them in `keep` (or remove them from the result)
+    /// to get the plain AND.
+    pub fn filtered(&self, keep: &SimpleVob) -> TokTrie {
+        let is_kept = |t: TokenId| (t as usize) < keep.len() && keep.is_allowed(t);
+        // the node keeps its token if kept; otherwise it goes to one of the kept duplicates
+        let words = (0..self.info.vocab_size)
+            .map(|t| {
+                let canonical = self.canonical_token(t);
+                if is_kept(t) && (canonical == t || !is_kept(canonical)) {
+                    self.token(t).to_vec()
+                } else {
+                    Vec::new()
+                }
+            })
+            .collect::<Vec<_>>();
+        // a subset of a valid trie is valid
+        let nodes = build_nodes(&words, false).unwrap();
+        let stats = token_stats_in(
+            &nodes,
+            &JumpTables::default(),
+            &self.data.token_offsets,
+            &self.data.token_data,
+            self.info.vocab_size,
+            Some(keep),
+        );
+        let mut data = TrieData::new(
+            self.info.vocab_size,
+            self.data.token_offsets.clone(),
+            self.data.token_data.clone(),
+            nodes,
+            Some(stats),
+            true,
+        )
+        .unwrap();
+        if let Some(special_tokens) = self.explicit_special_tokens() {
+            data.set_special_tokens(&special_tokens).unwrap();
+        }
+        TokTrie::with_data(self.info, self.stop_tokens.clone(), data)
+    }
+
+    /// The same trie, with the children of the root ordered by `rank()` of their bytes,
+    /// lowest first (ties in byte order), instead of by byte. Putting the bytes that
+    /// the recognizers mostly allow first keeps the parts of the node array that
+    /// `compute_bias()` visits together, which can help the cache when these bytes
+    /// are scattered in byte order. ASCII already comes first in byte order, so
+    /// ASCII-only constraints gain little.
+    ///
+    /// Tokens and the masks computed are the same. What goes by trie order changes:
+    /// `walk()`, `trace_bias()` (so only traces from tries in the same order can be
+    /// compared), and the order of children. Methods that rebuild the trie, like
+    /// `extend()` and `filtered()`, give back byte order.
+    pub fn reordered(&self, rank: &dyn Fn(u8) -> u32) -> TokTrie {
+        let nodes = &self.data.nodes;
+        let mut children = ChildOffsets::new(nodes, 0).collect::<Vec<_>>();
+        children.sort_by_key(|&c| (rank(nodes[c].byte()), nodes[c].byte()));
+        // children of the root all have num_parents 1, so their subtrees can be moved as is
+        let mut new_nodes = Vec::with_capacity(nodes.len());
+        new_nodes.push(nodes[0]);
+        for c in children {
+            new_nodes.extend_from_slice(&nodes[c..c + nodes[c].subtree_size()]);
+        }
+        // the same nodes, so this can't fail
+        let mut data = TrieData::new(
+            self.info.vocab_size,
+            self.data.token_offsets.clone(),
+            self.data.token_data.clone(),
+            new_nodes,
+            None,
+            true,
+        )
+        .unwrap();
+        if let Some(special_tokens) = self.explicit_special_tokens() {
+            data.set_special_tokens(&special_tokens).unwrap();
+        }
+        TokTrie::with_data(self.info, self.stop_tokens.clone(), data)
+    }
+
+    /// False for tries from `reordered()`.
+    fn is_in_byte_order(&self) -> bool {
+        let nodes = &self.data.nodes;
+        let mut children = ChildOffsets::new(nodes, 0).map(|c| nodes[c].byte());
+        let Some(mut prev) = children.next() else {
+            return true;
+        };
+        children.all(|b| {
+            let ok = prev < b;
+            prev = b;
+            ok
+        })
+    }
+
+    /// The tokens that have a trie node, sorted by bytes; empty and duplicate tokens
+    /// are missing, see `sorted_tokens_complete()`.
+    pub fn sorted_tokens(&self) -> Vec<(u32, Vec<u8>)> {
+        let mut res = vec![];
+        let mut bytes = vec![];
+        for ev in self.walk(self.root()) {
+            match ev {
+                WalkEvent::Push(b, tok) => {
+                    bytes.push(b);
+                    if let Some(t) = tok {
+                        res.push((t, bytes.clone()));
+                    }
+                }
+                WalkEvent::Pop(num) => bytes.truncate(bytes.len() - num),
+            }
+        }
+        if !self.is_in_byte_order() {
+            res.sort_by(|a, b| a.1.cmp(&b.1));
+        }
+        res
+    }
+
+    /// Every token with its bytes, in order of id; empty tokens included.
+    pub fn iter_tokens(&self) -> impl Iterator<Item = (TokenId, &[u8])> + '_ {
+        (0..self.vocab_size() as TokenId).map(move |t| (t, self.token(t)))
+    }
+
+    /// Every token with its bytes, like `iter_tokens()`, but sorted by bytes.
+    /// Empty tokens come first, by id; duplicates come right after their canonical
+    /// token (see `canonical_token()`), also by id. Unlike `sorted_tokens()`,
+    /// which only has the tokens in the trie, this doesn't copy the bytes.
+    pub fn sorted_tokens_complete(&self) -> Vec<(TokenId, &[u8])> {
+        let mut res = Vec::with_capacity(self.vocab_size());
+        res.extend(self.iter_tokens().filter(|(_, b)| b.is_empty()));
+        // the nodes are in the order of a depth-first walk, which goes by byte
+        for n in &self.data.nodes[1..] {
+            if let Some(t) = n.token_id() {
+                res.push((t, self.token(t)));
+                let mut dups = self.duplicates_of(t).to_vec();
+                dups.sort_unstable();
+                res.extend(dups.into_iter().map(|d| (d, self.token(d))));
+            }
+        }
+        if !self.is_in_byte_order() {
+            // stable, so the tokens with the same bytes stay in order
+            res.sort_by(|a, b| a.1.cmp(b.1));
+        }
+        res
+    }
+
+    pub fn trie_stats(&self) -> String {
+        self.stats().to_string()
+    }
+
+    /// Heap memory used by the trie. Most of it is shared with clones, including
+    /// the ones from `with_info()` and `with_eos_token()`; see `shares_data_with()`.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let d = &self.data;
+        MemoryUsage {
+            nodes: vec_heap_size(&d.nodes),
+            token_data: vec_heap_size(&d.token_data),
+            token_offsets: vec_heap_size(&d.token_offsets),
+            duplicates: map_heap_size(&d.token_duplicates)
+                + d.token_duplicates
+                    .values()
+                    .map(vec_heap_size)
+                    .sum::<usize>()
+                + map_heap_size(&d.token_canonical)
+                + d.dup_index.heap_size(),
+            derived: vob_heap_size(&d.special_tokens)
+                + map_heap_size(&d.num_parents_overflow)
+                + d.jump_tables.heap_size()
+                + d.depth_bounds.heap_size()
+                + d.token_classes.heap_size()
+                + vec_heap_size(&d.token_end_nodes),
+        }
+    }
+
+    /// True if the two tries share their nodes and tokens (and what is derived from them),
+    /// as clones made with `with_info()` and the like do.
+    pub fn shares_data_with(&self, other: &TokTrie) -> bool {
+        Arc::ptr_eq(&self.data, &other.data)
+    }
+
+    pub fn stats(&self) -> TrieStats {
+        self.stats_with_depth(0)
+    }
+
+    /// Like `stats()`, but also fills in `depth_histogram` up to `max_depth`.
+    pub fn stats_with_depth(&self, max_depth: usize) -> TrieStats {
+        let mut stats = TrieStats {
+            num_nodes: self.data.nodes.len(),
+            token_data_bytes: self.data.token_data.len(),
+            max_token_len: self.data.max_token_len,
+            num_duplicates: self.data.token_duplicates.values().map(|d| d.len()).sum(),
+            depth_histogram: vec![(0, 0); max_depth],
+            ..TrieStats::default()
+        };
+        // (end offset, number of children) for the ancestors of the current node
+        let mut stack = vec![(self.data.nodes.len(), 0)];
+        for p in 1..self.data.nodes.len() {
+            while stack.last().unwrap().0 <= p {
+                let (_, num_children) = stack.pop().unwrap();
+                stats.children_histogram[core::cmp::min(9, num_children)] += 1;
+            }
+            let depth = stack.len();
+            stack.last_mut().unwrap().1 += 1;
+            let n = &self.data.nodes[p];
+            let is_token = n.token_id().is_some();
+            if is_token {
+                stats.num_token_nodes += 1;
+            }
+            if let Some(e) = stats.depth_histogram.get_mut(depth - 1) {
+                e.0 += 1;
+                if is_token {
+                    e.1 += 1;
+                }
+            }
+            stack.push((p + n.subtree_size(), 0));
+        }
+        // skip the root
+        for (_, num_children) in stack.drain(1..) {
+            stats.children_histogram[core::cmp::min(9, num_children)] += 1;
+        }
+        stats
+    }
+}
+
+/// Numbers describing the trie; see `TokTrie::stats()`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TrieStats {
+    pub num_nodes: usize,
+    /// Nodes that have a token.
+    pub num_token_nodes: usize,
+    pub token_data_bytes: usize,
+    pub max_token_len: usize,
+    /// Tokens with the same bytes as another token, and thus without their own node.
+    pub num_duplicates: usize,
+    /// Number of nodes (other than the root) with 0, 1, ..., 8, and 9 or more children.
+    pub children_histogram: [usize; 10],
+    /// Number of nodes, and of token nodes, at depth 1, 2, ...;
+    /// only filled in by `TokTrie::stats_with_depth()`.
+    pub depth_histogram: Vec<(usize, usize)>,
+}
+
+impl core::fmt::Display for TrieStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for (idx, (count, num_tokens)) in self.depth_histogram.iter().enumerate() {
+            writeln!(
+                f,
+                "depth {}: {} nodes {} tokens",
+                idx + 1,
+                count,
+                num_tokens
+            )?;
+        }
+        write!(
+            f,
+            "{} nodes, {} token nodes, {} token bytes, {} max len",
+            self.num_nodes, self.num_token_nodes, self.token_data_bytes, self.max_token_len,
+        )
+    }
+}
+
+/// What `TokTrie::extend_ext()` does with ids that skip over some ids.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum GapPolicy {
+    /// Fail; the new ids have to be contiguous.
+    #[default]
+    Reject,
+    /// Fill the gaps with empty tokens; the new ids only have to be increasing.
+    FillEmpty,
+}
+
+/// Why a trie is malformed; see `TokTrie::validate()`.
+/// Offsets are of nodes, in depth-first order from the root at 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValidationError {
+    /// The number of tokens doesn't match the vocab size.
+    VocabSize {
+        vocab_size: u32,
+        num_tokens: usize,
+    },
+    /// The bytes of a token go past the end of the token data.
+    TokenData {
+        token: TokenId,
+        start: usize,
+        len: usize,
+        data_len: usize,
+    },
+    NoNodes,
+    /// The root's subtree doesn't span all the nodes.
+    RootSize {
+        subtree_size: usize,
+        num_nodes: usize,
+    },
+    /// The subtree of a node is empty, or goes past the end of its parent's.
+    SubtreeSize {
+        offset: usize,
+        subtree_size: usize,
+    },
+    /// The number of subtrees ending with the node is wrong.
+    NumParents {
+        offset: usize,
+        found: usize,
+        expected: usize,
+    },
+    TokenOutOfRange {
+        offset: usize,
+        token: TokenId,
+        vocab_size: u32,
+    },
+    /// The token is at an earlier node too.
+    DuplicateToken {
+        offset: usize,
+        token: TokenId,
+        first_offset: usize,
+    },
+    /// The node is deeper than the longest token.
+    TooDeep {
+        offset: usize,
+        depth: usize,
+        max_depth: usize,
+    },
+}
+
+impl ValidationError {
+    /// The offset of the node at fault, if the error is about a node.
+    pub fn offset(&self) -> Option<usize> {
+        match *self {
+            ValidationError::RootSize { .. } => Some(0),
+            ValidationError::SubtreeSize { offset, .. }
+            | ValidationError::NumParents { offset, .. }
+            | ValidationError::TokenOutOfRange { offset, .. }
+            | ValidationError::DuplicateToken { offset, .. }
+            | ValidationError::TooDeep { offset, .. } => Some(offset),
+            _ => None,
+        }
+    }
+}
+
+impl core::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match *self {
+            ValidationError::VocabSize {
+                vocab_size,
+                num_tokens,
+            } => write!(
+                f,
+                "TokTrie: vocab size {} doesn't match {} tokens",
+                vocab_size, num_tokens
+            ),
+            ValidationError::TokenData {
+                token,
+                start,
+                len,
+                data_len,
+            } => write!(
+                f,
+                "TokTrie: token {} at {}+{} is outside token data ({} bytes)",
+                token, start, len, data_len
+            ),
+            ValidationError::NoNodes => write!(f, "TokTrie: no nodes"),
+            ValidationError::RootSize {
+                subtree_size,
+                num_nodes,
+            } => write!(
+                f,
+                "TokTrie: root subtree size {} doesn't match {} nodes",
+                subtree_size, num_nodes
+            ),
+            ValidationError::SubtreeSize {
+                offset,
+                subtree_size,
+            } => write!(
+                f,
+                "TokTrie: node {} has invalid subtree size {}",
+                offset, subtree_size
+            ),
+            ValidationError::NumParents {
+                offset,
+                found,
+                expected,
+            } => write!(
+                f,
+                "TokTrie: node {} has num_parents {}, expected {}",
+                offset, found, expected
+            ),
+            ValidationError::TokenOutOfRange {
+                offset,
+                token,
+                vocab_size,
+            } => write!(
+                f,
+                "TokTrie: node {} has token {} >= vocab size {}",
+                offset, token, vocab_size
+            ),
+            ValidationError::DuplicateToken {
+                offset,
+                token,
+                first_offset,
+            } => write!(
+                f,
+                "TokTrie: token {} appears twice in the trie, at nodes {} and {}",
+                token, first_offset, offset
+            ),
+            ValidationError::TooDeep {
+                offset,
+                depth,
+                max_depth,
+            } => write!(
+                f,
+                "TokTrie: node {} is at depth {}, past the longest token ({} bytes)",
+                offset, depth, max_depth
+            ),
+        }
+    }
+}
+
+impl core::error::Error for ValidationError {}
+
+/// Returned (inside `anyhow::Error`) by `TokTrie::from_bytes_checked()` when the fingerprint
+/// of the serialized trie is not the expected one; `found` is `None` for tries serialized
+/// without a fingerprint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FingerprintMismatch {
+    pub expected: [u8; 32],
+    pub found: Option<[u8; 32]>,
+}
+
+impl core::fmt::Display for FingerprintMismatch {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "TokTrie: fingerprint mismatch: expected {}, found {}",
+            to_hex_string(&self.expected),
+            self.found
+                .as_ref()
+                .map_or_else(|| "none".to_string(), |fp| to_hex_string(fp))
+        )
+    }
+}
+
+impl core::error::Error for FingerprintMismatch {}
+
+/// Returned (inside `anyhow::Error`) by `TokTrie::append_tokens()` when the recognizer
+/// rejects a byte.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AppendTokenError {
+    /// Index of the rejected token in the tokens passed.
+    pub token_index: usize,
+    pub token: TokenId,
+    /// Offset of the rejected byte in the bytes of the token.
+    pub byte_offset_in_token: usize,
+    pub byte: u8,
+    /// Offset of the rejected byte in the bytes of all the tokens passed.
+    pub byte_offset: usize,
+    /// From `Recognizer::get_error()`, if any.
+    pub recognizer_error: Option<String>,
+}
+
+impl core::fmt::Display for AppendTokenError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "byte {:?} not allowed, at offset {} (byte {} of token {}, #{} of the tokens)",
+            self.byte as char,
+            self.byte_offset,
+            self.byte_offset_in_token,
+            self.token,
+            self.token_index
+        )?;
+        if let Some(e) = &self.recognizer_error {
+            write!(f, ": {}", e)?;
+        }
+        Ok(())
+    }
+}
+
+impl core::error::Error for AppendTokenError {}
+
+/// Differences between two vocabularies; see `TokTrie::compatibility()`.
+/// "self" and "other" refer to the arguments of `compatibility()`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TrieDiff {
+    /// `other.vocab_size() - self.vocab_size()`.
+    pub vocab_size_delta: i64,
+    /// Ids that are only in self's vocabulary, because other's is smaller.
+    pub ids_only_in_self: Vec<TokenId>,
+    /// Ids that are only in other's vocabulary, because self's is smaller.
+    pub ids_only_in_other: Vec<TokenId>,
+    /// Non-empty token bytes that no token of other has.
+    pub bytes_only_in_self: Vec<Vec<u8>>,
+    /// Non-empty token bytes that no token of self has.
+    pub bytes_only_in_other: Vec<Vec<u8>>,
+    /// Ids in both vocabularies, with different bytes.
+    pub changed_ids: Vec<TokenId>,
+    /// Special tokens (`"eos"`, `"bos"`, `"pad"`, `"unk"`, `"end_of_turn"`)
+    /// that differ, with self's and other's value.
+    pub special_diffs: Vec<(&'static str, Option<TokenId>, Option<TokenId>)>,
+    /// A token set computed against one trie means the same for the other:
+    /// the vocab sizes are the same and so are the bytes of every token.
+    pub mask_compatible: bool,
+}
+
+impl TrieDiff {
+    fn from_tokens(a: &[&[u8]], b: &[&[u8]]) -> Self {
+        let min_len = core::cmp::min(a.len(), b.len());
+        let only_in = |x: &[&[u8]], y: &[&[u8]]| {
+            let y_bytes = y.iter().copied().collect::<FxHashSet<_>>();
+            let mut seen = FxHashSet::default();
+            x.iter()
+                .filter(|t| !t.is_empty() && !y_bytes.contains(*t) && seen.insert(**t))
+                .map(|t| t.to_vec())
+                .collect::<Vec<_>>()
+        };
+        let changed_ids = (0..min_len)
+            .filter(|&i| a[i] != b[i])
+            .map(|i| i as TokenId)
+            .collect::<Vec<_>>();
+        TrieDiff {
+            vocab_size_delta: b.len() as i64 - a.len() as i64,
+            ids_only_in_self: (min_len..a.len()).map(|i| i as TokenId).collect(),
+            ids_only_in_other: (min_len..b.len()).map(|i| i as TokenId).collect(),
+            bytes_only_in_self: only_in(a, b),
+            bytes_only_in_other: only_in(b, a),
+            mask_compatible: a.len() == b.len() && changed_ids.is_empty(),
+            changed_ids,
+            special_diffs: Vec::new(),
+        }
+    }
+
+    /// No differences at all.
+    pub fn is_identical(&self) -> bool {
+        self.vocab_size_delta == 0
+            && self.changed_ids.is_empty()
+            && self.bytes_only_in_self.is_empty()
+            && self.bytes_only_in_other.is_empty()
+            && self.special_diffs.is_empty()
+    }
+}
+
+impl core::fmt::Display for TrieDiff {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.is_identical() {
+            return write!(f, "identical");
+        }
+        let mut parts = Vec::new();
+        if self.vocab_size_delta != 0 {
+            parts.push(format!("vocab size {:+}", self.vocab_size_delta));
+        }
+        if let Some(&t) = self.changed_ids.first() {
+            parts.push(format!(
+                "{} changed ids (first {})",
+                self.changed_ids.len(),
+                t
+            ));
+        }
+        if !self.bytes_only_in_self.is_empty() {
+            parts.push(format!("{} tokens removed", self.bytes_only_in_self.len()));
+        }
+        if !self.bytes_only_in_other.is_empty() {
+            parts.push(format!("{} tokens added", self.bytes_only_in_other.len()));
+        }
+        for (name, a, b) in &self.special_diffs {
+            parts.push(format!("{}: {:?} -> {:?}", name, a, b));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// Bytes of heap memory used by a trie; see `TokTrie::memory_usage()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub nodes: usize,
+    pub token_data: usize,
+    pub token_offsets: usize,
+    /// Tables of tokens with the same bytes.
+    pub duplicates: usize,
+    /// Other tables computed from the tokens and nodes when the trie is built or loaded.
+    pub derived: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.nodes + self.token_data + self.token_offsets + self.duplicates + self.derived
+    }
+}
+
+/// Properties of the vocabulary mostly of interest to offline tools; see `TokTrie::analysis()`.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug)]
+pub struct VocabAnalysis {
+    /// See `TokTrie::greedy_unreachable_tokens()`.
+    pub greedy_unreachable: SimpleVob,
+    /// See `TokTrie::tokens_never_extending()`.
+    pub never_extending: SimpleVob,
+    /// Number of sets of tokens with the same bytes.
+    pub num_duplicate_groups: usize,
+    /// Tokens in such sets, other than the canonical ones.
+    pub num_duplicates: usize,
+    /// Size of the largest set, canonical token included; 0 without duplicates.
+    pub max_duplicate_group_size: usize,
+}
+
+/// The bytes tokens start with and consist of; see `TokTrie::byte_coverage()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ByteCoverage {
+    /// Bytes some token starts with.
+    pub starts_token: [bool; 256],
+    /// Bytes that are tokens by themselves.
+    pub single_byte_token: [bool; 256],
+    /// All bytes are tokens by themselves, so any bytes can be split into tokens,
+    /// as with byte-level BPE or byte fallback; see `TokTrie::can_represent()`.
+    pub covers_all_bytes: bool,
+}
+
+/// Which subtrees of a trie have tokens from a set of candidates; see
+/// `TokTrie::index_candidates()` and `TokTrie::compute_bias_with_index()`.
+#[derive(Clone, Debug)]
+pub struct CandidateIndex {
+    candidates: SimpleVob,
+    // for each node, whether its token or one below it is a candidate
+    subtree_has_candidate: SimpleVob,
+}
+
+impl CandidateIndex {
+    pub fn candidates(&self) -> &SimpleVob {
+        &self.candidates
+    }
+}
+
+fn vec_heap_size<T>(v: &Vec<T>) -> usize {
+    v.capacity() * core::mem::size_of::<T>()
+}
+
+fn vob_heap_size(v: &SimpleVob) -> usize {
+    v.as_slice().len() * 4
+}
+
+// approximate: hashbrown has a control byte per bucket
+fn map_heap_size<K, V>(m: &FxHashMap<K, V>) -> usize {
+    m.capacity() * (core::mem::size_of::<(K, V)>() + 1)
+}
+
+/// Per-token flags computed from the token bytes; see `TokTrie::token_props()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TokenProps(u8);
+
+impl TokenProps {
+    /// Non-empty, and all bytes are ASCII whitespace (space, `\t`, `\n`, `\r`, `\x0c`).
+    pub const WHITESPACE_ONLY: TokenProps = TokenProps(1 << 0);
+    /// Exactly one byte; this includes byte fallback tokens `<0x00>` ... `<0xFF>`.
+    pub const SINGLE_BYTE: TokenProps = TokenProps(1 << 1);
+    /// Non-empty, and all bytes are below 0x80.
+    pub const ASCII_ONLY: TokenProps = TokenProps(1 << 2);
+    /// The first byte is a UTF-8 continuation byte (0x80-0xBF).
+    pub const STARTS_MID_UTF8: TokenProps = TokenProps(1 << 3);
+    /// The token may not end on a character boundary: it ends with an incomplete
+    /// UTF-8 sequence, or with continuation bytes only (which depends on the context).
+    pub const ENDS_MID_UTF8: TokenProps = TokenProps(1 << 4);
+    /// Same as `TokTrie::is_special_token()`.
+    pub const IS_SPECIAL: TokenProps = TokenProps(1 << 5);
+
+    pub const fn empty() -> Self {
+        TokenProps(0)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn from_bits_truncate(bits: u8) -> Self {
+        TokenProps(bits & 0x3f)
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// True if all flags in `other` are set.
+    pub const fn contains(self, other: TokenProps) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersects(self, other: TokenProps) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub fn insert(&mut self, other: TokenProps) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: TokenProps) {
+        self.0 &= !other.0;
+    }
+
+    pub fn from_token_bytes(bytes: &[u8]) -> Self {
+        let mut r = TokenProps::empty();
+        let Some((&first, _)) = bytes.split_first() else {
+            return r;
+        };
+        if bytes.len() == 1 {
+            r.insert(TokenProps::SINGLE_BYTE);
+        } else if first == TokTrie::SPECIAL_TOKEN_PREFIX_BYTE {
+            r.insert(TokenProps::IS_SPECIAL);
+        }
+        if bytes.is_ascii() {
+            r.insert(TokenProps::ASCII_ONLY);
+            if bytes.iter().all(|b| b.is_ascii_whitespace()) {
+                r.insert(TokenProps::WHITESPACE_ONLY);
+            }
+        }
+        if is_utf8_continuation(first) {
+            r.insert(TokenProps::STARTS_MID_UTF8);
+        }
+        if !ends_on_char_boundary(bytes) {
+            r.insert(TokenProps::ENDS_MID_UTF8);
+        }
+        r
+    }
+}
+
+impl core::ops::BitOr for TokenProps {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        TokenProps(self.0 | rhs.0)
+    }
+}
+
+impl core::ops::BitOrAssign for TokenProps {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl core::ops::BitAnd for TokenProps {
+    type Output = Self;
+    fn bitand(self, rhs: Self) -> Self {
+        TokenProps(self.0 & rhs.0)
+    }
+}
+
+fn is_utf8_continuation(b: u8) -> bool {
+    b & 0xc0 == 0x80
+}
+
+/// Bytes in the UTF-8 sequence starting with `b`; 1 for ASCII and invalid bytes.
+fn utf8_seq_len(b: u8) -> usize {
+    match b {
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => 1,
+    }
+}
+
+fn ends_on_char_boundary(bytes: &[u8]) -> bool {
+    // a sequence is at most 4 bytes, so only look at the last 4
+    for (back, &b) in bytes.iter().rev().take(4).enumerate() {
+        if !is_utf8_continuation(b) {
+            return utf8_seq_len(b) <= back + 1;
+        }
+    }
+    // only continuation bytes; the lead byte is in an earlier token
+    false
+}
+
+/// Next bytes allowed by a recognizer; see `TokTrie::compute_byte_bias()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteBias {
+    pub bytes: [bool; 256],
+    pub eos_allowed: bool,
+}
+
+impl ByteBias {
+    pub fn new() -> Self {
+        ByteBias {
+            bytes: [false; 256],
+            eos_allowed: false,
+        }
+    }
+
+    pub fn is_allowed(&self, b: u8) -> bool {
+        self.bytes[b as usize]
+    }
+
+    pub fn num_allowed(&self) -> usize {
+        self.bytes.iter().filter(|&&a| a).count()
+    }
+
+    /// The allowed bytes, in increasing order.
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..=255u8).filter(|&b| self.is_allowed(b))
+    }
+}
+
+impl Default for ByteBias {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Formatting options for `TokTrie::token_set_dbg_ext()` and `TokTrie::tokens_dbg_ext()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DbgOptions {
+    /// How many tokens of a set to list before "...".
+    pub max_examples: usize,
+    /// Tokens with more than this many invalid UTF-8 sequences are shown as `HEX[..]`.
+    pub hex_threshold: usize,
+    /// List the tokens not in the set ("ALL EXCEPT ...") when there are far fewer of them.
+    pub allow_negation: bool,
+    /// Append `[id]` to every token, so that tokens with identical bytes can be told apart.
+    pub show_ids: bool,
+    /// Produce JSON instead, with an `{"id", "repr", "special"}` object for every token.
+    pub json: bool,
+}
+
+impl Default for DbgOptions {
+    fn default() -> Self {
+        DbgOptions {
+            max_examples: 50,
+            hex_threshold: 0,
+            allow_negation: true,
+            show_ids: false,
+            json: false,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TokenDbg {
+    id: TokenId,
+    repr: String,
+    special: bool,
+    // repr is the text of the token, and is quoted outside of JSON
+    #[serde(skip)]
+    quoted: bool,
+}
+
+impl TokenDbg {
+    fn to_text(&self, opts: &DbgOptions) -> String {
+        let s = if self.quoted {
+            format!("{:?}", self.repr)
+        } else {
+            self.repr.clone()
+        };
+        if opts.show_ids {
+            format!("{}[{}]", s, self.id)
+        } else {
+            s
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TokenSetDbg {
+    num_set: usize,
+    vocab_size: usize,
+    negated: bool,
+    tokens: Vec<TokenDbg>,
+    truncated: bool,
+}
+
+pub struct NodeChildren<'a> {
+    offsets: ChildOffsets<'a>,
+}
+
+impl<'a> NodeChildren<'a> {
+    fn new(nodes: &'a [TrieNode], n: &TrieNode) -> Self {
+        NodeChildren {
+            offsets: ChildOffsets::new(nodes, node_offset_in(nodes, n)),
+        }
+    }
+}
+
+impl<'a> Iterator for NodeChildren<'a> {
+    type Item = &'a TrieNode;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let off = self.offsets.next()?;
+        Some(&self.offsets.nodes[off])
+    }
+}
+
+/// Offsets of the children of the node at an offset.
+struct ChildOffsets<'a> {
+    nodes: &'a [TrieNode],
+    current_offset: usize,
+    end_offset: usize,
+}
+
+impl<'a> ChildOffsets<'a> {
+    fn new(nodes: &'a [TrieNode], off: usize) -> Self {
+        ChildOffsets {
+            nodes,
+            current_offset: off + 1,
+            end_offset: off + nodes[off].subtree_size(),
+        }
+    }
+}
+
+impl Iterator for ChildOffsets<'_> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.current_offset < self.end_offset {
+            let off = self.current_offset;
+            self.current_offset += self.nodes[off].subtree_size();
+            Some(off)
+        } else {
+            None
+        }
+    }
+}
+
+/// Offsets of the nodes on the path from the root (excluded) down to a node (included).
+struct PathOffsets<'a> {
+    nodes: &'a [TrieNode],
+    current_offset: usize,
+    target: usize,
+}
+
+impl<'a> PathOffsets<'a> {
+    fn new(nodes: &'a [TrieNode], target: usize) -> Self {
+        PathOffsets {
+            nodes,
+            current_offset: 0,
+            target,
+        }
+    }
+}
+
+impl Iterator for PathOffsets<'_> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.current_offset == self.target {
+            return None;
+        }
+        // descend into the child whose subtree contains the target
+        let target = self.target;
+        self.current_offset = ChildOffsets::new(self.nodes, self.current_offset)
+            .find(|&c| target < c + self.nodes[c].subtree_size())
+            .unwrap();
+        Some(self.current_offset)
+    }
+}
+
+/// Read-only view of a serialized trie, borrowing from the buffer where possible.
+///
+/// Sections of the buffer that are suitably aligned are used in place;
+/// the others are copied. Use `into_owned()` to get the full `TokTrie` API.
+#[derive(Clone)]
+pub struct TokTrieRef<'a> {
+    info: TokRxInfo,
+    token_offsets: Cow<'a, [u32]>,
+    token_data: Cow<'a, [u8]>,
+    nodes: Cow<'a, [TrieNode]>,
+    max_token_len: usize,
+    token_duplicates: FxHashMap<TokenId, Vec<TokenId>>,
+    dup_index: DupIndex,
+    special_tokens: SimpleVob,
+    explicit_special_tokens: bool,
+    stop_tokens: Vec<TokenId>,
+    num_parents_overflow: FxHashMap<usize, usize>,
+    jump_tables: JumpTables,
+}
+
+impl<'a> TokTrieRef<'a> {
+    pub fn from_bytes(bytes: &'a [u8]) -> Self {
+        Self::try_from_bytes(bytes).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_from_bytes(bytes: &'a [u8]) -> Result<Self> {
+        Self::from_bytes_ext(bytes, true)
+    }
+
+    /// See `TokTrie::from_bytes_unchecked()`.
+    pub fn from_bytes_unchecked(bytes: &'a [u8]) -> Result<Self> {
+        Self::from_bytes_ext(bytes, false)
+    }
+
+    /// See `TokTrie::validate()`.
+    pub fn validate(&self) -> core::result::Result<(), ValidationError> {
+        let max_depth =
+            validate_token_offsets(&self.token_offsets, &self.token_data, self.info.vocab_size)?;
+        validate_nodes(&self.nodes, self.info.vocab_size, max_depth, true)?;
+        Ok(())
+    }
+
+    fn from_bytes_ext(bytes: &'a [u8], check: bool) -> Result<Self> {
+        let (hd, [nodes, token_offsets, token_data, stats], swap) = TokTrieHeader::parse(bytes)?;
+
+        let info = TokRxInfo::from_bin_ext(&hd.info, &hd.info_ext);
+        let (stats, stop_tokens, explicit_special) =
+            parse_token_stats(&bytes[stats], info.vocab_size, hd.version)?;
+        let mut nodes: Cow<[TrieNode]> = vec_or_slice_from_bytes(&bytes[nodes]);
+        let mut token_offsets: Cow<[u32]> = vec_or_slice_from_bytes(&bytes[token_offsets]);
+        if swap {
+            swap_u32_bytes(bytemuck::cast_slice_mut(nodes.to_mut()));
+            swap_u32_bytes(bytemuck::cast_slice_mut(token_offsets.to_mut()));
+        }
+        let mut token_data = Cow::Borrowed(&bytes[token_data]);
+        if hd.magic == TokTrieHeader::MAGIC {
+            if let Some((offs, data)) = upgrade_legacy_tokens(&token_offsets, &token_data)? {
+                token_offsets = Cow::Owned(offs);
+                token_data = Cow::Owned(data);
+            }
         }
-        self.validate();
+
+        let max_depth = if check {
+            validate_token_offsets(&token_offsets, &token_data, info.vocab_size)?
+        } else {
+            0
+        };
+        let num_parents_overflow = validate_nodes(&nodes, info.vocab_size, max_depth, check)?;
+        let jump_tables = JumpTables::new(&nodes);
+        let (max_token_len, token_duplicates) = check_token_stats_in(
+            stats,
+            &nodes,
+            &jump_tables,
+            &token_offsets,
+            &token_data,
+            info.vocab_size,
+        )?;
+        let dup_index = DupIndex::new(&token_duplicates, info.vocab_size);
+        let special_tokens = match &explicit_special {
+            Some(tokens) => {
+                explicit_special_tokens_in(&token_offsets, &token_data, info.vocab_size, tokens)?
+            }
+            None => special_tokens_in(&token_offsets, &token_data, info.vocab_size),
+        };
+
+        Ok(TokTrieRef {
+            info,
+            token_offsets,
+            token_data,
+            nodes,
+            max_token_len,
+            token_duplicates,
+            dup_index,
+            special_tokens,
+            explicit_special_tokens: explicit_special.is_some(),
+            stop_tokens,
+            num_parents_overflow,
+            jump_tables,
+        })
     }
 
-    fn node_offset(&self, n: &TrieNode) -> usize {
-        let off = unsafe { (n as *const TrieNode).offset_from(self.root() as *const TrieNode) };
-        assert!(off >= 0);
-        let off = off as usize;
-        assert!(off < self.nodes.len());
-        off
+    /// True if no section of the buffer had to be copied.
+    pub fn is_zero_copy(&self) -> bool {
+        matches!(self.nodes, Cow::Borrowed(_))
+            && matches!(self.token_offsets, Cow::Borrowed(_))
+            && matches!(self.token_data, Cow::Borrowed(_))
     }
 
-    fn next_node(&self, n: &TrieNode) -> usize {
-        return self.node_offset(n) + n.subtree_size();
+    pub fn into_owned(self) -> TokTrie {
+        let mut token_classes =
+            TokenClasses::new(&self.token_offsets, &self.token_data, self.info.vocab_size);
+        if self.explicit_special_tokens {
+            token_classes.restrict_special(&self.special_tokens);
+        }
+        let depth_bounds = DepthBounds::new(&self.nodes);
+        let token_end_nodes =
+            token_end_nodes_in(&self.nodes, &self.token_duplicates, self.info.vocab_size);
+        let data = TrieData {
+            token_offsets: self.token_offsets.into_owned(),
+            token_data: self.token_data.into_owned(),
+            nodes: self.nodes.into_owned(),
+            max_token_len: self.max_token_len,
+            token_canonical: canonical_map_in(&self.token_duplicates),
+            token_duplicates: self.token_duplicates,
+            dup_index: self.dup_index,
+            special_tokens: self.special_tokens,
+            explicit_special_tokens: self.explicit_special_tokens,
+            num_parents_overflow: self.num_parents_overflow,
+            jump_tables: self.jump_tables,
+            depth_bounds,
+            token_classes,
+            token_end_nodes,
+            #[cfg(feature = "std")]
+            analysis: OnceLock::new(),
+        };
+        TokTrie::with_data(self.info, self.stop_tokens, data)
     }
 
     pub fn info(&self) -> &TokRxInfo {
         &self.info
     }
 
-    pub fn special_token(&self, tok: SpecialToken) -> TokenId {
-        match tok {
-            SpecialToken::EndOfSentence => self.info.tok_eos,
-            _ => panic!("non-EOS special_token() called"), // TODO?
+    pub fn vocab_size(&self) -> usize {
+        self.info.vocab_size as usize
+    }
+
+    /// See `TokTrie::sentinel_token()`.
+    pub fn sentinel_token(&self) -> TokenId {
+        self.vocab_size() as TokenId
+    }
+
+    pub fn eos_token(&self) -> TokenId {
+        self.info.tok_eos
+    }
+
+    pub fn is_stop_token(&self, t: TokenId) -> bool {
+        t == self.info.tok_eos || self.stop_tokens.binary_search(&t).is_ok()
+    }
+
+    pub fn is_special_token(&self, t: TokenId) -> bool {
+        (t as usize) < self.special_tokens.len() && self.special_tokens.is_allowed(t)
+    }
+
+    pub fn max_token_len(&self) -> usize {
+        self.max_token_len
+    }
+
+    pub fn alloc_token_set(&self) -> SimpleVob {
+        SimpleVob::alloc_with_capacity(self.vocab_size(), self.vocab_size() + 1)
+    }
+
+    pub fn token(&self, idx: u32) -> &[u8] {
+        self.slices().token(idx)
+    }
+
+    pub fn root(&self) -> &TrieNode {
+        &self.nodes[0]
+    }
+
+    pub fn node_children(&self, n: &TrieNode) -> NodeChildren<'_> {
+        NodeChildren::new(&self.nodes, n)
+    }
+
+    pub fn child_at_byte<'b>(&'b self, n: &'b TrieNode, byte: u8) -> Option<&'b TrieNode> {
+        self.slices().child_at_byte(n, byte)
+    }
+
+    pub fn child_at_bytes<'b>(&'b self, n: &'b TrieNode, bytes: &[u8]) -> Option<&'b TrieNode> {
+        self.slices().child_at_bytes(n, bytes)
+    }
+
+    pub fn token_id(&self, bytes: &[u8]) -> Option<TokenId> {
+        self.slices().token_id(bytes)
+    }
+
+    pub fn compute_bias(&self, r: &mut impl Recognizer, logits: &mut SimpleVob) {
+        self.compute_bias_ext(r, logits, &[]);
+    }
+
+    /// Like `compute_bias()`, but fails if `r` reports an error; `logits` are then
+    /// what the walk found, which is not to be relied on.
+    pub fn try_compute_bias(&self, r: &mut impl Recognizer, logits: &mut SimpleVob) -> Result<()> {
+        self.compute_bias(r, logits);
+        check_recognizer_error(r)
+    }
+
+    pub fn compute_bias_ext(&self, r: &mut impl Recognizer, logits: &mut SimpleVob, start: &[u8]) {
+        self.compute_bias_ext_eos(r, logits, start, EosMode::Auto);
+    }
+
+    pub fn compute_bias_ext_eos(
+        &self,
+        r: &mut impl Recognizer,
+        logits: &mut SimpleVob,
+        start: &[u8],
+        eos_mode: EosMode,
+    ) {
+        self.slices()
+            .compute_bias_ext_eos(r, logits, start, eos_mode);
+    }
+
+    pub fn apply_duplicates(&self, logits: &mut SimpleVob) {
+        self.slices().apply_duplicates(logits)
+    }
+
+    pub fn add_bias(&self, r: &mut impl Recognizer, toks: &mut SimpleVob, start: &[u8]) {
+        self.slices().add_bias(r, toks, start);
+    }
+
+    fn slices(&self) -> TrieSlices<'_> {
+        TrieSlices {
+            info: &self.info,
+            stop_tokens: &self.stop_tokens,
+            nodes: &self.nodes,
+            token_offsets: &self.token_offsets,
+            token_data: &self.token_data,
+            num_parents_overflow: &self.num_parents_overflow,
+            jump_tables: &self.jump_tables,
+            dup_index: &self.dup_index,
+        }
+    }
+}
+
+/// What the lookups and walks use, borrowed from a `TokTrie` or a `TokTrieRef`,
+/// so that both run the same code.
+#[derive(Clone, Copy)]
+struct TrieSlices<'t> {
+    info: &'t TokRxInfo,
+    stop_tokens: &'t [TokenId],
+    nodes: &'t [TrieNode],
+    token_offsets: &'t [u32],
+    token_data: &'t [u8],
+    num_parents_overflow: &'t FxHashMap<usize, usize>,
+    jump_tables: &'t JumpTables,
+    dup_index: &'t DupIndex,
+}
+
+impl<'t> TrieSlices<'t> {
+    fn vocab_size(&self) -> usize {
+        self.info.vocab_size as usize
+    }
+
+    fn token(&self, idx: u32) -> &'t [u8] {
+        token_in(self.token_offsets, self.token_data, idx)
+    }
+
+    fn child_at_byte(&self, n: &TrieNode, byte: u8) -> Option<&'t TrieNode> {
+        child_at_byte_in(self.nodes, self.jump_tables, n, byte)
+    }
+
+    fn child_at_bytes<'b>(&self, n: &'b TrieNode, bytes: &[u8]) -> Option<&'b TrieNode>
+    where
+        't: 'b,
+    {
+        child_at_bytes_in(self.nodes, self.jump_tables, n, bytes)
+    }
+
+    fn token_id(&self, bytes: &[u8]) -> Option<TokenId> {
+        self.child_at_bytes(&self.nodes[0], bytes)
+            .and_then(|n| n.token_id())
+    }
+
+    fn compute_bias_ext_eos(
+        &self,
+        r: &mut impl Recognizer,
+        logits: &mut SimpleVob,
+        start: &[u8],
+        eos_mode: EosMode,
+    ) -> WalkCounters {
+        check_token_set_in(self.vocab_size(), logits);
+        logits.set_all(false);
+        allow_end_tokens_in(
+            self.info,
+            self.stop_tokens,
+            r,
+            logits,
+            start.is_empty(),
+            eos_mode,
+        );
+        let counters = self.add_bias(r, logits, start);
+        self.apply_duplicates(logits);
+        counters
+    }
+
+    fn apply_duplicates(&self, logits: &mut SimpleVob) {
+        check_token_set_in(self.vocab_size(), logits);
+        self.dup_index.apply(logits)
+    }
+
+    fn add_bias(
+        &self,
+        r: &mut impl Recognizer,
+        toks: &mut SimpleVob,
+        start: &[u8],
+    ) -> WalkCounters {
+        check_token_set_in(self.vocab_size(), toks);
+        add_bias_in(
+            self.nodes,
+            self.jump_tables,
+            self.num_parents_overflow,
+            self.vocab_size() as u32,
+            r,
+            toks,
+            start,
+            None,
+            None,
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WalkEvent {
+    /// Descend to a child node with the given byte and token (if any).
+    Push(u8, Option<TokenId>),
+    /// Go up this many levels.
+    Pop(usize),
+}
+
+/// Iterator over a subtree of the trie, as a sequence of push/pop events,
+/// see `TokTrie::walk()`.
+///
+/// This is the same traversal that `compute_bias()` does.
+/// After receiving `Push`, the caller may call `skip_subtree()`
+/// to reject the node: the push is then considered undone and the node's
+/// children are not visited.
+/// The pops always balance the pushes, so the walk ends at the starting node.
+pub struct TrieWalker<'a> {
+    nodes: &'a [TrieNode],
+    num_parents_overflow: &'a FxHashMap<usize, usize>,
+    p: usize,
+    endp: usize,
+    next_pop: usize,
+    last: usize,
+    depth: usize,
+}
+
+impl<'a> TrieWalker<'a> {
+    fn new(
+        nodes: &'a [TrieNode],
+        num_parents_overflow: &'a FxHashMap<usize, usize>,
+        from: &TrieNode,
+    ) -> Self {
+        let off = node_offset_in(nodes, from);
+        TrieWalker {
+            nodes,
+            num_parents_overflow,
+            p: off + 1,
+            endp: off + from.subtree_size(),
+            next_pop: 0,
+            last: off,
+            depth: 0,
+        }
+    }
+
+    /// Don't visit the children of the node from the last `Push` event,
+    /// and consider that push undone.
+    pub fn skip_subtree(&mut self) {
+        let n = &self.nodes[self.last];
+        assert!(self.p == self.last + 1, "skip_subtree() not after Push");
+        self.p = self.last + n.subtree_size();
+        self.next_pop = num_parents_in(self.nodes, self.num_parents_overflow, self.last) - 1;
+        self.depth -= 1;
+    }
+
+    /// Node from the last `Push` event.
+    pub fn node(&self) -> &'a TrieNode {
+        &self.nodes[self.last]
+    }
+
+    /// Number of pushes not yet popped.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+}
+
+impl<'a> Iterator for TrieWalker<'a> {
+    type Item = WalkEvent;
+
+    #[inline(always)]
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.p >= self.endp {
+            // the last node's num_parents may reach above the starting node
+            if self.depth > 0 {
+                let num = self.depth;
+                self.depth = 0;
+                return Some(WalkEvent::Pop(num));
+            }
+            return None;
+        }
+        if self.next_pop > 0 {
+            let num = self.next_pop;
+            self.next_pop = 0;
+            self.depth -= num;
+            return Some(WalkEvent::Pop(num));
+        }
+        let n = &self.nodes[self.p];
+        self.last = self.p;
+        self.p += 1;
+        self.depth += 1;
+        self.next_pop = if n.subtree_size() == 1 {
+            num_parents_in(self.nodes, self.num_parents_overflow, self.last)
+        } else {
+            0
+        };
+        Some(WalkEvent::Push(n.byte(), n.token_id()))
+    }
+}
+
+// The functions below operate on the raw trie arrays and are shared
+// between TokTrie and TokTrieRef.
+
+fn node_offset_in(nodes: &[TrieNode], n: &TrieNode) -> usize {
+    // plain address arithmetic, so that a node from elsewhere can't cause UB
+    let diff = (n as *const TrieNode as usize).wrapping_sub(nodes.as_ptr() as usize);
+    let off = diff / core::mem::size_of::<TrieNode>();
+    assert!(
+        diff % core::mem::size_of::<TrieNode>() == 0 && off < nodes.len(),
+        "TokTrie: TrieNode not borrowed from this trie (a copy, or from another trie?); \
+         use NodeRef to keep nodes around"
+    );
+    off
+}
+
+fn special_tokens_in(token_offsets: &[u32], token_data: &[u8], vocab_size: u32) -> SimpleVob {
+    let mut res = SimpleVob::alloc(vocab_size as usize);
+    add_special_tokens_in(&mut res, token_offsets, token_data, 0..vocab_size);
+    res
+}
+
+/// `tokens` as a set, checking that they are special tokens going by their bytes.
+fn explicit_special_tokens_in(
+    token_offsets: &[u32],
+    token_data: &[u8],
+    vocab_size: u32,
+    tokens: &[TokenId],
+) -> Result<SimpleVob> {
+    let mut res = SimpleVob::alloc(vocab_size as usize);
+    for &tok in tokens {
+        ensure!(
+            tok < vocab_size,
+            "TokTrie: special token {} out of range (vocab size {})",
+            tok,
+            vocab_size
+        );
+        let bytes = token_in(token_offsets, token_data, tok);
+        ensure!(
+            bytes.len() > 1 && bytes[0] == TokTrie::SPECIAL_TOKEN_PREFIX_BYTE,
+            "TokTrie: special token {} doesn't start with SPECIAL_TOKEN_PREFIX_BYTE",
+            tok
+        );
+        res.allow_token(tok);
+    }
+    Ok(res)
+}
+
+fn add_special_tokens_in(
+    res: &mut SimpleVob,
+    token_offsets: &[u32],
+    token_data: &[u8],
+    tokens: Range<u32>,
+) {
+    for tok in tokens {
+        let bytes = token_in(token_offsets, token_data, tok);
+        if bytes.len() > 1 && bytes[0] == TokTrie::SPECIAL_TOKEN_PREFIX_BYTE {
+            res.allow_token(tok);
+        }
+    }
+}
+
+fn token_in<'a>(token_offsets: &[u32], token_data: &'a [u8], idx: u32) -> &'a [u8] {
+    if idx >= token_offsets.len() as u32 {
+        return &[];
+    }
+    let desc = token_offsets[idx as usize];
+    let len = desc & LEN_ESCAPE;
+    let off = (desc >> LEN_BITS) as usize;
+    if len == LEN_ESCAPE {
+        let len = u32::from_le_bytes(token_data[off..off + 4].try_into().unwrap());
+        &token_data[off + 4..off + 4 + len as usize]
+    } else {
+        &token_data[off..(off + len as usize)]
+    }
+}
+
+/// Appends the token to token_offsets and token_data, escaping the length if needed.
+pub(crate) fn push_token(
+    token_offsets: &mut Vec<u32>,
+    token_data: &mut Vec<u8>,
+    word: &[u8],
+) -> Result<()> {
+    let off = token_data.len();
+    let escaped = word.len() >= LEN_ESCAPE as usize;
+    let size = word.len() + if escaped { 4 } else { 0 };
+    ensure!(
+        off + size <= MAX_TOKEN_DATA_LEN,
+        "TokTrie: token data too large; limit is {} bytes",
+        MAX_TOKEN_DATA_LEN
+    );
+    if escaped {
+        token_offsets.push(LEN_ESCAPE | ((off as u32) << LEN_BITS));
+        token_data.extend_from_slice(&(word.len() as u32).to_le_bytes());
+    } else {
+        token_offsets.push(word.len() as u32 | ((off as u32) << LEN_BITS));
+    }
+    token_data.extend_from_slice(word);
+    Ok(())
+}
+
+/// In files with the old magic, a length of LEN_ESCAPE is a literal length.
+/// Returns the tokens re-encoded, or None if there are no such tokens.
+fn upgrade_legacy_tokens(
+    token_offsets: &[u32],
+    token_data: &[u8],
+) -> Result<Option<(Vec<u32>, Vec<u8>)>> {
+    if !token_offsets
+        .iter()
+        .any(|&desc| desc & LEN_ESCAPE == LEN_ESCAPE)
+    {
+        return Ok(None);
+    }
+    let mut offs = Vec::with_capacity(token_offsets.len());
+    let mut data = Vec::with_capacity(token_data.len());
+    for (idx, &desc) in token_offsets.iter().enumerate() {
+        let len = (desc & LEN_ESCAPE) as usize;
+        let off = (desc >> LEN_BITS) as usize;
+        ensure!(
+            off + len <= token_data.len(),
+            "TokTrie: token {} at {}+{} is outside token data ({} bytes)",
+            idx,
+            off,
+            len,
+            token_data.len()
+        );
+        push_token(&mut offs, &mut data, &token_data[off..off + len])?;
+    }
+    Ok(Some((offs, data)))
+}
+
+/// num_parents of node at offset p, including the ones that don't fit in TrieNode.
+#[inline(always)]
+fn num_parents_in(
+    nodes: &[TrieNode],
+    num_parents_overflow: &FxHashMap<usize, usize>,
+    p: usize,
+) -> usize {
+    let np = nodes[p].num_parents();
+    if np == NUM_PARENTS_ESCAPE {
+        num_parents_overflow_at(num_parents_overflow, p)
+    } else {
+        np
+    }
+}
+
+#[cold]
+fn num_parents_overflow_at(num_parents_overflow: &FxHashMap<usize, usize>, p: usize) -> usize {
+    num_parents_overflow[&p]
+}
+
+fn child_at_byte_in<'a>(
+    nodes: &'a [TrieNode],
+    jump_tables: &JumpTables,
+    n: &TrieNode,
+    byte: u8,
+) -> Option<&'a TrieNode> {
+    let off = node_offset_in(nodes, n);
+    child_offset_at_byte_in(nodes, jump_tables, off, byte).map(|child| &nodes[child])
+}
+
+fn child_offset_at_byte_in(
+    nodes: &[TrieNode],
+    jump_tables: &JumpTables,
+    off: usize,
+    byte: u8,
+) -> Option<usize> {
+    if let Some(table) = jump_tables.table_at(off) {
+        return match table[byte as usize] {
+            0 => None,
+            child => Some(child as usize),
+        };
+    }
+    ChildOffsets::new(nodes, off).find(|&child| nodes[child].byte() == byte)
+}
+
+fn child_at_bytes_in<'a>(
+    nodes: &'a [TrieNode],
+    jump_tables: &JumpTables,
+    mut n: &'a TrieNode,
+    bytes: &[u8],
+) -> Option<&'a TrieNode> {
+    for &byte in bytes {
+        n = child_at_byte_in(nodes, jump_tables, n, byte)?;
+    }
+    Some(n)
+}
+
+/// max_token_len and token_duplicates
+type TokenStats = (usize, FxHashMap<TokenId, Vec<TokenId>>);
+
+/// The stats section: stats, stop tokens, and special tokens if they are listed.
+type StatsSection = (Option<TokenStats>, Vec<TokenId>, Option<Vec<TokenId>>);
+
+/// Computes max_token_len and token_duplicates.
+/// A token is a duplicate if its bytes lead to a node with a different token id.
+/// With `keep`, other tokens are ignored (see `TokTrie::filtered()`).
+fn token_stats_in(
+    nodes: &[TrieNode],
+    jump_tables: &JumpTables,
+    token_offsets: &[u32],
+    token_data: &[u8],
+    vocab_size: u32,
+    keep: Option<&SimpleVob>,
+) -> TokenStats {
+    let mut max_token_len = 0;
+    let mut token_duplicates = FxHashMap::default();
+    for tok_id in 0..vocab_size {
+        if let Some(keep) = keep {
+            if tok_id as usize >= keep.len() || !keep.is_allowed(tok_id) {
+                continue;
+            }
+        }
+        let bytes = token_in(token_offsets, token_data, tok_id);
+        max_token_len = core::cmp::max(max_token_len, bytes.len());
+        if bytes.is_empty() {
+            continue;
         }
+        if let Some(canonical) =
+            child_at_bytes_in(nodes, jump_tables, &nodes[0], bytes).and_then(|n| n.token_id())
+        {
+            if canonical != tok_id {
+                token_duplicates
+                    .entry(canonical)
+                    .or_insert_with(Vec::new)
+                    .push(tok_id);
+            }
+        }
+    }
+    (max_token_len, token_duplicates)
+}
+
+/// Returns the deserialized stats if present (checking them in debug builds),
+/// or computes them.
+/// Filtered tries have fewer duplicates and maybe a lower max_token_len
+/// than what the nodes imply, so this only checks the stats don't claim more.
+fn check_token_stats_in(
+    stats: Option<TokenStats>,
+    nodes: &[TrieNode],
+    jump_tables: &JumpTables,
+    token_offsets: &[u32],
+    token_data: &[u8],
+    vocab_size: u32,
+) -> Result<TokenStats> {
+    match stats {
+        Some(stats) => {
+            if cfg!(debug_assertions) {
+                let (max_len, dups) = &stats;
+                let (actual_max_len, actual_dups) = token_stats_in(
+                    nodes,
+                    jump_tables,
+                    token_offsets,
+                    token_data,
+                    vocab_size,
+                    None,
+                );
+                ensure!(
+                    *max_len <= actual_max_len
+                        && dups.iter().all(|(canonical, dups)| {
+                            actual_dups
+                                .get(canonical)
+                                .is_some_and(|actual| dups.iter().all(|d| actual.contains(d)))
+                        }),
+                    "TokTrie: serialized token stats don't match the trie"
+                );
+            }
+            Ok(stats)
+        }
+        None => Ok(token_stats_in(
+            nodes,
+            jump_tables,
+            token_offsets,
+            token_data,
+            vocab_size,
+            None,
+        )),
+    }
+}
+
+/// Writes `bytes`, made of u32 words in native byte order, little-endian.
+#[cfg(feature = "std")]
+fn write_u32_words(w: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    if cfg!(target_endian = "little") {
+        return w.write_all(bytes);
+    }
+    let mut buf = Vec::new();
+    for chunk in bytes.chunks(IO_CHUNK_SIZE) {
+        buf.clear();
+        buf.extend_from_slice(chunk);
+        swap_u32_bytes(&mut buf);
+        w.write_all(&buf)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "std")]
+fn read_section(r: &mut impl Read, buf: &mut [u8], what: &str) -> Result<()> {
+    r.read_exact(buf).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            anyhow!("TokTrie: truncated {}", what)
+        } else {
+            anyhow!("TokTrie: reading {}: {}", what, e)
+        }
+    })
+}
+
+/// Reads `len` bytes (a multiple of the size of `T`) in chunks, so that a bogus `len`
+/// fails at the end of the input, instead of allocating it all upfront.
+#[cfg(feature = "std")]
+fn read_pod_vec<T: bytemuck::Pod>(r: &mut impl Read, len: usize, what: &str) -> Result<Vec<T>> {
+    let elt_size = core::mem::size_of::<T>();
+    let num = len / elt_size;
+    let chunk = IO_CHUNK_SIZE / elt_size;
+    let mut res: Vec<T> = Vec::new();
+    while res.len() < num {
+        let start = res.len();
+        let end = core::cmp::min(num, start + chunk);
+        res.resize(end, T::zeroed());
+        read_section(r, bytemuck::cast_slice_mut(&mut res[start..end]), what)?;
+    }
+    Ok(res)
+}
+
+// stats section: MAGIC_STATS, (since version 5) the size of the section in bytes,
+// max_token_len, number of duplicates, then (canonical, duplicate) pairs,
+// then (since version 3) the number of stop tokens other than EOS and their ids,
+// then (since version 4, if listed) the number of special tokens and their ids;
+// all u32 LE
+fn serialize_token_stats(
+    bytes: &mut Vec<u8>,
+    max_token_len: usize,
+    token_duplicates: &FxHashMap<TokenId, Vec<TokenId>>,
+    stop_tokens: &[TokenId],
+    special_tokens: Option<&[TokenId]>,
+) {
+    let mut pairs = vec![];
+    for (&canonical, dups) in token_duplicates {
+        for &dup in dups {
+            pairs.push((canonical, dup));
+        }
+    }
+    // stable, so the order of each canonical's duplicates is preserved
+    pairs.sort_by_key(|&(canonical, _)| canonical);
+    let mut words = vec![
+        TokTrieHeader::MAGIC_STATS,
+        0,
+        max_token_len as u32,
+        pairs.len() as u32,
+    ];
+    for (canonical, dup) in pairs {
+        words.push(canonical);
+        words.push(dup);
+    }
+    words.push(stop_tokens.len() as u32);
+    words.extend_from_slice(stop_tokens);
+    if let Some(special_tokens) = special_tokens {
+        words.push(special_tokens.len() as u32);
+        words.extend_from_slice(special_tokens);
+    }
+    words[1] = (words.len() * 4) as u32;
+    for w in words {
+        bytes.extend_from_slice(&w.to_le_bytes());
     }
+}
 
-    pub fn eos_token(&self) -> TokenId {
-        self.info.tok_eos
+/// Stop tokens are the ones other than EOS. `version` is the format version of the file.
+fn parse_token_stats(bytes: &[u8], vocab_size: u32, version: u32) -> Result<StatsSection> {
+    if bytes.is_empty() {
+        ensure!(version < 5, "TokTrie: missing stats section");
+        return Ok((None, Vec::new(), None));
     }
-
-    pub fn vocab_size(&self) -> usize {
-        self.info.vocab_size as usize
+    let min_len = if version >= 5 { 16 } else { 12 };
+    ensure!(
+        bytes.len() % 4 == 0 && bytes.len() >= min_len,
+        "TokTrie: invalid stats section"
+    );
+    let mut words = bytes
+        .chunks(4)
+        .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+        .collect::<Vec<_>>();
+    if version >= 5 {
+        ensure!(
+            words[1] as usize == bytes.len(),
+            "TokTrie: stats section has {} bytes, its size says {}",
+            bytes.len(),
+            words[1]
+        );
+        words.remove(1);
     }
-
-    pub fn alloc_token_set(&self) -> SimpleVob {
-        SimpleVob::alloc_with_capacity(self.vocab_size(), self.vocab_size() + 1)
+    let num_pairs = words[2] as usize;
+    let pairs_end = 3 + 2 * num_pairs;
+    // each list is optional, and starts with its length
+    let list_end = |start: usize| match words.get(start) {
+        Some(&len) => start.checked_add(1 + len as usize),
+        None => Some(start),
+    };
+    let stop_end = list_end(pairs_end);
+    ensure!(
+        stop_end.and_then(list_end) == Some(words.len()),
+        "TokTrie: stats section has {} bytes, expected {} duplicates",
+        bytes.len(),
+        num_pairs
+    );
+    let stop_end = stop_end.unwrap();
+    let stop_tokens = words.get(pairs_end + 1..stop_end).unwrap_or(&[]).to_vec();
+    let special_tokens = words.get(stop_end + 1..).map(|w| w.to_vec());
+    for &t in &stop_tokens {
+        ensure!(t < vocab_size, "TokTrie: invalid stop token {}", t);
     }
-
-    pub fn singleton_token_set(&self, tok: TokenId) -> SimpleVob {
-        let mut r = self.alloc_token_set();
-        r.allow_token(tok);
-        r
+    ensure!(
+        stop_tokens.windows(2).all(|w| w[0] < w[1]),
+        "TokTrie: stop tokens not sorted"
+    );
+    let mut token_duplicates: FxHashMap<TokenId, Vec<TokenId>> = FxHashMap::default();
+    for pair in words[3..pairs_end].chunks(2) {
+        let (canonical, dup) = (pair[0], pair[1]);
+        ensure!(
+            canonical < vocab_size && dup < vocab_size && canonical != dup,
+            "TokTrie: invalid duplicate {} of token {}",
+            dup,
+            canonical
+        );
+        token_duplicates.entry(canonical).or_default().push(dup);
     }
+    Ok((
+        Some((words[1] as usize, token_duplicates)),
+        stop_tokens,
+        special_tokens,
+    ))
+}
 
-    pub fn token_set_dbg(&self, ts: &SimpleVob) -> String {
-        let max_examples = 50;
-
-        let ts_neg = ts.negated();
-        let use_neg = ts_neg.num_set() * 20 < ts.num_set();
-        let ts1 = if use_neg { &ts_neg } else { &ts };
-        let num_set = ts1.num_set();
-        let max_tok = std::cmp::min(max_examples, num_set);
-        let mut token_names = Vec::new();
-        // make sure we include EOS first if it's allowed
-        if ts1.is_allowed(self.info.tok_eos) {
-            token_names.push("EOS".to_string());
-        }
-        for idx in 0..self.vocab_size() {
-            if idx as TokenId != self.info.tok_eos && ts1.is_allowed(idx as TokenId) {
-                token_names.push(self.token_dbg(idx as TokenId));
-                if token_names.len() >= max_tok {
-                    break;
-                }
-            }
+fn token_end_nodes_in(
+    nodes: &[TrieNode],
+    token_duplicates: &FxHashMap<TokenId, Vec<TokenId>>,
+    vocab_size: u32,
+) -> Vec<u32> {
+    let mut res = vec![NO_NODE; vocab_size as usize];
+    for (off, n) in nodes.iter().enumerate() {
+        if let Some(tok) = n.token_id() {
+            res[tok as usize] = off as u32;
         }
-        if token_names.len() < num_set {
-            token_names.push("...".to_string());
+    }
+    for (&canonical, dups) in token_duplicates {
+        for &dup in dups {
+            res[dup as usize] = res[canonical as usize];
         }
-        format!(
-            "TokenSet: {}/{}; {}{}",
-            ts.num_set(),
-            self.vocab_size(),
-            if use_neg { "ALL EXCEPT " } else { "" },
-            token_names.join(", ")
-        )
     }
+    res
+}
 
-    pub fn alloc_logits(&self) -> Vec<f32> {
-        vec![0.0; self.vocab_size() + 1]
+fn canonical_map_in(
+    token_duplicates: &FxHashMap<TokenId, Vec<TokenId>>,
+) -> FxHashMap<TokenId, TokenId> {
+    let mut res = FxHashMap::default();
+    for (&canonical, dups) in token_duplicates {
+        for &dup in dups {
+            res.insert(dup, canonical);
+        }
     }
+    res
+}
 
-    pub fn test_trace_tokens(&self, toks: &[u32]) -> String {
-        toks.iter()
-            .map(|t| {
-                let s = self.token_dbg(*t);
-                if s.starts_with("\"") {
-                    self.token_str(*t)
-                } else {
-                    format!("≺{}≻", s)
-                }
-            })
-            .collect::<Vec<_>>()
-            .join("‧")
+/// Also checks there is an offset for every token, and no more, so that
+/// `vocab_size`, used as a fake token in add_bias_in(), is never a real token.
+/// Returns the length of the longest token.
+fn validate_token_offsets(
+    token_offsets: &[u32],
+    token_data: &[u8],
+    vocab_size: u32,
+) -> core::result::Result<usize, ValidationError> {
+    if token_offsets.len() != vocab_size as usize {
+        return Err(ValidationError::VocabSize {
+            vocab_size,
+            num_tokens: token_offsets.len(),
+        });
     }
-
-    pub fn tokens_dbg(&self, toks: &[u32]) -> String {
-        let joined = toks
-            .iter()
-            .map(|t| {
-                let s = self.token_dbg(*t);
-                if s.starts_with("\"") {
-                    s[1..s.len() - 1].to_string()
-                } else {
-                    format!("≺{}≻", s)
-                }
-            })
-            .collect::<Vec<_>>()
-            .join("‧");
-
-        format!("\"{}\"", joined)
+    let data_len = token_data.len();
+    let mut max_len = 0;
+    for (idx, &desc) in token_offsets.iter().enumerate() {
+        let mut len = (desc & LEN_ESCAPE) as usize;
+        let mut off = (desc >> LEN_BITS) as usize;
+        if len == LEN_ESCAPE as usize {
+            if off + 4 > data_len {
+                return Err(ValidationError::TokenData {
+                    token: idx as TokenId,
+                    start: off,
+                    len: 4,
+                    data_len,
+                });
+            }
+            len = u32::from_le_bytes(token_data[off..off + 4].try_into().unwrap()) as usize;
+            off += 4;
+        }
+        if off + len > data_len {
+            return Err(ValidationError::TokenData {
+                token: idx as TokenId,
+                start: off,
+                len,
+                data_len,
+            });
+        }
+        max_len = core::cmp::max(max_len, len);
     }
+    Ok(max_len)
+}
 
-    pub fn token_dbg(&self, idx: u32) -> String {
-        if idx == self.info.tok_eos {
-            "EOS".to_string()
-        } else if idx as usize >= self.vocab_size() {
-            format!("OOB[{}]", idx)
-        } else {
-            // format!("{:?}[{}]", self.token_str(idx), idx)
-            let bytes = self.token(idx);
-            if bytes.len() > 1 && bytes[0] == TokTrie::SPECIAL_TOKEN_PREFIX_BYTE {
-                String::from_utf8_lossy(&bytes[1..]).to_string()
-            } else {
-                let s = String::from_utf8_lossy(bytes);
-                if s.len() == 0 {
-                    format!("EMPTY[{}]", idx)
-                } else if !s.contains('\u{fffd}') {
-                    format!("{:?}", s)
-                } else {
-                    let bytes = self.token(idx);
-                    format!("HEX[{}]", to_hex_string(bytes))
+/// Checks the trie structure, and returns num_parents of the nodes where it is saturated.
+/// No node is deeper than `max_depth`, the length of the longest token.
+/// Without `check`, only checks the subtree sizes and that token ids are in range,
+/// which computing the other tables from the nodes relies on not to loop
+/// or index out of bounds.
+fn validate_nodes(
+    nodes: &[TrieNode],
+    vocab_size: u32,
+    max_depth: usize,
+    check: bool,
+) -> core::result::Result<FxHashMap<usize, usize>, ValidationError> {
+    if nodes.is_empty() {
+        return Err(ValidationError::NoNodes);
+    }
+    if nodes[0].subtree_size() != nodes.len() {
+        return Err(ValidationError::RootSize {
+            subtree_size: nodes[0].subtree_size(),
+            num_nodes: nodes.len(),
+        });
+    }
+    let mut num_parents_overflow = FxHashMap::default();
+    // for every token, the node it's at, plus one
+    let mut token_node = if check {
+        vec![0; vocab_size as usize]
+    } else {
+        Vec::new()
+    };
+    // (end of subtree, num_parents) of the ancestors of the current node;
+    // the nodes are in depth-first order, so this goes over them without recursion
+    let mut ancestors: Vec<(usize, usize)> = Vec::new();
+    for (off, n) in nodes.iter().enumerate() {
+        while ancestors.last().is_some_and(|a| a.0 <= off) {
+            ancestors.pop();
+        }
+        let (parent_end, num_parents) = match ancestors.last() {
+            // see build_nodes_rec()
+            Some(&(end, np)) if off + n.subtree_size() == end => (end, np + 1),
+            Some(&(end, _)) => (end, 1),
+            None => (nodes.len(), 0),
+        };
+        if let Some(tok) = n.token_id() {
+            if tok >= vocab_size {
+                return Err(ValidationError::TokenOutOfRange {
+                    offset: off,
+                    token: tok,
+                    vocab_size,
+                });
+            }
+            if check {
+                if token_node[tok as usize] != 0 {
+                    return Err(ValidationError::DuplicateToken {
+                        offset: off,
+                        token: tok,
+                        first_offset: token_node[tok as usize] - 1,
+                    });
                 }
+                token_node[tok as usize] = off + 1;
+            }
+        }
+        if n.subtree_size() == 0 || off + n.subtree_size() > parent_end {
+            return Err(ValidationError::SubtreeSize {
+                offset: off,
+                subtree_size: n.subtree_size(),
+            });
+        }
+        if check {
+            if n.num_parents() != core::cmp::min(num_parents, NUM_PARENTS_ESCAPE) {
+                return Err(ValidationError::NumParents {
+                    offset: off,
+                    found: n.num_parents(),
+                    expected: num_parents,
+                });
+            }
+            if ancestors.len() > max_depth {
+                return Err(ValidationError::TooDeep {
+                    offset: off,
+                    depth: ancestors.len(),
+                    max_depth,
+                });
             }
         }
+        if num_parents >= NUM_PARENTS_ESCAPE {
+            num_parents_overflow.insert(off, num_parents);
+        }
+        ancestors.push((off + n.subtree_size(), num_parents));
     }
+    Ok(num_parents_overflow)
+}
 
-    pub fn token_str(&self, idx: u32) -> String {
-        String::from_utf8_lossy(self.token(idx)).to_string()
+/// Allows EOS and the other stop tokens, and the end-of-turn token if there is one,
+/// as the recognizer permits.
+fn allow_end_tokens_in(
+    info: &TokRxInfo,
+    stop_tokens: &[TokenId],
+    r: &mut impl Recognizer,
+    logits: &mut SimpleVob,
+    at_root: bool,
+    eos_mode: EosMode,
+) {
+    let allowed = match eos_mode {
+        EosMode::Never => false,
+        EosMode::Auto => at_root,
+        EosMode::AfterPrefix => true,
+    };
+    if !allowed {
+        return;
     }
-
-    pub fn token(&self, idx: u32) -> &[u8] {
-        if idx >= self.token_offsets.len() as u32 {
-            return &[];
+    // out-of-range EOS or end-of-turn (from with_eos_token() or TokRxInfo) are never allowed
+    let in_range = |tok: TokenId| (tok as usize) < info.vocab_size as usize;
+    if r.special_allowed(SpecialToken::EndOfSentence) {
+        if in_range(info.tok_eos) {
+            logits.allow_token(info.tok_eos);
         }
-        let off = self.token_offsets[idx as usize];
-        let len = off & ((1 << LEN_BITS) - 1);
-        let off = (off >> LEN_BITS) as usize;
-        &self.token_data[off..(off + len as usize)]
-    }
-
-    pub fn decode(&self, tokens: &[TokenId]) -> Vec<u8> {
-        let mut bytes = self.decode_raw(tokens);
-        if bytes.contains(&TokTrie::SPECIAL_TOKEN_PREFIX_BYTE) {
-            bytes.retain(|&b| b != TokTrie::SPECIAL_TOKEN_PREFIX_BYTE);
+        for &tok in stop_tokens {
+            logits.allow_token(tok);
         }
-        bytes
     }
-
-    pub fn decode_raw(&self, tokens: &[TokenId]) -> Vec<u8> {
-        tokens
-            .iter()
-            .flat_map(|t| self.token(*t).to_vec())
-            .collect()
+    if let Some(tok) = info.tok_end_of_turn.filter(|&t| in_range(t)) {
+        if r.special_allowed(SpecialToken::EndOfTurn) {
+            logits.allow_token(tok);
+        }
     }
+}
 
-    pub fn decode_str(&self, tokens: &[TokenId]) -> String {
-        String::from_utf8_lossy(&self.decode(tokens)).to_string()
-    }
+/// token_duplicates, laid out so that apply() only looks at allowed tokens
+/// that have duplicates.
+#[derive(Clone, Default)]
+struct DupIndex {
+    // tokens that have duplicates
+    has_dups: SimpleVob,
+    // (canonical, end of its duplicates in dups), sorted by canonical
+    ranges: Vec<(TokenId, u32)>,
+    dups: Vec<TokenId>,
+}
 
-    pub fn get_special_token(&self, name: &str) -> Option<TokenId> {
-        self.child_at_byte(self.root(), TokTrie::SPECIAL_TOKEN_PREFIX_BYTE)
-            .and_then(|n| {
-                self.child_at_bytes(n, name.as_bytes())
-                    .and_then(|n| n.token_id())
-            })
+impl DupIndex {
+    fn heap_size(&self) -> usize {
+        vob_heap_size(&self.has_dups) + vec_heap_size(&self.ranges) + vec_heap_size(&self.dups)
     }
 
-    pub fn get_special_tokens(&self) -> Vec<TokenId> {
-        let mut res = Vec::new();
-        let pref_node = self
-            .child_at_byte(self.root(), TokTrie::SPECIAL_TOKEN_PREFIX_BYTE)
-            .expect("missing special token prefix");
-        let mut stack = vec![pref_node];
-        while let Some(n) = stack.pop() {
-            for c in self.node_children(n) {
-                if let Some(tok) = c.token_id() {
-                    res.push(tok);
-                }
-                stack.push(c);
-            }
+    fn new(token_duplicates: &FxHashMap<TokenId, Vec<TokenId>>, vocab_size: u32) -> Self {
+        let mut canonical: Vec<TokenId> = token_duplicates.keys().copied().collect();
+        canonical.sort_unstable();
+        let mut has_dups = SimpleVob::alloc(vocab_size as usize);
+        let mut ranges = Vec::with_capacity(canonical.len());
+        let mut dups = Vec::new();
+        for tok in canonical {
+            has_dups.allow_token(tok);
+            dups.extend_from_slice(&token_duplicates[&tok]);
+            ranges.push((tok, dups.len() as u32));
+        }
+        DupIndex {
+            has_dups,
+            ranges,
+            dups,
         }
-        res.remove(0);
-        res
     }
 
-    pub fn greedy_tokenize(&self, bytes: &[u8]) -> Vec<TokenId> {
-        let mut r = Vec::new();
-        if bytes.len() == 0 {
-            return r;
+    fn apply(&self, logits: &mut SimpleVob) {
+        if self.ranges.is_empty() {
+            return;
         }
-
-        let mut n = self.root();
-        let mut last_tok = None;
-        let mut last_idx = 0;
-        let mut idx = 0;
-        while idx < bytes.len() {
-            match self.child_at_byte(n, bytes[idx]) {
-                Some(c) => {
-                    if let Some(tok) = c.token_id() {
-                        last_tok = Some(tok);
-                        last_idx = idx;
-                    }
-                    n = c;
+        let mask = self.has_dups.as_slice();
+        // tokens come in increasing order, and so do ranges
+        let mut ridx = 0;
+        for (widx, &m) in mask.iter().enumerate() {
+            // duplicates are never canonical, so allowing them doesn't change
+            // the following words of logits & mask
+            let mut w = logits.as_slice()[widx] & m;
+            while w != 0 {
+                let tok = (widx * 32 + w.trailing_zeros() as usize) as TokenId;
+                w &= w - 1;
+                while self.ranges[ridx].0 != tok {
+                    ridx += 1;
                 }
-                None => {
-                    r.push(last_tok.unwrap());
-                    idx = last_idx;
-                    n = self.root();
+                let start = if ridx == 0 {
+                    0
+                } else {
+                    self.ranges[ridx - 1].1 as usize
+                };
+                for &dup in &self.dups[start..self.ranges[ridx].1 as usize] {
+                    logits.allow_token(dup);
                 }
             }
-            idx = idx + 1;
         }
-        r.push(last_tok.unwrap());
-        r
     }
+}
 
-    pub fn tokenize_with_greedy_fallback(
-        &self,
-        s: &[u8],
-        str_tokenize: impl FnOnce(&str) -> Vec<TokenId>,
-    ) -> Vec<TokenId> {
-        let utf8_str = String::from_utf8_lossy(s);
-        // if the string ends with a replacement character, remove them
-        let to_tokenize = if utf8_str.ends_with('\u{FFFD}') {
-            utf8_str.trim_end_matches('\u{FFFD}')
-        } else {
-            &utf8_str
-        };
-        let mut r = str_tokenize(to_tokenize);
-        // if we didn't tokenize everything (because of the replacement character)
-        // we tokenize the suffix using greedy tokenizer that is happy with bytes
-        let last_tokenized = to_tokenize.len();
-        if last_tokenized < s.len() {
-            let mut added = self.greedy_tokenize(&s[last_tokenized..]);
-            r.append(&mut added);
-        }
-        r
+/// `TokenProps` of every token, and masks of the common classes.
+#[derive(Clone, Default, PartialEq)]
+struct TokenClasses {
+    props: Vec<TokenProps>,
+    whitespace: SimpleVob,
+    ascii: SimpleVob,
+    // sorted
+    empty: Vec<TokenId>,
+    // per token, for samplers; saturated at u16::MAX, 0 for empty tokens
+    lengths: Vec<u16>,
+    first_bytes: Vec<u8>,
+    last_bytes: Vec<u8>,
+}
+
+impl TokenClasses {
+    fn heap_size(&self) -> usize {
+        vec_heap_size(&self.props)
+            + vob_heap_size(&self.whitespace)
+            + vob_heap_size(&self.ascii)
+            + vec_heap_size(&self.empty)
+            + vec_heap_size(&self.lengths)
+            + vec_heap_size(&self.first_bytes)
+            + vec_heap_size(&self.last_bytes)
     }
 
-    pub fn has_extensions(&self, bytes: &[u8]) -> bool {
-        match self.child_at_bytes(self.root(), bytes) {
-            None => false,
-            Some(n) => n.subtree_size() > 1,
-        }
+    fn new(token_offsets: &[u32], token_data: &[u8], vocab_size: u32) -> Self {
+        TokenClasses::default().extended(token_offsets, token_data, vocab_size)
     }
 
-    pub fn token_id(&self, bytes: &[u8]) -> Option<TokenId> {
-        let (tok, len) = self.prefix_token_id(bytes);
-        // println!("tok_id {:?} {:?} {:?} ", bytes, tok, len);
-        if len == bytes.len() {
-            Some(tok)
-        } else {
-            None
+    /// These classes, with the tokens from `props.len()` up to `vocab_size` added.
+    fn extended(&self, token_offsets: &[u32], token_data: &[u8], vocab_size: u32) -> Self {
+        let mut res = self.clone();
+        res.props.reserve(vocab_size as usize - self.props.len());
+        res.whitespace.resize(vocab_size as usize, false);
+        res.ascii.resize(vocab_size as usize, false);
+        let added = vocab_size as usize - self.props.len();
+        res.lengths.reserve(added);
+        res.first_bytes.reserve(added);
+        res.last_bytes.reserve(added);
+        for tok in self.props.len() as u32..vocab_size {
+            let bytes = token_in(token_offsets, token_data, tok);
+            res.lengths
+                .push(u16::try_from(bytes.len()).unwrap_or(u16::MAX));
+            res.first_bytes.push(bytes.first().copied().unwrap_or(0));
+            res.last_bytes.push(bytes.last().copied().unwrap_or(0));
+            if bytes.is_empty() {
+                res.empty.push(tok);
+            }
+            let props = TokenProps::from_token_bytes(bytes);
+            if props.contains(TokenProps::WHITESPACE_ONLY) {
+                res.whitespace.allow_token(tok);
+            }
+            if props.contains(TokenProps::ASCII_ONLY) {
+                res.ascii.allow_token(tok);
+            }
+            res.props.push(props);
         }
+        res
     }
 
-    pub fn prefix_token_id(&self, bytes: &[u8]) -> (TokenId, usize) {
-        assert!(bytes.len() > 0);
-        let mut last = (0, 0);
-        let mut n = self.root();
-        for (idx, byte) in bytes.iter().enumerate() {
-            n = match self.child_at_byte(n, *byte) {
-                Some(n) => n,
-                None => break,
-            };
-            if let Some(tok) = n.token_id() {
-                last = (tok, idx + 1);
+    /// Drop `TokenProps::IS_SPECIAL` of tokens not in `special_tokens`.
+    fn restrict_special(&mut self, special_tokens: &SimpleVob) {
+        for (tok, p) in self.props.iter_mut().enumerate() {
+            if p.contains(TokenProps::IS_SPECIAL) && !special_tokens.is_allowed(tok as TokenId) {
+                p.remove(TokenProps::IS_SPECIAL);
             }
         }
-        return last;
     }
+}
 
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        let pref = std::mem::size_of::<TokTrieHeader>();
-        let hd: &TokTrieHeader = bytemuck::from_bytes(&bytes[0..pref]);
+/// Nodes with more children than this get a jump table.
+const JUMP_TABLE_MIN_CHILDREN: usize = 16;
 
-        assert!(hd.magic == TokTrieHeader::MAGIC);
-        assert!(hd.hd_size as usize == pref);
+/// Offsets of the children of nodes with many children, indexed by byte,
+/// so that child_at_byte_in() doesn't have to scan them.
+/// 0 means there is no child (the root is never a child).
+#[derive(Clone, Default, PartialEq)]
+struct JumpTables {
+    // nodes that have a table
+    has_table: SimpleVob,
+    // node offset -> index in tables
+    index: FxHashMap<usize, u32>,
+    tables: Vec<[u32; 256]>,
+}
 
-        let trie_end = pref + hd.trie_bytes as usize;
-        let nodes = vec_from_bytes(&bytes[pref..trie_end]);
-        let offsets_end = trie_end + hd.token_offset_bytes as usize;
-        let token_offsets = vec_from_bytes(&bytes[trie_end..offsets_end]);
-        let token_data = vec_from_bytes(&bytes[offsets_end..]);
+impl JumpTables {
+    fn new(nodes: &[TrieNode]) -> Self {
+        JumpTables::with_candidates(nodes, 0..nodes.len())
+    }
 
-        let mut r = TokTrie {
-            info: TokRxInfo::from_bin(&hd.info),
-            token_offsets,
-            token_data,
-            nodes,
-            max_token_len: 0,
-            token_duplicates: FxHashMap::default(),
+    /// Tables for the nodes among `candidates` (in increasing order) with enough children.
+    fn with_candidates(nodes: &[TrieNode], candidates: impl IntoIterator<Item = usize>) -> Self {
+        let mut res = JumpTables {
+            has_table: SimpleVob::alloc(nodes.len()),
+            ..Default::default()
         };
-        r.finalize_ctor();
-        r
+        for off in candidates {
+            if ChildOffsets::new(nodes, off)
+                .nth(JUMP_TABLE_MIN_CHILDREN)
+                .is_none()
+            {
+                continue;
+            }
+            let mut table = [0u32; 256];
+            for child in ChildOffsets::new(nodes, off) {
+                table[nodes[child].byte() as usize] = child as u32;
+            }
+            res.has_table.set(off, true);
+            res.index.insert(off, res.tables.len() as u32);
+            res.tables.push(table);
+        }
+        res
     }
 
-    pub fn max_token_len(&self) -> usize {
-        self.max_token_len
+    fn heap_size(&self) -> usize {
+        vob_heap_size(&self.has_table) + map_heap_size(&self.index) + vec_heap_size(&self.tables)
     }
 
-    fn validate_node(&self, n: &TrieNode, ep: usize, used: &mut [bool]) {
-        if let Some(tok) = n.token_id() {
-            assert!(tok < self.info.vocab_size);
-            assert!(!used[tok as usize]);
-            used[tok as usize] = true;
-        }
-        let endp = self.next_node(n);
-        assert!(endp <= ep);
-        for child in self.node_children(n) {
-            self.validate_node(child, endp, used);
+    #[inline(always)]
+    fn table_at(&self, off: usize) -> Option<&[u32; 256]> {
+        if off < self.has_table.len() && self.has_table.get(off) {
+            Some(&self.tables[self.index[&off] as usize])
+        } else {
+            None
         }
     }
+}
 
-    fn validate(&self) {
-        self.validate_node(
-            self.root(),
-            self.next_node(self.root()),
-            &mut vec![false; self.info.vocab_size as usize],
-        );
-        for idx in 0..self.info.vocab_size {
-            let _ = self.token(idx);
-        }
+/// Token sets passed to the trie must come from `alloc_token_set()` of a trie with
+/// the same vocab size (or be resized to it); anything else gives wrong results.
+#[inline(always)]
+fn check_recognizer_error(r: &mut impl Recognizer) -> Result<()> {
+    match r.get_error() {
+        Some(e) => bail!("TokTrie: recognizer error: {}", e),
+        None => Ok(()),
     }
+}
 
-    pub fn serialize(&self) -> Vec<u8> {
-        let trie_data: &[u8] = bytemuck::cast_slice(&self.nodes);
-        let token_offsets: &[u8] = bytemuck::cast_slice(&self.token_offsets);
-        let token_data: &[u8] = bytemuck::cast_slice(&self.token_data);
-
-        let hd = TokTrieHeader {
-            magic: TokTrieHeader::MAGIC,
-            hd_size: std::mem::size_of::<TokTrieHeader>() as u32,
-            trie_bytes: trie_data.len() as u32,
-            token_offset_bytes: token_offsets.len() as u32,
-            token_data_bytes: trie_data.len() as u32,
-            info: self.info.to_bin(),
-            align: [],
-        };
-
-        let mut bytes = bytemuck::bytes_of(&hd).to_vec();
-        bytes.extend_from_slice(trie_data);
-        bytes.extend_from_slice(token_offsets);
-        bytes.extend_from_slice(token_data);
-        bytes
+fn byte_not_allowed(r: &mut impl Recognizer, byte: u8) -> anyhow::Error {
+    match r.get_error() {
+        Some(e) => anyhow!("byte {:?} not allowed: {}", byte as char, e),
+        None => anyhow!("byte {:?} not allowed", byte as char),
     }
+}
 
-    pub fn root(&self) -> &TrieNode {
-        &self.nodes[0]
-    }
+fn check_token_set_in(vocab_size: usize, ts: &SimpleVob) {
+    try_check_token_set_in(vocab_size, ts).unwrap_or_else(|e| panic!("{}", e))
+}
 
-    pub fn check_against(&self, tokens: &Vec<Vec<u8>>) {
-        let vocab_size = tokens.len();
-        for idx in 0..vocab_size {
-            let bytes = &tokens[idx];
-            let tid = idx as TokenId;
-            assert!(bytes == self.token(tid));
-            let root = self.root();
-            if bytes.len() > 0 {
-                let tid2 = self
-                    .child_at_bytes(root, &bytes)
-                    .unwrap()
-                    .token_id()
-                    .unwrap();
-                if tid != tid2 {
-                    assert!(self.token_duplicates[&tid2].contains(&tid));
-                }
-            }
+fn try_check_token_set_in(vocab_size: usize, ts: &SimpleVob) -> Result<()> {
+    ensure!(
+        ts.len() == vocab_size && ts.capacity() > vocab_size,
+        "TokTrie: token set of size {} (capacity {}) used with vocab size {}; \
+         it needs room for the sentinel token past the vocabulary, \
+         see alloc_token_set() and SimpleVob::resize()",
+        ts.len(),
+        ts.capacity(),
+        vocab_size
+    );
+    Ok(())
+}
+
+/// Distances from each node to the nearest and farthest node with a token below it
+/// (or the node itself), used to skip subtrees in `compute_bias_with_limit()`.
+#[derive(Clone, Default, PartialEq)]
+struct DepthBounds {
+    min_token_depth: Vec<u16>,
+    max_token_depth: Vec<u16>,
+}
+
+impl DepthBounds {
+    fn new(nodes: &[TrieNode]) -> Self {
+        let mut res = DepthBounds {
+            min_token_depth: vec![u16::MAX; nodes.len()],
+            max_token_depth: vec![0; nodes.len()],
+        };
+        // children come after their parent, so go backwards
+        for off in (0..nodes.len()).rev() {
+            res.compute_at(nodes, off);
         }
+        res
     }
 
-    pub fn child_at_byte<'a>(&'a self, n: &'a TrieNode, byte: u8) -> Option<&'a TrieNode> {
-        for child in self.node_children(n) {
-            if child.byte() == byte {
-                return Some(child);
-            }
+    /// Bounds for `nodes`, made from the old ones by `NodeMerge`: the bounds of
+    /// `copied` subtrees are the old ones; `touched` are the other nodes, in order.
+    fn extended(
+        &self,
+        nodes: &[TrieNode],
+        copied: &[(usize, usize, usize)],
+        touched: &[usize],
+    ) -> Self {
+        let mut res = DepthBounds {
+            min_token_depth: vec![u16::MAX; nodes.len()],
+            max_token_depth: vec![0; nodes.len()],
+        };
+        for &(old_off, off, size) in copied {
+            res.min_token_depth[off..off + size]
+                .copy_from_slice(&self.min_token_depth[old_off..old_off + size]);
+            res.max_token_depth[off..off + size]
+                .copy_from_slice(&self.max_token_depth[old_off..old_off + size]);
         }
-        None
+        for &off in touched.iter().rev() {
+            res.compute_at(nodes, off);
+        }
+        res
     }
 
-    pub fn all_subtokens(&self, bytes: &[u8]) -> Vec<TokenId> {
-        let mut r = Vec::new();
-        for i in 0..bytes.len() {
-            let mut n = self.root();
-            for j in i..bytes.len() {
-                n = match self.child_at_byte(n, bytes[j]) {
-                    Some(n) => n,
-                    None => break,
-                };
-                if let Some(tok) = n.token_id() {
-                    r.push(tok);
-                }
+    // needs the bounds of the children
+    fn compute_at(&mut self, nodes: &[TrieNode], off: usize) {
+        let n = &nodes[off];
+        let (mut min, mut max) = if n.token_id().is_some() {
+            (0, 0)
+        } else {
+            (u16::MAX, 0)
+        };
+        for c in ChildOffsets::new(nodes, off) {
+            // skip subtrees without tokens, like the empty leaves under the root
+            if self.min_token_depth[c] == u16::MAX {
+                continue;
             }
+            min = min.min(self.min_token_depth[c].saturating_add(1));
+            max = max.max(self.max_token_depth[c].saturating_add(1));
         }
-        r
+        self.min_token_depth[off] = min;
+        self.max_token_depth[off] = if min == u16::MAX { u16::MAX } else { max };
     }
 
-    pub fn node_children(&self, n: &TrieNode) -> NodeChildren {
-        let off = self.node_offset(n);
-        NodeChildren {
-            trie: self,
-            current_offset: off + 1,
-            end_offset: off + n.subtree_size(),
-        }
+    fn heap_size(&self) -> usize {
+        vec_heap_size(&self.min_token_depth) + vec_heap_size(&self.max_token_depth)
     }
 
-    pub fn child_at_bytes<'a>(&'a self, mut n: &'a TrieNode, bytes: &[u8]) -> Option<&'a TrieNode> {
-        for &byte in bytes {
-            n = match self.child_at_byte(n, byte) {
-                Some(n) => n,
-                None => return None,
+    fn at(&self, off: usize) -> (u16, u16) {
+        (self.min_token_depth[off], self.max_token_depth[off])
+    }
+}
+
+/// Tokens longer than `max_bytes` in `limit` (if given) are not allowed.
+/// `stats`, if given, gets the walk's counts added.
+#[allow(clippy::too_many_arguments)]
+fn add_bias_in(
+    nodes: &[TrieNode],
+    jump_tables: &JumpTables,
+    num_parents_overflow: &FxHashMap<usize, usize>,
+    vocab_size: u32,
+    r: &mut impl Recognizer,
+    toks: &mut SimpleVob,
+    start: &[u8],
+    limit: Option<(usize, &DepthBounds)>,
+    stats: Option<&mut BiasStats>,
+) -> WalkCounters {
+    let root = &nodes[0];
+    // all prefixes of 'start' are also allowed
+    if start.len() > 0 {
+        let max_len = core::cmp::min(start.len(), limit.map_or(usize::MAX, |l| l.0));
+        for len in 1..=max_len {
+            let bytes = &start[0..len];
+            if let Some(tok) =
+                child_at_bytes_in(nodes, jump_tables, root, bytes).and_then(|n| n.token_id())
+            {
+                toks.allow_token(tok);
             }
         }
-        Some(n)
     }
 
-    pub fn compute_bias(&self, r: &mut impl Recognizer, logits: &mut SimpleVob) {
-        self.compute_bias_ext(r, logits, &[]);
+    let n = child_at_bytes_in(nodes, jump_tables, root, start);
+    if n.is_none() {
+        return WalkCounters::default();
     }
+    let off = node_offset_in(nodes, n.unwrap());
+    add_bias_from_in(
+        nodes,
+        num_parents_overflow,
+        vocab_size,
+        r,
+        toks,
+        off,
+        start.len(),
+        limit,
+        stats,
+    )
+}
 
-    pub fn compute_bias_ext(&self, r: &mut impl Recognizer, logits: &mut SimpleVob, start: &[u8]) {
-        logits.set_all(false);
-        if start.is_empty() {
-            // EOS is only allowed if there is no forced byte prefix
-            for tok in vec![SpecialToken::EndOfSentence] {
-                if r.special_allowed(tok) {
-                    logits.allow_token(self.special_token(tok))
-                }
-            }
+/// The walk of `add_bias_in()` below the node at `off`, which is `start_len` bytes deep;
+/// doesn't allow the tokens on the way to it.
+/// All bytes pushed are popped before `trie_finished()`.
+#[allow(clippy::too_many_arguments)]
+fn add_bias_from_in(
+    nodes: &[TrieNode],
+    num_parents_overflow: &FxHashMap<usize, usize>,
+    vocab_size: u32,
+    r: &mut impl Recognizer,
+    toks: &mut SimpleVob,
+    off: usize,
+    start_len: usize,
+    limit: Option<(usize, &DepthBounds)>,
+    stats: Option<&mut BiasStats>,
+) -> WalkCounters {
+    r.trie_started();
+    let range = off + 1..off + nodes[off].subtree_size();
+    let (min_token_depth, budget) = match limit {
+        Some((max_bytes, depth_bounds)) => (
+            &depth_bounds.min_token_depth[..],
+            max_bytes.saturating_sub(start_len),
+        ),
+        None => (&[][..], 0),
+    };
+    let range_end = range.end;
+    let with_stats = stats.is_some();
+    let mut no_stats = BiasStats::default();
+    let stats = stats.unwrap_or(&mut no_stats);
+    let (next_pop, counters) = match (limit.is_some(), with_stats) {
+        (true, true) => add_bias_inner_in::<true, true>(
+            nodes,
+            num_parents_overflow,
+            min_token_depth,
+            vocab_size,
+            r,
+            toks,
+            range,
+            budget,
+            stats,
+        ),
+        (true, false) => add_bias_inner_in::<true, false>(
+            nodes,
+            num_parents_overflow,
+            min_token_depth,
+            vocab_size,
+            r,
+            toks,
+            range,
+            budget,
+            stats,
+        ),
+        (false, true) => add_bias_inner_in::<false, true>(
+            nodes,
+            num_parents_overflow,
+            min_token_depth,
+            vocab_size,
+            r,
+            toks,
+            range,
+            budget,
+            stats,
+        ),
+        (false, false) => add_bias_inner_in::<false, false>(
+            nodes,
+            num_parents_overflow,
+            min_token_depth,
+            vocab_size,
+            r,
+            toks,
+            range,
+            budget,
+            stats,
+        ),
+    };
+    // next_pop of the last node also counts the nodes above the range
+    // whose subtrees end with it: the start node, and maybe some of its ancestors
+    let above = PathOffsets::new(nodes, off)
+        .filter(|&p| p + nodes[p].subtree_size() == range_end)
+        .count();
+    r.pop_bytes(next_pop.saturating_sub(above));
+    r.trie_finished();
+    // revert the fake token, see TokTrie::sentinel_token()
+    let defl_tok = vocab_size;
+    toks.disallow_token(defl_tok);
+    counters
+}
+
+/// What `TokTrie::compute_bias_with_stats()` saw while walking the trie.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BiasStats {
+    /// Tokens in the result, including EOS and duplicates.
+    pub tokens_allowed: usize,
+    pub nodes_visited: usize,
+    /// Most bytes the recognizer accepted in a row, after `start`.
+    pub max_depth_reached: usize,
+    /// Bytes the recognizer accepted right after `start`, that some token continues with;
+    /// see `TokTrie::num_allowed_children()`.
+    pub distinct_first_bytes: usize,
+}
+
+/// Work done by a trie walk in `add_bias()`.
+#[cfg(feature = "metrics")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TrieCounters {
+    pub nodes_visited: usize,
+    /// Bytes accepted by the recognizer.
+    pub bytes_pushed: usize,
+    /// Subtrees skipped because their first byte was rejected (or over the byte limit).
+    pub subtrees_skipped: usize,
+}
+
+/// Limits on the work of `TokTrie::compute_bias_with_budget()`;
+/// the default is unlimited.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WalkBudget {
+    /// Maximum number of trie nodes visited.
+    pub max_nodes: usize,
+    /// Stop the walk at this time; checked every few dozen nodes,
+    /// so a slow recognizer may overshoot it a little.
+    pub deadline: Option<Instant>,
+}
+
+#[cfg(feature = "std")]
+impl Default for WalkBudget {
+    fn default() -> Self {
+        WalkBudget {
+            max_nodes: usize::MAX,
+            deadline: None,
         }
-        self.add_bias(r, logits, start);
-        self.apply_duplicates(logits);
     }
+}
 
-    pub fn apply_duplicates(&self, logits: &mut SimpleVob) {
-        for (tok, dups) in &self.token_duplicates {
-            if logits.is_allowed(*tok) {
-                for &dup in dups {
-                    logits.allow_token(dup);
-                }
-            }
-        }
+#[cfg(feature = "std")]
+impl WalkBudget {
+    pub fn with_max_nodes(self, max_nodes: usize) -> Self {
+        WalkBudget { max_nodes, ..self }
     }
 
-    pub fn append_tokens(&self, r: &mut impl Recognizer, ts: &[TokenId]) -> Result<()> {
-        for t in ts {
-            self.append_token(r, *t)?;
+    pub fn with_deadline(self, deadline: Instant) -> Self {
+        WalkBudget {
+            deadline: Some(deadline),
+            ..self
         }
-        Ok(())
     }
 
-    pub fn append_token(&self, r: &mut impl Recognizer, t: TokenId) -> Result<()> {
-        // println!("append_token: {}", self.token_dbg(t));
-        let bytes = self.token(t);
-        for &byte in bytes {
-            if !r.try_push_byte(byte) {
-                r.collapse();
-                return Err(anyhow::anyhow!("byte {:?} not allowed", byte as char));
-            }
-        }
-        r.collapse();
-        Ok(())
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.with_deadline(Instant::now() + timeout)
     }
+}
 
-    pub fn token_allowed(&self, r: &mut impl Recognizer, t: TokenId) -> bool {
-        let bytes = self.token(t);
-        let mut num = 0;
-        let mut ok = true;
-        r.trie_started();
-        for &byte in bytes {
-            if r.try_push_byte(byte) {
-                num += 1;
-            } else {
-                ok = false;
-                break;
-            }
+/// Result of `TokTrie::compute_bias_with_budget()`.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WalkOutcome {
+    /// The budget ran out before the whole trie was walked.
+    pub truncated: bool,
+    pub nodes_visited: usize,
+}
+
+/// Counts the work of add_bias_inner_in(); empty unless the `metrics` feature is on,
+/// so that the counting compiles away.
+#[derive(Clone, Copy, Default)]
+struct WalkCounters {
+    #[cfg(feature = "metrics")]
+    counters: TrieCounters,
+}
+
+impl WalkCounters {
+    #[inline(always)]
+    fn visited(&mut self) {
+        #[cfg(feature = "metrics")]
+        {
+            self.counters.nodes_visited += 1;
         }
-        r.pop_bytes(num);
-        r.trie_finished();
-        ok
     }
 
-    /// Return how many tokens and bytes need to chopped off tokens,
-    /// so that we do not limit all possible future tokenizations matching the recognizer.
-    pub fn chop_tokens(&self, r: &mut impl Recognizer, tokens: &[TokenId]) -> (usize, usize) {
-        let mut suff = Vec::new();
-        let mut chop_tokens = 0;
-        let mut chop_bytes = 0;
-        for (idx, t) in tokens.iter().rev().enumerate() {
-            suff.splice(0..0, self.token(*t).iter().cloned());
-            if suff.len() > self.max_token_len() {
-                break;
-            }
-            if self.has_valid_extensions(r, &suff) {
-                chop_tokens = idx + 1;
-                chop_bytes = suff.len();
-            }
+    #[inline(always)]
+    fn pushed(&mut self) {
+        #[cfg(feature = "metrics")]
+        {
+            self.counters.bytes_pushed += 1;
         }
-        (chop_tokens, chop_bytes)
     }
 
-    /// Check if add_bias() would have returned any tokens.
-    #[inline(never)]
-    pub fn has_valid_extensions(&self, r: &mut impl Recognizer, start: &[u8]) -> bool {
-        let n = self.child_at_bytes(self.root(), start);
-        if n.is_none() {
-            return false;
-        }
-        let n = n.unwrap();
-        r.trie_started();
-        let off = self.node_offset(n);
-        let mut p = off + 1;
-        let endp = off + n.subtree_size();
-        let mut ok = false;
-        let mut next_pop = 0;
-        while p < endp {
-            r.pop_bytes(next_pop);
-            let n = &self.nodes[p];
-            let b = n.byte();
-            if r.try_push_byte(b) {
-                if n.token_id().is_some() {
-                    ok = true;
-                    break;
-                }
-                next_pop = if n.subtree_size() == 1 {
-                    n.num_parents()
-                } else {
-                    0
-                };
-                p += 1;
-            } else {
-                p += n.subtree_size();
-                next_pop = n.num_parents() - 1;
-            }
-        }
-        if start.len() == 0 {
-            // if start was non-empty, trie_finished() is supposed to clean this up
-            r.pop_bytes(next_pop);
+    #[inline(always)]
+    fn skipped(&mut self) {
+        #[cfg(feature = "metrics")]
+        {
+            self.counters.subtrees_skipped += 1;
         }
-        r.trie_finished();
-        ok
     }
 
-    pub fn add_bias(&self, r: &mut impl Recognizer, toks: &mut SimpleVob, start: &[u8]) {
-        // all prefixes of 'start' are also allowed
-        if start.len() > 0 {
-            for len in 1..=start.len() {
-                let bytes = &start[0..len];
-                if let Some(tok) = self.token_id(bytes) {
-                    toks.allow_token(tok);
-                }
-            }
+    #[cfg(feature = "rayon")]
+    fn add(&mut self, _other: &WalkCounters) {
+        #[cfg(feature = "metrics")]
+        {
+            self.counters.nodes_visited += _other.counters.nodes_visited;
+            self.counters.bytes_pushed += _other.counters.bytes_pushed;
+            self.counters.subtrees_skipped += _other.counters.subtrees_skipped;
         }
+    }
+}
 
-        let n = self.child_at_bytes(self.root(), start);
-        if n.is_none() {
-            return;
+/// The counters of the last walk, kept in the trie; empty unless the `metrics` feature is on.
+#[derive(Default)]
+struct LastWalk {
+    #[cfg(feature = "metrics")]
+    nodes_visited: AtomicUsize,
+    #[cfg(feature = "metrics")]
+    bytes_pushed: AtomicUsize,
+    #[cfg(feature = "metrics")]
+    subtrees_skipped: AtomicUsize,
+}
+
+impl LastWalk {
+    #[inline(always)]
+    fn store(&self, _counters: &WalkCounters) {
+        #[cfg(feature = "metrics")]
+        {
+            let c = &_counters.counters;
+            self.nodes_visited.store(c.nodes_visited, Ordering::Relaxed);
+            self.bytes_pushed.store(c.bytes_pushed, Ordering::Relaxed);
+            self.subtrees_skipped
+                .store(c.subtrees_skipped, Ordering::Relaxed);
         }
-        let n = n.unwrap();
-        r.trie_started();
-        let next_pop = self.add_bias_inner(r, toks, n);
-        if start.len() == 0 {
-            // if start was non-empty, trie_finished() is supposed to clean this up
-            r.pop_bytes(next_pop);
+    }
+
+    #[cfg(feature = "metrics")]
+    fn load(&self) -> TrieCounters {
+        TrieCounters {
+            nodes_visited: self.nodes_visited.load(Ordering::Relaxed),
+            bytes_pushed: self.bytes_pushed.load(Ordering::Relaxed),
+            subtrees_skipped: self.subtrees_skipped.load(Ordering::Relaxed),
         }
-        r.trie_finished();
-        // revert the fake token
-        let defl_tok = self.vocab_size() as u32;
-        toks.disallow_token(defl_tok);
     }
+}
 
-    #[inline(never)]
-    fn add_bias_inner(&self, r: &mut impl Recognizer, toks: &mut SimpleVob, n: &TrieNode) -> usize {
-        let defl_tok = self.vocab_size() as u32;
-        let off = self.node_offset(n);
-        let mut p = off + 1;
-        let endp = off + n.subtree_size();
-        let mut next_pop = 0;
-        while p < endp {
-            r.pop_bytes(next_pop);
-            let n = &self.nodes[p];
-            let b = n.byte();
-            if r.try_push_byte(b) {
-                toks.allow_token(n.token_id().unwrap_or(defl_tok));
-                next_pop = if n.subtree_size() == 1 {
-                    n.num_parents()
-                } else {
-                    0
-                };
-                p += 1;
+impl Clone for LastWalk {
+    fn clone(&self) -> Self {
+        let r = LastWalk::default();
+        #[cfg(feature = "metrics")]
+        r.store(&WalkCounters {
+            counters: self.load(),
+        });
+        r
+    }
+}
+
+/// Walks the sibling subtrees in `range`; returns the number of bytes left to pop.
+/// With `LIMIT`, only `budget` more bytes can be pushed below the parent of the range,
+/// and subtrees where `min_token_depth` says no token fits are skipped.
+/// With `STATS`, the walk is counted in `stats`.
+#[inline(never)]
+#[allow(clippy::too_many_arguments)]
+fn add_bias_inner_in<const LIMIT: bool, const STATS: bool>(
+    nodes: &[TrieNode],
+    num_parents_overflow: &FxHashMap<usize, usize>,
+    min_token_depth: &[u16],
+    vocab_size: u32,
+    r: &mut impl Recognizer,
+    toks: &mut SimpleVob,
+    range: Range<usize>,
+    mut budget: usize,
+    stats: &mut BiasStats,
+) -> (usize, WalkCounters) {
+    let defl_tok = vocab_size;
+    let mut p = range.start;
+    let endp = range.end;
+    let mut next_pop = 0;
+    let mut counters = WalkCounters::default();
+    // for STATS: nodes in skipped subtrees that were not visited,
+    // bytes pushed below the parent of the range, and the most of these
+    let num_nodes = endp - p;
+    let (mut not_visited, mut depth, mut max_depth, mut first_bytes) = (0, 0, 0, 0);
+    while p < endp {
+        r.pop_bytes(next_pop);
+        if LIMIT {
+            budget += next_pop;
+        }
+        if STATS {
+            depth -= next_pop;
+        }
+        let n = &nodes[p];
+        let b = n.byte();
+        counters.visited();
+        if (!LIMIT || budget > min_token_depth[p] as usize) && r.try_push_byte(b) {
+            counters.pushed();
+            if LIMIT {
+                budget -= 1;
+            }
+            if STATS {
+                // not the empty leaves of nodes with 256 children
+                if depth == 0 && (n.token_id().is_some() || n.subtree_size() > 1) {
+                    first_bytes += 1;
+                }
+                depth += 1;
+            }
+            toks.allow_token(n.token_id().unwrap_or(defl_tok));
+            next_pop = if n.subtree_size() == 1 {
+                if STATS {
+                    max_depth = core::cmp::max(max_depth, depth);
+                }
+                num_parents_in(nodes, num_parents_overflow, p)
             } else {
-                p += n.subtree_size();
-                next_pop = n.num_parents() - 1;
+                0
+            };
+            p += 1;
+        } else {
+            counters.skipped();
+            if STATS {
+                // the parent was pushed at this depth
+                max_depth = core::cmp::max(max_depth, depth);
+                not_visited += n.subtree_size() - 1;
             }
+            next_pop = num_parents_in(nodes, num_parents_overflow, p) - 1;
+            p += n.subtree_size();
         }
-        next_pop
     }
+    if STATS {
+        stats.nodes_visited += num_nodes - not_visited;
+        stats.max_depth_reached = core::cmp::max(stats.max_depth_reached, max_depth);
+        stats.distinct_first_bytes += first_bytes;
+    }
+    (next_pop, counters)
+}
 
-    pub fn sorted_tokens(&self) -> Vec<(u32, Vec<u8>)> {
-        let mut res = vec![];
-        let n = self.root();
-        let off = self.node_offset(n);
-        let mut p = off + 1;
-        let endp = off + n.subtree_size();
-        let mut next_pop = 0;
-        let mut bytes = vec![];
-        while p < endp {
-            bytes.drain(bytes.len() - next_pop..);
-            let n = &self.nodes[p];
-            let b = n.byte();
-            bytes.push(b);
-            if let Some(t) = n.token_id() {
-                res.push((t, bytes.clone()));
-            }
+/// How often `add_bias_budget_in()` checks the deadline, in nodes.
+#[cfg(feature = "std")]
+const DEADLINE_CHECK_INTERVAL: usize = 64;
+
+/// Like `add_bias_inner_in::<false, false>()`, but stops before visiting a node once `budget`
+/// is exhausted. Returns the number of bytes left to pop, also when truncated.
+#[cfg(feature = "std")]
+fn add_bias_budget_in(
+    nodes: &[TrieNode],
+    num_parents_overflow: &FxHashMap<usize, usize>,
+    vocab_size: u32,
+    r: &mut impl Recognizer,
+    toks: &mut SimpleVob,
+    range: Range<usize>,
+    budget: &WalkBudget,
+) -> (usize, WalkCounters, WalkOutcome) {
+    let defl_tok = vocab_size;
+    let mut p = range.start;
+    let endp = range.end;
+    let mut next_pop = 0;
+    // bytes pushed and not yet popped
+    let mut depth = 0;
+    let mut counters = WalkCounters::default();
+    let mut outcome = WalkOutcome::default();
+    while p < endp {
+        if outcome.nodes_visited >= budget.max_nodes
+            || (outcome.nodes_visited % DEADLINE_CHECK_INTERVAL == 0
+                && budget.deadline.is_some_and(|d| Instant::now() >= d))
+        {
+            outcome.truncated = true;
+            break;
+        }
+        r.pop_bytes(next_pop);
+        depth -= next_pop;
+        let n = &nodes[p];
+        let b = n.byte();
+        outcome.nodes_visited += 1;
+        counters.visited();
+        if r.try_push_byte(b) {
+            counters.pushed();
+            depth += 1;
+            toks.allow_token(n.token_id().unwrap_or(defl_tok));
             next_pop = if n.subtree_size() == 1 {
-                n.num_parents()
+                num_parents_in(nodes, num_parents_overflow, p)
             } else {
                 0
             };
             p += 1;
+        } else {
+            counters.skipped();
+            next_pop = num_parents_in(nodes, num_parents_overflow, p) - 1;
+            p += n.subtree_size();
         }
-        res
     }
+    (depth, counters, outcome)
+}
 
-    fn count_until_depth(&self, depth: usize) -> (usize, usize) {
-        let mut count = 0;
-        let mut num_tokens = 0;
-        let mut stack = vec![(self.root(), 0)];
-        while let Some((n, d)) = stack.pop() {
-            if d == depth {
-                continue;
+/// Like `add_bias_inner_in::<false, false>()` over all the nodes below the root,
+/// but skips, without asking `r`, the subtrees not marked in `subtree_has_candidate`.
+fn add_bias_within_in(
+    nodes: &[TrieNode],
+    num_parents_overflow: &FxHashMap<usize, usize>,
+    vocab_size: u32,
+    r: &mut impl Recognizer,
+    toks: &mut SimpleVob,
+    subtree_has_candidate: &SimpleVob,
+) -> (usize, WalkCounters) {
+    let defl_tok = vocab_size;
+    let mut p = 1;
+    let endp = nodes.len();
+    let mut next_pop = 0;
+    let mut counters = WalkCounters::default();
+    while p < endp {
+        r.pop_bytes(next_pop);
+        let n = &nodes[p];
+        let b = n.byte();
+        counters.visited();
+        if subtree_has_candidate.get(p) && r.try_push_byte(b) {
+            counters.pushed();
+            toks.allow_token(n.token_id().unwrap_or(defl_tok));
+            next_pop = if n.subtree_size() == 1 {
+                num_parents_in(nodes, num_parents_overflow, p)
             } else {
-                for c in self.node_children(n) {
-                    count += 1;
-                    if c.token_id().is_some() {
-                        num_tokens += 1;
-                    }
-                    stack.push((c, d + 1));
-                }
-            }
+                0
+            };
+            p += 1;
+        } else {
+            counters.skipped();
+            next_pop = num_parents_in(nodes, num_parents_overflow, p) - 1;
+            p += n.subtree_size();
         }
-        (count, num_tokens)
     }
+    (next_pop, counters)
+}
 
-    pub fn trie_stats(&self) -> String {
-        let mut nodes_histogram = vec![0; 256];
-
-        let mut token_nodes = 0;
-
-        let n = self.root();
-        let off = self.node_offset(n);
-        let mut p = off + 1;
-        let endp = off + n.subtree_size();
-        while p < endp {
-            let n = &self.nodes[p];
-
-            if n.token_id().is_some() {
-                token_nodes += 1;
-            }
-
-            let last_ch = self.next_node(n);
-            let mut ch_p = p + 1;
-            let mut num_children = 0;
-
-            while ch_p < last_ch {
-                let ch = &self.nodes[ch_p];
-                ch_p += ch.subtree_size();
-                num_children += 1;
-            }
-
-            nodes_histogram[std::cmp::min(9, num_children)] += 1;
+/// Builds the trie nodes for non-empty `words` directly from the sorted tokens.
+/// When several tokens have the same bytes, the node gets the last one,
+/// or the first with `keep_first_duplicate`.
+fn build_nodes(words: &[Vec<u8>], keep_first_duplicate: bool) -> Result<Vec<TrieNode>> {
+    let mut entries = words
+        .iter()
+        .enumerate()
+        .filter(|(_, w)| !w.is_empty())
+        .map(|(idx, w)| (&w[..], idx as TokenId))
+        .collect::<Vec<_>>();
+    entries.sort_unstable();
+    if keep_first_duplicate {
+        entries.dedup_by_key(|e| e.0);
+    } else {
+        // dedup_by() keeps the first of a run
+        entries.reverse();
+        entries.dedup_by_key(|e| e.0);
+        entries.reverse();
+    }
+    let mut data = Vec::new();
+    // validate_nodes() finds these again
+    let mut num_parents_overflow = FxHashMap::default();
+    build_nodes_rec(&entries, 0, 0xff, 0, &mut data, &mut num_parents_overflow)?;
+    Ok(data)
+}
 
-            p += 1;
-        }
+/// Sets the subtree size of the node at `idx`, the nodes after it being its subtree.
+fn set_subtree_size(data: &mut [TrieNode], idx: usize) -> Result<()> {
+    let subtree_size = data.len() - idx;
+    // can't happen while MAX_TOKEN_DATA_LEN < MAX_SUBTREE_SIZE
+    ensure!(
+        subtree_size <= MAX_SUBTREE_SIZE,
+        "TokTrie: subtree of {} nodes is too large; limit is {}",
+        subtree_size,
+        MAX_SUBTREE_SIZE
+    );
+    data[idx].bits2 |= (subtree_size as u32) << 8;
+    Ok(())
+}
 
-        let mut histogram = String::new();
+/// Emits the trie nodes for `entries`, which are sorted by bytes without repeats,
+/// and all share their first `depth` bytes; `byte` is the last of these.
+/// The num_parents that don't fit in the nodes go to `num_parents_overflow`.
+fn build_nodes_rec(
+    mut entries: &[(&[u8], TokenId)],
+    mut depth: usize,
+    mut byte: u8,
+    mut num_parents: usize,
+    data: &mut Vec<TrieNode>,
+    num_parents_overflow: &mut FxHashMap<usize, usize>,
+) -> Result<()> {
+    // Only children are emitted in this loop rather than by recursion, so that
+    // long tokens don't overflow the stack; these are the nodes of the chain.
+    let mut chain = Vec::new();
+    let (rest, groups) = loop {
+        chain.push(data.len());
+        let (token_id, rest) = match entries.first() {
+            Some(&(word, tok)) if word.len() == depth => (tok, &entries[1..]),
+            _ => (NO_TOKEN, entries),
+        };
+        push_node(data, num_parents_overflow, byte, token_id, num_parents);
 
-        if false {
-            for (idx, num) in nodes_histogram.iter().enumerate() {
-                if *num > 0 {
-                    if !histogram.is_empty() {
-                        histogram.push_str(", ");
-                    }
-                    histogram.push_str(&format!("{}:{}", idx, num));
-                }
-            }
+        // ranges of rest with the same next byte
+        let mut groups = Vec::new();
+        let mut start = 0;
+        while start < rest.len() {
+            let b = rest[start].0[depth];
+            let end = start + rest[start..].partition_point(|e| e.0[depth] == b);
+            groups.push((b, start..end));
+            start = end;
         }
-
-        if false {
-            for n in self.node_children(self.root()) {
-                histogram.push_str(&format!(
-                    "\n{} => {} {}",
-                    n.byte(),
-                    self.node_children(n).count(),
-                    n.subtree_size()
-                ));
-            }
+        if groups.len() != 1 {
+            break (rest, groups);
         }
+        let (b, range) = groups.pop().unwrap();
+        entries = &rest[range];
+        depth += 1;
+        byte = b;
+        num_parents += 1;
+    };
 
-        if false {
-            for depth in 0..30 {
-                let (count, num_tokens) = self.count_until_depth(depth);
-                histogram.push_str(&format!(
-                    "\ndepth {}: {} nodes {} tokens",
-                    depth, count, num_tokens
-                ));
+    // Nodes with more than 250 children get all 256, the missing ones as empty leaves;
+    // this is the layout tries have always had, and the serialized form depends on it.
+    if groups.len() > 250 {
+        let mut groups = groups.into_iter().peekable();
+        for b in 0..=255u8 {
+            let np = if b == 255 { num_parents + 1 } else { 1 };
+            match groups.next_if(|g| g.0 == b) {
+                Some((_, range)) => {
+                    build_nodes_rec(&rest[range], depth + 1, b, np, data, num_parents_overflow)?
+                }
+                None => push_empty_leaf(data, num_parents_overflow, b, np)?,
             }
         }
-
-        if histogram.len() > 0 {
-            histogram = format!("\n{}", histogram);
+    } else {
+        let num_groups = groups.len();
+        for (i, (b, range)) in groups.into_iter().enumerate() {
+            let np = if i + 1 == num_groups {
+                num_parents + 1
+            } else {
+                1
+            };
+            build_nodes_rec(&rest[range], depth + 1, b, np, data, num_parents_overflow)?;
         }
-
-        format!(
-            "{}{} nodes, {} token nodes, {} token bytes, {} max len",
-            histogram,
-            self.nodes.len(),
-            token_nodes,
-            self.token_data.len(),
-            self.max_token_len,
-        )
     }
-}
 
-pub struct NodeChildren<'a> {
-    trie: &'a TokTrie,
-    current_offset: usize,
-    end_offset: usize,
+    for idx in chain {
+        set_subtree_size(data, idx)?;
+    }
+    Ok(())
 }
 
-impl<'a> Iterator for NodeChildren<'a> {
-    type Item = &'a TrieNode;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.current_offset < self.end_offset {
-            let node = &self.trie.nodes[self.current_offset];
-            self.current_offset += node.subtree_size();
-            Some(node)
-        } else {
-            None
-        }
+fn push_node(
+    data: &mut Vec<TrieNode>,
+    num_parents_overflow: &mut FxHashMap<usize, usize>,
+    byte: u8,
+    token_id: u32,
+    num_parents: usize,
+) {
+    if num_parents >= NUM_PARENTS_ESCAPE {
+        num_parents_overflow.insert(data.len(), num_parents);
     }
+    data.push(TrieNode::new(byte, token_id, num_parents));
 }
 
-struct TrieHash {
-    token_id: u32,
+/// Nodes with more than 250 children get the missing ones as these.
+fn push_empty_leaf(
+    data: &mut Vec<TrieNode>,
+    num_parents_overflow: &mut FxHashMap<usize, usize>,
     byte: u8,
-    children: Vec<TrieHash>,
+    num_parents: usize,
+) -> Result<()> {
+    let idx = data.len();
+    push_node(data, num_parents_overflow, byte, NO_TOKEN, num_parents);
+    set_subtree_size(data, idx)
 }
 
-impl TrieHash {
-    fn new(byte: u8) -> TrieHash {
-        TrieHash {
-            token_id: NO_TOKEN,
-            byte,
-            children: Vec::new(),
+/// Builds the nodes of a trie with tokens added to `old`, copying the subtrees
+/// without new tokens, so that `TrieData::extended()` can copy what's derived from them.
+struct NodeMerge<'a> {
+    old: &'a TrieData,
+    // old nodes in num_parents_overflow, sorted
+    old_overflow: Vec<usize>,
+    nodes: Vec<TrieNode>,
+    num_parents_overflow: FxHashMap<usize, usize>,
+    // (offset in old, offset in nodes, size) of the copied subtrees, in order
+    copied: Vec<(usize, usize, usize)>,
+}
+
+impl<'a> NodeMerge<'a> {
+    fn new(old: &'a TrieData, capacity: usize) -> Self {
+        let mut old_overflow = old.num_parents_overflow.keys().copied().collect::<Vec<_>>();
+        old_overflow.sort_unstable();
+        NodeMerge {
+            old,
+            old_overflow,
+            nodes: Vec::with_capacity(capacity),
+            num_parents_overflow: FxHashMap::default(),
+            copied: Vec::new(),
         }
     }
-    fn insert(&mut self, word: &[u8], token_id: u32) {
-        if word.len() == 0 {
-            // Some tokenizers have duplicate tokens...
-            // we just override
-            // assert!(self.token_id == NO_TOKEN);
-            self.token_id = token_id;
-        } else {
-            if self.children.len() == 0x100 {
-                // assert!(self.children[word[0] as usize].byte == word[0]);
-                self.children[word[0] as usize].insert(&word[1..], token_id);
-                return;
-            }
 
-            for ch in &mut self.children {
-                if ch.byte == word[0] {
-                    ch.insert(&word[1..], token_id);
-                    return;
-                }
+    /// Like `build_nodes_rec()`, but merges `entries` into the subtree at `old_off`
+    /// in the old nodes, if there is one.
+    /// Tokens in `entries` replace the old ones with the same bytes.
+    fn merge_rec(
+        &mut self,
+        old_off: Option<usize>,
+        entries: &[(&[u8], TokenId)],
+        depth: usize,
+        byte: u8,
+        num_parents: usize,
+    ) -> Result<()> {
+        let old_off = match old_off {
+            None => {
+                return build_nodes_rec(
+                    entries,
+                    depth,
+                    byte,
+                    num_parents,
+                    &mut self.nodes,
+                    &mut self.num_parents_overflow,
+                )
             }
+            Some(off) if entries.is_empty() => {
+                self.copy_subtree(off, num_parents);
+                return Ok(());
+            }
+            Some(off) => off,
+        };
+        let old = &self.old.nodes[..];
+        let idx = self.nodes.len();
+        let (token_id, rest) = match entries.first() {
+            Some(&(word, tok)) if word.len() == depth => (tok, &entries[1..]),
+            _ => (old[old_off].token_id().unwrap_or(NO_TOKEN), entries),
+        };
+        push_node(
+            &mut self.nodes,
+            &mut self.num_parents_overflow,
+            byte,
+            token_id,
+            num_parents,
+        );
 
-            let mut ch = TrieHash::new(word[0]);
-            ch.insert(&word[1..], token_id);
-            self.children.push(ch);
+        // old children (without empty leaves), merged with the ranges of rest
+        // with the same next byte
+        let mut children: Vec<(u8, Option<usize>, Range<usize>)> = Vec::new();
+        let mut old_children = ChildOffsets::new(old, old_off)
+            .filter(|&c| old[c].token_id().is_some() || old[c].subtree_size() > 1)
+            .map(|c| (old[c].byte(), c))
+            .collect::<Vec<_>>();
+        // the root of a reordered() trie has them in another order
+        old_children.sort_unstable_by_key(|c| c.0);
+        let mut old_children = old_children.into_iter().peekable();
+        let mut start = 0;
+        while start < rest.len() {
+            let b = rest[start].0[depth];
+            while let Some((ob, off)) = old_children.next_if(|c| c.0 < b) {
+                children.push((ob, Some(off), start..start));
+            }
+            let end = start + rest[start..].partition_point(|e| e.0[depth] == b);
+            let off = old_children.next_if(|c| c.0 == b).map(|c| c.1);
+            children.push((b, off, start..end));
+            start = end;
+        }
+        children.extend(old_children.map(|(ob, off)| (ob, Some(off), start..start)));
 
-            // if it's getting dense, make it full
-            // for cl100k threshold 60->15 nodes, 50->22, 40->45 30->94
-            // for llama (32k) 50->5, 40->15
-            // TODO remove this?
-            if self.children.len() > 250 {
-                let mut v2 = (0..=255).map(TrieHash::new).collect::<Vec<_>>();
-                for ch in self.children.drain(..) {
-                    let idx = ch.byte as usize;
-                    v2[idx] = ch;
+        // see build_nodes_rec()
+        if children.len() > 250 {
+            let mut children = children.into_iter().peekable();
+            for b in 0..=255u8 {
+                let np = if b == 255 { num_parents + 1 } else { 1 };
+                match children.next_if(|c| c.0 == b) {
+                    Some((_, off, range)) => self.merge_rec(off, &rest[range], depth + 1, b, np)?,
+                    None => {
+                        push_empty_leaf(&mut self.nodes, &mut self.num_parents_overflow, b, np)?
+                    }
                 }
-                self.children = v2;
+            }
+        } else {
+            let num_children = children.len();
+            for (i, (b, off, range)) in children.into_iter().enumerate() {
+                let np = if i + 1 == num_children {
+                    num_parents + 1
+                } else {
+                    1
+                };
+                self.merge_rec(off, &rest[range], depth + 1, b, np)?;
             }
         }
+
+        set_subtree_size(&mut self.nodes, idx)
     }
-    fn serialize(&mut self, data: &mut Vec<TrieNode>, num_parents: u8) {
-        let idx = data.len();
-        let mut num_ch = self.children.len();
-        data.push(TrieNode::new(self.byte, self.token_id, num_parents));
-        self.children.sort_by_key(|e| e.byte);
-        for entry in &mut self.children {
-            num_ch -= 1;
-            entry.serialize(data, if num_ch == 0 { num_parents + 1 } else { 1 });
+
+    /// Copies the old subtree at `off`, giving its root `num_parents`;
+    /// the last node on each level below changes by as much as the root.
+    fn copy_subtree(&mut self, off: usize, num_parents: usize) {
+        let old = self.old;
+        let start = self.nodes.len();
+        let size = old.nodes[off].subtree_size();
+        self.nodes.extend_from_slice(&old.nodes[off..off + size]);
+        self.copied.push((off, start, size));
+        for &p in offsets_in(&self.old_overflow, off..off + size) {
+            self.num_parents_overflow
+                .insert(start + p - off, old.num_parents_overflow[&p]);
+        }
+
+        let old_num_parents = num_parents_in(&old.nodes, &old.num_parents_overflow, off);
+        if old_num_parents == num_parents {
+            return;
+        }
+        let mut p = off;
+        loop {
+            let np = num_parents_in(&old.nodes, &old.num_parents_overflow, p) - old_num_parents
+                + num_parents;
+            let q = start + p - off;
+            self.nodes[q].set_num_parents(np);
+            if np >= NUM_PARENTS_ESCAPE {
+                self.num_parents_overflow.insert(q, np);
+            } else {
+                self.num_parents_overflow.remove(&q);
+            }
+            let endp = p + old.nodes[p].subtree_size();
+            p += 1;
+            if p == endp {
+                break;
+            }
+            while p + old.nodes[p].subtree_size() < endp {
+                p += old.nodes[p].subtree_size();
+            }
         }
-        data[idx].bits2 |= ((data.len() - idx) as u32) << 8;
     }
 }
+
+/// The elements of sorted `offsets` in `range`.
+fn offsets_in(offsets: &[usize], range: Range<usize>) -> &[usize] {
+    let start = offsets.partition_point(|&o| o < range.start);
+    let end = offsets.partition_point(|&o| o < range.end);
+    &offsets[start..end]
+}
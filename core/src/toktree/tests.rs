@@ -0,0 +1,4113 @@
+use alloc::vec::Vec;
+
+use super::*;
+use crate::{
+    recognizer::{AnyOfRecognizer, CharClassRecognizer, FnRecognizer, StackRecognizer},
+    recognizer_check::{check_peek_byte, RecognizerOp, RecordingRecognizer},
+    rng::Rng,
+    testing::synthetic_vocab,
+};
+
+fn synthetic_trie(size: usize, seed: usize) -> TokTrie {
+    let words = synthetic_vocab(size, seed);
+    TokTrie::from(&TokRxInfo::new(size as u32, 0), &words)
+}
+
+fn trie_of(words: &[&[u8]]) -> TokTrie {
+    let words = words.iter().map(|w| w.to_vec()).collect::<Vec<_>>();
+    TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words)
+}
+
+// accepts about `percent` percent of the bytes after any given prefix, always the same ones
+fn random_recognizer(
+    seed: u64,
+    percent: u64,
+) -> FnRecognizer<impl Fn(&[u8], u8) -> bool + Clone + Send> {
+    FnRecognizer::new(move |bytes: &[u8], b: u8| random_byte_allowed(seed, percent, bytes, b))
+}
+
+fn random_byte_allowed(seed: u64, percent: u64, bytes: &[u8], b: u8) -> bool {
+    let mut h = 0xcbf29ce484222325u64 ^ seed;
+    for &x in bytes.iter().chain(core::iter::once(&b)) {
+        h = (h ^ x as u64).wrapping_mul(0x100000001b3);
+    }
+    (h >> 40) % 100 < percent
+}
+
+#[test]
+fn borrowed_trie_matches_owned() {
+    let trie = synthetic_trie(3000, 1);
+    let bytes = trie.serialize();
+    let borrowed = [
+        TokTrieRef::try_from_bytes(&bytes).unwrap(),
+        TokTrieRef::from_bytes_unchecked(&bytes).unwrap(),
+    ];
+    for r in &borrowed {
+        assert_eq!(r.vocab_size(), trie.vocab_size());
+        assert_eq!(r.max_token_len(), trie.max_token_len());
+        r.validate().unwrap();
+        for t in 0..trie.vocab_size() as TokenId {
+            assert_eq!(r.token(t), trie.token(t));
+            assert_eq!(r.is_special_token(t), trie.is_special_token(t));
+            assert_eq!(r.token_id(trie.token(t)), trie.token_id(trie.token(t)));
+        }
+        for b in 0..=255u8 {
+            let a = r
+                .child_at_byte(r.root(), b)
+                .map(|n| (n.byte(), n.token_id()));
+            let e = trie
+                .child_at_byte(trie.root(), b)
+                .map(|n| (n.byte(), n.token_id()));
+            assert_eq!(a, e);
+        }
+        for seed in 0..8 {
+            let mut expected = trie.alloc_token_set();
+            trie.compute_bias(&mut random_recognizer(seed, 70), &mut expected);
+            let mut actual = r.alloc_token_set();
+            r.compute_bias(&mut random_recognizer(seed, 70), &mut actual);
+            assert_eq!(actual, expected);
+        }
+    }
+    let owned = borrowed[1].clone().into_owned();
+    assert_eq!(owned.serialize(), bytes);
+}
+
+#[test]
+fn unchecked_load_defers_validation() {
+    let trie = trie_of(&[b"a", b"b", b"ab", b"abc"]);
+    let mut bytes = trie.serialize();
+    let (_, [nodes, ..], _) = TokTrieHeader::parse(&bytes).unwrap();
+    // num_parents of the last node
+    bytes[nodes.end - 4] ^= 1;
+
+    assert!(TokTrie::try_from_bytes(&bytes).is_err());
+    assert!(TokTrieRef::try_from_bytes(&bytes).is_err());
+    let unchecked = TokTrie::from_bytes_unchecked(&bytes).unwrap();
+    assert!(matches!(
+        unchecked.validate(),
+        Err(ValidationError::NumParents { .. })
+    ));
+    let unchecked = TokTrieRef::from_bytes_unchecked(&bytes).unwrap();
+    assert!(matches!(
+        unchecked.validate(),
+        Err(ValidationError::NumParents { .. })
+    ));
+}
+
+#[test]
+fn long_token() {
+    let long = vec![b'x'; 4096];
+    let words = vec![b"y".to_vec(), long.clone(), b"x".to_vec(), b"xx".to_vec()];
+    let trie = TokTrie::from(&TokRxInfo::new(4, 0), &words);
+    let bytes = trie.serialize();
+    let loaded = TokTrie::try_from_bytes(&bytes).unwrap();
+    let borrowed = TokTrieRef::try_from_bytes(&bytes).unwrap();
+    for t in [&trie, &loaded] {
+        assert_eq!(t.max_token_len(), 4096);
+        assert_eq!(t.token(1), &long[..]);
+        assert_eq!(t.token(2), b"x");
+        assert_eq!(t.token_id(&long), Some(1));
+        assert_eq!(t.greedy_tokenize(&long), vec![1]);
+    }
+    assert_eq!(borrowed.max_token_len(), 4096);
+    assert_eq!(borrowed.token(1), &long[..]);
+    assert_eq!(loaded.serialize(), bytes);
+
+    let mut r = FnRecognizer::new(|_: &[u8], _: u8| true);
+    for n in [1, 100, 4095] {
+        let mut tokens = vec![0];
+        tokens.extend(core::iter::repeat(2).take(n));
+        assert_eq!(loaded.chop_tokens(&mut r, &tokens), (n, n));
+    }
+    // nothing extends the long token
+    assert_eq!(loaded.chop_tokens(&mut r, &[0, 1]), (0, 0));
+
+    // merged into the existing chain of nodes
+    let mut longer = long.clone();
+    longer.extend_from_slice(b"yy");
+    let added = trie.with_added_tokens(&[(longer.clone(), false)]).unwrap();
+    assert_eq!(added.max_token_len(), 4098);
+    assert_eq!(added.token_id(&longer), Some(4));
+    assert_eq!(added.token_id(&long), Some(1));
+    assert_eq!(added.chop_tokens(&mut r, &[0, 1]), (1, 4096));
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn compute_bias_parallel_matches_sequential() {
+    for (size, seed) in [(300, 1), (2000, 2), (8000, 3)] {
+        let trie = synthetic_trie(size, seed);
+        for (i, percent) in [0, 5, 50, 90, 100].into_iter().enumerate() {
+            let r = random_recognizer(seed as u64 * 10 + i as u64, percent);
+            let mut expected = trie.alloc_token_set();
+            trie.compute_bias(&mut r.clone(), &mut expected);
+            let mut actual = trie.alloc_token_set();
+            // left over from a previous call
+            actual.set_all(true);
+            trie.compute_bias_parallel(&r, &mut actual);
+            assert_eq!(actual, expected, "size {} percent {}", size, percent);
+        }
+    }
+}
+
+#[test]
+fn and_or_recognizer_masks() {
+    let trie = synthetic_trie(4000, 4);
+    for seed in 0..6 {
+        let (pa, pb) = [(90, 90), (70, 95), (100, 50), (50, 50), (0, 80), (95, 0)][seed];
+        let a = || random_recognizer(2 * seed as u64, pa);
+        let b = || random_recognizer(2 * seed as u64 + 1, pb);
+        let mut mask_a = trie.alloc_token_set();
+        trie.compute_bias(&mut a(), &mut mask_a);
+        let mut mask_b = trie.alloc_token_set();
+        trie.compute_bias(&mut b(), &mut mask_b);
+
+        let mut expected = mask_a.clone();
+        expected.and(&mask_b);
+        let mut and = AndRecognizer::new(a(), b());
+        let mut actual = trie.alloc_token_set();
+        trie.compute_bias(&mut and, &mut actual);
+        assert_eq!(actual, expected, "and, seed {}", seed);
+        let (ra, rb) = and.into_inner();
+        assert!(ra.bytes().is_empty() && rb.bytes().is_empty());
+
+        let mut expected = mask_a.clone();
+        expected.or(&mask_b);
+        let mut or = OrRecognizer::new(a(), b());
+        trie.compute_bias(&mut or, &mut actual);
+        assert_eq!(actual, expected, "or, seed {}", seed);
+        let (ra, rb) = or.into_inner();
+        assert!(ra.bytes().is_empty() && rb.bytes().is_empty());
+    }
+}
+
+#[test]
+fn and_or_recognizer_stack_discipline() {
+    let trie = synthetic_trie(1000, 5);
+    let mut rng = Rng::new(5);
+    let a = || random_recognizer(10, 80);
+    let b = || random_recognizer(11, 80);
+    crate::recognizer_check::check_recognizer_stack_discipline(
+        &trie,
+        &mut AndRecognizer::new(a(), b()),
+        &mut rng,
+        200,
+    )
+    .unwrap();
+    crate::recognizer_check::check_recognizer_stack_discipline(
+        &trie,
+        &mut OrRecognizer::new(a(), b()),
+        &mut rng,
+        200,
+    )
+    .unwrap();
+}
+
+type ByteFn = fn(&[u8], u8) -> bool;
+type EosFn = fn(&[u8]) -> bool;
+
+// a accepts "ab..." and b accepts "ac...", so they disagree at the second byte
+fn ab_ac() -> (FnRecognizer<ByteFn>, FnRecognizer<ByteFn>) {
+    fn accept(bytes: &[u8], b: u8, second: u8) -> bool {
+        match bytes.len() {
+            0 => b == b'a',
+            1 => b == second,
+            _ => true,
+        }
+    }
+    let a: ByteFn = |bytes, b| accept(bytes, b, b'b');
+    let b: ByteFn = |bytes, b| accept(bytes, b, b'c');
+    (FnRecognizer::new(a), FnRecognizer::new(b))
+}
+
+#[test]
+fn and_recognizer_pops_rejected_byte() {
+    let (a, b) = ab_ac();
+    let mut r = AndRecognizer::new(a, b);
+    r.trie_started();
+    assert!(r.try_push_byte(b'a'));
+    // a accepts, b rejects; a is popped back
+    assert!(!r.try_push_byte(b'b'));
+    assert!(!r.try_push_byte(b'c'));
+    assert_eq!(r.try_push_bytes(b"bx"), 0);
+    r.pop_bytes(1);
+    r.trie_finished();
+    let (a, b) = r.into_inner();
+    assert_eq!(a.bytes(), b"");
+    assert_eq!(b.bytes(), b"");
+
+    let words: Vec<Vec<u8>> = vec![
+        b"a".to_vec(),
+        b"ab".to_vec(),
+        b"ac".to_vec(),
+        b"abd".to_vec(),
+    ];
+    let trie = TokTrie::from(&TokRxInfo::new(4, 0), &words);
+    let (a, b) = ab_ac();
+    let mut r = AndRecognizer::new(a, b);
+    let mut mask = trie.alloc_token_set();
+    trie.compute_bias(&mut r, &mut mask);
+    assert_eq!(mask.iter().collect::<Vec<_>>(), vec![0]);
+}
+
+#[test]
+fn or_recognizer_pop_and_collapse() {
+    let (a, b) = ab_ac();
+    let mut r = OrRecognizer::new(a, b);
+    assert!(r.try_push_byte(b'a'));
+    // only b is alive after "ac"
+    assert!(r.try_push_byte(b'c'));
+    assert!(r.try_push_byte(b'x'));
+    {
+        let (a, b) = (&r.a, &r.b);
+        assert_eq!(a.bytes(), b"a");
+        assert_eq!(b.bytes(), b"acx");
+    }
+    // popping "cx" brings a back
+    r.pop_bytes(2);
+    assert_eq!(r.a.bytes(), b"a");
+    assert_eq!(r.b.bytes(), b"a");
+    assert!(r.try_push_byte(b'b'));
+    assert_eq!(r.a.bytes(), b"ab");
+    assert_eq!(r.b.bytes(), b"a");
+    // b is rejected at the point of collapse(), and stays so
+    r.collapse();
+    assert!(r.try_push_byte(b'y'));
+    r.pop_bytes(1);
+    assert_eq!(r.a.bytes(), b"ab");
+    assert_eq!(r.b.bytes(), b"a");
+
+    let words: Vec<Vec<u8>> = vec![
+        b"a".to_vec(),
+        b"ab".to_vec(),
+        b"ac".to_vec(),
+        b"ad".to_vec(),
+    ];
+    let trie = TokTrie::from(&TokRxInfo::new(4, 0), &words);
+    let (a, b) = ab_ac();
+    let mut r = OrRecognizer::new(a, b);
+    let mut mask = trie.alloc_token_set();
+    trie.compute_bias(&mut r, &mut mask);
+    assert_eq!(mask.iter().collect::<Vec<_>>(), vec![0, 1, 2]);
+
+    // a walk after some bytes leaves which recognizer is alive below it as it was
+    assert!(r.try_push_byte(b'a'));
+    assert!(r.try_push_byte(b'c'));
+    trie.compute_bias(&mut r, &mut mask);
+    assert_eq!(r.alive.len(), 3);
+    r.pop_bytes(1);
+    assert_eq!(r.a.bytes(), b"a");
+    assert_eq!(r.b.bytes(), b"a");
+    assert!(r.try_push_byte(b'b'));
+    assert_eq!(r.a.bytes(), b"ab");
+}
+
+#[test]
+fn duplicate_tokens() {
+    // 3 has the bytes of 0, 4 and 6 those of 2
+    let trie = trie_of(&[b"a", b"b", b"ab", b"a", b"ab", b"c", b"ab"]);
+    let loaded = TokTrie::from_bytes(&trie.serialize());
+    for trie in [&trie, &loaded] {
+        for t in 0..trie.vocab_size() as TokenId {
+            let c = trie.canonical_token(t);
+            assert_eq!(trie.token(c), trie.token(t));
+            assert_eq!(trie.canonical_token(c), c);
+            assert_eq!(trie.token_id(trie.token(t)), Some(c));
+            assert_eq!(trie.duplicates_of(t), trie.duplicates_of(c));
+            assert!(!trie.duplicates_of(t).contains(&c));
+            assert_eq!(trie.duplicates_of(t).contains(&t), t != c);
+        }
+        for (t, dups) in [(0, 1), (1, 0), (2, 2), (5, 0)] {
+            assert_eq!(trie.duplicates_of(t).len(), dups);
+        }
+        let ab = trie.canonical_token(2);
+        assert_eq!(trie.canonical_token(4), ab);
+        assert_eq!(trie.canonical_token(6), ab);
+
+        let tokens = [0, 3, 2, 4, 6, 5, 1];
+        let canonical = trie.canonicalize_tokens(&tokens);
+        assert_eq!(trie.decode(&canonical), trie.decode(&tokens));
+        assert_eq!(trie.decode(&tokens), b"aaabababcb");
+        assert!(canonical.iter().all(|&t| trie.canonical_token(t) == t));
+        assert_eq!(trie.greedy_tokenize(b"ab"), vec![ab]);
+
+        // all duplicates get allowed along with the canonical token
+        let mut r = FnRecognizer::new(|bytes: &[u8], _: u8| bytes.is_empty());
+        let mut mask = trie.alloc_token_set();
+        trie.compute_bias(&mut r, &mut mask);
+        assert_eq!(mask.iter().collect::<Vec<_>>(), vec![0, 1, 3, 5]);
+    }
+}
+
+#[test]
+fn literal_masks() {
+    // 7 is a duplicate of 2
+    let trie = trie_of(&[
+        b"\xff<eos>",
+        b"a",
+        b"ab",
+        b"abc",
+        b"b",
+        b"ba",
+        b"c",
+        b"ab",
+        b"x",
+    ]);
+    let toks = |v: &SimpleVob| v.iter().collect::<Vec<_>>();
+    let lits: &[&[u8]] = &[b"abc", b"ba", b""];
+    assert_eq!(
+        toks(&trie.mask_for_literals(lits, true)),
+        [1, 2, 3, 4, 5, 7]
+    );
+    assert_eq!(toks(&trie.mask_for_literals(lits, false)), [3, 5]);
+    assert_eq!(toks(&trie.mask_for_literals(&[b"ab"], false)), [2, 7]);
+    // "abcd" has no token past "abc"
+    assert!(trie.mask_for_literals(&[b"abcd"], false).is_zero());
+    assert_eq!(
+        toks(&trie.mask_for_literals(&[b"abcd"], true)),
+        [1, 2, 3, 7]
+    );
+    assert!(trie.mask_for_literals(&[b""], true).is_zero());
+    assert!(trie.mask_for_literals(&[], true).is_zero());
+
+    let advance = |lits: &[&[u8]], consumed: &[u8]| {
+        let (mask, matched) = trie.advance_literals(lits, consumed);
+        (toks(&mask), matched)
+    };
+    assert_eq!(advance(lits, b""), (alloc::vec![1, 2, 3, 4, 5, 7], true));
+    assert_eq!(advance(lits, b"a"), (alloc::vec![4], false));
+    assert_eq!(advance(lits, b"ab"), (alloc::vec![6], false));
+    assert_eq!(advance(lits, b"abc"), (alloc::vec![], true));
+    assert_eq!(advance(lits, b"b"), (alloc::vec![1], false));
+    assert_eq!(advance(lits, b"x"), (alloc::vec![], false));
+    assert_eq!(advance(&[b"abc", b"ab"], b"ab"), (alloc::vec![6], true));
+    assert_eq!(advance(&[b"abcd"], b"ab"), (alloc::vec![6], false));
+    assert_eq!(advance(&[b"abcd"], b"abc"), (alloc::vec![], false));
+}
+
+#[test]
+fn node_paths() {
+    for trie in [
+        trie_of(&[b"a", b"b", b"ab", b"abc", b"abd", b"b\xff", b"ca"]),
+        synthetic_trie(2000, 6),
+    ] {
+        let root = trie.root();
+        assert_eq!(trie.node_path(root), b"");
+        for off in 0..root.subtree_size() {
+            let n = trie.node_at_offset(off);
+            assert_eq!(trie.node_offset(n), off);
+            let path = trie.node_path(n);
+            let found = trie.child_at_bytes(root, &path).unwrap();
+            assert_eq!(trie.node_offset(found), off, "path {:?}", path);
+            if let Some(t) = n.token_id() {
+                assert_eq!(path, trie.token(t));
+            }
+        }
+    }
+}
+
+#[test]
+fn eos_modes() {
+    let trie = trie_of(&[b"\xff<eos>", b"a", b"ab", b"abc", b"b", b"ba"]);
+    let allowed = |r: &mut FnRecognizer<ByteFn, EosFn>, start: &[u8], mode: EosMode| {
+        let mut mask = trie.alloc_token_set();
+        trie.compute_bias_ext_eos(r, &mut mask, start, mode);
+        mask.iter().collect::<Vec<_>>()
+    };
+    // special tokens start with 0xff, which is never valid UTF-8
+    let any: ByteFn = |_, b| b != TokTrie::SPECIAL_TOKEN_PREFIX_BYTE;
+    let eos_ok: EosFn = |_| true;
+    let no_eos: EosFn = |_| false;
+    let mut r = FnRecognizer::new(any).with_eos(eos_ok);
+    let mut r_no_eos = FnRecognizer::new(any).with_eos(no_eos);
+
+    assert_eq!(allowed(&mut r, b"", EosMode::Auto), vec![0, 1, 2, 3, 4, 5]);
+    assert_eq!(allowed(&mut r, b"", EosMode::Never), vec![1, 2, 3, 4, 5]);
+    assert_eq!(
+        allowed(&mut r, b"", EosMode::AfterPrefix),
+        vec![0, 1, 2, 3, 4, 5]
+    );
+    assert_eq!(
+        allowed(&mut r_no_eos, b"", EosMode::Auto),
+        vec![1, 2, 3, 4, 5]
+    );
+
+    // the tokens starting with "a", and "a" itself
+    assert_eq!(allowed(&mut r, b"a", EosMode::Auto), vec![1, 2, 3]);
+    assert_eq!(allowed(&mut r, b"a", EosMode::Never), vec![1, 2, 3]);
+    assert_eq!(
+        allowed(&mut r, b"a", EosMode::AfterPrefix),
+        vec![0, 1, 2, 3]
+    );
+    assert_eq!(
+        allowed(&mut r_no_eos, b"a", EosMode::AfterPrefix),
+        vec![1, 2, 3]
+    );
+    // compute_bias_ext() is Auto
+    let mut mask = trie.alloc_token_set();
+    trie.compute_bias_ext(&mut r, &mut mask, b"a");
+    assert_eq!(mask.iter().collect::<Vec<_>>(), vec![1, 2, 3]);
+
+    // token healing: "b" "a" -> "b" + any token starting with "a", or the end
+    let (num_tokens, num_bytes) = trie.chop_tokens(&mut r, &[4, 1]);
+    assert_eq!((num_tokens, num_bytes), (1, 1));
+    let start = trie.decode_raw(&[1]);
+    assert_eq!(
+        allowed(&mut r, &start, EosMode::AfterPrefix),
+        vec![0, 1, 2, 3]
+    );
+
+    // the recognizer forces "ab"; with "a" already generated, only "b" is left
+    let forced: ByteFn = |bytes, b| bytes.len() < 2 && b == b"ab"[bytes.len()];
+    let eos_at_end: EosFn = |bytes| bytes.len() == 2;
+    let mut r = FnRecognizer::new(forced).with_eos(eos_at_end);
+    assert_eq!(trie.forced_bytes(&mut r), b"ab");
+    assert_eq!(allowed(&mut r, b"", EosMode::Auto), vec![1, 2]);
+    assert!(r.try_push_byte(b'a'));
+    assert_eq!(allowed(&mut r, b"", EosMode::Auto), vec![4]);
+    assert!(r.try_push_byte(b'b'));
+    assert_eq!(allowed(&mut r, b"", EosMode::Auto), vec![0]);
+}
+
+// the bug compute_bias_ext_eos() fixes: the healed prefix is a complete token,
+// after which the recognizer allows EOS
+#[test]
+fn eos_after_healed_prefix() {
+    let trie = trie_of(&[b"\xff<eos>", b"a", b"ab", b"abc", b"b", b"ba"]);
+    let allowed = |r: &mut FnRecognizer<ByteFn, EosFn>, start: &[u8], mode: EosMode| {
+        let mut mask = trie.alloc_token_set();
+        trie.compute_bias_ext_eos(r, &mut mask, start, mode);
+        mask.iter().collect::<Vec<_>>()
+    };
+    // "ab" or "abc"
+    let abc: ByteFn = |bytes, b| b"abc".get(bytes.len()) == Some(&b);
+    let mut r = FnRecognizer::new(abc).with_eos((|bytes| bytes.len() >= 2) as EosFn);
+    // the prompt "a" "b" was generated, so the recognizer is past it
+    let prompt = [1, 4];
+    trie.append_tokens(&mut r, &prompt).unwrap();
+    assert!(r.special_allowed(SpecialToken::EndOfSentence));
+    assert!(trie.forced_bytes(&mut r).is_empty());
+    // "ab" can be retokenized as "a" "b", "ab" or "abc"
+    let heal = trie.heal_tokens(&mut r, &prompt);
+    assert_eq!((heal.keep_tokens, &heal.forced_prefix[..]), (0, &b"ab"[..]));
+    assert_eq!(
+        allowed(&mut r, &heal.forced_prefix, EosMode::AfterPrefix),
+        [0, 1, 2, 3]
+    );
+    // without EOS, the sequence couldn't end with what was generated
+    assert_eq!(
+        allowed(&mut r, &heal.forced_prefix, EosMode::Auto),
+        [1, 2, 3]
+    );
+
+    // only "abc": "c" is forced, and EOS waits for it
+    let mut r = FnRecognizer::new(abc).with_eos((|bytes| bytes.len() == 3) as EosFn);
+    trie.append_tokens(&mut r, &prompt).unwrap();
+    assert_eq!(trie.forced_bytes(&mut r), b"c");
+    let heal = trie.heal_tokens(&mut r, &prompt);
+    assert_eq!(heal.forced_prefix, b"ab");
+    assert_eq!(
+        allowed(&mut r, &heal.forced_prefix, EosMode::AfterPrefix),
+        [1, 2, 3]
+    );
+    // with the forced byte pushed, nothing is left to heal, and EOS is allowed
+    assert!(r.try_push_byte(b'c'));
+    assert_eq!(trie.chop_tokens(&mut r, &[3]), (0, 0));
+    assert_eq!(allowed(&mut r, b"", EosMode::AfterPrefix), [0]);
+}
+
+#[test]
+fn consume_and_check_bytes() {
+    let trie = trie_of(&[b"a", b"b"]);
+    let only_a: ByteFn = |_, b| b == b'a';
+    for (bytes, num) in [(&b"bbb"[..], 0), (b"aab", 2), (b"aaa", 3), (b"", 0)] {
+        let mut r = RecordingRecognizer::new(FnRecognizer::new(only_a));
+        assert_eq!(trie.check_bytes(&mut r, bytes), num);
+        assert_eq!(r.inner().bytes(), b"");
+        assert_eq!(r.depth(), 0);
+        assert_eq!(r.ops().first(), Some(&RecognizerOp::TrieStarted));
+        assert_eq!(r.ops().last(), Some(&RecognizerOp::TrieFinished(0)));
+
+        r.clear_ops();
+        assert_eq!(trie.consume_bytes(&mut r, bytes), num);
+        if num == bytes.len() {
+            // all pushed and collapsed, as in append_token()
+            assert_eq!(r.inner().bytes(), bytes);
+            assert_eq!(r.ops().last(), Some(&RecognizerOp::Collapse));
+            assert_eq!(r.depth(), 0);
+        } else {
+            assert_eq!(r.inner().bytes(), b"");
+            assert!(!r.ops().contains(&RecognizerOp::Collapse));
+        }
+        assert!(r.violation().is_none());
+    }
+}
+
+// synthetic_vocab() with about 30% of the tokens after the single bytes
+// replaced by copies of other tokens
+fn trie_with_duplicates(size: usize, seed: usize) -> TokTrie {
+    let mut words = synthetic_vocab(size, seed);
+    let mut rng = Rng::new(seed);
+    for t in 256..size {
+        if rng.gen_up_to(9) < 3 {
+            words[t] = words[rng.gen_up_to(size - 1)].clone();
+        }
+    }
+    TokTrie::from(&TokRxInfo::new(size as u32, 0), &words)
+}
+
+#[test]
+fn apply_duplicates_matches_map_walk() {
+    let trie = trie_with_duplicates(20_000, 7);
+    let dups = &trie.data.token_duplicates;
+    assert!(dups.values().map(|d| d.len()).sum::<usize>() > 4000);
+    let mut rng = Rng::new(7);
+    for density in [1, 2, 10, 1000, 20_000] {
+        let mut mask = trie.alloc_token_set();
+        for t in 0..trie.vocab_size() as TokenId {
+            if rng.gen_up_to(density - 1) == 0 {
+                mask.allow_token(t);
+            }
+        }
+        // what apply_duplicates() did before DupIndex
+        let mut expected = mask.clone();
+        for (&canonical, dups) in dups.iter() {
+            if expected.is_allowed(canonical) {
+                for &d in dups {
+                    expected.allow_token(d);
+                }
+            }
+        }
+        trie.apply_duplicates(&mut mask);
+        assert_eq!(mask, expected, "density {}", density);
+    }
+}
+
+// any bytes but the special token prefix, and the given special tokens
+struct AllowSpecials(&'static [SpecialToken]);
+
+impl crate::recognizer::FunctionalRecognizer<()> for AllowSpecials {
+    fn initial(&self) {}
+
+    fn try_append(&self, _state: (), byte: u8) -> Option<()> {
+        (byte != TokTrie::SPECIAL_TOKEN_PREFIX_BYTE).then_some(())
+    }
+
+    fn special_allowed(&self, _state: (), tok: SpecialToken) -> bool {
+        self.0.contains(&tok)
+    }
+}
+
+#[test]
+fn end_of_turn_token() {
+    let words = [
+        b"\xff<eos>",
+        b"\xff<eot>",
+        b"\xff<bos>",
+        b"a\0\0\0\0\0",
+        b"b\0\0\0\0\0",
+    ]
+    .map(|w| {
+        w.iter()
+            .copied()
+            .take_while(|&b| b != 0)
+            .collect::<Vec<_>>()
+    });
+    let plain = TokTrie::from(&TokRxInfo::new(5, 0), &words.to_vec());
+    let info = TokRxInfo {
+        tok_end_of_turn: Some(1),
+        tok_bos: Some(2),
+        tok_unk: Some(4),
+        ..TokRxInfo::new(5, 0)
+    };
+    let with_eot = TokTrie::from(&info, &words.to_vec());
+
+    assert_eq!(plain.try_special_token(SpecialToken::EndOfTurn), None);
+    assert_eq!(
+        plain.try_special_token(SpecialToken::BeginningOfSentence),
+        None
+    );
+    for trie in [&plain, &with_eot] {
+        assert_eq!(trie.special_token(SpecialToken::EndOfSentence), 0);
+        assert_eq!(trie.eos_token(), 0);
+        assert_eq!(trie.try_special_token(SpecialToken::Separator), None);
+        assert_eq!(trie.try_special_token(SpecialToken::Padding), None);
+    }
+    assert_eq!(with_eot.special_token(SpecialToken::EndOfTurn), 1);
+    assert_eq!(with_eot.special_token(SpecialToken::BeginningOfSentence), 2);
+    assert_eq!(with_eot.special_token(SpecialToken::Unknown), 4);
+
+    let mask = |trie: &TokTrie, specials: &'static [SpecialToken]| {
+        let mut r = StackRecognizer::from(AllowSpecials(specials));
+        let mut mask = trie.alloc_token_set();
+        trie.compute_bias(&mut r, &mut mask);
+        mask.iter().collect::<Vec<_>>()
+    };
+    let eos: &[SpecialToken] = &[SpecialToken::EndOfSentence];
+    let eot: &[SpecialToken] = &[SpecialToken::EndOfTurn];
+    let both: &[SpecialToken] = &[SpecialToken::EndOfSentence, SpecialToken::EndOfTurn];
+    // recognizers that only know about EOS see no change
+    assert_eq!(mask(&plain, eos), vec![0, 3, 4]);
+    assert_eq!(mask(&with_eot, eos), vec![0, 3, 4]);
+    let mut r = FnRecognizer::new(|_: &[u8], b: u8| b != TokTrie::SPECIAL_TOKEN_PREFIX_BYTE);
+    let mut fn_mask = with_eot.alloc_token_set();
+    with_eot.compute_bias(&mut r, &mut fn_mask);
+    assert_eq!(fn_mask.iter().collect::<Vec<_>>(), vec![0, 3, 4]);
+
+    assert_eq!(mask(&plain, both), vec![0, 3, 4]);
+    assert_eq!(mask(&plain, eot), vec![3, 4]);
+    assert_eq!(mask(&with_eot, both), vec![0, 1, 3, 4]);
+    assert_eq!(mask(&with_eot, eot), vec![1, 3, 4]);
+}
+
+#[test]
+fn subtree_size_limit() {
+    let mut data = vec![TrieNode::new(0, 0, 0); MAX_SUBTREE_SIZE + 1];
+    let err = set_subtree_size(&mut data, 0).unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("subtree of 16777216 nodes is too large"));
+    assert_eq!(data[0].subtree_size(), 0);
+
+    set_subtree_size(&mut data, 1).unwrap();
+    assert_eq!(data[1].subtree_size(), MAX_SUBTREE_SIZE);
+}
+
+#[test]
+fn trie_stats() {
+    let trie = trie_of(&[b"a", b"ab", b"abc", b"b", b"ba", b"bb", b"ab"]);
+    let stats = trie.stats_with_depth(4);
+    assert_eq!(stats.num_nodes, 7);
+    assert_eq!(stats.num_token_nodes, 6);
+    assert_eq!(stats.token_data_bytes, 13);
+    assert_eq!(stats.max_token_len, 3);
+    assert_eq!(stats.num_duplicates, 1);
+    assert_eq!(stats.children_histogram, [3, 2, 1, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(stats.depth_histogram, vec![(2, 2), (3, 3), (1, 1), (0, 0)]);
+    assert_eq!(
+        stats.to_string(),
+        "depth 1: 2 nodes 2 tokens\n\
+         depth 2: 3 nodes 3 tokens\n\
+         depth 3: 1 nodes 1 tokens\n\
+         depth 4: 0 nodes 0 tokens\n\
+         7 nodes, 6 token nodes, 13 token bytes, 3 max len"
+    );
+
+    let shallow = trie.stats();
+    assert!(shallow.depth_histogram.is_empty());
+    assert_eq!(
+        TrieStats {
+            depth_histogram: stats.depth_histogram.clone(),
+            ..shallow
+        },
+        stats
+    );
+    assert_eq!(trie.trie_stats(), shallow.to_string());
+}
+
+#[test]
+fn subtrie_snapshot() {
+    let words: Vec<Vec<u8>> = [
+        &b"\xff<eos>"[..],
+        b"the",
+        b"th",
+        b"then",
+        b"there",
+        b"a",
+        b"thx",
+        b"\xff<eot>",
+        b"the!",
+        b"theres",
+    ]
+    .iter()
+    .map(|w| w.to_vec())
+    .collect();
+    let info = TokRxInfo {
+        tok_end_of_turn: Some(7),
+        tok_bos: Some(8),
+        tok_pad: Some(5),
+        ..TokRxInfo::new(words.len() as u32, 0)
+    };
+    let trie = TokTrie::from(&info, &words);
+
+    let (sub, old_ids) = trie.subtrie(b"the").unwrap();
+    let all = (0..sub.vocab_size() as TokenId).collect::<Vec<_>>();
+    assert_eq!(old_ids, vec![0, 7, 3, 4, 8, 9]);
+    assert_eq!(sub.tokens_dbg(&all), "\"≺EOS≻‧≺EMPTY[1]≻‧n‧re‧!‧res\"");
+    assert_eq!(
+        format!("{:?}", sub.info()),
+        "TokRxInfo { vocab_size: 6, tok_eos: 0, tok_bos: Some(4), \
+         tok_pad: None, tok_unk: None, tok_end_of_turn: Some(1) }"
+    );
+
+    let (sub, old_ids) = trie.subtrie(b"").unwrap();
+    let all = (0..sub.vocab_size() as TokenId).collect::<Vec<_>>();
+    assert_eq!(old_ids, vec![0, 7, 1, 2, 3, 4, 5, 6, 8, 9]);
+    assert_eq!(
+        sub.tokens_dbg(&all),
+        "\"≺EOS≻‧≺<eot>≻‧the‧th‧then‧there‧a‧thx‧the!‧theres\""
+    );
+    assert_eq!(
+        format!("{:?}", sub.info()),
+        "TokRxInfo { vocab_size: 10, tok_eos: 0, tok_bos: Some(8), \
+         tok_pad: Some(6), tok_unk: None, tok_end_of_turn: Some(1) }"
+    );
+
+    assert!(trie.subtrie(b"theres").is_none());
+    assert!(trie.subtrie(b"b").is_none());
+
+    // compute_bias() on the subtrie, mapped back
+    for seed in 0..20 {
+        let mut r = random_recognizer(seed, 60);
+        let (sub, old_ids) = trie.subtrie(b"th").unwrap();
+        let mut expected = trie.alloc_token_set();
+        trie.compute_bias_ext_eos(&mut r, &mut expected, b"th", EosMode::AfterPrefix);
+        // tokens that are a prefix of "th"
+        expected.disallow_token(2);
+        let mut sub_mask = sub.alloc_token_set();
+        sub.compute_bias(&mut r, &mut sub_mask);
+        let mut mapped = trie.alloc_token_set();
+        for t in sub_mask.iter() {
+            mapped.allow_token(old_ids[t as usize]);
+        }
+        assert_eq!(mapped, expected, "seed {}", seed);
+    }
+}
+
+#[test]
+fn dbg_snapshots() {
+    let trie = trie_of(&[
+        b"\xff<eos>",
+        b"a",
+        b"b",
+        b"\xff<eot>",
+        br#"hi "x""#,
+        b"\x80\x81",
+        b"",
+        b"a",
+        b"\xc3\xa9\xc3",
+    ]);
+    let ids = [0, 1, 3, 4, 5, 6, 7, 8, 9];
+    let set = |ids: &[TokenId]| {
+        let mut s = trie.alloc_token_set();
+        for &t in ids {
+            s.allow_token(t);
+        }
+        s
+    };
+    let defaults = DbgOptions::default();
+
+    assert_eq!(
+        trie.tokens_dbg(&ids),
+        r#""≺EOS≻‧a‧≺<eot>≻‧hi \"x\"‧≺HEX[8081]≻‧≺EMPTY[6]≻‧a‧≺HEX[c3a9c3]≻‧≺OOB[9]≻""#
+    );
+    let opts = DbgOptions {
+        show_ids: true,
+        hex_threshold: 1,
+        ..defaults.clone()
+    };
+    assert_eq!(
+        trie.tokens_dbg_ext(&ids, &opts),
+        r#""≺EOS≻[0]‧a[1]‧≺<eot>≻[3]‧hi \"x\"[4]‧≺HEX[8081]≻[5]‧≺EMPTY[6]≻[6]‧a[7]‧é�[8]‧≺OOB[9]≻[9]""#
+    );
+    let json = DbgOptions {
+        json: true,
+        ..defaults.clone()
+    };
+    assert_eq!(
+        trie.tokens_dbg_ext(&[0, 3, 4, 5], &json),
+        r#"[{"id":0,"repr":"EOS","special":true},{"id":3,"repr":"<eot>","special":true},{"id":4,"repr":"hi \"x\"","special":false},{"id":5,"repr":"HEX[8081]","special":false}]"#
+    );
+
+    let some = set(&[1, 2, 4, 7]);
+    assert_eq!(
+        trie.token_set_dbg(&some),
+        r#"TokenSet: 4/9; "a", "b", "hi \"x\"", "a""#
+    );
+    let opts = DbgOptions {
+        max_examples: 2,
+        show_ids: true,
+        ..defaults.clone()
+    };
+    assert_eq!(
+        trie.token_set_dbg_ext(&some, &opts),
+        r#"TokenSet: 4/9; "a"[1], "b"[2], ..."#
+    );
+    assert_eq!(
+        trie.token_set_dbg_ext(&some, &json),
+        r#"{"num_set":4,"vocab_size":9,"negated":false,"tokens":[{"id":1,"repr":"a","special":false},{"id":2,"repr":"b","special":false},{"id":4,"repr":"hi \"x\"","special":false},{"id":7,"repr":"a","special":false}],"truncated":false}"#
+    );
+
+    // EOS is listed first
+    let with_eos = set(&[0, 2, 8]);
+    assert_eq!(
+        trie.token_set_dbg(&with_eos),
+        r#"TokenSet: 3/9; EOS, "b", HEX[c3a9c3]"#
+    );
+
+    let mut most = trie.alloc_token_set();
+    most.set_all(true);
+    most.disallow_token(2);
+    let opts = DbgOptions {
+        allow_negation: false,
+        max_examples: 3,
+        ..defaults.clone()
+    };
+    assert_eq!(
+        trie.token_set_dbg(&most),
+        r#"TokenSet: 8/9; EOS, "a", <eot>, "hi \"x\"", HEX[8081], EMPTY[6], "a", HEX[c3a9c3]"#
+    );
+    assert_eq!(
+        trie.token_set_dbg_ext(&most, &opts),
+        r#"TokenSet: 8/9; EOS, "a", <eot>, ..."#
+    );
+    assert_eq!(
+        trie.token_set_dbg_ext(&most, &json),
+        r#"{"num_set":8,"vocab_size":9,"negated":false,"tokens":[{"id":0,"repr":"EOS","special":true},{"id":1,"repr":"a","special":false},{"id":3,"repr":"<eot>","special":true},{"id":4,"repr":"hi \"x\"","special":false},{"id":5,"repr":"HEX[8081]","special":false},{"id":6,"repr":"EMPTY[6]","special":false},{"id":7,"repr":"a","special":false},{"id":8,"repr":"HEX[c3a9c3]","special":false}],"truncated":false}"#
+    );
+    assert_eq!(
+        trie.token_set_dbg(&trie.alloc_token_set()),
+        "TokenSet: 0/9; "
+    );
+
+    // few tokens missing
+    let words = (0..25).map(|i| format!("t{}", i)).collect::<Vec<_>>();
+    let trie = trie_of(&words.iter().map(|w| w.as_bytes()).collect::<Vec<_>>());
+    let mut most = trie.alloc_token_set();
+    most.set_all(true);
+    most.disallow_token(3);
+    assert_eq!(
+        trie.token_set_dbg(&most),
+        r#"TokenSet: 24/25; ALL EXCEPT "t3""#
+    );
+    assert_eq!(
+        trie.token_set_dbg_ext(&most, &json),
+        r#"{"num_set":24,"vocab_size":25,"negated":true,"tokens":[{"id":3,"repr":"t3","special":false}],"truncated":false}"#
+    );
+}
+
+// the jump tables are those built from scratch, and agree with a scan of the children
+fn check_jump_tables(trie: &TokTrie) {
+    let nodes = &trie.data.nodes;
+    let tables = &trie.data.jump_tables;
+    assert!(*tables == JumpTables::new(nodes));
+    assert!(!tables.tables.is_empty());
+    for (off, n) in nodes.iter().enumerate() {
+        let num_children = trie.node_children(n).count();
+        assert_eq!(
+            tables.table_at(off).is_some(),
+            num_children > JUMP_TABLE_MIN_CHILDREN,
+            "node {}",
+            off
+        );
+        if tables.table_at(off).is_none() {
+            continue;
+        }
+        for b in 0..=255u8 {
+            let expected = trie.node_children(n).find(|c| c.byte() == b);
+            let actual = trie.child_at_byte(n, b);
+            assert_eq!(
+                expected.map(|c| c as *const TrieNode),
+                actual.map(|c| c as *const TrieNode),
+                "node {} byte {}",
+                off,
+                b
+            );
+        }
+    }
+}
+
+#[test]
+fn jump_tables() {
+    let mut trie = synthetic_trie(5000, 3);
+    check_jump_tables(&trie);
+    let loaded = TokTrie::from_bytes(&trie.serialize());
+    assert!(loaded.data.jump_tables == trie.data.jump_tables);
+
+    // a new dense node, and more children for the root's children
+    let vocab_size = trie.vocab_size() as TokenId;
+    let new_tokens = (b'A'..=b'Z')
+        .map(|b| vec![b'~', b'~', b])
+        .chain((b'A'..=b'Z').map(|b| vec![b' ', b'~', b]))
+        .enumerate()
+        .map(|(i, w)| (vocab_size + i as TokenId, w))
+        .collect::<Vec<_>>();
+    trie.extend(&new_tokens).unwrap();
+    check_jump_tables(&trie);
+    let tilde = trie.child_at_bytes(trie.root(), b"~~").unwrap();
+    assert_eq!(trie.node_children(tilde).count(), 26);
+    assert_eq!(
+        trie.child_at_bytes(trie.root(), b"~~Q")
+            .and_then(|n| n.token_id()),
+        Some(vocab_size + 16)
+    );
+
+    let added = trie
+        .with_added_tokens(&[(b"~~~".to_vec(), false), (b"\xff<x>".to_vec(), true)])
+        .unwrap();
+    check_jump_tables(&added);
+}
+
+#[test]
+fn first_valid_extension() {
+    let trie = synthetic_trie(3000, 4);
+    let mut rng = Rng::new(5);
+    for seed in 0..200 {
+        let mut r = RecordingRecognizer::new(random_recognizer(seed, 1 + seed % 40));
+        let tok = trie.token(rng.gen_up_to(trie.vocab_size() - 1) as TokenId);
+        let start = &tok[0..rng.gen_up_to(core::cmp::min(tok.len(), 2))];
+        let n = trie.child_at_bytes(trie.root(), start).unwrap();
+        let off = trie.node_offset(n);
+
+        let mut allowed = trie.alloc_token_set();
+        trie.add_bias(&mut r, &mut allowed, start);
+        // first token node below n, in trie order, that add_bias() allowed
+        let expected = (off + 1..off + n.subtree_size())
+            .filter_map(|p| trie.node_at_offset(p).token_id())
+            .find(|&t| allowed.is_allowed(t));
+
+        r.clear_ops();
+        let found = trie.first_valid_extension(&mut r, start);
+        assert_eq!(found, expected, "seed {} start {:?}", seed, start);
+        assert_eq!(trie.has_valid_extensions(&mut r, start), expected.is_some());
+        assert!(r.violation().is_none(), "{:?}", r.violation());
+        assert_eq!(r.depth(), 0);
+        if let Some(t) = found {
+            assert!(trie.token(t).starts_with(start));
+            assert!(trie.token(t).len() > start.len());
+        }
+    }
+
+    // stops at the first token
+    let trie = trie_of(&[b"\xff<eos>", b"a", b"ab", b"b"]);
+    let mut r = RecordingRecognizer::new(FnRecognizer::new(|_: &[u8], _| true));
+    assert_eq!(trie.first_valid_extension(&mut r, b""), Some(1));
+    assert!(!r.ops().contains(&RecognizerOp::TryPushByte(b'b', true)));
+    assert_eq!(trie.first_valid_extension(&mut r, b"a"), Some(2));
+    assert_eq!(trie.first_valid_extension(&mut r, b"ab"), None);
+    assert_eq!(trie.first_valid_extension(&mut r, b"c"), None);
+    assert_eq!(r.depth(), 0);
+}
+
+// Counts pushes; answers peek_byte() from the inner recognizer only if `peek`,
+// or gets it wrong on purpose with `lie`.
+struct CountingPushes<R> {
+    inner: R,
+    peek: bool,
+    lie: bool,
+    pushes: usize,
+}
+
+impl<R: Recognizer> CountingPushes<R> {
+    fn new(inner: R, peek: bool) -> Self {
+        CountingPushes {
+            inner,
+            peek,
+            lie: false,
+            pushes: 0,
+        }
+    }
+}
+
+impl<R: Recognizer> Recognizer for CountingPushes<R> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.inner.pop_bytes(num)
+    }
+
+    fn collapse(&mut self) {
+        self.inner.collapse()
+    }
+
+    fn peek_byte(&self, byte: u8) -> Option<bool> {
+        if !self.peek {
+            return None;
+        }
+        let res = self.inner.peek_byte(byte);
+        if self.lie && byte == b'b' {
+            res.map(|r| !r)
+        } else {
+            res
+        }
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        self.inner.special_allowed(tok)
+    }
+
+    fn trie_finished(&mut self) {
+        self.inner.trie_finished()
+    }
+
+    fn trie_started(&mut self) {
+        self.inner.trie_started()
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        self.pushes += 1;
+        self.inner.try_push_byte(byte)
+    }
+}
+
+#[test]
+fn peek_byte_queries() {
+    let trie = synthetic_trie(3000, 18);
+    let mut rng = Rng::new(19);
+    for seed in 0..50 {
+        let percent = [50, 90, 100][seed as usize % 3];
+        let mut peeking = CountingPushes::new(random_recognizer(seed, percent), true);
+        let mut pushing = CountingPushes::new(random_recognizer(seed, percent), false);
+        for _ in 0..20 {
+            let t = rng.gen_up_to(trie.vocab_size() - 1) as TokenId;
+            assert_eq!(
+                trie.token_allowed(&mut peeking, t),
+                trie.token_allowed(&mut pushing, t)
+            );
+            let tok = trie.token(t);
+            let start = &tok[..rng.gen_up_to(tok.len())];
+            assert_eq!(
+                trie.first_valid_extension(&mut peeking, start),
+                trie.first_valid_extension(&mut pushing, start)
+            );
+        }
+        assert_eq!(
+            trie.forced_bytes(&mut peeking),
+            trie.forced_bytes(&mut pushing)
+        );
+        assert!(peeking.pushes < pushing.pushes);
+        assert!(peeking.inner.bytes().is_empty());
+        assert!(pushing.inner.bytes().is_empty());
+    }
+
+    // the last byte of a token is only peeked at
+    let long = [b'x'; 40];
+    let trie = trie_of(&[b"\xff<eos>", &long, b"ab"]);
+    let mut peeking = CountingPushes::new(FnRecognizer::new(|_: &[u8], _| true), true);
+    let mut pushing = CountingPushes::new(FnRecognizer::new(|_: &[u8], _| true), false);
+    assert!(trie.token_allowed(&mut peeking, 1));
+    assert!(trie.token_allowed(&mut pushing, 1));
+    assert_eq!((peeking.pushes, pushing.pushes), (39, 40));
+    assert!(trie.has_valid_extensions(&mut peeking, b"a"));
+    assert_eq!(peeking.pushes, 39);
+}
+
+#[test]
+fn check_peek_byte_finds_wrong_answers() {
+    let mut rng = Rng::new(20);
+    let mut r = CountingPushes::new(random_recognizer(1, 60), true);
+    check_peek_byte(&mut r, &mut rng, 200).unwrap();
+    check_peek_byte(&mut CharClassRecognizer::digits(), &mut rng, 50).unwrap();
+    check_peek_byte(
+        &mut AnyOfRecognizer::new([&b"abc"[..], b"abd", b"b"]),
+        &mut rng,
+        50,
+    )
+    .unwrap();
+    let (a, c) = ab_ac();
+    check_peek_byte(&mut AndRecognizer::new(a.clone(), c.clone()), &mut rng, 50).unwrap();
+    check_peek_byte(&mut OrRecognizer::new(a, c), &mut rng, 50).unwrap();
+
+    r.lie = true;
+    let err = check_peek_byte(&mut r, &mut rng, 200)
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("peek_byte(0x62) is Some("), "{}", err);
+    // left as it was
+    assert!(r.inner.bytes().is_empty());
+}
+
+// The TrieHash the nodes used to be built with, as a reference for build_nodes().
+struct TrieHash {
+    token_id: u32,
+    byte: u8,
+    children: Vec<TrieHash>,
+}
+
+impl TrieHash {
+    fn new(byte: u8) -> TrieHash {
+        TrieHash {
+            token_id: NO_TOKEN,
+            byte,
+            children: Vec::new(),
+        }
+    }
+
+    fn insert(&mut self, word: &[u8], token_id: u32, keep_first: bool) {
+        if word.is_empty() {
+            if !keep_first || self.token_id == NO_TOKEN {
+                self.token_id = token_id;
+            }
+            return;
+        }
+        if self.children.len() == 0x100 {
+            self.children[word[0] as usize].insert(&word[1..], token_id, keep_first);
+            return;
+        }
+        if let Some(ch) = self.children.iter_mut().find(|ch| ch.byte == word[0]) {
+            ch.insert(&word[1..], token_id, keep_first);
+            return;
+        }
+        let mut ch = TrieHash::new(word[0]);
+        ch.insert(&word[1..], token_id, keep_first);
+        self.children.push(ch);
+        if self.children.len() > 250 {
+            let mut full = (0..=255).map(TrieHash::new).collect::<Vec<_>>();
+            for ch in self.children.drain(..) {
+                let idx = ch.byte as usize;
+                full[idx] = ch;
+            }
+            self.children = full;
+        }
+    }
+
+    fn serialize(&mut self, data: &mut Vec<TrieNode>, num_parents: usize) {
+        let idx = data.len();
+        let mut num_ch = self.children.len();
+        data.push(TrieNode::new(self.byte, self.token_id, num_parents));
+        self.children.sort_by_key(|e| e.byte);
+        for entry in &mut self.children {
+            num_ch -= 1;
+            entry.serialize(data, if num_ch == 0 { num_parents + 1 } else { 1 });
+        }
+        data[idx].bits2 |= ((data.len() - idx) as u32) << 8;
+    }
+}
+
+fn trie_hash_nodes(words: &[Vec<u8>], keep_first: bool) -> Vec<TrieNode> {
+    let mut trie = TrieHash::new(0xff);
+    for (idx, word) in words.iter().enumerate() {
+        if !word.is_empty() {
+            trie.insert(word, idx as u32, keep_first);
+        }
+    }
+    let mut data = Vec::new();
+    trie.serialize(&mut data, 0);
+    data
+}
+
+#[test]
+fn build_nodes_matches_trie_hash() {
+    let mut vocabs = vec![synthetic_vocab(20_000, 8)];
+
+    let mut words = synthetic_vocab(5000, 9);
+    let mut rng = Rng::new(9);
+    for t in 256..words.len() {
+        if rng.gen_up_to(9) < 3 {
+            words[t] = words[rng.gen_up_to(words.len() - 1)].clone();
+        }
+    }
+    words[300] = Vec::new();
+    vocabs.push(words);
+
+    // nodes with 250 and 251 children, the latter filled up to 256
+    let mut words = vec![b"\xff<eos>".to_vec()];
+    words.extend((0..250).map(|b| vec![b'a', b]));
+    words.extend((0..251).map(|b| vec![b'b', b]));
+    words.extend((0..=255).map(|b| vec![b'b', 7, b]));
+    // a chain longer than 255 nodes, with tokens along it
+    words.extend((1..400).step_by(37).map(|len| vec![b'c'; len]));
+    vocabs.push(words);
+
+    for words in &vocabs {
+        for keep_first in [false, true] {
+            let nodes = build_nodes(words, keep_first).unwrap();
+            let expected = trie_hash_nodes(words, keep_first);
+            assert_eq!(nodes.len(), expected.len());
+            for (p, (a, b)) in nodes.iter().zip(&expected).enumerate() {
+                assert!(
+                    a == b,
+                    "node {}: byte {} token {:?} subtree {} / byte {} token {:?} subtree {}",
+                    p,
+                    a.byte(),
+                    a.token_id(),
+                    a.subtree_size(),
+                    b.byte(),
+                    b.token_id(),
+                    b.subtree_size()
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn reordered_trie() {
+    let ranks: [fn(u8) -> u32; 2] = [|b| (b < 0x80) as u32, |b| 255 - b as u32];
+    for (idx, trie) in [synthetic_trie(5000, 13), trie_with_duplicates(3000, 14)]
+        .iter()
+        .enumerate()
+    {
+        let rank = ranks[idx];
+        let re = trie.reordered(&rank);
+        let root_bytes = re
+            .node_children(re.root())
+            .map(|n| n.byte())
+            .collect::<Vec<_>>();
+        let mut expected = root_bytes.clone();
+        expected.sort_by_key(|&b| (rank(b), b));
+        assert_eq!(root_bytes, expected);
+        assert_ne!(root_bytes[0], 1);
+        check_jump_tables(&re);
+
+        // lookups scan the children, so they don't depend on their order
+        for t in 0..trie.vocab_size() as TokenId {
+            assert_eq!(re.token_id(trie.token(t)), trie.token_id(trie.token(t)));
+        }
+        let text = crate::testing::synthetic_text(3000, 15);
+        assert_eq!(re.greedy_tokenize(&text), trie.greedy_tokenize(&text));
+        assert_eq!(re.sorted_tokens(), trie.sorted_tokens());
+        assert_eq!(re.sorted_tokens_complete(), trie.sorted_tokens_complete());
+        assert_eq!(re.get_special_tokens(), trie.get_special_tokens());
+
+        for seed in 0..20 {
+            let percent = [5, 30, 70, 100][seed as usize % 4];
+            let mut expected = trie.alloc_token_set();
+            trie.compute_bias(&mut random_recognizer(seed, percent), &mut expected);
+            let mut r = RecordingRecognizer::new(random_recognizer(seed, percent));
+            let mut logits = re.alloc_token_set();
+            re.compute_bias(&mut r, &mut logits);
+            assert_eq!(logits, expected, "seed {}", seed);
+            assert!(r.violation().is_none(), "{:?}", r.violation());
+        }
+
+        let copy = TokTrie::from_bytes(&re.serialize());
+        assert_eq!(copy.serialize(), re.serialize());
+        // extend() merges into byte order
+        let new_tokens = [(trie.vocab_size() as TokenId, b"\xce\xb1zz".to_vec())];
+        let mut extended = trie.clone();
+        extended.extend(&new_tokens).unwrap();
+        let mut re_extended = re.clone();
+        re_extended.extend(&new_tokens).unwrap();
+        assert_eq!(re_extended.serialize(), extended.serialize());
+    }
+}
+
+#[test]
+fn compute_bias_from_matches_ext() {
+    let mut words = synthetic_vocab(4000, 16);
+    // a chain longer than the 255 parents that fit in a node, with tokens along it
+    words[3000] = vec![b'q'; 600];
+    words[3001] = vec![b'q'; 300];
+    words[3002] = [vec![b'q'; 300], b"xy".to_vec()].concat();
+    let trie = TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words);
+    assert_eq!(trie.resolve_prefix(b""), Some(trie.root_ref()));
+    assert_eq!(trie.resolve_prefix(b"q\x00"), None);
+
+    let mut rng = Rng::new(17);
+    let mut starts = (0..200)
+        .map(|_| {
+            let w = &words[rng.gen_up_to(words.len() - 1)];
+            w[..rng.gen_up_to(w.len())].to_vec()
+        })
+        .collect::<Vec<_>>();
+    starts.extend([
+        Vec::new(),
+        vec![b'q'; 299],
+        vec![b'q'; 300],
+        [vec![b'q'; 300], b"x".to_vec()].concat(),
+        vec![b'q'; 599],
+    ]);
+    for (idx, start) in starts.iter().enumerate() {
+        let seed = idx as u64;
+        let percent = [30, 70, 100][idx % 3];
+        let node = trie.resolve_prefix(start).unwrap();
+        assert_eq!(trie.node_path_ref(node), *start);
+
+        let mut r_ext = RecordingRecognizer::new(random_recognizer(seed, percent));
+        let mut expected = trie.alloc_token_set();
+        trie.compute_bias_ext(&mut r_ext, &mut expected, start);
+        let mut r = RecordingRecognizer::new(random_recognizer(seed, percent));
+        let mut logits = trie.alloc_token_set();
+        trie.compute_bias_from(&mut r, &mut logits, node);
+        assert_eq!(logits, expected, "start {:?}", start);
+        // the same walk, ending with the stack as it was
+        assert_eq!(r.ops(), r_ext.ops());
+        assert_eq!(r.ops().last(), Some(&RecognizerOp::TrieFinished(0)));
+        assert!(r.violation().is_none(), "{:?}", r.violation());
+        assert_eq!(r.depth(), 0);
+
+        r.clear_ops();
+        let mut toks = trie.alloc_token_set();
+        trie.add_bias(&mut r, &mut toks, start);
+        let mut toks_from = trie.alloc_token_set();
+        trie.add_bias_from(&mut r, &mut toks_from, node);
+        assert_eq!(toks_from, toks);
+        assert_eq!(r.depth(), 0);
+    }
+}
+
+#[test]
+fn filtered_is_and_with_keep() {
+    let trie = trie_with_duplicates(3000, 13);
+    let mut rng = Rng::new(13);
+    for seed in 0..20 {
+        let mut keep = trie.alloc_token_set();
+        let percent = [5, 50, 95, 100][seed as usize % 4];
+        for t in 0..trie.vocab_size() as TokenId {
+            if rng.gen_up_to(99) < percent {
+                keep.allow_token(t);
+            }
+        }
+        let eos_kept = keep.is_allowed(0);
+        let sub = trie.filtered(&keep);
+        assert_eq!(sub.info(), trie.info());
+        // a node has a kept token if any token with its bytes is kept
+        let mut kept_bytes = crate::FxHashMap::default();
+        for t in 0..trie.vocab_size() as TokenId {
+            *kept_bytes.entry(trie.token(t)).or_insert(false) |= keep.is_allowed(t);
+        }
+        for t in 0..trie.vocab_size() as TokenId {
+            assert_eq!(sub.token(t), trie.token(t));
+            let bytes = trie.token(t);
+            let any_kept = kept_bytes[bytes];
+            if !bytes.is_empty() && (bytes[0] != 0xff || t != 0) {
+                assert_eq!(sub.token_id(bytes).is_some(), any_kept, "token {}", t);
+            }
+            if let Some(c) = sub.token_id(bytes) {
+                assert!(keep.is_allowed(c));
+            }
+        }
+
+        let mut r = random_recognizer(seed, 80);
+        let mut expected = trie.alloc_token_set();
+        trie.compute_bias(&mut r, &mut expected);
+        let eos_allowed = expected.is_allowed(0);
+        expected.and(&keep);
+        let mut logits = sub.alloc_token_set();
+        sub.compute_bias(&mut r, &mut logits);
+        if !eos_kept && eos_allowed {
+            // EOS is allowed as a special token, whatever keep says
+            expected.allow_token(0);
+        }
+        assert_eq!(logits, expected, "seed {}", seed);
+    }
+
+    let empty = trie.filtered(&trie.alloc_token_set());
+    let mut logits = empty.alloc_token_set();
+    empty.compute_bias(&mut FnRecognizer::new(|_: &[u8], _| true), &mut logits);
+    assert_eq!(logits.iter().collect::<Vec<_>>(), vec![0]);
+    assert_eq!(empty.token_id(b"a"), None);
+}
+
+#[test]
+fn eq_and_debug() {
+    let trie = synthetic_trie(500, 3);
+    let copy = TokTrie::from_bytes(&trie.serialize());
+    assert_eq!(copy, trie);
+    assert_eq!(trie.clone(), trie);
+    assert_ne!(synthetic_trie(500, 4), trie);
+    // same tokens, different EOS
+    let words = [&b"a"[..], b"b"];
+    let eos0 = trie_of(&words);
+    let eos1 = TokTrie::from(
+        &TokRxInfo::new(2, 1),
+        &alloc::vec![b"a".to_vec(), b"b".to_vec()],
+    );
+    assert_ne!(eos0, eos1);
+
+    assert_eq!(
+        std::format!("{:?}", eos1),
+        "TokTrie { vocab_size: 2, num_nodes: 3, tok_eos: 1, .. }"
+    );
+    assert_eq!(
+        std::format!("{:?}", eos1.data.nodes[1]),
+        "TrieNode { byte: 0x61, token_id: Some(0), subtree_size: 1, num_parents: 1 }"
+    );
+}
+
+#[test]
+fn ordinary_tokens_with_prefix_byte() {
+    let words = [&b"\xff<eos>"[..], b"a", b"\xffa", b"\xff<|x|>", b"b"]
+        .iter()
+        .map(|w| w.to_vec())
+        .collect::<Vec<_>>();
+    let trie = TokTrie::from_ext(&TokRxInfo::new(5, 0), &words, Some(&[0, 3]));
+    let check = |trie: &TokTrie, specials: &[TokenId]| {
+        assert_eq!(trie.explicit_special_tokens().unwrap(), specials);
+        assert!(!trie.is_special_token(2));
+        assert!(trie.is_special_token(3));
+        assert_eq!(trie.decode(&[2, 3, 1]), b"\xffa<|x|>a");
+        assert_eq!(trie.decode_raw(&[2, 3]), b"\xffa\xff<|x|>");
+        assert_eq!(trie.greedy_tokenize(b"\xffab"), alloc::vec![2, 4]);
+        assert_eq!(trie.get_special_tokens(), specials);
+        assert_eq!(trie.get_special_token("<|x|>"), Some(3));
+        assert_eq!(trie.get_special_token("a"), None);
+    };
+    check(&trie, &[0, 3]);
+
+    let copy = TokTrie::from_bytes(&trie.serialize());
+    assert_eq!(copy, trie);
+    check(&copy, &[0, 3]);
+
+    let mut keep = trie.alloc_token_set();
+    for t in [0, 2, 3] {
+        keep.allow_token(t);
+    }
+    let filtered = trie.filtered(&keep);
+    assert_eq!(filtered.explicit_special_tokens(), Some(alloc::vec![0, 3]));
+    assert!(!filtered.is_special_token(2));
+    assert_eq!(filtered.token_id(b"\xffa"), Some(2));
+    assert_eq!(filtered.decode(&[2, 3]), b"\xffa<|x|>");
+    let copy = TokTrie::from_bytes(&filtered.serialize());
+    assert!(!copy.is_special_token(2));
+    assert_eq!(copy.explicit_special_tokens(), Some(alloc::vec![0, 3]));
+
+    let added = trie
+        .with_added_tokens(&[(b"\xffb".to_vec(), false), (b"y".to_vec(), true)])
+        .unwrap();
+    check(&added, &[0, 3, 6]);
+    assert!(!added.is_special_token(5));
+    assert_eq!(added.decode(&[5, 6]), b"\xffby");
+    check(&TokTrie::from_bytes(&added.serialize()), &[0, 3, 6]);
+}
+
+// tries of the same tokens, with different special tokens
+fn tries_with_specials() -> [TokTrie; 3] {
+    let words = [&b"\xff<eos>"[..], b"\xff<x>", b"a"]
+        .iter()
+        .map(|w| w.to_vec())
+        .collect::<Vec<_>>();
+    let info = TokRxInfo::new(3, 0);
+    [
+        TokTrie::from(&info, &words),
+        TokTrie::from_ext(&info, &words, Some(&[0])),
+        // the same set as by the prefix byte, but given explicitly
+        TokTrie::from_ext(&info, &words, Some(&[0, 1])),
+    ]
+}
+
+#[test]
+fn specials_in_equality() {
+    let tries = tries_with_specials();
+    for (i, a) in tries.iter().enumerate() {
+        assert_eq!(&TokTrie::from_bytes(&a.serialize()), a);
+        for (j, b) in tries.iter().enumerate() {
+            assert_eq!(a == b, i == j, "{} {}", i, j);
+        }
+    }
+}
+
+// all splits of `bytes` into tokens, as from token_id()
+fn all_tokenizations(trie: &TokTrie, bytes: &[u8]) -> Vec<Vec<TokenId>> {
+    if bytes.is_empty() {
+        return alloc::vec![Vec::new()];
+    }
+    let mut res = Vec::new();
+    for len in 1..=bytes.len() {
+        if let Some(tok) = trie.token_id(&bytes[..len]) {
+            for rest in all_tokenizations(trie, &bytes[len..]) {
+                res.push([alloc::vec![tok], rest].concat());
+            }
+        }
+    }
+    res
+}
+
+#[test]
+fn tokenizations() {
+    // 8 has the bytes of 3, and is the one token_id() gives
+    let trie = trie_of(&[
+        b"\xff<eos>",
+        b"a",
+        b"b",
+        b"ab",
+        b"ba",
+        b"aba",
+        b"bb",
+        b"abab",
+        b"ab",
+    ]);
+    let lens = |split: &Vec<TokenId>| {
+        split
+            .iter()
+            .map(|&t| core::cmp::Reverse(trie.token(t).len()))
+            .collect::<Vec<_>>()
+    };
+    let mut rng = Rng::new(24);
+    for _ in 0..300 {
+        let len = rng.gen_up_to(12);
+        let bytes = (0..len)
+            .map(|_| [b'a', b'b', b'a', b'b', b'a', b'b', b'c'][rng.gen_up_to(6)])
+            .collect::<Vec<_>>();
+        // longest first token first, and so on
+        let mut all = all_tokenizations(&trie, &bytes);
+        all.sort_by_key(lens);
+        assert!(all.iter().flatten().all(|&t| t != 3));
+
+        let shortest = all.iter().map(|s| s.len()).min();
+        let expected = shortest.map(|n| all.iter().find(|s| s.len() == n).unwrap().clone());
+        assert_eq!(trie.shortest_tokenization(&bytes), expected, "{:?}", bytes);
+        if bytes.contains(&b'c') {
+            assert_eq!(expected, None);
+        }
+
+        for max_len in [0, 1, 3, 6, 12] {
+            let within = all
+                .iter()
+                .filter(|s| s.len() <= max_len)
+                .cloned()
+                .collect::<Vec<_>>();
+            for max_results in [0, 1, 5, usize::MAX] {
+                let expected = within.iter().take(max_results).cloned().collect::<Vec<_>>();
+                assert_eq!(
+                    trie.tokenizations_bounded(&bytes, max_results, max_len),
+                    expected,
+                    "{:?} {} {}",
+                    bytes,
+                    max_len,
+                    max_results
+                );
+            }
+        }
+    }
+    assert_eq!(trie.shortest_tokenization(b""), Some(Vec::new()));
+    assert_eq!(
+        trie.tokenizations_bounded(b"", 1, 0),
+        [Vec::<TokenId>::new()]
+    );
+    assert_eq!(
+        trie.shortest_tokenization(b"ababab"),
+        Some(alloc::vec![7, 8])
+    );
+}
+
+#[test]
+fn fingerprints() {
+    let trie = synthetic_trie(1000, 21);
+    let fp = [1u8; 32];
+    let bytes = trie.serialize_with_fingerprint(&fp);
+    let plain = trie.serialize();
+    assert_eq!(TokTrie::serialized_fingerprint(&bytes), Some(fp));
+    assert_eq!(TokTrie::serialized_fingerprint(&plain), None);
+    assert_eq!(TokTrie::serialized_fingerprint(b"not a trie"), None);
+
+    // match
+    assert_eq!(
+        TokTrie::from_bytes_checked(&bytes, Some(&fp)).unwrap(),
+        trie
+    );
+    assert_eq!(TokTrie::from_bytes_checked(&bytes, None).unwrap(), trie);
+    assert_eq!(TokTrie::from_bytes_checked(&plain, None).unwrap(), trie);
+    // the other loaders skip the fingerprint
+    assert_eq!(TokTrie::try_from_bytes(&bytes).unwrap(), trie);
+    assert_eq!(TokTrie::from_bytes_borrowed(&bytes).into_owned(), trie);
+
+    // mismatch
+    let err = TokTrie::from_bytes_checked(&bytes, Some(&[2; 32])).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<FingerprintMismatch>(),
+        Some(&FingerprintMismatch {
+            expected: [2; 32],
+            found: Some(fp),
+        })
+    );
+    assert_eq!(
+        err.to_string(),
+        std::format!(
+            "TokTrie: fingerprint mismatch: expected {}, found {}",
+            "02".repeat(32),
+            "01".repeat(32)
+        )
+    );
+    // absent
+    let err = TokTrie::from_bytes_checked(&plain, Some(&fp)).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<FingerprintMismatch>().unwrap().found,
+        None
+    );
+    assert!(err.to_string().ends_with("found none"));
+
+    assert_eq!(
+        TokTrie::try_from_bytes(&bytes[..20])
+            .unwrap_err()
+            .to_string(),
+        "TokTrie: buffer too short for fingerprint: 20 bytes"
+    );
+    let mut bad_version = bytes.clone();
+    bad_version[4] = 2;
+    assert_eq!(
+        TokTrie::from_bytes_checked(&bad_version, None)
+            .unwrap_err()
+            .to_string(),
+        "TokTrie: unsupported fingerprint version 2"
+    );
+}
+
+#[test]
+fn content_hash() {
+    let trie = trie_with_duplicates(2000, 22);
+    let hash = trie.content_hash();
+    assert_eq!(TokTrie::from_bytes(&trie.serialize()).content_hash(), hash);
+    // built another way, from the tokens in reverse order
+    let built = TokTrie::from_sparse(
+        &TokRxInfo::new(0, 0),
+        (0..2000).rev().map(|t| (t, trie.token(t).to_vec())),
+    )
+    .unwrap();
+    assert_eq!(built, trie);
+    assert_eq!(built.content_hash(), hash);
+
+    assert_ne!(trie.with_eos_token(1).content_hash(), hash);
+    assert_ne!(trie.with_stop_tokens(&[5]).content_hash(), hash);
+    assert_ne!(trie_with_duplicates(2000, 23).content_hash(), hash);
+    let mut keep = trie.alloc_token_set();
+    keep.set_all(true);
+    keep.disallow_token(300);
+    assert_ne!(trie.filtered(&keep).content_hash(), hash);
+
+    // the same tokens, with different special tokens
+    let tries = tries_with_specials();
+    for (i, a) in tries.iter().enumerate() {
+        for (j, b) in tries.iter().enumerate() {
+            assert_eq!(a.content_hash() == b.content_hash(), i == j, "{} {}", i, j);
+        }
+    }
+}
+
+#[cfg(all(feature = "serde", feature = "std"))]
+#[test]
+fn serde_round_trip() {
+    #[derive(serde::Serialize, serde::Deserialize)]
+    struct Config {
+        name: std::string::String,
+        trie: TokTrie,
+    }
+
+    let trie = synthetic_trie(500, 3);
+    let config = Config {
+        name: "fixture".into(),
+        trie: trie.clone(),
+    };
+
+    let json = serde_json::to_string(&config).unwrap();
+    let back: Config = serde_json::from_str(&json).unwrap();
+    assert_eq!(back.name, "fixture");
+    assert_eq!(back.trie, trie);
+
+    let bin = bincode::serialize(&config).unwrap();
+    let back: Config = bincode::deserialize(&bin).unwrap();
+    assert_eq!(back.trie, trie);
+    // bincode stores the bytes as they are, after the length
+    assert!(bin.len() < trie.serialize().len() + 32);
+
+    let err = serde_json::from_str::<TokTrie>("[1,2,3]").unwrap_err();
+    assert!(err.to_string().contains("TokTrie"), "{}", err);
+}
+
+// (nearest, farthest) token below `n`, walking the whole subtree
+fn depth_bounds_naive(trie: &TokTrie, n: &TrieNode) -> Option<(u16, u16)> {
+    let mut res = n.token_id().map(|_| (0, 0));
+    for c in trie.node_children(n) {
+        if let Some((min, max)) = depth_bounds_naive(trie, c) {
+            let (rmin, rmax) = res.unwrap_or((u16::MAX, 0));
+            res = Some((rmin.min(min + 1), rmax.max(max + 1)));
+        }
+    }
+    res
+}
+
+#[test]
+fn depth_bounds_and_limit() {
+    let trie = trie_with_duplicates(2000, 5);
+    for n in &trie.data.nodes {
+        let expected = depth_bounds_naive(&trie, n).unwrap_or((u16::MAX, u16::MAX));
+        assert_eq!(trie.node_depth_bounds(n), expected);
+    }
+    let single = trie_of(&[b"ab"]);
+    assert_eq!(single.node_depth_bounds(single.root()), (2, 2));
+    let empty = trie_of(&[]);
+    assert_eq!(empty.node_depth_bounds(empty.root()), (u16::MAX, u16::MAX));
+
+    let mut rng = Rng::new(5);
+    let mut total = (0, 0);
+    for seed in 0..30 {
+        let max_bytes = 1 + rng.gen_up_to(trie.max_token_len() + 1);
+        let pushes = core::cell::Cell::new(0);
+        let mut r = FnRecognizer::new(|bytes: &[u8], b: u8| {
+            pushes.set(pushes.get() + 1);
+            random_byte_allowed(seed, 90, bytes, b)
+        });
+
+        // the unpruned walk, with the long tokens dropped afterwards
+        let mut expected = trie.alloc_token_set();
+        trie.compute_bias(&mut r, &mut expected);
+        for t in 0..trie.vocab_size() as TokenId {
+            if trie.token(t).len() > max_bytes && t != trie.eos_token() {
+                expected.disallow_token(t);
+            }
+        }
+        let unpruned_pushes = pushes.replace(0);
+
+        let mut logits = trie.alloc_token_set();
+        trie.compute_bias_with_limit(&mut r, &mut logits, max_bytes);
+        assert_eq!(logits, expected, "seed {} max_bytes {}", seed, max_bytes);
+        assert!(pushes.get() <= unpruned_pushes);
+        total = (total.0 + pushes.get(), total.1 + unpruned_pushes);
+    }
+    // short limits skip subtrees whose tokens are all too long
+    assert!(total.0 < total.1, "{:?}", total);
+}
+
+#[test]
+fn heal_tokens() {
+    let trie = trie_of(&[
+        b"\xff<eos>",
+        b"a",
+        b"b",
+        b"ab",
+        b"ba",
+        b"\xff<x>",
+        b"abc",
+        b"\xff<x>!",
+    ]);
+    let accept_all: ByteFn = |_, _| true;
+    let mut r = FnRecognizer::new(accept_all);
+    let mut scratch = trie.new_scratch();
+
+    let mut rng = Rng::new(63);
+    for _ in 0..500 {
+        let len = rng.gen_up_to(6);
+        let tokens = (0..len)
+            .map(|_| rng.gen_up_to(trie.vocab_size() - 1) as TokenId)
+            .collect::<Vec<_>>();
+        for allow_special in [false, true] {
+            let res = trie.heal_tokens_ext(&mut r, &tokens, allow_special);
+            assert_eq!(
+                [&tokens[..res.keep_tokens], &res.chopped_tokens[..]].concat(),
+                tokens
+            );
+            assert_eq!(res.forced_prefix, trie.decode_raw(&res.chopped_tokens));
+            if !allow_special {
+                assert!(!res.chopped_tokens.iter().any(|&t| trie.is_special_token(t)));
+                assert_eq!(trie.heal_tokens(&mut r, &tokens), res);
+                let (keep, prefix) = trie.heal_tokens_with_scratch(&mut r, &tokens, &mut scratch);
+                assert_eq!((keep, prefix), (res.keep_tokens, &res.forced_prefix[..]));
+            } else {
+                // chop_tokens() doesn't stop at special tokens
+                assert_eq!(
+                    trie.chop_tokens(&mut r, &tokens),
+                    (res.chopped_tokens.len(), res.forced_prefix.len())
+                );
+            }
+        }
+    }
+
+    // "a" could be the start of "ab" or "abc"
+    let res = trie.heal_tokens(&mut r, &[2, 1]);
+    assert_eq!(res.keep_tokens, 1);
+    assert_eq!(res.chopped_tokens, vec![1]);
+    assert_eq!(res.forced_prefix, b"a");
+    let mut mask = trie.alloc_token_set();
+    trie.compute_bias_ext(&mut r, &mut mask, &res.forced_prefix);
+    assert_eq!(mask.iter().collect::<Vec<_>>(), vec![1, 3, 6]);
+
+    // "<x>" could be the start of "<x>!", but healing stops at special tokens
+    let res = trie.heal_tokens(&mut r, &[1, 5]);
+    assert_eq!(res.keep_tokens, 2);
+    assert!(res.chopped_tokens.is_empty() && res.forced_prefix.is_empty());
+    let res = trie.heal_tokens_ext(&mut r, &[1, 5], true);
+    assert_eq!(res.keep_tokens, 1);
+    assert_eq!(res.chopped_tokens, vec![5]);
+    assert_eq!(res.forced_prefix, b"\xff<x>");
+    trie.compute_bias_ext(&mut r, &mut mask, &res.forced_prefix);
+    assert_eq!(mask.iter().collect::<Vec<_>>(), vec![5, 7]);
+    // and only if the recognizer allows a longer token after it
+    let no_bang: ByteFn = |_, b| b != b'!';
+    let res = trie.heal_tokens_ext(&mut FnRecognizer::new(no_bang), &[1, 5], true);
+    assert_eq!(res.keep_tokens, 2);
+    // a special token in the middle stops it too
+    let res = trie.heal_tokens(&mut r, &[1, 5, 1]);
+    assert_eq!((res.keep_tokens, res.chopped_tokens), (2, vec![1]));
+}
+
+#[test]
+fn clones_share_data() {
+    let mut info = TokRxInfo::new(2000, 0);
+    info.tok_end_of_turn = Some(7);
+    let trie = TokTrie::from(&info, &synthetic_vocab(2000, 9));
+    let chat1 = trie.build_chat_mode_trie();
+    let chat2 = trie.build_chat_mode_trie();
+    let eos5 = trie.with_eos_token(5).with_stop_tokens(&[9]);
+    for t in [&chat1, &chat2, &eos5, &trie.clone()] {
+        assert!(t.shares_data_with(&trie));
+        assert_eq!(t.memory_usage(), trie.memory_usage());
+    }
+    assert!(chat1.shares_data_with(&chat2));
+    assert_eq!(chat1.eos_token(), 7);
+    assert_eq!(trie.eos_token(), 0);
+    assert!(!trie.shares_data_with(&synthetic_trie(2000, 9)));
+    assert!(!trie.shares_data_with(&trie.filtered(&trie.alloc_token_set())));
+
+    // nodes of a clone are its own, and the same as the original's
+    let word = trie.token(100);
+    let n = chat1.child_at_bytes(chat1.root(), word).unwrap();
+    assert_eq!(
+        chat1.node_offset(n),
+        trie.node_offset(trie.child_at_bytes(trie.root(), word).unwrap())
+    );
+    assert_eq!(chat1.token_id(word), trie.token_id(word));
+    assert!((0..2000).all(|t| chat1.token(t) == trie.token(t)));
+    assert_eq!(chat1.with_eos_token(0), trie);
+
+    // only the EOS handling differs
+    let eos_at_2: EosFn = |bytes| bytes.len() == 2;
+    for seed in 0..5 {
+        let mut r = random_recognizer(seed, 50).with_eos(eos_at_2);
+        let mut expected = trie.alloc_token_set();
+        trie.compute_bias(&mut r, &mut expected);
+        let mut logits = chat1.alloc_token_set();
+        chat1.compute_bias(&mut r, &mut logits);
+        assert_eq!(logits, expected);
+    }
+    let mut r = FnRecognizer::new(|_: &[u8], _| false).with_eos(|_: &[u8]| true);
+    let mut logits = chat1.alloc_token_set();
+    chat1.compute_bias(&mut r, &mut logits);
+    assert_eq!(logits.iter().collect::<Vec<_>>(), vec![7]);
+    eos5.compute_bias(&mut r, &mut logits);
+    assert_eq!(logits.iter().collect::<Vec<_>>(), vec![5, 9]);
+
+    let usage = trie.memory_usage();
+    assert!(usage.nodes >= trie.data.nodes.len() * core::mem::size_of::<TrieNode>());
+    assert!(usage.token_offsets >= 2000 * 4);
+    assert!(usage.token_data >= trie.data.token_data.len());
+    assert_eq!(
+        usage.total(),
+        usage.nodes + usage.token_data + usage.token_offsets + usage.duplicates + usage.derived
+    );
+}
+
+#[test]
+fn out_of_range_token_ids() {
+    let trie = trie_of(&[b"\xff<eos>", b"a", b"b", b"ab"]);
+    let vocab_size = trie.vocab_size() as TokenId;
+    let sentinel = trie.sentinel_token();
+    assert_eq!(sentinel, vocab_size);
+    let accept_all: ByteFn = |_, _| true;
+    let mut r = RecordingRecognizer::new(FnRecognizer::new(accept_all));
+
+    for t in [
+        0,
+        vocab_size - 1,
+        vocab_size,
+        sentinel,
+        vocab_size + 1,
+        u32::MAX,
+    ] {
+        let valid = t < vocab_size;
+        let ok = valid || t == sentinel;
+        assert_eq!(trie.is_valid_token(t), valid);
+
+        // lenient methods: out-of-range ids are tokens without bytes
+        assert_eq!(trie.token(t).is_empty(), !valid, "{}", t);
+        assert_eq!(trie.token_str(t).is_empty(), !valid);
+        if !valid {
+            assert_eq!(trie.token_dbg(t), std::format!("OOB[{}]", t));
+        }
+        let decoded = if t == 0 { &b"<eos>"[..] } else { trie.token(t) };
+        assert_eq!(trie.decode(&[1, t]), [b"a", decoded].concat());
+        assert_eq!(trie.decode_raw(&[1, t]), [b"a", trie.token(t)].concat());
+        assert!(trie.decode_str(&[t, 1]).ends_with('a'));
+        assert_eq!(trie.is_special_token(t), t == 0);
+        assert_eq!(trie.is_stop_token(t), t == 0);
+        if !valid {
+            assert_eq!(trie.token_props(t), TokenProps::default());
+            assert!(trie.token_end_node(t).is_none());
+            assert_eq!(trie.merge_token(t, 1), None);
+            assert_eq!(trie.merge_token(1, t), None);
+        }
+        assert_eq!(trie.canonical_token(t), t);
+        assert!(trie.duplicates_of(t).is_empty());
+        assert_eq!(trie.canonicalize_tokens(&[t]), vec![t]);
+        trie.find_mergeable_pairs(&[t, 1, t]);
+        trie.chop_tokens(&mut r, &[1, t]);
+        trie.heal_tokens(&mut r, &[t, 1]);
+        assert_eq!(trie.token_allowed(&mut r, t), valid);
+        assert_eq!(trie.try_token_allowed(&mut r, t).unwrap(), valid);
+        assert_eq!(
+            trie.with_stop_tokens(&[t]).stop_tokens().to_vec(),
+            if valid && t != 0 { vec![t] } else { vec![] }
+        );
+        assert_eq!(r.depth(), 0);
+        r.clear_ops();
+
+        // checked methods: fail on out-of-range ids, except the sentinel
+        assert_eq!(trie.try_decode(&[1, t]).is_ok(), ok);
+        if ok {
+            assert_eq!(trie.try_decode(&[1, t]).unwrap(), trie.decode(&[1, t]));
+        }
+        match trie.singleton_token_set(t) {
+            Ok(set) => {
+                assert!(ok);
+                let expected = if valid { vec![t] } else { vec![] };
+                assert_eq!(set.iter().collect::<Vec<_>>(), expected);
+            }
+            Err(e) => {
+                assert!(!ok);
+                assert!(e.to_string().contains("out of range"), "{}", e);
+            }
+        }
+        for res in [
+            trie.append_token(&mut r, t),
+            trie.try_append_token(&mut r, t),
+            trie.append_tokens(&mut r, &[1, t]),
+        ] {
+            assert_eq!(res.is_ok(), ok, "{}", t);
+        }
+        if !ok {
+            // nothing was pushed, not even the valid token before
+            assert!(r.ops().is_empty(), "{:?}", r.ops());
+        }
+        r.clear_ops();
+    }
+}
+
+#[test]
+fn fuzzy_token_matches() {
+    let mut words = [
+        &b"\xff<eos>"[..],
+        b"cat",
+        b"car",
+        b"cut",
+        b"ca",
+        b"c",
+        b"bat",
+        b"cat",
+        b"cart",
+    ]
+    .iter()
+    .map(|w| w.to_vec())
+    .collect::<Vec<_>>();
+    words.push(b"\xffcat".to_vec());
+    let trie = TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words);
+    assert_eq!(
+        trie.fuzzy_token_matches(b"cat", 0),
+        vec![(1, 0), (7, 0), (4, 0), (5, 0)]
+    );
+    assert_eq!(
+        trie.fuzzy_token_matches(b"cat", 1),
+        vec![(1, 0), (7, 0), (2, 1), (3, 1), (6, 1), (4, 0), (5, 0)]
+    );
+    assert_eq!(trie.fuzzy_token_matches(b"xyz", 0), vec![]);
+    assert_eq!(trie.fuzzy_token_matches(b"", 3), vec![]);
+    assert_eq!(trie.fuzzy_token_matches(b"\xffcut", 1), vec![(9, 1)]);
+
+    // against all tokens, one by one
+    let trie = trie_with_duplicates(2000, 7);
+    let mut rng = Rng::new(7);
+    for _ in 0..50 {
+        let mut bytes = trie.token(1 + rng.gen_up_to(1998) as TokenId).to_vec();
+        bytes.push(rng.gen_up_to(255) as u8);
+        let k = rng.gen_up_to(2);
+        let mut expected = (0..trie.vocab_size() as TokenId)
+            .filter_map(|t| {
+                let tok = trie.token(t);
+                if tok.is_empty()
+                    || tok.len() > bytes.len()
+                    || (tok[0] == 0xff) != (bytes[0] == 0xff)
+                {
+                    return None;
+                }
+                let m = tok.iter().zip(&bytes).filter(|(a, b)| a != b).count();
+                (m <= k).then_some((t, m))
+            })
+            .collect::<Vec<_>>();
+        expected.sort_by_key(|&(t, m)| (core::cmp::Reverse(trie.token(t).len()), m, t));
+        assert_eq!(
+            trie.fuzzy_token_matches(&bytes, k),
+            expected,
+            "{:?} {}",
+            bytes,
+            k
+        );
+    }
+}
+
+#[test]
+fn fuzzy_token_matches_limits() {
+    let trie = synthetic_trie(3000, 8);
+    // everything up to 8 bytes matches with 8 mismatches
+    let bytes = [b'x'; 8];
+    let (all, truncated) = trie.fuzzy_token_matches_ext(&bytes, 8, usize::MAX, usize::MAX);
+    assert!(!truncated);
+    assert!(all.len() > 100);
+    let (res, truncated) = trie.fuzzy_token_matches_ext(&bytes, 8, 100, usize::MAX);
+    assert!(truncated);
+    assert_eq!(res.len(), 100);
+    let (res, truncated) = trie.fuzzy_token_matches_ext(&bytes, 8, usize::MAX, 50);
+    assert!(truncated);
+    assert!(res.len() <= 50 && res.iter().all(|m| all.contains(m)));
+    // the default limits
+    assert!(trie.fuzzy_token_matches(&[b'x'; 64], 64).len() <= 1000);
+}
+
+#[test]
+fn compute_bias_within_candidates() {
+    let trie = trie_with_duplicates(3000, 21);
+    let mut rng = Rng::new(21);
+    for seed in 0..20 {
+        let mut candidates = trie.alloc_token_set();
+        // from very sparse to everything
+        let percent = [1, 5, 50, 100][seed as usize % 4];
+        for t in 0..trie.vocab_size() as TokenId {
+            if rng.gen_up_to(99) < percent {
+                candidates.allow_token(t);
+            }
+        }
+        let mut r = random_recognizer(seed, 70);
+        let mut expected = trie.alloc_token_set();
+        trie.compute_bias(&mut r, &mut expected);
+        expected.and(&candidates);
+
+        let mut logits = trie.alloc_token_set();
+        trie.compute_bias_within(&mut r, &candidates, &mut logits);
+        assert_eq!(logits, expected, "seed {}", seed);
+        let index = trie.index_candidates(&candidates);
+        assert_eq!(index.candidates(), &candidates);
+        // reused for another recognizer
+        for seed2 in 100..103 {
+            let mut r = random_recognizer(seed2, 70);
+            trie.compute_bias(&mut r, &mut expected);
+            expected.and(&candidates);
+            trie.compute_bias_with_index(&mut r, &index, &mut logits);
+            assert_eq!(logits, expected, "seed {}", seed2);
+        }
+    }
+
+    // a duplicate that is a candidate, of a token that is not
+    let trie = trie_of(&[b"\xff<eos>", b"a", b"ab", b"ab"]);
+    let mut candidates = trie.alloc_token_set();
+    candidates.allow_token(3);
+    let mut logits = trie.alloc_token_set();
+    let accept_all: ByteFn = |_, _| true;
+    trie.compute_bias_within(&mut FnRecognizer::new(accept_all), &candidates, &mut logits);
+    assert_eq!(logits.iter().collect::<Vec<_>>(), vec![3]);
+}
+
+#[test]
+#[should_panic(expected = "CandidateIndex is from another trie")]
+fn candidate_index_of_another_trie() {
+    let trie = synthetic_trie(1000, 1);
+    let index = synthetic_trie(1000, 2).index_candidates(&trie.alloc_token_set());
+    let mut logits = trie.alloc_token_set();
+    trie.compute_bias_with_index(&mut random_recognizer(1, 50), &index, &mut logits);
+}
+
+// rejects bytes past `limit`, and reports that as an error until `get_error()` is called
+#[derive(Default)]
+struct DepthLimit {
+    bytes: Vec<u8>,
+    base: Vec<usize>,
+    limit: usize,
+    error: Option<std::string::String>,
+}
+
+impl Recognizer for DepthLimit {
+    fn pop_bytes(&mut self, num: usize) {
+        self.bytes.truncate(self.bytes.len() - num);
+    }
+
+    fn collapse(&mut self) {}
+
+    fn special_allowed(&mut self, _tok: SpecialToken) -> bool {
+        true
+    }
+
+    fn trie_started(&mut self) {
+        self.base.push(self.bytes.len());
+    }
+
+    fn trie_finished(&mut self) {
+        let base = self.base.pop().unwrap();
+        self.bytes.truncate(base);
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        if self.bytes.len() >= self.limit {
+            self.error = Some(std::format!("depth limit {}", self.limit));
+            return false;
+        }
+        self.bytes.push(byte);
+        true
+    }
+
+    fn get_error(&mut self) -> Option<std::string::String> {
+        self.error.take()
+    }
+}
+
+#[test]
+fn recognizer_errors() {
+    let trie = trie_of(&[b"\xff<eos>", b"a", b"ab", b"abcde", b"b"]);
+    let mut r = DepthLimit {
+        limit: 3,
+        ..Default::default()
+    };
+    let mut logits = trie.alloc_token_set();
+    let e = trie.try_compute_bias(&mut r, &mut logits).unwrap_err();
+    assert_eq!(e.to_string(), "TokTrie: recognizer error: depth limit 3");
+    assert!(r.bytes.is_empty() && r.base.is_empty());
+
+    assert!(trie.try_token_allowed(&mut r, 2).unwrap());
+    let e = trie.try_token_allowed(&mut r, 3).unwrap_err();
+    assert!(e.to_string().ends_with("depth limit 3"), "{}", e);
+    // only the bytes after the prefix are pushed
+    r.limit = 1;
+    assert!(trie.try_has_valid_extensions(&mut r, b"a").unwrap());
+    assert!(trie.try_has_valid_extensions(&mut r, b"ab").is_err());
+    assert!(!trie.try_has_valid_extensions(&mut r, b"b").unwrap());
+    r.limit = 3;
+    assert!(r.bytes.is_empty() && r.base.is_empty());
+
+    // append_token() keeps the bytes accepted
+    let e = trie.append_token(&mut r, 3).unwrap_err();
+    assert_eq!(e.to_string(), "byte 'd' not allowed: depth limit 3");
+    assert_eq!(r.bytes, b"abc");
+    r.bytes.clear();
+    // append_tokens() pops the bytes of the rejected token
+    let e = trie.append_tokens(&mut r, &[1, 3]).unwrap_err();
+    let e = e.downcast::<AppendTokenError>().unwrap();
+    assert_eq!((e.token_index, e.byte_offset), (1, 3));
+    assert_eq!(e.recognizer_error.as_deref(), Some("depth limit 3"));
+    assert_eq!(r.bytes, b"a");
+
+    // the recognizer can be used again
+    r.limit = 10;
+    trie.try_compute_bias(&mut r, &mut logits).unwrap();
+    assert_eq!(logits.iter().collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+    trie.append_tokens(&mut r, &[1, 3]).unwrap();
+    assert_eq!(r.bytes, b"aaabcde");
+
+    // the rejected token is not collapsed
+    let mut r = RecordingRecognizer::new(FnRecognizer::new(|bytes: &[u8], b| {
+        bytes.len() < 3 && b"aab".starts_with(&[bytes, &[b]].concat())
+    }));
+    let e = trie.append_tokens(&mut r, &[1, 3]).unwrap_err();
+    let e = e.downcast::<AppendTokenError>().unwrap();
+    assert_eq!(
+        (e.token_index, e.byte_offset_in_token, e.byte),
+        (1, 2, b'c')
+    );
+    assert_eq!(
+        r.ops(),
+        [
+            RecognizerOp::TryPushByte(b'a', true),
+            RecognizerOp::Collapse,
+            RecognizerOp::TryPushByte(b'a', true),
+            RecognizerOp::TryPushByte(b'b', true),
+            RecognizerOp::TryPushByte(b'c', false),
+            RecognizerOp::PopBytes(2),
+        ]
+    );
+    assert_eq!(r.depth(), 0);
+    assert_eq!(r.inner().bytes(), b"a");
+}
+
+#[test]
+fn mask_ops() {
+    // 4 has the bytes of 2, and 6 those of 3
+    let words = [
+        &b"\xff<eos>"[..],
+        b"a",
+        b"\xff<x>",
+        b"b",
+        b"\xff<x>",
+        b"ab",
+        b"b",
+        b"\xff<s>",
+    ]
+    .iter()
+    .map(|w| w.to_vec())
+    .collect::<Vec<_>>();
+    let info = TokRxInfo::new(8, 0);
+    let trie = TokTrie::from(&info, &words);
+    let set_of = |tokens: &[TokenId]| {
+        let mut m = trie.alloc_token_set();
+        for &t in tokens {
+            m.allow_token(t);
+        }
+        m
+    };
+    let tokens_of = |m: &SimpleVob| m.iter_set_bits().map(|t| t as TokenId).collect::<Vec<_>>();
+
+    let mut a = set_of(&[0, 1, 3, 5, 6]);
+    trie.mask_and(&mut a, &set_of(&[1, 2, 5, 6, 7])).unwrap();
+    assert_eq!(tokens_of(&a), [1, 5, 6]);
+
+    // a set of another vocabulary size, and one of a capacity the words of `a` don't have
+    let other = TokTrie::from(&TokRxInfo::new(7, 0), &words[..7].to_vec());
+    let mut other_size = other.alloc_token_set();
+    other_size.allow_token(1);
+    let mut other_capacity = SimpleVob::alloc_with_capacity(8, 100);
+    other_capacity.allow_token(1);
+    for (mut bad, msg) in [
+        (
+            other_size,
+            "token set of size 7 (capacity 32) used with vocab size 8",
+        ),
+        (other_capacity, "SimpleVob capacity mismatch: 1 vs 4 words"),
+    ] {
+        let mut a = set_of(&[1, 5]);
+        let e = trie.mask_and(&mut a, &bad).unwrap_err();
+        assert!(e.to_string().contains(msg), "{}", e);
+        assert_eq!(tokens_of(&a), [1, 5]);
+        assert!(trie.mask_and(&mut bad, &set_of(&[5])).is_err());
+        assert_eq!(tokens_of(&bad), [1]);
+    }
+
+    // by the prefix byte, and with explicit lists naming one of the duplicates, or neither
+    let explicit = TokTrie::from_ext(&info, &words, Some(&[0, 2]));
+    let neither = TokTrie::from_ext(&info, &words, Some(&[0, 7]));
+    for (trie, left) in [
+        (&trie, &[1, 3, 5, 6][..]),
+        (&explicit, &[1, 3, 5, 6, 7]),
+        (&neither, &[1, 2, 3, 4, 5, 6]),
+    ] {
+        let mut m = trie.alloc_token_set();
+        m.set_all(true);
+        trie.mask_without_specials(&mut m);
+        assert_eq!(tokens_of(&m), left);
+    }
+
+    assert_eq!(trie.mask_count_and_example(&set_of(&[])), (0, None));
+    assert_eq!(
+        trie.mask_count_and_example(&set_of(&[6, 3, 7])),
+        (3, Some(3))
+    );
+
+    let b = trie.canonical_token(3);
+    assert_eq!(trie.mask_is_singleton(&set_of(&[])), None);
+    assert_eq!(trie.mask_is_singleton(&set_of(&[1])), Some(1));
+    assert_eq!(trie.mask_is_singleton(&set_of(&[3, 6])), Some(b));
+    // either duplicate alone is still the same token
+    assert_eq!(trie.mask_is_singleton(&set_of(&[3])), Some(b));
+    assert_eq!(trie.mask_is_singleton(&set_of(&[6])), Some(b));
+    assert_eq!(trie.mask_is_singleton(&set_of(&[1, 3])), None);
+    assert_eq!(trie.mask_is_singleton(&set_of(&[2, 3, 4])), None);
+
+    // a forced token, with the duplicates compute_bias() allows
+    let mut r = FnRecognizer::new((|bytes, b| bytes.is_empty() && b == b'b') as ByteFn)
+        .with_eos((|_| false) as EosFn);
+    let mut m = trie.alloc_token_set();
+    trie.compute_bias(&mut r, &mut m);
+    assert_eq!(tokens_of(&m), [3, 6]);
+    assert_eq!(trie.mask_count_and_example(&m), (2, Some(3)));
+    assert_eq!(trie.mask_is_singleton(&m), Some(b));
+}
+
+// chop_tokens() as it was, growing the suffix at the front
+fn chop_tokens_splice(
+    trie: &TokTrie,
+    r: &mut impl Recognizer,
+    tokens: &[TokenId],
+) -> (usize, usize) {
+    let mut suff = Vec::new();
+    let mut chop_tokens = 0;
+    let mut chop_bytes = 0;
+    for (idx, t) in tokens.iter().rev().enumerate() {
+        suff.splice(0..0, trie.token(*t).iter().cloned());
+        if suff.len() > trie.max_token_len() {
+            break;
+        }
+        if trie.has_valid_extensions(r, &suff) {
+            chop_tokens = idx + 1;
+            chop_bytes = suff.len();
+        }
+    }
+    (chop_tokens, chop_bytes)
+}
+
+#[test]
+fn chop_tokens_matches_splice() {
+    let mut rng = Rng::new(12);
+    let mut num_chopped = 0;
+    for trie in [synthetic_trie(3000, 12), trie_with_duplicates(1000, 12)] {
+        let mut scratch = trie.new_scratch();
+        for seed in 0..30 {
+            let mut r = random_recognizer(seed, [30, 70, 95][seed as usize % 3]);
+            for _ in 0..20 {
+                // the greedy tokens of a cut-off run of tokens end with parts of longer ones
+                let mut bytes = Vec::new();
+                for _ in 0..rng.gen_up_to(12) {
+                    bytes.extend_from_slice(
+                        trie.token(rng.gen_up_to(trie.vocab_size() - 1) as TokenId),
+                    );
+                }
+                bytes.truncate(rng.gen_up_to(bytes.len()));
+                let tokens = trie.greedy_tokenize(&bytes);
+                let expected = chop_tokens_splice(&trie, &mut r, &tokens);
+                assert_eq!(trie.chop_tokens(&mut r, &tokens), expected, "{:?}", tokens);
+                assert_eq!(
+                    trie.chop_tokens_with_scratch(&mut r, &tokens, &mut scratch),
+                    expected
+                );
+                num_chopped += expected.0;
+            }
+        }
+    }
+    assert!(num_chopped > 100, "{}", num_chopped);
+}
+
+#[test]
+fn constraint_stepper() {
+    let words = [
+        &b"\xff<eos>"[..],
+        b"a",
+        b"b",
+        b"ab",
+        b"abc",
+        b"c",
+        b"\xff<eot>",
+        b"x",
+        b"ac",
+    ]
+    .iter()
+    .map(|w| w.to_vec())
+    .collect::<Vec<_>>();
+    let info = TokRxInfo {
+        tok_end_of_turn: Some(6),
+        ..TokRxInfo::new(9, 0)
+    };
+    let trie = TokTrie::from(&info, &words);
+    // "abcab", then anything, which can end there
+    let r =
+        FnRecognizer::new((|bytes, b| bytes.len() >= 5 || b"abcab"[bytes.len()] == b) as ByteFn)
+            .with_eos((|bytes| bytes.len() >= 5) as EosFn);
+    let mut st = ConstraintStepper::new(&trie, RecordingRecognizer::new(r), &[]);
+    let allowed = |st: &mut ConstraintStepper<_>| {
+        let mask = st.mask();
+        mask.iter_set_bits().collect::<Vec<_>>()
+    };
+
+    assert_eq!(allowed(&mut st), [1, 3, 4]);
+    assert_eq!(
+        st.advance(2).unwrap(),
+        StepOutcome::Rejected { byte_idx: 0 }
+    );
+    // EOS and end-of-turn are not allowed yet
+    assert_eq!(
+        st.advance(0).unwrap(),
+        StepOutcome::Rejected { byte_idx: 0 }
+    );
+    assert_eq!(
+        st.advance(6).unwrap(),
+        StepOutcome::Rejected { byte_idx: 0 }
+    );
+    assert_eq!(allowed(&mut st), [1, 3, 4]);
+    assert_eq!(st.advance(4).unwrap(), StepOutcome::Continue);
+    // "abc" again would be fine after "ab"
+    assert_eq!(allowed(&mut st), [1, 3, 4]);
+    // "a" is pushed, and popped when "c" is rejected
+    assert_eq!(
+        st.advance(8).unwrap(),
+        StepOutcome::Rejected { byte_idx: 1 }
+    );
+    assert_eq!(st.recognizer().depth(), 0);
+    assert_eq!(allowed(&mut st), [1, 3, 4]);
+    assert_eq!(st.advance(3).unwrap(), StepOutcome::Continue);
+
+    // anything goes, but the end-of-turn token only as such, and the recognizer
+    // doesn't allow that, though it would take its bytes
+    let mask = allowed(&mut st);
+    assert_eq!(mask, [0, 1, 2, 3, 4, 5, 7, 8]);
+    assert_eq!(
+        st.advance(6).unwrap(),
+        StepOutcome::Rejected { byte_idx: 0 }
+    );
+    assert_eq!(allowed(&mut st), mask);
+    assert_eq!(st.advance(7).unwrap(), StepOutcome::Continue);
+    assert_eq!(st.advance(0).unwrap(), StepOutcome::Eos);
+    assert_eq!(st.tokens(), [4, 3, 7, 0]);
+    assert!(st.advance(9).is_err());
+    let r = st.into_recognizer();
+    assert!(r.violation().is_none(), "{:?}", r.violation());
+}
+
+// spends at least `per_byte` in every try_push_byte()
+#[cfg(feature = "std")]
+struct SlowRecognizer<R> {
+    inner: R,
+    per_byte: Duration,
+}
+
+#[cfg(feature = "std")]
+impl<R: Recognizer> Recognizer for SlowRecognizer<R> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.inner.pop_bytes(num)
+    }
+
+    fn collapse(&mut self) {
+        self.inner.collapse()
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        self.inner.special_allowed(tok)
+    }
+
+    fn trie_finished(&mut self) {
+        self.inner.trie_finished()
+    }
+
+    fn trie_started(&mut self) {
+        self.inner.trie_started()
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        // spin rather than sleep, which may take much longer than asked
+        let start = Instant::now();
+        while start.elapsed() < self.per_byte {}
+        self.inner.try_push_byte(byte)
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn compute_bias_with_budget() {
+    let trie = synthetic_trie(2000, 5);
+    let mut r = RecordingRecognizer::new(random_recognizer(5, 70));
+    let mut full = trie.alloc_token_set();
+    trie.compute_bias(&mut r, &mut full);
+
+    let mut logits = trie.alloc_token_set();
+    let outcome = trie.compute_bias_with_budget(&mut r, &mut logits, WalkBudget::default());
+    assert!(!outcome.truncated);
+    assert_eq!(logits, full);
+    let num_nodes = outcome.nodes_visited;
+    assert!(num_nodes > 100);
+
+    for max_nodes in [0, 1, 2, 63, 64, 65, num_nodes / 2, num_nodes - 1, num_nodes] {
+        let budget = WalkBudget::default().with_max_nodes(max_nodes);
+        let outcome = trie.compute_bias_with_budget(&mut r, &mut logits, budget);
+        assert_eq!(outcome.truncated, max_nodes < num_nodes);
+        assert_eq!(outcome.nodes_visited, max_nodes);
+        // a subset of the full mask, growing with the budget
+        let mut sub = logits.clone();
+        sub.and_with(&full).unwrap();
+        assert_eq!(sub, logits);
+        if max_nodes == num_nodes {
+            assert_eq!(logits, full);
+        }
+        assert_eq!(r.depth(), 0);
+    }
+    assert!(r.violation().is_none(), "{:?}", r.violation());
+    let r = r.into_inner();
+
+    // a deadline long past still lets EOS through, and stops before the first node
+    let budget = WalkBudget::default().with_deadline(Instant::now());
+    let mut r = RecordingRecognizer::new(r);
+    let outcome = trie.compute_bias_with_budget(&mut r, &mut logits, budget);
+    assert!(outcome.truncated);
+    assert_eq!(outcome.nodes_visited, 0);
+    assert_eq!(logits.iter_set_bits().collect::<Vec<_>>(), [0]);
+
+    // a recognizer that takes 20µs a byte, much longer than the deadline for the whole trie;
+    // the walk overshoots by at most one check interval, plus slack for a busy machine
+    let per_byte = Duration::from_micros(20);
+    let timeout = Duration::from_millis(10);
+    let mut slow = RecordingRecognizer::new(SlowRecognizer {
+        inner: r.into_inner(),
+        per_byte,
+    });
+    for _ in 0..3 {
+        let start = Instant::now();
+        let budget = WalkBudget::default().with_timeout(timeout);
+        let outcome = trie.compute_bias_with_budget(&mut slow, &mut logits, budget);
+        let elapsed = start.elapsed();
+        assert!(outcome.truncated);
+        assert!(outcome.nodes_visited < num_nodes);
+        assert!(elapsed >= timeout);
+        assert!(
+            elapsed < timeout + per_byte * DEADLINE_CHECK_INTERVAL as u32 + timeout,
+            "{elapsed:?}"
+        );
+        let mut sub = logits.clone();
+        sub.and_with(&full).unwrap();
+        assert_eq!(sub, logits);
+        assert_eq!(slow.depth(), 0);
+    }
+    assert!(slow.violation().is_none(), "{:?}", slow.violation());
+}
+
+#[test]
+fn byte_coverage_and_can_represent() {
+    // no byte fallback: "c", "d" and "e" are not tokens by themselves
+    let trie = trie_of(&[b"\xff<eos>", b"abc", b"a", b"bcd", b"b", b"x", b"e\xc3\xa9"]);
+    let cov = trie.byte_coverage();
+    let bytes_of = |set: &[bool; 256]| (0..=255u8).filter(|&b| set[b as usize]).collect::<Vec<_>>();
+    assert_eq!(bytes_of(&cov.starts_token), b"abex\xff");
+    assert_eq!(bytes_of(&cov.single_byte_token), b"abx");
+    assert!(!cov.covers_all_bytes);
+
+    assert!(trie.can_represent(b""));
+    // greedy takes "abc" and is stuck at "d"; "a" "bcd" works
+    assert!(trie.try_greedy_tokenize(b"abcd").is_err());
+    assert!(trie.can_represent(b"abcd"));
+    assert!(trie.try_greedy_tokenize(b"xabcdabcx").is_err());
+    assert!(trie.can_represent(b"xabcdabcx"));
+    assert!(trie.can_represent("e\u{e9}b".as_bytes()));
+    assert!(trie.can_represent(b"\xff<eos>"));
+    for bytes in [
+        &b"c"[..],
+        b"d",
+        b"abd",
+        b"abcdc",
+        b"e",
+        b"\xc3\xa9",
+        b"\xff<eo",
+    ] {
+        assert!(!trie.can_represent(bytes), "{bytes:?}");
+    }
+
+    // all strings of up to 6 bytes over "abcdx"
+    for (len, n) in (0..=6).flat_map(|len| (0..5usize.pow(len)).map(move |n| (len, n))) {
+        let bytes = (0..len)
+            .map(|i| b"abcdx"[n / 5usize.pow(i) % 5])
+            .collect::<Vec<_>>();
+        assert_eq!(
+            trie.can_represent(&bytes),
+            !all_tokenizations(&trie, &bytes).is_empty(),
+            "{bytes:?}"
+        );
+    }
+
+    // with every byte a token, anything goes
+    let words = (0..=255u8).map(|b| alloc::vec![b]).collect::<Vec<_>>();
+    let trie = TokTrie::from(&TokRxInfo::new(256, 0), &words);
+    let cov = trie.byte_coverage();
+    assert!(cov.covers_all_bytes);
+    assert_eq!(cov.starts_token, [true; 256]);
+    assert!(trie.can_represent(b"\x00\xff\xc3any bytes"));
+}
+
+#[test]
+fn bias_stats() {
+    let trie = trie_of(&[
+        b"\xff<eos>",
+        b"1",
+        b"12",
+        b"123",
+        b"1a",
+        b"2",
+        b"a",
+        b"ab",
+        b"3x4",
+        b"45",
+    ]);
+    let mut r =
+        FnRecognizer::new((|_, b: u8| b.is_ascii_digit()) as ByteFn).with_eos((|_| false) as EosFn);
+    let mut logits = trie.alloc_token_set();
+    let mut stats = BiasStats {
+        tokens_allowed: 100,
+        ..Default::default()
+    };
+    trie.compute_bias_with_stats(&mut r, &mut logits, &mut stats);
+    assert_eq!(logits.iter_set_bits().collect::<Vec<_>>(), [1, 2, 3, 5, 9]);
+    // of the 18 nodes below the root, not "<eos>" after 0xff, "4" after "3x", "b" after "a"
+    assert_eq!(
+        stats,
+        BiasStats {
+            tokens_allowed: 5,
+            nodes_visited: 11,
+            max_depth_reached: 3,
+            distinct_first_bytes: 4,
+        }
+    );
+    assert_eq!(trie.num_allowed_children(&mut r, b""), 4);
+    let mut expected = trie.alloc_token_set();
+    trie.compute_bias(&mut r, &mut expected);
+    assert_eq!(logits, expected);
+
+    // below "1": "2", "3" and "a"; "1" is allowed as a prefix of the start
+    trie.compute_bias_ext_with_stats(&mut r, &mut logits, b"1", &mut stats);
+    assert_eq!(logits.iter_set_bits().collect::<Vec<_>>(), [1, 2, 3]);
+    assert_eq!(
+        stats,
+        BiasStats {
+            tokens_allowed: 3,
+            nodes_visited: 3,
+            max_depth_reached: 2,
+            distinct_first_bytes: 1,
+        }
+    );
+    assert_eq!(trie.num_allowed_children(&mut r, b"1"), 1);
+    assert_eq!(trie.num_allowed_children(&mut r, b"3"), 0);
+    assert_eq!(trie.num_allowed_children(&mut r, b"5"), 0);
+
+    // and EOS counts too
+    let mut r = r.with_eos((|_| true) as EosFn);
+    trie.compute_bias_with_stats(&mut r, &mut logits, &mut stats);
+    assert_eq!(stats.tokens_allowed, 6);
+    assert!(logits.is_allowed(0));
+}
+
+#[test]
+fn with_vocab_size() {
+    let trie = trie_with_duplicates(3000, 7);
+    let info = TokRxInfo {
+        tok_bos: Some(1),
+        tok_unk: Some(2500),
+        tok_end_of_turn: Some(2999),
+        ..*trie.info()
+    };
+    let trie = trie.with_info(info).with_stop_tokens(&[5, 300, 2600]);
+    assert!(trie.try_with_info(TokRxInfo::new(2000, 0)).is_err());
+    assert!(trie.with_vocab_size(0).is_err());
+
+    let mut full = trie.alloc_token_set();
+    for n in [1, 256, 257, 1000, 2999, 3000, 3100] {
+        let small = trie.with_vocab_size(n).unwrap();
+        let kept = |t: TokenId| Some(t).filter(|&t| t < n);
+        assert_eq!(small.vocab_size(), n as usize);
+        assert_eq!(small.info().tok_bos, kept(1));
+        assert_eq!(small.info().tok_unk, kept(2500));
+        assert_eq!(small.info().tok_end_of_turn, kept(2999));
+        let stops = [5, 300, 2600].into_iter().filter_map(kept);
+        assert_eq!(small.stop_tokens(), stops.collect::<Vec<_>>());
+        for t in 0..n {
+            let bytes: &[u8] = if t < 3000 { trie.token(t) } else { &[] };
+            assert_eq!(small.token(t), bytes);
+        }
+
+        let mut logits = small.alloc_token_set();
+        assert_eq!(logits.len(), n as usize);
+        for seed in 0..4 {
+            let mut r = random_recognizer(seed, 60);
+            trie.compute_bias(&mut r, &mut full);
+            small.compute_bias(&mut r, &mut logits);
+            // the same tokens, as far as both have them; a dropped canonical token
+            // leaves a duplicate to stand for its bytes
+            let expected = full.iter_set_bits().filter(|&t| t < n).collect::<Vec<_>>();
+            assert_eq!(logits.iter_set_bits().collect::<Vec<_>>(), expected);
+            assert!(logits.iter_set_bits().all(|t| t < n));
+        }
+    }
+}
+
+#[test]
+fn compatibility_and_check_against() {
+    let words = [&b"\xff<eos>"[..], b"a", b"b", b"ab", b"c"]
+        .iter()
+        .map(|w| w.to_vec())
+        .collect::<Vec<_>>();
+    let trie = TokTrie::from(&TokRxInfo::new(5, 0), &words);
+    let with_words = |f: &dyn Fn(&mut Vec<Vec<u8>>)| {
+        let mut words = words.clone();
+        f(&mut words);
+        TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words)
+    };
+
+    let diff = trie.compatibility(&trie.clone());
+    assert!(diff.is_identical() && diff.mask_compatible);
+    assert_eq!(
+        diff,
+        TrieDiff {
+            mask_compatible: true,
+            ..Default::default()
+        }
+    );
+    assert_eq!(std::format!("{}", diff), "identical");
+    trie.check_against(&words).unwrap();
+    trie.check_against(&words[..3]).unwrap();
+
+    // a token added
+    let longer = with_words(&|w| w.push(b"d".to_vec()));
+    let diff = trie.compatibility(&longer);
+    assert_eq!(
+        diff,
+        TrieDiff {
+            vocab_size_delta: 1,
+            ids_only_in_other: alloc::vec![5],
+            bytes_only_in_other: alloc::vec![b"d".to_vec()],
+            ..Default::default()
+        }
+    );
+    assert_eq!(std::format!("{}", diff), "vocab size +1, 1 tokens added");
+    let diff = longer.compatibility(&trie);
+    assert_eq!(diff.vocab_size_delta, -1);
+    assert_eq!(diff.ids_only_in_self, [5]);
+    assert_eq!(diff.bytes_only_in_self, [b"d".to_vec()]);
+    assert!(!diff.mask_compatible);
+    let mut more = words.clone();
+    more.push(b"d".to_vec());
+    let err = trie.check_against(&more);
+    assert!(err.unwrap_err().to_string().contains("vocab size +1"));
+
+    // the bytes of a token changed
+    let changed = with_words(&|w| w[3] = b"ba".to_vec());
+    let diff = trie.compatibility(&changed);
+    assert_eq!(
+        diff,
+        TrieDiff {
+            changed_ids: alloc::vec![3],
+            bytes_only_in_self: alloc::vec![b"ab".to_vec()],
+            bytes_only_in_other: alloc::vec![b"ba".to_vec()],
+            ..Default::default()
+        }
+    );
+    assert_eq!(
+        std::format!("{}", diff),
+        "1 changed ids (first 3), 1 tokens removed, 1 tokens added"
+    );
+    let mut bad = words.clone();
+    bad[3] = b"ba".to_vec();
+    assert_eq!(
+        trie.check_against(&bad).unwrap_err().to_string(),
+        std::format!("TokTrie: tokens don't match: {}", diff)
+    );
+
+    // the same bytes under other ids
+    let swapped = with_words(&|w| w.swap(1, 2));
+    let diff = trie.compatibility(&swapped);
+    assert_eq!(diff.changed_ids, [1, 2]);
+    assert!(diff.bytes_only_in_self.is_empty() && diff.bytes_only_in_other.is_empty());
+    assert!(!diff.mask_compatible && !diff.is_identical());
+
+    // each special token on its own; masks are still compatible
+    let info = *trie.info();
+    let others = [
+        ("eos", Some(0), Some(4), TokRxInfo { tok_eos: 4, ..info }),
+        (
+            "bos",
+            None,
+            Some(1),
+            TokRxInfo {
+                tok_bos: Some(1),
+                ..info
+            },
+        ),
+        (
+            "pad",
+            None,
+            Some(2),
+            TokRxInfo {
+                tok_pad: Some(2),
+                ..info
+            },
+        ),
+        (
+            "unk",
+            None,
+            Some(3),
+            TokRxInfo {
+                tok_unk: Some(3),
+                ..info
+            },
+        ),
+        (
+            "end_of_turn",
+            None,
+            Some(4),
+            TokRxInfo {
+                tok_end_of_turn: Some(4),
+                ..info
+            },
+        ),
+    ];
+    for (name, a, b, other) in others {
+        let diff = trie.compatibility(&trie.with_info(other));
+        assert_eq!(
+            diff,
+            TrieDiff {
+                special_diffs: alloc::vec![(name, a, b)],
+                mask_compatible: true,
+                ..Default::default()
+            }
+        );
+        assert!(!diff.is_identical());
+        assert_eq!(
+            std::format!("{}", diff),
+            std::format!("{}: {:?} -> {:?}", name, a, b)
+        );
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn vocab_analysis() {
+    let trie = trie_of(&[
+        b"\xff<eos>",
+        b"a",
+        b"b",
+        b"ab",
+        b"a",
+        b"abc",
+        b"",
+        b"ab",
+        b"b",
+        b"ab",
+    ]);
+    let set = |v: &SimpleVob| v.iter_set_bits().collect::<Vec<_>>();
+    let a = trie.analysis();
+    // the last of the same bytes is the canonical token; the others are unreachable,
+    // and the empty token is not included
+    assert_eq!(set(&a.greedy_unreachable), [1, 2, 3, 7]);
+    assert_eq!(set(&trie.greedy_unreachable_tokens()), [1, 2, 3, 7]);
+    for t in [4, 5, 8, 9] {
+        assert_eq!(trie.greedy_tokenize(trie.token(t)), [t]);
+    }
+    // the leaves, with both tokens of "b"
+    assert_eq!(set(&a.never_extending), [0, 2, 5, 8]);
+    assert_eq!(set(&trie.tokens_never_extending()), [0, 2, 5, 8]);
+    assert_eq!(a.num_duplicate_groups, 3);
+    assert_eq!(a.num_duplicates, 4);
+    assert_eq!(a.max_duplicate_group_size, 3);
+
+    // computed once for all the clones
+    let other = trie.with_eos_token(5);
+    assert!(core::ptr::eq(other.analysis(), a));
+
+    let a = trie_of(&[b"\xff<eos>", b"a", b"b"]).analysis().clone();
+    assert!(a.greedy_unreachable.is_zero());
+    assert_eq!(set(&a.never_extending), [0, 1, 2]);
+    assert_eq!(a.num_duplicate_groups, 0);
+    assert_eq!(a.max_duplicate_group_size, 0);
+}
+
+fn check_token_tables(trie: &TokTrie) {
+    let n = trie.vocab_size();
+    assert_eq!(trie.token_lengths().len(), n);
+    assert_eq!(trie.token_first_bytes().len(), n);
+    assert_eq!(trie.token_last_bytes().len(), n);
+    for t in 0..n as TokenId {
+        let bytes = trie.token(t);
+        let len = trie.token_lengths()[t as usize];
+        assert_eq!(len as usize, bytes.len().min(u16::MAX as usize), "{}", t);
+        assert_eq!(
+            trie.token_first_bytes()[t as usize],
+            *bytes.first().unwrap_or(&0)
+        );
+        assert_eq!(
+            trie.token_last_bytes()[t as usize],
+            *bytes.last().unwrap_or(&0)
+        );
+    }
+    let naive = |f: &dyn Fn(&[u8]) -> bool| {
+        (0..n as TokenId)
+            .filter(|&t| f(trie.token(t)))
+            .collect::<Vec<_>>()
+    };
+    for b in [0, b'a', b' ', 0x80, 0xff] {
+        assert_eq!(
+            trie.tokens_starting_with_byte(b)
+                .iter_set_bits()
+                .collect::<Vec<_>>(),
+            naive(&|bytes| bytes.first() == Some(&b))
+        );
+    }
+    for len in [0, 1, 2, 3, 7, 65534, 65535, 65536, 70000] {
+        assert_eq!(
+            trie.tokens_with_len_at_most(len)
+                .iter_set_bits()
+                .collect::<Vec<_>>(),
+            naive(&|bytes| bytes.len() <= len),
+            "{}",
+            len
+        );
+    }
+}
+
+#[test]
+fn token_tables() {
+    let mut words = synthetic_vocab(3000, 11);
+    words[5] = Vec::new();
+    words[300] = Vec::new();
+    // over u16::MAX bytes, and exactly that
+    words[1000] = alloc::vec![b'y'; 70_000];
+    words[1001] = alloc::vec![b'z'; 65_535];
+    let trie = TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words);
+    check_token_tables(&trie);
+    check_token_tables(&TokTrie::from_bytes(&trie.serialize()));
+    // extended rather than recomputed
+    let added = trie
+        .with_added_tokens(&[
+            (b"new".to_vec(), false),
+            (Vec::new(), false),
+            (b"sep".to_vec(), true),
+            (alloc::vec![b'q'; 65_536], false),
+        ])
+        .unwrap();
+    check_token_tables(&added);
+    assert_eq!(added.token_lengths()[3002], 4);
+    assert_eq!(added.token_first_bytes()[3002], 0xff);
+    assert_eq!(added.token_lengths()[3003], u16::MAX);
+}
+
+#[test]
+fn merge_tokens() {
+    let trie = trie_of(&[
+        b"\xff<eos>",
+        b"a",
+        b"b",
+        b"ab",
+        b"abc",
+        b"c",
+        b"ab",
+        b"",
+        b"bc",
+    ]);
+    // "ab" is 3 and 6, and 6 is canonical
+    assert_eq!(trie.token_id(b"ab"), Some(6));
+    assert_eq!(trie.merge_token(1, 2), Some(6));
+    assert_eq!(trie.token_end_node(3), trie.token_end_node(6));
+    let end = trie.token_end_node(3).unwrap();
+    assert_eq!(trie.node(end).token_id(), Some(6));
+    assert_eq!(trie.merge_token(3, 5), Some(4));
+    assert_eq!(trie.merge_token(6, 5), Some(4));
+    assert_eq!(trie.merge_token(1, 8), Some(4));
+    assert_eq!(trie.merge_token(2, 5), Some(8));
+    // "ca" and "ba" are not tokens, "aab" neither, although "ab" is
+    assert_eq!(trie.merge_token(5, 1), None);
+    assert_eq!(trie.merge_token(2, 1), None);
+    assert_eq!(trie.merge_token(1, 3), None);
+    // empty tokens don't merge
+    assert_eq!(trie.merge_token(1, 7), None);
+    assert_eq!(trie.merge_token(7, 1), None);
+    assert_eq!(trie.find_mergeable_pairs(&[1, 2, 5, 1, 8]), [0, 1, 3]);
+    assert!(trie.find_mergeable_pairs(&[1]).is_empty());
+
+    // the same as looking up the concatenation
+    let trie = trie_with_duplicates(2000, 13);
+    let mut rng = Rng::new(13);
+    for _ in 0..20_000 {
+        let a = rng.gen_up_to(1999) as TokenId;
+        let b = rng.gen_up_to(1999) as TokenId;
+        let bytes = [trie.token(a), trie.token(b)].concat();
+        assert_eq!(trie.merge_token(a, b), trie.token_id(&bytes), "{} {}", a, b);
+    }
+}
+
+// serialized `trie`, with `f` applied to its nodes as (bits, bits2) pairs
+fn with_nodes_patched(trie: &TokTrie, f: impl FnOnce(&mut [[u32; 2]])) -> Vec<u8> {
+    let mut bytes = trie.serialize();
+    let (_, [nodes, ..], _) = TokTrieHeader::parse(&bytes).unwrap();
+    let mut words = bytes[nodes.clone()]
+        .chunks(8)
+        .map(|c| {
+            [
+                u32::from_le_bytes(c[..4].try_into().unwrap()),
+                u32::from_le_bytes(c[4..].try_into().unwrap()),
+            ]
+        })
+        .collect::<Vec<_>>();
+    f(&mut words);
+    for (c, w) in bytes[nodes].chunks_mut(8).zip(&words) {
+        c[..4].copy_from_slice(&w[0].to_le_bytes());
+        c[4..].copy_from_slice(&w[1].to_le_bytes());
+    }
+    bytes
+}
+
+#[test]
+fn malformed_nodes() {
+    // "a" "ab" "abc" "b" under the root
+    let trie = trie_of(&[b"a", b"b", b"ab", b"abc"]);
+    let subtree = |w: &mut [u32; 2], size: u32| w[1] = (size << 8) | (w[1] & 0xff);
+    let token = |w: &[u32; 2]| w[0] >> 8;
+    // the structure is checked also when loading unchecked, as loading relies on it
+    let check = |bytes: &[u8], expected: ValidationError, msg: &str, structure: bool| {
+        let err = TokTrie::try_from_bytes(bytes).unwrap_err();
+        assert_eq!(err.to_string(), msg);
+        assert_eq!(err.downcast_ref::<ValidationError>(), Some(&expected));
+        assert!(TokTrieRef::try_from_bytes(bytes).is_err());
+        if structure {
+            let err = TokTrie::from_bytes_unchecked(bytes).unwrap_err();
+            assert_eq!(err.downcast_ref::<ValidationError>(), Some(&expected));
+            let err = TokTrieRef::from_bytes_unchecked(bytes).err().unwrap();
+            assert_eq!(err.downcast_ref::<ValidationError>(), Some(&expected));
+        } else {
+            let unchecked = TokTrie::from_bytes_unchecked(bytes).unwrap();
+            assert_eq!(unchecked.validate(), Err(expected));
+            let unchecked = TokTrieRef::from_bytes_unchecked(bytes).unwrap();
+            assert_eq!(unchecked.validate(), Err(expected));
+        }
+    };
+
+    // a node that is its own subtree, forever
+    check(
+        &with_nodes_patched(&trie, |n| subtree(&mut n[2], 0)),
+        ValidationError::SubtreeSize {
+            offset: 2,
+            subtree_size: 0,
+        },
+        "TokTrie: node 2 has invalid subtree size 0",
+        true,
+    );
+    // "ab" overlapping "b", its parent's sibling
+    check(
+        &with_nodes_patched(&trie, |n| subtree(&mut n[2], 3)),
+        ValidationError::SubtreeSize {
+            offset: 2,
+            subtree_size: 3,
+        },
+        "TokTrie: node 2 has invalid subtree size 3",
+        true,
+    );
+    // the root not covering the last node
+    check(
+        &with_nodes_patched(&trie, |n| subtree(&mut n[0], 4)),
+        ValidationError::RootSize {
+            subtree_size: 4,
+            num_nodes: 5,
+        },
+        "TokTrie: root subtree size 4 doesn't match 5 nodes",
+        true,
+    );
+    // "b" pointing back at the node of "a"
+    check(
+        &with_nodes_patched(&trie, |n| n[4][0] = (token(&n[1]) << 8) | (n[4][0] & 0xff)),
+        ValidationError::DuplicateToken {
+            offset: 4,
+            token: 0,
+            first_offset: 1,
+        },
+        "TokTrie: token 0 appears twice in the trie, at nodes 1 and 4",
+        false,
+    );
+
+    // a chain of 100k nodes is checked without recursion; the last one is off
+    let long = alloc::vec![b'x'; 100_000];
+    let trie = trie_of(&[b"y", &long]);
+    let bytes = with_nodes_patched(&trie, |n| {
+        let last = n.len() - 1;
+        n[last][1] ^= 1;
+    });
+    let err = TokTrie::from_bytes_unchecked(&bytes)
+        .unwrap()
+        .validate()
+        .unwrap_err();
+    assert!(matches!(err, ValidationError::NumParents { .. }));
+    assert_eq!(err.offset(), Some(100_001));
+}
+
+#[test]
+fn several_stop_tokens() {
+    let trie = trie_of(&[b"\xff<eos>", b"a", b"\xff<end>", b"b", b"\xff<stop>"]);
+    // "a"s, then EOS
+    let mut r = FnRecognizer::new((|_, b| b == b'a') as ByteFn)
+        .with_eos((|bytes| !bytes.is_empty()) as EosFn);
+    let mut logits = trie.alloc_token_set();
+    let mut mask = |trie: &TokTrie, r: &mut FnRecognizer<ByteFn, EosFn>| {
+        trie.compute_bias(r, &mut logits);
+        logits.iter_set_bits().collect::<Vec<_>>()
+    };
+
+    // with EOS alone, as before
+    assert!(trie.stop_tokens().is_empty());
+    assert_eq!(trie.eos_token(), 0);
+    assert!(trie.is_stop_token(0) && !trie.is_stop_token(2));
+    assert_eq!(mask(&trie, &mut r), [1]);
+
+    let stops = trie.with_stop_tokens(&[4, 2, 0, 2, 99]);
+    assert_eq!(stops.stop_tokens(), [2, 4]);
+    assert_eq!(stops.eos_token(), 0);
+    let stop_set = (0..5)
+        .filter(|&t| stops.is_stop_token(t))
+        .collect::<Vec<_>>();
+    assert_eq!(stop_set, [0, 2, 4]);
+    assert_eq!(mask(&stops, &mut r), [1]);
+
+    trie.append_token(&mut r, 1).unwrap();
+    assert_eq!(mask(&trie, &mut r), [0, 1]);
+    // all of them together with EOS
+    assert_eq!(mask(&stops, &mut r), [0, 1, 2, 4]);
+    let loaded = TokTrie::from_bytes(&stops.serialize());
+    assert_eq!(loaded.stop_tokens(), [2, 4]);
+    assert_eq!(mask(&loaded, &mut r), [0, 1, 2, 4]);
+    // replaced, not added to
+    let other = stops.with_stop_tokens(&[4]);
+    assert_eq!(mask(&other, &mut r), [0, 1, 4]);
+    // a stop token as EOS
+    let eos4 = other.with_eos_token(4);
+    assert_eq!(eos4.eos_token(), 4);
+    assert_eq!(mask(&eos4, &mut r), [1, 4]);
+}
+
+#[test]
+fn byte_bias_covers_token_bias() {
+    let trie = synthetic_trie(3000, 17).with_stop_tokens(&[7]);
+    let mut logits = trie.alloc_token_set();
+    for seed in 0..40 {
+        // from all bytes down to hardly any
+        let percent = 100 - (seed % 10) * 11;
+        let mut r = RecordingRecognizer::new(
+            FnRecognizer::new(move |bytes: &[u8], b| random_byte_allowed(seed, percent, bytes, b))
+                .with_eos(move |bytes: &[u8]| random_byte_allowed(seed, 50, bytes, 0)),
+        );
+        // starting after one byte
+        for b in 0..=255 {
+            if trie.child_at_byte(trie.root(), b).is_some() && r.try_push_byte(b) {
+                break;
+            }
+        }
+        r.collapse();
+        let bytes = trie.compute_byte_bias(&mut r);
+        let first = trie.allowed_first_bytes_of_tokens(&mut r);
+        trie.compute_bias(&mut r, &mut logits);
+        assert_eq!(r.depth(), 0);
+
+        for t in logits.iter_set_bits() {
+            // stop tokens may be there as EOS, and not by their bytes
+            if trie.is_stop_token(t) && bytes.eos_allowed {
+                continue;
+            }
+            let b = trie.token(t)[0] as usize;
+            assert!(bytes.bytes[b], "{} {}", seed, trie.token_dbg(t));
+            assert!(first.bytes[b], "{} {}", seed, trie.token_dbg(t));
+        }
+        assert_eq!(first.eos_allowed, bytes.eos_allowed);
+        if bytes.eos_allowed {
+            assert!(logits.is_allowed(trie.eos_token()));
+        }
+        for b in 0..256 {
+            let starts = trie.child_at_byte(trie.root(), b as u8).is_some();
+            assert_eq!(first.bytes[b], starts && bytes.bytes[b]);
+        }
+        if !first.bytes.iter().any(|&b| b) {
+            assert!(logits.iter_set_bits().all(|t| trie.is_stop_token(t)));
+        }
+    }
+}
+
+#[test]
+fn trie_tokenizer_env_round_trips() {
+    let mut words = alloc::vec![b"\xff<|endoftext|>".to_vec(), b"\xff<|im_end|>".to_vec()];
+    words.extend((0..=255u8).map(|b| alloc::vec![b]));
+    for w in [
+        "\u{e9}",
+        "caf\u{e9}",
+        "\u{1f600}",
+        " the",
+        "\u{65e5}\u{672c}",
+    ] {
+        words.push(w.as_bytes().to_vec());
+    }
+    let trie = TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words);
+    let env = TrieTokenizerEnv::new(trie.clone());
+
+    for s in [
+        "",
+        "caf\u{e9}",
+        "\u{1f600}\u{1f600} the",
+        "\u{65e5}\u{672c}\u{8a9e}",
+        "a\u{0}b\u{ffff}",
+        "<|im_end|>",
+    ] {
+        let tokens = env.tokenize(s);
+        assert_eq!(trie.decode_str(&tokens), s);
+        assert!(!tokens.contains(&1), "{:?}", s);
+    }
+    assert_eq!(env.tokenize("caf\u{e9}").len(), 1);
+    assert_eq!(env.tokenize("\u{1f600}").len(), 1);
+
+    // bytes that are not UTF-8, all kept
+    for bytes in [
+        &b"a\xffb"[..],
+        b"\xc3",
+        b"caf\xc3",
+        b"\xa9\xc3\xa9",
+        b"\xf0\x9f\x98",
+    ] {
+        assert_eq!(trie.decode_raw(&env.tokenize_bytes(bytes)), bytes);
+    }
+
+    // special tokens by name, or with the prefix byte
+    let s = "hi<|im_end|>\u{e9}<|nope|><|endoftext|>";
+    let tokens = env.tokenize_special(s);
+    assert_eq!(tokens.iter().filter(|&&t| t < 2).count(), 2);
+    assert_eq!(trie.decode_str(&tokens), s);
+    let bytes = b"x\xff<|im_end|>\xc3\xa9\xff<|x|>";
+    let tokens = env.tokenize_bytes_prefix(bytes);
+    assert!(tokens.contains(&1));
+    assert_eq!(trie.decode_raw(&tokens), bytes);
+}
+
+#[test]
+fn greedy_tokenize_with_special() {
+    let words = [
+        &b"\xff<eos>"[..],
+        b"\xff<s>",
+        // the name of 1 is a prefix of this one
+        b"\xff<s>x",
+        b"\xff",
+        b"<",
+        b"s",
+        b">",
+        b"x",
+        b"a",
+        b"<s>",
+        b"\xffa",
+    ]
+    .iter()
+    .map(|w| w.to_vec())
+    .collect::<Vec<_>>();
+    let trie = TokTrie::from_ext(&TokRxInfo::new(11, 0), &words, Some(&[0, 1, 2]));
+    let cases: [(&[u8], &[TokenId]); 12] = [
+        (b"", &[]),
+        (b"a<s>", &[8, 9]),
+        // back to back
+        (b"\xff<s>\xff<eos>", &[1, 0]),
+        (b"\xff<eos>\xff<eos>\xff<s>", &[0, 0, 1]),
+        // the longest name wins
+        (b"\xff<s>x", &[2]),
+        (b"\xff<s>xx", &[2, 7]),
+        (b"\xff<s>a", &[1, 8]),
+        // text is not merged across specials
+        (b"<s\xff<s>>", &[4, 5, 1, 6]),
+        // stray prefix bytes are text
+        (b"a\xff<s", &[8, 3, 4, 5]),
+        (b"a\xff", &[8, 3]),
+        (b"\xff\xff<s>", &[3, 1]),
+        (b"\xffa\xff<s>", &[10, 1]),
+    ];
+    let env = TrieTokenizerEnv::new(trie.clone());
+    for (bytes, tokens) in cases {
+        assert_eq!(
+            trie.greedy_tokenize_with_special(bytes),
+            tokens,
+            "{:?}",
+            bytes
+        );
+        assert_eq!(env.tokenize_bytes_prefix(bytes), tokens, "{:?}", bytes);
+        assert_eq!(trie.decode_raw(tokens), bytes);
+    }
+}
+
+// records the methods called on it, each answering with its own token
+#[cfg(feature = "std")]
+struct CountingEnv {
+    trie: TokTrie,
+    calls: std::sync::Mutex<Vec<&'static str>>,
+}
+
+#[cfg(feature = "std")]
+impl CountingEnv {
+    fn call(&self, name: &'static str, tok: TokenId) -> Vec<TokenId> {
+        self.calls.lock().unwrap().push(name);
+        alloc::vec![tok]
+    }
+
+    fn take_calls(&self) -> Vec<&'static str> {
+        core::mem::take(&mut *self.calls.lock().unwrap())
+    }
+}
+
+#[cfg(feature = "std")]
+impl TokenizerEnv for CountingEnv {
+    fn stop(&self) -> ! {
+        panic!("stop")
+    }
+
+    fn tok_trie(&self) -> &TokTrie {
+        &self.trie
+    }
+
+    fn tokenize_bytes(&self, _s: &[u8]) -> Vec<TokenId> {
+        self.call("tokenize_bytes", 1)
+    }
+
+    fn tokenize_bytes_into(&self, _s: &[u8], out: &mut Vec<TokenId>) {
+        out.extend(self.call("tokenize_bytes_into", 2))
+    }
+
+    fn tokenize_bytes_prefix(&self, _s: &[u8]) -> Vec<TokenId> {
+        self.call("tokenize_bytes_prefix", 3)
+    }
+
+    fn tokenize(&self, _s: &str) -> Vec<TokenId> {
+        self.call("tokenize", 4)
+    }
+
+    fn tokenize_special(&self, _s: &str) -> Vec<TokenId> {
+        self.call("tokenize_special", 5)
+    }
+
+    fn eos_token(&self) -> TokenId {
+        self.call("eos_token", 6)[0]
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn tok_env_with_trie_forwards() {
+    let base = Arc::new(CountingEnv {
+        trie: trie_of(&[b"\xff<eos>", b"a", b"b", b"c", b"d", b"e", b"f", b"g"]),
+        calls: Default::default(),
+    });
+    let env = TokEnvWithTrie::map_trie(base.clone(), |t| t.with_eos_token(7));
+    assert_eq!(env.tok_trie().eos_token(), 7);
+    assert_eq!(env.base_env().tok_trie().eos_token(), 0);
+
+    let mut out = alloc::vec![0];
+    env.tokenize_bytes_into(b"x", &mut out);
+    assert_eq!(out, [0, 2]);
+    assert_eq!(env.tokenize_bytes(b"x"), [1]);
+    assert_eq!(env.tokenize_bytes_prefix(b"x"), [3]);
+    assert_eq!(env.tokenize("x"), [4]);
+    assert_eq!(env.tokenize_special("x"), [5]);
+    // each once, and not through the default methods
+    assert_eq!(
+        base.take_calls(),
+        [
+            "tokenize_bytes_into",
+            "tokenize_bytes",
+            "tokenize_bytes_prefix",
+            "tokenize",
+            "tokenize_special"
+        ]
+    );
+
+    // from the overriding trie, unless asked otherwise
+    assert_eq!(env.eos_token(), 7);
+    assert!(base.take_calls().is_empty());
+    let env =
+        TokEnvWithTrie::new_with_eos_source(base.clone(), env.tok_trie().clone(), EosSource::Base);
+    assert_eq!(env.eos_token(), 6);
+    assert_eq!(base.take_calls(), ["eos_token"]);
+
+    // the provided methods use the forwarded ones
+    env.retokenize_check(&[1], b"x");
+    env.tokenize_is_canonical(&[1]);
+    assert_eq!(
+        base.take_calls(),
+        ["tokenize_bytes_prefix", "tokenize_bytes_prefix"]
+    );
+}
+
+// only built with `RUSTFLAGS="--cfg toktrie_local_env"`, which drops the `Send` bound
+// as on wasm32, where envs may hold JS objects
+#[cfg(all(feature = "std", toktrie_local_env))]
+#[test]
+fn non_send_env() {
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+
+    struct LocalEnv {
+        trie: TokTrie,
+        calls: Rc<Cell<usize>>,
+    }
+
+    impl TokenizerEnv for LocalEnv {
+        fn stop(&self) -> ! {
+            panic!("stop")
+        }
+
+        fn tok_trie(&self) -> &TokTrie {
+            &self.trie
+        }
+
+        fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
+            self.calls.set(self.calls.get() + 1);
+            self.trie.greedy_tokenize(s)
+        }
+    }
+
+    let calls = Rc::new(Cell::new(0));
+    let trie = trie_of(&[b"\xff<eos>", b"a", b"b", b"ab"]);
+    let env: TokEnv = Arc::new(LocalEnv {
+        trie: trie.clone(),
+        calls: calls.clone(),
+    });
+    let wrapped: TokEnv = Arc::new(TokEnvWithTrie::new(env, trie.with_eos_token(1)));
+    assert_eq!(wrapped.tokenize("abab"), [3, 3]);
+    let mut out = Vec::new();
+    wrapped.tokenize_bytes_into(b"ba", &mut out);
+    assert_eq!(out, [2, 1]);
+    assert_eq!(wrapped.eos_token(), 1);
+    assert_eq!(calls.get(), 2);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn retokenize_check() {
+    let trie = trie_of(&[
+        b"\xff<eos>",
+        b"a",
+        b"b",
+        b"c",
+        b"ab",
+        b"abc",
+        b"bc",
+        b"\xff<s>",
+        b"x",
+    ]);
+    let env = TrieTokenizerEnv::new(trie.clone());
+    let check = |prefix: &[TokenId], text: &[u8], num_changed: usize, tokens: &[TokenId]| {
+        let expected = RetokenizeResult {
+            num_changed,
+            tokens: tokens.to_vec(),
+        };
+        assert_eq!(trie.retokenize_check(&env, prefix, text), expected);
+        assert_eq!(env.retokenize_check(prefix, text), expected);
+        // the prefix is canonical if it stays with no text
+        let canonical = env.retokenize_check(prefix, b"").num_changed == 0;
+        assert_eq!(env.tokenize_is_canonical(prefix), canonical, "{:?}", prefix);
+    };
+    check(&[], b"ab", 0, &[4]);
+    // canonical prefixes that stay
+    check(&[4], b"x", 0, &[8]);
+    check(&[8, 4], b"", 0, &[]);
+    check(&[7, 4], b"x", 0, &[8]);
+    // the last token merges with the text
+    check(&[8, 4], b"c", 1, &[5]);
+    check(&[7, 1], b"b", 1, &[4]);
+    // not how the tokenizer splits the bytes, even with no text
+    check(&[1, 2], b"", 2, &[4]);
+    check(&[8, 1, 6], b"x", 2, &[5, 8]);
+    assert!(!env.tokenize_is_canonical(&[1, 2]));
+    assert!(env.tokenize_is_canonical(&[8, 5, 7]));
+}
+
+#[test]
+fn greedy_tokenize_missing_byte() {
+    // there is no token for "b"
+    let trie = trie_of(&[b"\xff<eos>", b"a", b"c", b"ac", b"<unk>"]);
+    let with_unk = trie.with_info(TokRxInfo {
+        tok_unk: Some(4),
+        ..*trie.info()
+    });
+    // `skipped` without tok_unk, `unks` with it
+    let check = |bytes: &[u8], offset: usize, skipped: &[TokenId], unks: &[TokenId]| {
+        assert_eq!(trie.greedy_tokenize(bytes), skipped, "{:?}", bytes);
+        assert_eq!(with_unk.greedy_tokenize(bytes), unks, "{:?}", bytes);
+        for t in [&trie, &with_unk] {
+            let err = t.try_greedy_tokenize(bytes).unwrap_err();
+            assert_eq!(
+                std::format!("{}", err),
+                std::format!("no token for byte 0x62 at offset {}", offset)
+            );
+        }
+    };
+    check(b"bac", 0, &[3], &[4, 3]);
+    check(b"abc", 1, &[1, 2], &[1, 4, 2]);
+    check(b"acab", 3, &[3, 1], &[3, 1, 4]);
+    check(b"bbb", 0, &[], &[4, 4, 4]);
+    assert_eq!(trie.try_greedy_tokenize(b"aca").unwrap(), [3, 1]);
+}
+
+#[test]
+fn decode_prefix_byte_only() {
+    // token 2 is just the special prefix byte, which doesn't make it special
+    let trie = trie_of(&[b"\xff<eos>", b"a", b"\xff", b"b\xffc", b"\xff\xff"]);
+    assert!(!trie.is_special_token(2));
+    assert!(trie.is_special_token(4));
+    let cases: [&[TokenId]; 6] = [&[], &[2], &[2, 2, 4], &[1, 2, 1], &[2, 0], &[3, 2, 3]];
+    for tokens in cases {
+        // only the prefix byte of special tokens goes
+        let expected = tokens
+            .iter()
+            .flat_map(|&t| {
+                let bytes = trie.token(t);
+                if trie.is_special_token(t) {
+                    &bytes[1..]
+                } else {
+                    bytes
+                }
+            })
+            .copied()
+            .collect::<Vec<_>>();
+        assert_eq!(trie.decode(tokens), expected, "{:?}", tokens);
+
+        let mut buf = b"x\xff".to_vec();
+        trie.decode_into(tokens, &mut buf);
+        assert_eq!(buf, [&b"x\xff"[..], &expected].concat());
+        let mut buf = b"x\xff".to_vec();
+        trie.decode_raw_into(tokens, &mut buf);
+        assert_eq!(buf, [&b"x\xff"[..], &trie.decode_raw(tokens)].concat());
+    }
+    assert_eq!(trie.decode(&[2, 4, 2]), b"\xff\xff\xff");
+    assert_eq!(trie.decode(&[1, 2, 0]), b"a\xff<eos>");
+    assert_eq!(trie.decode(&[3, 2, 1]), b"b\xffc\xffa");
+}
+
+#[test]
+fn special_tokens_nested() {
+    // zero special tokens, with and without a token made of just the prefix byte
+    for words in [&[&b"<eos>"[..], b"a", b"b"][..], &[b"<eos>", b"\xff", b"a"]] {
+        let trie = trie_of(words);
+        assert!(trie.get_special_tokens().is_empty());
+        assert!(trie.get_special_tokens_with_names().is_empty());
+        assert!((0..trie.vocab_size() as TokenId).all(|t| !trie.is_special_token(t)));
+    }
+
+    // one, listed first or after the prefix byte
+    for (words, tok) in [
+        (&[&b"\xff<eos>"[..], b"a", b"\xff"][..], 0),
+        (&[b"a", b"\xff", b"\xff<eos>"], 2),
+    ] {
+        let trie = trie_of(words);
+        assert_eq!(trie.get_special_tokens(), [tok]);
+        assert_eq!(
+            trie.get_special_tokens_with_names(),
+            [("<eos>".to_string(), tok)]
+        );
+        let specials = (0..trie.vocab_size() as TokenId)
+            .filter(|&t| trie.is_special_token(t))
+            .collect::<Vec<_>>();
+        assert_eq!(specials, [tok]);
+    }
+
+    // nested: "<s" is a byte-prefix of "<s>" and "<s>x"; in byte order, whatever the ids
+    let trie = trie_of(&[
+        b"\xff<s>x",
+        b"a",
+        b"\xff<s>",
+        b"\xff",
+        b"\xff<s",
+        b"\xff</s>",
+        b"<s>",
+    ]);
+    assert_eq!(
+        trie.get_special_tokens_with_names(),
+        [
+            ("</s>".to_string(), 5),
+            ("<s".to_string(), 4),
+            ("<s>".to_string(), 2),
+            ("<s>x".to_string(), 0),
+        ]
+    );
+    assert_eq!(trie.get_special_tokens(), [5, 4, 2, 0]);
+    for t in 0..trie.vocab_size() as TokenId {
+        assert_eq!(trie.is_special_token(t), [0, 2, 4, 5].contains(&t), "{}", t);
+    }
+    assert_eq!(trie.get_special_token("<s"), Some(4));
+    assert_eq!(trie.get_special_token("<s>"), Some(2));
+    assert_eq!(trie.get_special_token("<"), None);
+}
+
+#[test]
+fn token_props_tricky() {
+    const WS: TokenProps = TokenProps::WHITESPACE_ONLY;
+    const SINGLE: TokenProps = TokenProps::SINGLE_BYTE;
+    const ASCII: TokenProps = TokenProps::ASCII_ONLY;
+    const STARTS: TokenProps = TokenProps::STARTS_MID_UTF8;
+    const ENDS: TokenProps = TokenProps::ENDS_MID_UTF8;
+    const SPECIAL: TokenProps = TokenProps::IS_SPECIAL;
+    const NONE: TokenProps = TokenProps::empty();
+    // U+1F600 is f0 9f 98 80
+    let props: [(&[u8], TokenProps); 14] = [
+        (b"\xff<eos>", SPECIAL),
+        (b" \t \t", WS | ASCII),
+        (b"\t", WS | SINGLE | ASCII),
+        (b"\n\r\x0c ", WS | ASCII),
+        (b"a \t", ASCII),
+        (b"\x0b", SINGLE | ASCII),
+        // the second byte of the emoji
+        (b"\x9f", SINGLE | STARTS | ENDS),
+        (b"\xf0\x9f", ENDS),
+        (b"\x98\x80", STARTS | ENDS),
+        (b"\x80a", STARTS),
+        (b"\xf0\x9f\x98\x80", NONE),
+        // just the prefix byte isn't special
+        (b"\xff", SINGLE),
+        (b"\xff<|tab|>\t", SPECIAL),
+        (b"\xff<\xf0\x9f", SPECIAL | ENDS),
+    ];
+    let trie = trie_of(&props.map(|(w, _)| w));
+    for (t, &(w, p)) in props.iter().enumerate() {
+        assert_eq!(trie.token_props(t as TokenId), p, "{:?}", w);
+    }
+    let with = |p: TokenProps| {
+        (0..props.len() as TokenId)
+            .filter(|&t| trie.token_props(t).contains(p))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(with(WS), [1, 2, 3]);
+    assert_eq!(
+        trie.whitespace_tokens().iter().collect::<Vec<_>>(),
+        with(WS)
+    );
+    assert_eq!(with(ASCII), [1, 2, 3, 4, 5]);
+    assert_eq!(trie.ascii_tokens().iter().collect::<Vec<_>>(), with(ASCII));
+    assert_eq!(with(SPECIAL), trie.get_special_tokens());
+    assert_eq!(trie.token_props(props.len() as TokenId), NONE);
+}
+
+#[test]
+fn empty_tokens() {
+    let words: [&[u8]; 8] = [b"\xff<eos>", b"", b"a", b"", b"ab", b"", b"b", b""];
+    let trie = trie_of(&words);
+    assert_eq!(trie.empty_tokens(), [1, 3, 5, 7]);
+
+    // prefix_token_id() and token_id()
+    assert_eq!(trie.prefix_token_id(b"").1, 0);
+    assert_eq!(trie.prefix_token_id(b"x").1, 0);
+    assert_eq!(trie.prefix_token_id(b"abx"), (4, 2));
+    assert_eq!(trie.token_id(b""), None);
+    assert_eq!(trie.greedy_tokenize(b"abab"), [4, 4]);
+
+    // append_token() and try_append_token() don't touch the recognizer
+    let mut r = RecordingRecognizer::new(FnRecognizer::new(|_: &[u8], _| true));
+    for t in trie.empty_tokens() {
+        trie.append_token(&mut r, t).unwrap();
+        trie.try_append_token(&mut r, t).unwrap();
+    }
+    assert!(r.ops().is_empty());
+    trie.append_token(&mut r, 2).unwrap();
+    assert_eq!(
+        r.ops(),
+        [
+            RecognizerOp::TryPushByte(b'a', true),
+            RecognizerOp::Collapse
+        ]
+    );
+    r.clear_ops();
+    trie.append_token(&mut r, 7).unwrap();
+    assert!(r.ops().is_empty());
+    assert_eq!(r.inner().bytes(), b"a");
+
+    // compute_bias() leaves them out, unless asked
+    let mut r = FnRecognizer::new(|_: &[u8], _| true).with_eos(|_: &[u8]| true);
+    let mut logits = trie.alloc_token_set();
+    trie.compute_bias(&mut r, &mut logits);
+    assert_eq!(logits.iter().collect::<Vec<_>>(), [0, 2, 4, 6]);
+    trie.compute_bias_with_empty_tokens(&mut r, &mut logits, false);
+    assert_eq!(logits.iter().collect::<Vec<_>>(), [0, 2, 4, 6]);
+    trie.compute_bias_with_empty_tokens(&mut r, &mut logits, true);
+    assert_eq!(logits.num_set(), words.len());
+
+    // check_against() compares them by id
+    let vocab = |words: &[&[u8]]| words.iter().map(|w| w.to_vec()).collect::<Vec<_>>();
+    trie.check_against(&vocab(&words)).unwrap();
+    let mut moved = words;
+    moved.swap(1, 2);
+    assert!(trie.check_against(&vocab(&moved)).is_err());
+    let mut filled = words;
+    filled[3] = b"x";
+    assert!(trie.check_against(&vocab(&filled)).is_err());
+    let mut emptied = words;
+    emptied[6] = b"";
+    assert!(trie.check_against(&vocab(&emptied)).is_err());
+    // the trie may have more tokens than the list
+    trie.check_against(&vocab(&words[..7])).unwrap();
+}
+
+#[test]
+fn token_sets_across_added_tokens() {
+    let trie = synthetic_trie(300, 1);
+    let extra = (0..40)
+        .map(|i| (std::format!("<new{}>", i).into_bytes(), i % 3 == 0))
+        .collect::<Vec<_>>();
+    let grown = trie.with_added_tokens(&extra).unwrap();
+    assert_eq!(grown.vocab_size(), 340);
+
+    let mut r = random_recognizer(3, 60);
+    let mut logits = trie.alloc_token_set();
+    trie.compute_bias(&mut r, &mut logits);
+    let old = logits.clone();
+    assert_eq!(logits.capacity(), trie.alloc_token_set().capacity());
+
+    // growing keeps the bits, and gives what alloc_token_set() would
+    logits.resize(grown.vocab_size(), false);
+    assert_eq!(logits.len(), 340);
+    assert!(logits.capacity() > 340);
+    assert_eq!(logits.capacity(), grown.alloc_token_set().capacity());
+    assert_eq!(
+        logits.iter().collect::<Vec<_>>(),
+        old.iter().collect::<Vec<_>>()
+    );
+    grown.compute_bias(&mut r, &mut logits);
+    let mut fresh = grown.alloc_token_set();
+    grown.compute_bias(&mut r, &mut fresh);
+    assert_eq!(logits, fresh);
+    // the old tokens are unchanged
+    for t in 0..300 {
+        assert_eq!(fresh.is_allowed(t), old.is_allowed(t), "{}", t);
+    }
+
+    // filled, and back
+    let mut filled = old.clone();
+    filled.resize(340, true);
+    assert_eq!(filled.num_set(), old.num_set() + 40);
+    assert!((300..340).all(|t| filled.is_allowed(t)));
+    assert!(!filled.get(340));
+    filled.resize(300, false);
+    assert_eq!(filled, old);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn token_set_of_wrong_size() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    let trie = synthetic_trie(300, 1);
+    let grown = trie
+        .with_added_tokens(&[(b"<new>".to_vec(), true)])
+        .unwrap();
+    let mut r = random_recognizer(1, 50);
+    for mut ts in [
+        grown.alloc_token_set(),
+        synthetic_trie(1000, 1).alloc_token_set(),
+    ] {
+        let (len, capacity) = (ts.len(), ts.capacity());
+        for name in [
+            "compute_bias",
+            "add_bias",
+            "apply_duplicates",
+            "token_set_dbg",
+        ] {
+            let op = || match name {
+                "compute_bias" => trie.compute_bias(&mut r, &mut ts),
+                "add_bias" => trie.add_bias(&mut r, &mut ts, b""),
+                "apply_duplicates" => trie.apply_duplicates(&mut ts),
+                _ => drop(trie.token_set_dbg(&ts)),
+            };
+            let err = catch_unwind(AssertUnwindSafe(op)).unwrap_err();
+            let msg = err.downcast_ref::<std::string::String>().unwrap();
+            let expected = std::format!(
+                "TokTrie: token set of size {} (capacity {}) used with vocab size 300;",
+                len,
+                capacity
+            );
+            assert!(msg.starts_with(&expected), "{}: {}", name, msg);
+        }
+        // resized, it works
+        ts.resize(trie.vocab_size(), false);
+        trie.compute_bias(&mut r, &mut ts);
+        trie.token_set_dbg(&ts);
+    }
+}
+
+#[test]
+fn sorted_tokens_complete_with_empties_and_duplicates() {
+    let words: [&[u8]; 10] = [
+        b"\xff<eos>",
+        b"b",
+        b"",
+        b"ab",
+        b"a",
+        b"b",
+        b"",
+        b"ab",
+        b"ba",
+        b"b",
+    ];
+    let trie = trie_of(&words);
+    assert_eq!(
+        trie.iter_tokens().collect::<Vec<_>>(),
+        words
+            .iter()
+            .enumerate()
+            .map(|(t, &w)| (t as TokenId, w))
+            .collect::<Vec<_>>()
+    );
+    // empties first, then each canonical token (the last one) before its duplicates
+    let expected: [(TokenId, &[u8]); 10] = [
+        (2, b""),
+        (6, b""),
+        (4, b"a"),
+        (7, b"ab"),
+        (3, b"ab"),
+        (9, b"b"),
+        (1, b"b"),
+        (5, b"b"),
+        (8, b"ba"),
+        (0, b"\xff<eos>"),
+    ];
+    assert_eq!(trie.sorted_tokens_complete(), expected);
+    assert_eq!(
+        trie.sorted_tokens(),
+        [
+            (4, &b"a"[..]),
+            (7, b"ab"),
+            (9, b"b"),
+            (8, b"ba"),
+            (0, b"\xff<eos>")
+        ]
+        .map(|(t, w)| (t, w.to_vec()))
+    );
+
+    for trie in [trie_with_duplicates(5000, 3), synthetic_trie(5000, 4)] {
+        let all = trie.iter_tokens().collect::<Vec<_>>();
+        assert_eq!(all.len(), trie.vocab_size());
+        trie.check_against(&all.iter().map(|(_, w)| w.to_vec()).collect::<Vec<_>>())
+            .unwrap();
+        // the ground truth: by bytes, the canonical token first, then by id
+        let mut expected = all.clone();
+        expected.sort_by_key(|&(t, w)| (w, t != trie.canonical_token(t), t));
+        let complete = trie.sorted_tokens_complete();
+        assert_eq!(complete, expected);
+        // sorted_tokens() is the canonical ones
+        let canonical = complete
+            .iter()
+            .filter(|&&(t, w)| !w.is_empty() && trie.canonical_token(t) == t)
+            .map(|&(t, w)| (t, w.to_vec()))
+            .collect::<Vec<_>>();
+        assert_eq!(trie.sorted_tokens(), canonical);
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn sentinel_and_logit_bias() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    let trie = trie_of(&[b"\xff<eos>", b"a", b"b", b"ab", b"c"]);
+    let sentinel = trie.sentinel_token();
+    assert_eq!(sentinel, 5);
+    let ts = trie.alloc_token_set();
+    assert_eq!((ts.len(), ts.capacity()), (5, 32));
+
+    // a set that counts the sentinel in its length
+    let mut r = FnRecognizer::new(|_: &[u8], b| b != b'c' && b != 0xff).with_eos(|_: &[u8]| false);
+    let mut with_sentinel = SimpleVob::alloc(trie.vocab_size() + 1);
+    let err = catch_unwind(AssertUnwindSafe(|| {
+        trie.compute_bias(&mut r, &mut with_sentinel)
+    }))
+    .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<std::string::String>().unwrap(),
+        "TokTrie: token set of size 6 (capacity 32) used with vocab size 5; \
+         it needs room for the sentinel token past the vocabulary, \
+         see alloc_token_set() and SimpleVob::resize()"
+    );
+    let mut ok = trie.alloc_token_set();
+    let e = trie.mask_and(&mut ok, &with_sentinel).unwrap_err();
+    assert!(std::format!("{}", e).starts_with("TokTrie: token set of size 6 (capacity 32)"));
+    // alloc() has the room too
+    trie.compute_bias(&mut r, &mut SimpleVob::alloc(trie.vocab_size()));
+
+    // with and without EOS
+    let mut mask = trie.alloc_token_set();
+    trie.compute_bias(&mut r, &mut mask);
+    assert!(!mask.get(sentinel as usize));
+    let mut bias = trie.alloc_logits();
+    assert_eq!(bias.len(), 5);
+    trie.mask_to_logit_bias(&mask, f32::NEG_INFINITY, &mut bias)
+        .unwrap();
+    assert_eq!(bias, [f32::NEG_INFINITY, 0.0, 0.0, 0.0, f32::NEG_INFINITY]);
+    let mut r = r.with_eos(|_: &[u8]| true);
+    trie.compute_bias(&mut r, &mut mask);
+    trie.mask_to_logit_bias(&mask, -100.0, &mut bias).unwrap();
+    assert_eq!(bias, [0.0, 0.0, 0.0, 0.0, -100.0]);
+    // a set sentinel bit is dropped
+    mask.set(sentinel as usize, true);
+    trie.mask_to_logit_bias(&mask, -100.0, &mut bias).unwrap();
+    assert_eq!(bias, [0.0, 0.0, 0.0, 0.0, -100.0]);
+
+    // both have to be of vocab_size()
+    let e = trie
+        .mask_to_logit_bias(&mask, -1.0, &mut [0.0; 6])
+        .unwrap_err();
+    assert_eq!(
+        std::format!("{}", e),
+        "TokTrie: logit bias of size 6 used with vocab size 5"
+    );
+    let e = trie
+        .mask_to_logit_bias(&with_sentinel, -1.0, &mut bias)
+        .unwrap_err();
+    assert_eq!(
+        std::format!("{}", e),
+        "TokTrie: mask of size 6 used with vocab size 5"
+    );
+}
+
+#[test]
+fn extend_matches_rebuild() {
+    let mut words = synthetic_vocab(3000, 5);
+    let base = TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words);
+    let mut rng = Rng::new(6);
+    let mut new_tokens = Vec::new();
+    for i in 0..64 {
+        let w = match i % 4 {
+            // an existing token, which becomes a duplicate
+            0 => words[rng.gen_up_to(words.len() - 1)].clone(),
+            // a prefix or an extension of one
+            1 => {
+                let w = &words[300 + rng.gen_up_to(words.len() - 301)];
+                w[..1 + rng.gen_up_to(w.len() - 1)].to_vec()
+            }
+            2 => [&words[300 + i][..], b"~x"].concat(),
+            _ => std::format!("\u{ff}<new{}>", i).into_bytes(),
+        };
+        new_tokens.push(((words.len() + i) as TokenId, w));
+    }
+    new_tokens.push((3064, b"\xff<added>".to_vec()));
+
+    let mut extended = base.clone();
+    extended.extend(&new_tokens).unwrap();
+    extended.validate().unwrap();
+    words.extend(new_tokens.iter().map(|(_, w)| w.clone()));
+    let rebuilt = TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words);
+    assert_eq!(extended.vocab_size(), rebuilt.vocab_size());
+    assert_eq!(extended.serialize(), rebuilt.serialize());
+    for t in 0..words.len() as TokenId {
+        assert_eq!(
+            extended.canonical_token(t),
+            rebuilt.canonical_token(t),
+            "{}",
+            t
+        );
+    }
+    for seed in 0..20 {
+        let mut logits = extended.alloc_token_set();
+        extended.compute_bias(&mut random_recognizer(seed, 70), &mut logits);
+        let mut expected = rebuilt.alloc_token_set();
+        rebuilt.compute_bias(&mut random_recognizer(seed, 70), &mut expected);
+        assert_eq!(logits, expected, "seed {}", seed);
+    }
+
+    // ids have to follow on, and a failed extend() changes nothing
+    let mut trie = base.clone();
+    for bad in [[(2999, b"x".to_vec())], [(3001, b"x".to_vec())]] {
+        assert!(trie.extend(&bad).is_err());
+        assert_eq!(trie.serialize(), base.serialize());
+    }
+    let gap = [(3000, b"~a".to_vec()), (3003, b"~b".to_vec())];
+    let e = trie.extend(&gap).unwrap_err();
+    assert_eq!(
+        std::format!("{}", e),
+        "TokTrie: can't add token 3003; the next token is 3001 (see GapPolicy)"
+    );
+    assert_eq!(trie.serialize(), base.serialize());
+    // unless the gaps are filled
+    trie.extend_ext(&gap, GapPolicy::FillEmpty).unwrap();
+    trie.validate().unwrap();
+    let mut words = synthetic_vocab(3000, 5);
+    words.extend([b"~a".to_vec(), Vec::new(), Vec::new(), b"~b".to_vec()]);
+    let rebuilt = TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words);
+    assert_eq!(trie.serialize(), rebuilt.serialize());
+    assert_eq!(trie.empty_tokens(), [3001, 3002]);
+}
+
+#[test]
+fn node_refs_match_references() {
+    let trie = trie_with_duplicates(5000, 8);
+    let mut rng = Rng::new(9);
+    for _ in 0..200 {
+        let mut n = trie.root();
+        let mut nr = trie.root_ref();
+        let mut bytes = Vec::new();
+        loop {
+            assert!(core::ptr::eq(trie.node(nr), n));
+            assert_eq!(nr.offset(), trie.node_offset(n));
+            assert_eq!(trie.token_id_of(nr), n.token_id());
+            assert_eq!(trie.resolve_prefix(&bytes), Some(nr));
+
+            let children = trie.node_children(n).collect::<Vec<_>>();
+            let child_refs = trie.children_refs(nr).collect::<Vec<_>>();
+            assert_eq!(children.len(), child_refs.len());
+            for (c, &cr) in children.iter().zip(child_refs.iter()) {
+                assert!(core::ptr::eq(trie.node(cr), *c));
+            }
+            for _ in 0..4 {
+                let b = rng.gen_up_to(255) as u8;
+                assert_eq!(
+                    trie.child_at_byte_ref(nr, b)
+                        .map(|c| trie.node(c) as *const TrieNode),
+                    trie.child_at_byte(n, b).map(|c| c as *const TrieNode),
+                );
+            }
+            if children.is_empty() {
+                break;
+            }
+            let idx = rng.gen_up_to(children.len() - 1);
+            let b = children[idx].byte();
+            bytes.push(b);
+            n = trie.child_at_byte(n, b).unwrap();
+            nr = trie.child_at_byte_ref(nr, b).unwrap();
+            assert_eq!(nr, child_refs[idx]);
+        }
+        // a leaf, and so a token
+        assert_eq!(
+            trie.token_id_of(nr).map(|t| trie.token(t)),
+            Some(&bytes[..])
+        );
+    }
+}
+
+// accepts the prefixes of `target`, pushing a whole slice at once in try_push_bytes()
+struct BulkPrefix {
+    target: &'static [u8],
+    len: usize,
+    base: usize,
+    num_bulk_pushes: usize,
+}
+
+impl BulkPrefix {
+    fn new(target: &'static [u8]) -> Self {
+        BulkPrefix {
+            target,
+            len: 0,
+            base: 0,
+            num_bulk_pushes: 0,
+        }
+    }
+}
+
+impl Recognizer for BulkPrefix {
+    fn pop_bytes(&mut self, num: usize) {
+        assert!(self.len - num >= self.base);
+        self.len -= num;
+    }
+    fn collapse(&mut self) {
+        self.base = self.len;
+    }
+    fn special_allowed(&mut self, _tok: SpecialToken) -> bool {
+        false
+    }
+    fn trie_finished(&mut self) {}
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        if self.target.get(self.len) == Some(&byte) {
+            self.len += 1;
+            true
+        } else {
+            false
+        }
+    }
+    fn try_push_bytes(&mut self, bytes: &[u8]) -> usize {
+        self.num_bulk_pushes += 1;
+        let rest = &self.target[self.len..];
+        let num = bytes.iter().zip(rest).take_while(|(a, b)| a == b).count();
+        self.len += num;
+        num
+    }
+}
+
+#[test]
+fn append_partially_accepted_token() {
+    let trie = trie_of(&[b"\xff<eos>", b"ab", b"cd", b"abcd", b"cx", b"abcx"]);
+    let target = b"abcdab";
+    let by_byte = || FnRecognizer::new(|bytes: &[u8], b| target.get(bytes.len()) == Some(&b));
+
+    // "abcx": only "abc" is allowed
+    let mut r = BulkPrefix::new(target);
+    let e = trie.append_token(&mut r, 5).unwrap_err();
+    assert_eq!(std::format!("{}", e), "byte 'x' not allowed");
+    // the accepted prefix stays, collapsed
+    assert_eq!((r.len, r.base, r.num_bulk_pushes), (3, 3, 1));
+    let mut f = by_byte();
+    assert!(trie.append_token(&mut f, 5).is_err());
+    assert_eq!(f.bytes(), b"abc");
+
+    // try_append_token() and token_allowed() leave no trace
+    let mut r = BulkPrefix::new(target);
+    let mut f = by_byte();
+    assert!(trie.try_append_token(&mut r, 5).is_err());
+    assert!(trie.try_append_token(&mut f, 5).is_err());
+    assert!(!trie.token_allowed(&mut r, 5));
+    assert!(!trie.token_allowed(&mut f, 5));
+    assert!(trie.token_allowed(&mut r, 3));
+    assert!(trie.token_allowed(&mut f, 3));
+    assert_eq!((r.len, r.base), (0, 0));
+    assert!(f.bytes().is_empty());
+
+    // append_tokens() keeps the tokens before the rejected one
+    let e = trie.append_tokens(&mut r, &[1, 4]).unwrap_err();
+    let e = e.downcast::<AppendTokenError>().unwrap();
+    assert_eq!(
+        (
+            e.token_index,
+            e.token,
+            e.byte_offset_in_token,
+            e.byte,
+            e.byte_offset
+        ),
+        (1, 4, 1, b'x', 3)
+    );
+    assert_eq!((r.len, r.base), (2, 2));
+    assert!(trie.append_tokens(&mut f, &[1, 4]).is_err());
+    assert_eq!(f.bytes(), b"ab");
+    trie.append_tokens(&mut r, &[2, 1]).unwrap();
+    trie.append_tokens(&mut f, &[2, 1]).unwrap();
+    assert_eq!((r.len, r.base), (6, 6));
+    assert_eq!(f.bytes(), target);
+    assert!(trie.try_append_token(&mut r, 1).is_err());
+}
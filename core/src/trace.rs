@@ -0,0 +1,491 @@
+//! Recording what a recognizer is asked during `compute_bias()`, to find where two
+//! versions of a recognizer (or of the trie walk) start to disagree; see
+//! `TokTrie::trace_bias()` and `diff_traces()`.
+
+use alloc::{format, string::String, vec, vec::Vec};
+use std::io::Write;
+
+use anyhow::{bail, ensure, Result};
+
+use crate::{
+    toktree::{Recognizer, SpecialToken},
+    NodeRef, SimpleVob, TokTrie, TokenId,
+};
+
+const TRACE_MAGIC: &[u8; 4] = b"TKTR";
+const TRACE_VERSION: u8 = 1;
+
+// binary events; pops and tokens are followed by a u32 LE
+const EV_ACCEPT: u8 = b'+';
+const EV_REJECT: u8 = b'-';
+const EV_POP: u8 = b'p';
+const EV_TOKEN: u8 = b't';
+const EV_FINISHED: u8 = b'e';
+
+/// Default number of divergences `diff_traces()` reports.
+pub const MAX_TRACE_DIVERGENCES: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum TraceFormat {
+    /// Compact, and what `diff_traces()` reads.
+    #[default]
+    Binary,
+    /// A line per event: `+ 61` and `- 62` for accepted and rejected bytes (in hex),
+    /// `pop 3`, `token 123`, and `finished` for `trie_finished()`.
+    Text,
+}
+
+/// What a trace has for a byte tried at some point of the walk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceStep {
+    pub accepted: bool,
+    /// The token allowed by accepting the byte, if any.
+    pub token: Option<TokenId>,
+}
+
+/// A point where two traces disagree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceDivergence {
+    /// Bytes from the root of the trie, the last one being the byte tried.
+    pub path: Vec<u8>,
+    /// What each of the traces has there; None if the byte wasn't tried at all.
+    pub a: Option<TraceStep>,
+    pub b: Option<TraceStep>,
+}
+
+/// Passes everything to the wrapped recognizer, writing down pushes, pops and tokens
+/// allowed. Bytes are followed down the trie, to know the tokens.
+struct TracingRecognizer<'a, R, W> {
+    trie: &'a TokTrie,
+    inner: R,
+    out: W,
+    format: TraceFormat,
+    // node for every byte pushed, after the root; None when off the trie
+    nodes: Vec<Option<NodeRef>>,
+    // nodes.len() at trie_started()
+    base: usize,
+    err: Option<std::io::Error>,
+}
+
+impl<R: Recognizer, W: Write> TracingRecognizer<'_, R, W> {
+    fn emit(&mut self, ev: u8, arg: u32) {
+        if self.err.is_some() {
+            return;
+        }
+        let res = match (self.format, ev) {
+            (TraceFormat::Binary, EV_POP | EV_TOKEN) => {
+                let [a0, a1, a2, a3] = arg.to_le_bytes();
+                self.out.write_all(&[ev, a0, a1, a2, a3])
+            }
+            (TraceFormat::Binary, EV_FINISHED) => self.out.write_all(&[ev]),
+            (TraceFormat::Binary, _) => self.out.write_all(&[ev, arg as u8]),
+            (TraceFormat::Text, EV_ACCEPT | EV_REJECT) => {
+                writeln!(self.out, "{} {:02x}", ev as char, arg)
+            }
+            (TraceFormat::Text, EV_POP) => writeln!(self.out, "pop {}", arg),
+            (TraceFormat::Text, EV_TOKEN) => writeln!(self.out, "token {}", arg),
+            (TraceFormat::Text, _) => writeln!(self.out, "finished"),
+        };
+        self.err = res.err();
+    }
+}
+
+impl<R: Recognizer, W: Write> Recognizer for TracingRecognizer<'_, R, W> {
+    fn pop_bytes(&mut self, num: usize) {
+        if num > 0 {
+            self.emit(EV_POP, num as u32);
+            self.nodes.truncate(self.nodes.len() - num);
+        }
+        self.inner.pop_bytes(num);
+    }
+
+    fn collapse(&mut self) {
+        let top = *self.nodes.last().unwrap();
+        self.nodes = vec![top];
+        self.base = 1;
+        self.inner.collapse();
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        self.inner.special_allowed(tok)
+    }
+
+    fn trie_started(&mut self) {
+        self.base = self.nodes.len();
+        self.inner.trie_started();
+    }
+
+    fn trie_finished(&mut self) {
+        self.emit(EV_FINISHED, 0);
+        self.nodes.truncate(self.base);
+        self.inner.trie_finished();
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        let accepted = self.inner.try_push_byte(byte);
+        if accepted {
+            self.emit(EV_ACCEPT, byte as u32);
+            let n = self
+                .nodes
+                .last()
+                .unwrap()
+                .and_then(|n| self.trie.child_at_byte_ref(n, byte));
+            self.nodes.push(n);
+            if let Some(tok) = n.and_then(|n| self.trie.token_id_of(n)) {
+                self.emit(EV_TOKEN, tok);
+            }
+        } else {
+            self.emit(EV_REJECT, byte as u32);
+        }
+        accepted
+    }
+
+    fn get_error(&mut self) -> Option<String> {
+        self.inner.get_error()
+    }
+}
+
+impl TokTrie {
+    /// Same as `compute_bias()`, returning the tokens allowed, but also writes to `out`
+    /// a trace of the walk in the binary format; see `trace_bias_ext()`.
+    pub fn trace_bias(
+        &self,
+        r: &mut impl Recognizer,
+        out: &mut impl Write,
+    ) -> std::io::Result<SimpleVob> {
+        self.trace_bias_ext(r, out, TraceFormat::Binary)
+    }
+
+    /// Same as `compute_bias()`, returning the tokens allowed, but also writes to `out`
+    /// every byte `r` is asked to push and whether it was accepted, every pop
+    /// (of more than 0 bytes), every token allowed by the walk of the trie (so not EOS
+    /// or duplicates), and the end of the walk. The walk is the same as without tracing,
+    /// except that `r.byte_allowed()` is seen by `r` as a push and a pop.
+    pub fn trace_bias_ext(
+        &self,
+        r: &mut impl Recognizer,
+        out: &mut impl Write,
+        format: TraceFormat,
+    ) -> std::io::Result<SimpleVob> {
+        if format == TraceFormat::Binary {
+            out.write_all(TRACE_MAGIC)?;
+            out.write_all(&[TRACE_VERSION])?;
+        }
+        let mut tr = TracingRecognizer {
+            trie: self,
+            inner: r,
+            out,
+            format,
+            nodes: vec![Some(self.root_ref())],
+            base: 1,
+            err: None,
+        };
+        let mut logits = self.alloc_token_set();
+        self.compute_bias(&mut tr, &mut logits);
+        match tr.err {
+            Some(e) => Err(e),
+            None => Ok(logits),
+        }
+    }
+}
+
+/// The bytes tried by a binary trace, in order, with their paths from the root.
+fn trace_steps(trace: &[u8]) -> Result<Vec<(Vec<u8>, TraceStep)>> {
+    ensure!(
+        trace.len() >= 5 && &trace[0..4] == TRACE_MAGIC,
+        "trace: not a binary trace"
+    );
+    ensure!(
+        trace[4] == TRACE_VERSION,
+        "trace: unsupported version {}",
+        trace[4]
+    );
+    let mut steps: Vec<(Vec<u8>, TraceStep)> = Vec::new();
+    let mut path = Vec::new();
+    let mut pos = 5;
+    while pos < trace.len() {
+        let ev = trace[pos];
+        let arg_len = match ev {
+            EV_ACCEPT | EV_REJECT => 1,
+            EV_POP | EV_TOKEN => 4,
+            EV_FINISHED => 0,
+            _ => bail!("trace: invalid event {:?} at {}", ev as char, pos),
+        };
+        ensure!(pos + 1 + arg_len <= trace.len(), "trace: truncated");
+        let arg = &trace[pos + 1..pos + 1 + arg_len];
+        pos += 1 + arg_len;
+        match ev {
+            EV_ACCEPT | EV_REJECT => {
+                let mut p = path.clone();
+                p.push(arg[0]);
+                let accepted = ev == EV_ACCEPT;
+                if accepted {
+                    path.push(arg[0]);
+                }
+                steps.push((
+                    p,
+                    TraceStep {
+                        accepted,
+                        token: None,
+                    },
+                ));
+            }
+            EV_POP => {
+                let num = u32::from_le_bytes(arg.try_into().unwrap()) as usize;
+                ensure!(
+                    num <= path.len(),
+                    "trace: popping {} of {} bytes",
+                    num,
+                    path.len()
+                );
+                path.truncate(path.len() - num);
+            }
+            EV_TOKEN => {
+                let tok = u32::from_le_bytes(arg.try_into().unwrap());
+                match steps.last_mut() {
+                    Some((_, step)) if step.accepted && step.token.is_none() => {
+                        step.token = Some(tok)
+                    }
+                    _ => bail!("trace: token {} not after an accepted byte", tok),
+                }
+            }
+            _ => path.clear(),
+        }
+    }
+    Ok(steps)
+}
+
+/// Panics if the traces are not from `TokTrie::trace_bias()`; see `try_diff_traces()`.
+pub fn diff_traces(a: &[u8], b: &[u8]) -> Vec<TraceDivergence> {
+    try_diff_traces(a, b, MAX_TRACE_DIVERGENCES).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// The first `max_divergences` points where two binary traces of `TokTrie::trace_bias()`
+/// disagree, in the order of the walk. Bytes only tried below a byte that one trace
+/// accepted and the other didn't are not reported, as they follow from that one.
+pub fn try_diff_traces(a: &[u8], b: &[u8], max_divergences: usize) -> Result<Vec<TraceDivergence>> {
+    let steps_a = trace_steps(a)?;
+    let steps_b = trace_steps(b)?;
+    // paths accepted by only one of the traces
+    let mut cut: Vec<Vec<u8>> = Vec::new();
+    let mut res = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while res.len() < max_divergences && (i < steps_a.len() || j < steps_b.len()) {
+        // the walk goes in the order of paths, so merge on them
+        let (path, sa, sb) = match (steps_a.get(i), steps_b.get(j)) {
+            (Some((pa, sa)), Some((pb, sb))) if pa == pb => {
+                i += 1;
+                j += 1;
+                (pa, Some(*sa), Some(*sb))
+            }
+            (Some((pa, sa)), Some((pb, _))) if pa < pb => {
+                i += 1;
+                (pa, Some(*sa), None)
+            }
+            (Some((pa, sa)), None) => {
+                i += 1;
+                (pa, Some(*sa), None)
+            }
+            (_, Some((pb, sb))) => {
+                j += 1;
+                (pb, None, Some(*sb))
+            }
+            (None, None) => unreachable!(),
+        };
+        if sa == sb || cut.iter().any(|c| path.starts_with(c)) {
+            continue;
+        }
+        let accepted = |s: Option<TraceStep>| s.is_some_and(|s| s.accepted);
+        if accepted(sa) != accepted(sb) {
+            cut.push(path.clone());
+        }
+        res.push(TraceDivergence {
+            path: path.clone(),
+            a: sa,
+            b: sb,
+        });
+    }
+    Ok(res)
+}
+
+impl core::fmt::Display for TraceDivergence {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let step = |s: Option<TraceStep>| match s {
+            None => "not tried".into(),
+            Some(TraceStep {
+                accepted: false, ..
+            }) => "rejected".into(),
+            Some(TraceStep { token: None, .. }) => "accepted".into(),
+            Some(TraceStep { token: Some(t), .. }) => format!("accepted, token {}", t),
+        };
+        write!(
+            f,
+            "at {:?}: {} vs {}",
+            crate::bytes::limit_bytes(&self.path, 100),
+            step(self.a),
+            step(self.b)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use std::string::{String, ToString};
+
+    use super::*;
+    use crate::{recognizer::FnRecognizer, testing::synthetic_vocab, TokRxInfo};
+
+    fn trie_of(words: &[&[u8]]) -> TokTrie {
+        let words = words.iter().map(|w| w.to_vec()).collect::<Vec<_>>();
+        TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words)
+    }
+
+    // accepts about `percent` percent of the bytes after any given prefix, always the same ones
+    fn random_recognizer(
+        seed: u64,
+        percent: u64,
+    ) -> FnRecognizer<impl Fn(&[u8], u8) -> bool + Clone> {
+        FnRecognizer::new(move |bytes: &[u8], b: u8| {
+            let mut h = 0xcbf29ce484222325u64 ^ seed;
+            for &x in bytes.iter().chain(core::iter::once(&b)) {
+                h = (h ^ x as u64).wrapping_mul(0x100000001b3);
+            }
+            (h >> 40) % 100 < percent
+        })
+    }
+
+    fn prefix_of(words: &'static [&'static [u8]]) -> FnRecognizer<impl Fn(&[u8], u8) -> bool> {
+        FnRecognizer::new(move |bytes: &[u8], b: u8| {
+            words
+                .iter()
+                .any(|w| w.len() > bytes.len() && w.starts_with(bytes) && w[bytes.len()] == b)
+        })
+    }
+
+    fn trace(trie: &TokTrie, r: &mut impl Recognizer, format: TraceFormat) -> (SimpleVob, Vec<u8>) {
+        let mut out = Vec::new();
+        let mask = trie.trace_bias_ext(r, &mut out, format).unwrap();
+        (mask, out)
+    }
+
+    #[test]
+    fn mask_matches_compute_bias() {
+        let size = 5000;
+        let trie = TokTrie::from(&TokRxInfo::new(size as u32, 0), &synthetic_vocab(size, 1));
+        for seed in 0..4 {
+            for percent in [5, 50, 95] {
+                let mut expected = trie.alloc_token_set();
+                trie.compute_bias(&mut random_recognizer(seed, percent), &mut expected);
+                for format in [TraceFormat::Binary, TraceFormat::Text] {
+                    let mut r = random_recognizer(seed, percent);
+                    let (mask, out) = trace(&trie, &mut r, format);
+                    assert_eq!(mask, expected);
+                    assert_eq!(trace(&trie, &mut r, format), (mask, out));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn text_format() {
+        let trie = trie_of(&[b"\xff<eos>", b"a", b"ab", b"b"]);
+        let (mask, out) = trace(&trie, &mut prefix_of(&[b"ab"]), TraceFormat::Text);
+        assert_eq!(mask.iter().collect::<Vec<_>>(), vec![0, 1, 2]);
+        // `b` is tried again after popping "ab", and rejected at the root
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "+ 61\ntoken 1\n+ 62\ntoken 2\npop 2\n- 62\n- ff\nfinished\n"
+        );
+    }
+
+    #[test]
+    fn binary_format() {
+        let trie = trie_of(&[b"\xff<eos>", b"a", b"ab", b"b"]);
+        let (_, out) = trace(&trie, &mut prefix_of(&[b"ab"]), TraceFormat::Binary);
+        let mut expected = b"TKTR\x01".to_vec();
+        expected.extend_from_slice(b"+at\x01\0\0\0+bt\x02\0\0\0p\x02\0\0\0-b-\xffe");
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn divergences() {
+        let trie = trie_of(&[b"\xff<eos>", b"a", b"ab", b"abc", b"b", b"ba"]);
+        let (_, a) = trace(&trie, &mut prefix_of(&[b"abc", b"ba"]), TraceFormat::Binary);
+        let (_, b) = trace(&trie, &mut prefix_of(&[b"a", b"b"]), TraceFormat::Binary);
+        assert_eq!(diff_traces(&a, &a), vec![]);
+
+        let accepted = |token| {
+            Some(TraceStep {
+                accepted: true,
+                token: Some(token),
+            })
+        };
+        let rejected = Some(TraceStep {
+            accepted: false,
+            token: None,
+        });
+        // "abc" is only tried by `a`, below "ab", so it's not reported
+        let divs = diff_traces(&a, &b);
+        assert_eq!(
+            divs,
+            vec![
+                TraceDivergence {
+                    path: b"ab".to_vec(),
+                    a: accepted(2),
+                    b: rejected,
+                },
+                TraceDivergence {
+                    path: b"ba".to_vec(),
+                    a: accepted(5),
+                    b: rejected,
+                },
+            ]
+        );
+        assert_eq!(
+            divs[0].to_string(),
+            "at \"ab\": accepted, token 2 vs rejected"
+        );
+        let divs = diff_traces(&b, &a);
+        assert_eq!(
+            divs[1].to_string(),
+            "at \"ba\": rejected vs accepted, token 5"
+        );
+        assert_eq!(try_diff_traces(&a, &b, 1).unwrap().len(), 1);
+
+        // same bytes accepted, different tokens
+        let other = trie_of(&[b"\xff<eos>", b"ab", b"a", b"abc", b"b", b"ba"]);
+        let (_, c) = trace(
+            &other,
+            &mut prefix_of(&[b"abc", b"ba"]),
+            TraceFormat::Binary,
+        );
+        let divs = diff_traces(&a, &c);
+        assert_eq!(divs.len(), 2);
+        assert_eq!(divs[0].path, b"a");
+        assert_eq!((divs[0].a, divs[0].b), (accepted(1), accepted(2)));
+        assert_eq!(divs[1].path, b"ab");
+    }
+
+    #[test]
+    fn malformed_traces() {
+        let trie = trie_of(&[b"\xff<eos>", b"a", b"ab", b"b"]);
+        let (_, good) = trace(&trie, &mut prefix_of(&[b"ab"]), TraceFormat::Binary);
+        let (_, text) = trace(&trie, &mut prefix_of(&[b"ab"]), TraceFormat::Text);
+        let err = |bad: &[u8]| {
+            try_diff_traces(&good, bad, MAX_TRACE_DIVERGENCES)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(err(&text), "trace: not a binary trace");
+        assert_eq!(err(b"TKTR\x02"), "trace: unsupported version 2");
+        assert_eq!(err(&good[..good.len() - 2]), "trace: truncated");
+        assert_eq!(err(b"TKTR\x01+ax"), "trace: invalid event 'x' at 7");
+        assert_eq!(err(b"TKTR\x01+ap\x02\0\0\0"), "trace: popping 2 of 1 bytes");
+        assert_eq!(
+            err(b"TKTR\x01-at\x01\0\0\0"),
+            "trace: token 1 not after an accepted byte"
+        );
+        assert!(try_diff_traces(&text, &good, 1).is_err());
+    }
+}
@@ -0,0 +1,241 @@
+use alloc::vec::Vec;
+
+use crate::toktree::{TokTrie, TokenId};
+
+const UNMAPPED: TokenId = TokenId::MAX;
+
+/// Translates sequences of tokens of one trie into tokens of another with the same bytes,
+/// as between the tokenizers of a draft and a target model in speculative decoding.
+///
+/// Tokens whose bytes are a token of the destination trie too are mapped directly,
+/// special tokens only to special tokens (that is, by name). Runs of other tokens are
+/// decoded and tokenized again with `greedy_tokenize()` of the destination trie;
+/// this includes special tokens with no special token of the same name there.
+#[derive(Clone)]
+pub struct TokenTranslator {
+    src: TokTrie,
+    dst: TokTrie,
+    // the dst token with the bytes of each src token, or UNMAPPED
+    table: Vec<TokenId>,
+    num_mapped: usize,
+}
+
+impl TokenTranslator {
+    /// The tries are cloned, which is cheap, as clones share the nodes and tokens.
+    pub fn new(src: &TokTrie, dst: &TokTrie) -> Self {
+        let table = (0..src.vocab_size() as TokenId)
+            .map(|t| match dst.token_id(src.token(t)) {
+                Some(d) if dst.is_special_token(d) == src.is_special_token(t) => d,
+                _ => UNMAPPED,
+            })
+            .collect::<Vec<_>>();
+        let num_mapped = table.iter().filter(|&&d| d != UNMAPPED).count();
+        TokenTranslator {
+            src: src.clone(),
+            dst: dst.clone(),
+            table,
+            num_mapped,
+        }
+    }
+
+    pub fn src(&self) -> &TokTrie {
+        &self.src
+    }
+
+    pub fn dst(&self) -> &TokTrie {
+        &self.dst
+    }
+
+    /// The destination token with the bytes of `t`, if `t` is mapped directly;
+    /// the canonical one, as from `token_id()`.
+    pub fn direct(&self, t: TokenId) -> Option<TokenId> {
+        self.table
+            .get(t as usize)
+            .copied()
+            .filter(|&d| d != UNMAPPED)
+    }
+
+    /// Number of source tokens mapped directly.
+    pub fn num_mapped(&self) -> usize {
+        self.num_mapped
+    }
+
+    /// `num_mapped()` as a fraction of the source vocabulary.
+    pub fn mapped_fraction(&self) -> f64 {
+        if self.table.is_empty() {
+            0.0
+        } else {
+            self.num_mapped as f64 / self.table.len() as f64
+        }
+    }
+
+    /// The tokens of the destination trie for `src_tokens`.
+    /// They decode (with `decode_raw()`) to the same bytes, unless the destination
+    /// trie has no token for some byte of a run of tokens not mapped directly;
+    /// that byte is then handled as in `greedy_tokenize()`.
+    pub fn translate(&self, src_tokens: &[TokenId]) -> Vec<TokenId> {
+        let mut res = Vec::with_capacity(src_tokens.len());
+        // bytes of the current run of tokens not mapped directly
+        let mut run = Vec::new();
+        let mut lossy = false;
+        for &t in src_tokens {
+            match self.direct(t) {
+                Some(d) => {
+                    lossy |= self.flush_run(&mut run, &mut res);
+                    res.push(d);
+                }
+                None => run.extend_from_slice(self.src.token(t)),
+            }
+        }
+        lossy |= self.flush_run(&mut run, &mut res);
+        debug_assert!(
+            lossy || self.dst.decode_raw(&res) == self.src.decode_raw(src_tokens),
+            "TokenTranslator: bytes changed in translation"
+        );
+        res
+    }
+
+    // true if some bytes of the run have no token in dst
+    fn flush_run(&self, run: &mut Vec<u8>, res: &mut Vec<TokenId>) -> bool {
+        if run.is_empty() {
+            return false;
+        }
+        let lossy = match self.dst.try_greedy_tokenize(run) {
+            Ok(tokens) => {
+                res.extend_from_slice(&tokens);
+                false
+            }
+            Err(_) => {
+                res.extend_from_slice(&self.dst.greedy_tokenize(run));
+                true
+            }
+        };
+        run.clear();
+        lossy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::*;
+    use crate::{rng::Rng, TokRxInfo};
+
+    fn trie_of(words: &[&[u8]], eos: TokenId) -> TokTrie {
+        let words = words.iter().map(|w| w.to_vec()).collect::<Vec<_>>();
+        TokTrie::from(&TokRxInfo::new(words.len() as u32, eos), &words)
+    }
+
+    // "abc" and "ca" are split, and <tool> has no special token in dst;
+    // the other specials have the same names, with different ids
+    fn src() -> TokTrie {
+        trie_of(
+            &[
+                b"\xff<eos>",
+                b"\xff<tool>",
+                b"a",
+                b"b",
+                b"c",
+                b"ab",
+                b"abc",
+                b"ca",
+                b"\xff<pad>",
+            ],
+            0,
+        )
+    }
+
+    fn dst() -> TokTrie {
+        trie_of(
+            &[
+                b"a",
+                b"b",
+                b"c",
+                b"\xff<eos>",
+                b"bc",
+                b"ab",
+                b"\xff<pad>",
+                b"<tool>",
+            ],
+            3,
+        )
+    }
+
+    // source tokens mapped directly, or with bytes the destination can tokenize
+    fn random_tokens(rng: &mut Rng, tr: &TokenTranslator, len: usize) -> Vec<TokenId> {
+        let tokens = (0..tr.src().vocab_size() as TokenId)
+            .filter(|&t| {
+                tr.direct(t).is_some() || tr.dst().try_greedy_tokenize(tr.src().token(t)).is_ok()
+            })
+            .collect::<Vec<_>>();
+        (0..len)
+            .map(|_| tokens[rng.gen_up_to(tokens.len() - 1)])
+            .collect()
+    }
+
+    #[test]
+    fn direct_table() {
+        let tr = TokenTranslator::new(&src(), &dst());
+        let table = (0..10).map(|t| tr.direct(t)).collect::<Vec<_>>();
+        assert_eq!(
+            table,
+            vec![
+                Some(3),
+                None,
+                Some(0),
+                Some(1),
+                Some(2),
+                Some(5),
+                None,
+                None,
+                Some(6),
+                None
+            ]
+        );
+        assert_eq!(tr.num_mapped(), 6);
+        assert_eq!(tr.mapped_fraction(), 6.0 / 9.0);
+
+        let back = TokenTranslator::new(&dst(), &src());
+        // "<tool>" is an ordinary token in dst, and only a part of a special token in src
+        assert_eq!(back.direct(7), None);
+        assert_eq!(back.direct(4), None);
+        assert_eq!(back.num_mapped(), 6);
+    }
+
+    #[test]
+    fn specials_by_name() {
+        let (src, dst) = (src(), dst());
+        let tr = TokenTranslator::new(&src, &dst);
+        for (name, t) in src.get_special_tokens_with_names() {
+            assert_eq!(tr.direct(t), dst.get_special_token(&name), "{}", name);
+        }
+        assert_eq!(tr.translate(&[2, 0]), vec![0, 3]);
+        assert_eq!(tr.translate(&[8, 5, 8]), vec![6, 5, 6]);
+        // no special token of that name: tokenized as bytes, which dst can't all do
+        assert_eq!(
+            tr.translate(&[1, 2]),
+            [dst.greedy_tokenize(b"\xff<tool>"), vec![0]].concat()
+        );
+    }
+
+    #[test]
+    fn same_bytes() {
+        let (src, dst) = (src(), dst());
+        let mut rng = Rng::new(1);
+        for (a, b) in [(&src, &dst), (&dst, &src)] {
+            let tr = TokenTranslator::new(a, b);
+            for len in 0..20 {
+                let tokens = random_tokens(&mut rng, &tr, len);
+                let res = tr.translate(&tokens);
+                assert_eq!(b.decode_raw(&res), a.decode_raw(&tokens), "{:?}", tokens);
+            }
+        }
+
+        let tr = TokenTranslator::new(&src, &dst);
+        // runs of split tokens are tokenized together
+        assert_eq!(tr.translate(&[6, 7, 3]), vec![5, 2, 2, 0, 1]);
+        assert_eq!(tr.translate(&[2, 6, 0, 7]), vec![0, 5, 2, 3, 2, 0]);
+        assert_eq!(tr.translate(&[]), Vec::<TokenId>::new());
+    }
+}
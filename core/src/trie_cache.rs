@@ -0,0 +1,354 @@
+//! Sharing deserialized tries between the parts of a process that load the same bytes.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::string::{String, ToString};
+use std::sync::{Arc, OnceLock, RwLock, Weak};
+use std::vec::Vec;
+
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+
+use crate::{FxHashMap, TokTrie};
+
+/// `TokTrie::content_hash()` of a cached trie, or SHA-256 of the bytes it was loaded from.
+type Key = [u8; 32];
+
+fn bytes_key(bytes: &[u8]) -> Key {
+    Sha256::digest(bytes).into()
+}
+
+enum CachedTrie {
+    Strong(Arc<TokTrie>),
+    Weak(Weak<TokTrie>),
+}
+
+struct Entry {
+    trie: CachedTrie,
+    // value of TokTrieCache::clock when last returned
+    last_used: AtomicU64,
+}
+
+impl Entry {
+    fn get(&self) -> Option<Arc<TokTrie>> {
+        match &self.trie {
+            CachedTrie::Strong(t) => Some(t.clone()),
+            CachedTrie::Weak(t) => t.upgrade(),
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        match &self.trie {
+            CachedTrie::Strong(_) => true,
+            CachedTrie::Weak(t) => t.strong_count() > 0,
+        }
+    }
+}
+
+/// A load in progress; everyone asking for the same bytes waits on it.
+/// The error is kept as a string, as `anyhow::Error` is not `Clone`.
+type Loading = Arc<OnceLock<Result<(Arc<TokTrie>, Key), String>>>;
+
+#[derive(Default)]
+struct Entries {
+    // by content_hash()
+    tries: FxHashMap<Key, Entry>,
+    // key of bytes loaded by try_get_or_load() -> content_hash() of their trie
+    loaded: FxHashMap<Key, Key>,
+    // by key of bytes
+    loading: FxHashMap<Key, Loading>,
+}
+
+impl Entries {
+    fn get_loaded(&self, bkey: &Key) -> Option<&Entry> {
+        self.loaded.get(bkey).and_then(|k| self.tries.get(k))
+    }
+}
+
+/// Tries keyed by their `TokTrie::content_hash()`, so that loading the same bytes
+/// again (or inserting an equal trie) returns the same `Arc<TokTrie>`.
+///
+/// Holds at most `max_entries` tries, evicting the least recently used one.
+/// A weak cache (see `with_weak()`) doesn't keep the tries alive: they are dropped
+/// when the last `Arc` outside the cache is, and loaded again next time.
+pub struct TokTrieCache {
+    entries: RwLock<Entries>,
+    max_entries: usize,
+    weak: bool,
+    clock: AtomicU64,
+    num_loads: AtomicUsize,
+}
+
+impl TokTrieCache {
+    pub fn new(max_entries: usize) -> Self {
+        TokTrieCache {
+            entries: RwLock::new(Entries::default()),
+            max_entries: max_entries.max(1),
+            weak: false,
+            clock: AtomicU64::new(0),
+            num_loads: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn with_weak(self, weak: bool) -> Self {
+        TokTrieCache { weak, ..self }
+    }
+
+    /// The process-wide cache; it's weak, with up to 64 entries.
+    pub fn global() -> &'static TokTrieCache {
+        static GLOBAL: OnceLock<TokTrieCache> = OnceLock::new();
+        GLOBAL.get_or_init(|| TokTrieCache::new(64).with_weak(true))
+    }
+
+    /// Panics if `bytes` are not a valid trie; see `try_get_or_load()`.
+    pub fn get_or_load(&self, bytes: &[u8]) -> Arc<TokTrie> {
+        self.try_get_or_load(bytes)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// The cached trie for `bytes`, or `TokTrie::try_from_bytes(bytes)`, which is then cached.
+    /// Loading holds no lock of the cache, so lookups of other tries don't wait for it;
+    /// concurrent calls with the same bytes wait for the first one, and load them only once.
+    pub fn try_get_or_load(&self, bytes: &[u8]) -> Result<Arc<TokTrie>> {
+        let bkey = bytes_key(bytes);
+        let loading = {
+            let entries = self.entries.read().unwrap();
+            if let Some(t) = entries.get_loaded(&bkey).and_then(|e| self.touch(e)) {
+                return Ok(t);
+            }
+            entries.loading.get(&bkey).cloned()
+        };
+        let loading = match loading {
+            Some(l) => l,
+            None => {
+                let mut entries = self.entries.write().unwrap();
+                // someone may have loaded it while we waited for the lock
+                if let Some(t) = entries.get_loaded(&bkey).and_then(|e| self.touch(e)) {
+                    return Ok(t);
+                }
+                entries.loading.entry(bkey).or_default().clone()
+            }
+        };
+
+        let res = loading
+            .get_or_init(|| {
+                let trie = TokTrie::try_from_bytes(bytes).map_err(|e| e.to_string())?;
+                self.num_loads.fetch_add(1, Ordering::Relaxed);
+                let key = trie.content_hash();
+                Ok((Arc::new(trie), key))
+            })
+            .clone();
+
+        let mut entries = self.entries.write().unwrap();
+        if entries
+            .loading
+            .get(&bkey)
+            .is_some_and(|l| Arc::ptr_eq(l, &loading))
+        {
+            entries.loading.remove(&bkey);
+        }
+        let (trie, key) = res.map_err(|e| anyhow!(e))?;
+        let trie = self.insert_locked(&mut entries, key, trie);
+        entries.loaded.insert(bkey, key);
+        Ok(trie)
+    }
+
+    /// Cache `trie`, keyed by `trie.content_hash()`. If an equal trie is cached already,
+    /// that one is returned instead.
+    pub fn insert(&self, trie: TokTrie) -> Arc<TokTrie> {
+        let key = trie.content_hash();
+        let mut entries = self.entries.write().unwrap();
+        self.insert_locked(&mut entries, key, Arc::new(trie))
+    }
+
+    /// Number of entries, including weak ones whose trie was dropped already.
+    pub fn len(&self) -> usize {
+        self.entries.read().unwrap().tries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        let mut entries = self.entries.write().unwrap();
+        entries.tries.clear();
+        entries.loaded.clear();
+    }
+
+    /// Number of tries deserialized by `try_get_or_load()` so far.
+    pub fn num_loads(&self) -> usize {
+        self.num_loads.load(Ordering::Relaxed)
+    }
+
+    fn touch(&self, e: &Entry) -> Option<Arc<TokTrie>> {
+        let t = e.get()?;
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        e.last_used.store(now, Ordering::Relaxed);
+        Some(t)
+    }
+
+    /// The cached trie under `key` if there is one, otherwise `trie`, which is cached.
+    fn insert_locked(&self, entries: &mut Entries, key: Key, trie: Arc<TokTrie>) -> Arc<TokTrie> {
+        if let Some(t) = entries.tries.get(&key).and_then(|e| self.touch(e)) {
+            return t;
+        }
+        let cached = if self.weak {
+            CachedTrie::Weak(Arc::downgrade(&trie))
+        } else {
+            CachedTrie::Strong(trie.clone())
+        };
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        entries.tries.insert(
+            key,
+            Entry {
+                trie: cached,
+                last_used: AtomicU64::new(now),
+            },
+        );
+        if entries.tries.len() > self.max_entries {
+            let Entries { tries, loaded, .. } = entries;
+            tries.retain(|_, e| e.is_alive());
+            while tries.len() > self.max_entries {
+                let oldest = tries
+                    .iter()
+                    .min_by_key(|(_, e)| e.last_used.load(Ordering::Relaxed))
+                    .map(|(k, _)| *k)
+                    .unwrap();
+                tries.remove(&oldest);
+            }
+            loaded.retain(|_, k| tries.contains_key(k));
+        }
+        trie
+    }
+}
+
+impl Default for TokTrieCache {
+    fn default() -> Self {
+        TokTrieCache::new(16)
+    }
+}
+
+impl core::fmt::Debug for TokTrieCache {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let keys = self
+            .entries
+            .read()
+            .unwrap()
+            .tries
+            .keys()
+            .map(|k| {
+                k.iter()
+                    .map(|b| std::format!("{:02x}", b))
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>();
+        f.debug_struct("TokTrieCache")
+            .field("max_entries", &self.max_entries)
+            .field("weak", &self.weak)
+            .field("keys", &keys)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Barrier;
+    use std::thread;
+
+    use super::*;
+    use crate::{testing::synthetic_vocab, TokRxInfo};
+
+    fn trie_bytes(size: usize, seed: usize) -> Vec<u8> {
+        TokTrie::from(
+            &TokRxInfo::new(size as u32, 0),
+            &synthetic_vocab(size, seed),
+        )
+        .serialize()
+    }
+
+    #[test]
+    fn concurrent_load() {
+        let cache = TokTrieCache::new(4);
+        let bytes = trie_bytes(5000, 1);
+        let barrier = Barrier::new(8);
+        let tries = thread::scope(|s| {
+            let handles = (0..8)
+                .map(|_| {
+                    s.spawn(|| {
+                        barrier.wait();
+                        (0..20)
+                            .map(|_| cache.get_or_load(&bytes))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .flat_map(|h| h.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(cache.num_loads(), 1);
+        assert_eq!(cache.len(), 1);
+        assert!(tries.iter().all(|t| Arc::ptr_eq(t, &tries[0])));
+    }
+
+    #[test]
+    fn keys_and_eviction() {
+        let cache = TokTrieCache::new(2);
+        let a = trie_bytes(300, 1);
+        let ta = cache.get_or_load(&a);
+        // an equal trie is found by its content, whatever bytes it came from
+        let inserted = cache.insert(TokTrie::from_bytes(&a));
+        assert!(Arc::ptr_eq(&ta, &inserted));
+        let b = TokTrie::from_bytes(&trie_bytes(300, 2));
+        let tb = cache.insert(b.clone());
+        // the bytes of b are new, so they are loaded to find the content hash
+        assert!(Arc::ptr_eq(&cache.get_or_load(&b.serialize()), &tb));
+        assert_eq!(cache.num_loads(), 2);
+        assert_eq!(cache.len(), 2);
+
+        // a was used less recently than b
+        cache.get_or_load(&trie_bytes(300, 3));
+        assert_eq!(cache.len(), 2);
+        assert!(Arc::ptr_eq(&cache.get_or_load(&b.serialize()), &tb));
+        assert!(!Arc::ptr_eq(&cache.get_or_load(&a), &ta));
+        assert_eq!(cache.num_loads(), 4);
+
+        assert!(cache.try_get_or_load(b"not a trie").is_err());
+        // errors are not cached
+        assert!(cache.try_get_or_load(b"not a trie").is_err());
+        assert_eq!(cache.len(), 2);
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn specials_in_key() {
+        let cache = TokTrieCache::new(4);
+        let words = [&b"\xff<eos>"[..], b"\xff<x>", b"a"]
+            .iter()
+            .map(|w| w.to_vec())
+            .collect::<Vec<_>>();
+        let info = TokRxInfo::new(3, 0);
+        let by_prefix = cache.get_or_load(&TokTrie::from(&info, &words).serialize());
+        assert!(by_prefix.is_special_token(1));
+        // the same tokens, but only the first one special
+        let explicit = TokTrie::from_ext(&info, &words, Some(&[0])).serialize();
+        let t = cache.get_or_load(&explicit);
+        assert!(!Arc::ptr_eq(&t, &by_prefix));
+        assert!(!t.is_special_token(1));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn weak_entries() {
+        let cache = TokTrieCache::new(4).with_weak(true);
+        let bytes = trie_bytes(300, 1);
+        let t = cache.get_or_load(&bytes);
+        assert!(Arc::ptr_eq(&cache.get_or_load(&bytes), &t));
+        assert_eq!(Arc::strong_count(&t), 1);
+        drop(t);
+        cache.get_or_load(&bytes);
+        assert_eq!(cache.num_loads(), 2);
+    }
+}
@@ -0,0 +1,273 @@
+//! JavaScript bindings for loading a trie, tokenizing, decoding and computing token masks,
+//! with wasm-bindgen.
+//!
+//! Errors are thrown as JS exceptions. `JsTokTrie.fromBytes()` installs
+//! `console_error_panic_hook`, so that a panic is logged to the console
+//! (before the wasm module traps) rather than failing silently.
+
+use alloc::format;
+use std::string::String;
+use std::vec::Vec;
+
+use js_sys::{Array, Uint32Array, Uint8Array};
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    toktree::{Recognizer, SpecialToken},
+    TokTrie, TokenId,
+};
+
+/// Passed to `JsRecognizer.specialAllowed()`.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JsSpecialToken {
+    Unknown = 0,
+    Padding = 1,
+    Separator = 2,
+    BeginningOfSentence = 3,
+    EndOfSentence = 4,
+    EndOfTurn = 5,
+}
+
+impl From<SpecialToken> for JsSpecialToken {
+    fn from(tok: SpecialToken) -> Self {
+        match tok {
+            SpecialToken::Unknown => JsSpecialToken::Unknown,
+            SpecialToken::Padding => JsSpecialToken::Padding,
+            SpecialToken::Separator => JsSpecialToken::Separator,
+            SpecialToken::BeginningOfSentence => JsSpecialToken::BeginningOfSentence,
+            SpecialToken::EndOfSentence => JsSpecialToken::EndOfSentence,
+            SpecialToken::EndOfTurn => JsSpecialToken::EndOfTurn,
+        }
+    }
+}
+
+#[wasm_bindgen]
+extern "C" {
+    /// A `Recognizer` implemented in JS: an object with methods
+    /// `tryPushByte(byte: number): boolean`, `popBytes(num: number)`, `collapse()`
+    /// and `specialAllowed(tok: JsSpecialToken): boolean`;
+    /// see the `Recognizer` trait for the semantics. The methods must not throw.
+    pub type JsRecognizer;
+
+    #[wasm_bindgen(method, js_name = tryPushByte)]
+    fn try_push_byte(this: &JsRecognizer, byte: u8) -> bool;
+
+    #[wasm_bindgen(method, js_name = popBytes)]
+    fn pop_bytes(this: &JsRecognizer, num: usize);
+
+    #[wasm_bindgen(method)]
+    fn collapse(this: &JsRecognizer);
+
+    #[wasm_bindgen(method, js_name = specialAllowed)]
+    fn special_allowed(this: &JsRecognizer, tok: JsSpecialToken) -> bool;
+}
+
+/// The methods of `JsRecognizer`, so that `JsRecognizerRef` can be tested natively.
+trait JsRecognizerMethods {
+    fn try_push_byte(&self, byte: u8) -> bool;
+    fn pop_bytes(&self, num: usize);
+    fn collapse(&self);
+    fn special_allowed(&self, tok: JsSpecialToken) -> bool;
+}
+
+impl JsRecognizerMethods for JsRecognizer {
+    fn try_push_byte(&self, byte: u8) -> bool {
+        JsRecognizer::try_push_byte(self, byte)
+    }
+
+    fn pop_bytes(&self, num: usize) {
+        JsRecognizer::pop_bytes(self, num)
+    }
+
+    fn collapse(&self) {
+        JsRecognizer::collapse(self)
+    }
+
+    fn special_allowed(&self, tok: JsSpecialToken) -> bool {
+        JsRecognizer::special_allowed(self, tok)
+    }
+}
+
+// Every call crosses into JS, so this is much slower than a Rust recognizer.
+struct JsRecognizerRef<'a, R: ?Sized>(&'a R);
+
+impl<R: JsRecognizerMethods + ?Sized> Recognizer for JsRecognizerRef<'_, R> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.0.pop_bytes(num)
+    }
+
+    fn collapse(&mut self) {
+        self.0.collapse()
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        self.0.special_allowed(tok.into())
+    }
+
+    // only used with compute_bias() from the root, which pops all the bytes it pushes
+    fn trie_finished(&mut self) {}
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        self.0.try_push_byte(byte)
+    }
+}
+
+// TokTrie::compute_bias() as one byte per token, 1 if it's allowed
+fn bias_mask(trie: &TokTrie, r: &mut impl Recognizer) -> Vec<u8> {
+    let mut logits = trie.alloc_token_set();
+    trie.compute_bias(r, &mut logits);
+    (0..trie.vocab_size() as TokenId)
+        .map(|t| logits.is_allowed(t) as u8)
+        .collect()
+}
+
+fn js_error(e: anyhow::Error) -> JsError {
+    JsError::new(&format!("{}", e))
+}
+
+#[wasm_bindgen]
+pub struct JsTokTrie {
+    trie: TokTrie,
+}
+
+#[wasm_bindgen]
+impl JsTokTrie {
+    /// Load a trie serialized with `TokTrie::serialize()`.
+    #[wasm_bindgen(js_name = fromBytes)]
+    pub fn from_bytes(bytes: &Uint8Array) -> Result<JsTokTrie, JsError> {
+        console_error_panic_hook::set_once();
+        let trie = TokTrie::try_from_bytes(&bytes.to_vec()).map_err(js_error)?;
+        Ok(JsTokTrie { trie })
+    }
+
+    #[wasm_bindgen(getter, js_name = vocabSize)]
+    pub fn vocab_size(&self) -> usize {
+        self.trie.vocab_size()
+    }
+
+    /// See `TokTrie::greedy_tokenize()`.
+    #[wasm_bindgen(js_name = greedyTokenize)]
+    pub fn greedy_tokenize(&self, bytes: &Uint8Array) -> Uint32Array {
+        let tokens = self.trie.greedy_tokenize(&bytes.to_vec());
+        Uint32Array::from(&tokens[..])
+    }
+
+    /// See `TokTrie::try_decode()`; throws on out-of-range tokens.
+    pub fn decode(&self, tokens: &Uint32Array) -> Result<Uint8Array, JsError> {
+        let bytes = self.trie.try_decode(&tokens.to_vec()).map_err(js_error)?;
+        Ok(Uint8Array::from(&bytes[..]))
+    }
+
+    #[wasm_bindgen(js_name = tokenDbg)]
+    pub fn token_dbg(&self, token: TokenId) -> String {
+        self.trie.token_dbg(token)
+    }
+
+    /// Array of `[name, token]` pairs; see `TokTrie::get_special_tokens_with_names()`.
+    #[wasm_bindgen(js_name = getSpecialTokensWithNames)]
+    pub fn get_special_tokens_with_names(&self) -> JsValue {
+        self.trie
+            .get_special_tokens_with_names()
+            .into_iter()
+            .map(|(name, tok)| Array::of2(&JsValue::from(name), &JsValue::from(tok)))
+            .collect::<Array>()
+            .into()
+    }
+
+    /// The tokens allowed by `recognizer`, see `TokTrie::compute_bias()`;
+    /// one byte per token, 1 if it's allowed and 0 otherwise.
+    #[wasm_bindgen(js_name = computeBias)]
+    pub fn compute_bias(&self, recognizer: &JsRecognizer) -> Uint8Array {
+        let mask = bias_mask(&self.trie, &mut JsRecognizerRef(recognizer));
+        Uint8Array::from(&mask[..])
+    }
+}
+
+// js-sys and wasm-bindgen imports only work on wasm32, so these test the Rust side
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use core::cell::RefCell;
+
+    use super::*;
+    use crate::{recognizer::FnRecognizer, TokRxInfo};
+
+    // what a JS recognizer accepting a prefix of `target` would do
+    struct MockJsRecognizer {
+        target: &'static [u8],
+        len: RefCell<usize>,
+        specials: RefCell<Vec<JsSpecialToken>>,
+    }
+
+    impl JsRecognizerMethods for MockJsRecognizer {
+        fn try_push_byte(&self, byte: u8) -> bool {
+            let mut len = self.len.borrow_mut();
+            if self.target.get(*len) == Some(&byte) {
+                *len += 1;
+                true
+            } else {
+                false
+            }
+        }
+
+        fn pop_bytes(&self, num: usize) {
+            *self.len.borrow_mut() -= num;
+        }
+
+        fn collapse(&self) {}
+
+        fn special_allowed(&self, tok: JsSpecialToken) -> bool {
+            self.specials.borrow_mut().push(tok);
+            tok == JsSpecialToken::EndOfSentence && *self.len.borrow() == self.target.len()
+        }
+    }
+
+    #[test]
+    fn compute_bias_through_callbacks() {
+        let words = [&b"\xff<eos>"[..], b"a", b"b", b"ab", b"ba", b"abc"]
+            .iter()
+            .map(|w| w.to_vec())
+            .collect::<Vec<_>>();
+        let trie = TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words);
+        let js = MockJsRecognizer {
+            target: b"ab",
+            len: RefCell::new(0),
+            specials: RefCell::new(Vec::new()),
+        };
+        let mask = bias_mask(&trie, &mut JsRecognizerRef(&js));
+        assert_eq!(mask, vec![0, 1, 0, 1, 0, 0]);
+        // everything pushed was popped, though trie_finished() does nothing
+        assert_eq!(*js.len.borrow(), 0);
+        assert!(js
+            .specials
+            .borrow()
+            .contains(&JsSpecialToken::EndOfSentence));
+
+        // the same as a Rust recognizer
+        let mut r = FnRecognizer::new(|bytes: &[u8], b: u8| b"ab".get(bytes.len()) == Some(&b))
+            .with_eos(|bytes: &[u8]| bytes == b"ab");
+        assert_eq!(bias_mask(&trie, &mut r), mask);
+
+        *js.len.borrow_mut() = 2;
+        assert_eq!(
+            bias_mask(&trie, &mut JsRecognizerRef(&js)),
+            vec![1, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn special_token_values() {
+        // the values are part of the JS API
+        for (tok, value) in [
+            (SpecialToken::Unknown, 0),
+            (SpecialToken::Padding, 1),
+            (SpecialToken::Separator, 2),
+            (SpecialToken::BeginningOfSentence, 3),
+            (SpecialToken::EndOfSentence, 4),
+            (SpecialToken::EndOfTurn, 5),
+        ] {
+            assert_eq!(JsSpecialToken::from(tok) as u32, value);
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! The `_with_scratch()` methods of `TokTrie` don't allocate once `TrieScratch` is warmed up,
+//! and the `_into()` ones don't once the buffer is; counted by a global allocator.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use toktrie::{Recognizer, SpecialToken, TokRxInfo, TokTrie, TokenId};
+
+struct Counting;
+
+thread_local! {
+    // only the allocations of the thread running the test count
+    static NUM_ALLOCS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn count() {
+    let _ = NUM_ALLOCS.try_with(|n| n.set(n.get() + 1));
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc(layout)
+    }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc_zeroed(layout)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count();
+        System.realloc(ptr, layout, new_size)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn num_allocs() -> usize {
+    NUM_ALLOCS.with(|n| n.get())
+}
+
+fn trie() -> TokTrie {
+    let words = [
+        &b"\xff<eos>"[..],
+        b"a",
+        b"b",
+        b"c",
+        b"ab",
+        b"ba",
+        b"abc",
+        b"bca",
+        b"cab",
+        b"abca",
+    ]
+    .iter()
+    .map(|w| w.to_vec())
+    .collect::<Vec<_>>();
+    TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words)
+}
+
+// accepts the prefixes of `target`, and EOS after all of it; the stack is just its length
+struct Prefix {
+    target: &'static [u8],
+    len: usize,
+}
+
+impl Recognizer for Prefix {
+    fn pop_bytes(&mut self, num: usize) {
+        self.len -= num;
+    }
+    fn collapse(&mut self) {}
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        tok == SpecialToken::EndOfSentence && self.len == self.target.len()
+    }
+    fn trie_finished(&mut self) {}
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        if self.target.get(self.len) == Some(&byte) {
+            self.len += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[test]
+fn scratch_methods_dont_allocate() {
+    let trie = trie();
+    let mut scratch = trie.new_scratch();
+    let tokens: [&[TokenId]; 3] = [&[4, 3, 4], &[1, 2, 9, 4], &[]];
+    let targets: [&[u8]; 3] = [b"abcab", b"abcabcabca", b""];
+
+    let mut run = |check: bool| {
+        for (tokens, target) in tokens.iter().zip(targets.iter()) {
+            let mut r = Prefix { target, len: 0 };
+            let chopped = trie.chop_tokens_with_scratch(&mut r, tokens, &mut scratch);
+            let (keep, prefix) = trie.heal_tokens_with_scratch(&mut r, tokens, &mut scratch);
+            let num_bytes = prefix.len();
+            let allowed = trie
+                .compute_bias_with_scratch(&mut r, &mut scratch)
+                .num_set();
+            let forced = trie.forced_bytes_with_scratch(&mut r, &mut scratch).len();
+            assert_eq!(r.len, 0);
+            if check {
+                // the same answers as the allocating methods
+                assert_eq!(chopped, trie.chop_tokens(&mut r, tokens));
+                let heal = trie.heal_tokens(&mut r, tokens);
+                assert_eq!(keep, heal.keep_tokens);
+                assert_eq!(num_bytes, heal.forced_prefix.len());
+                let mut logits = trie.alloc_token_set();
+                trie.compute_bias(&mut r, &mut logits);
+                assert_eq!(
+                    &logits,
+                    trie.compute_bias_with_scratch(&mut r, &mut scratch)
+                );
+                assert_eq!(allowed, logits.num_set());
+                assert_eq!(
+                    trie.forced_bytes(&mut r),
+                    trie.forced_bytes_with_scratch(&mut r, &mut scratch)
+                );
+                assert_eq!(forced, target.len());
+            }
+        }
+    };
+
+    // warm-up
+    run(true);
+    let before = num_allocs();
+    for _ in 0..10 {
+        run(false);
+    }
+    assert_eq!(num_allocs(), before);
+
+    // the counter works
+    let v = vec![1u8; 10];
+    assert_eq!(num_allocs(), before + 1);
+    drop(v);
+}
+
+#[test]
+fn decode_into_doesnt_allocate() {
+    let trie = trie();
+    let tokens = (0..10_000)
+        .map(|i| (i * 7 % 10) as TokenId)
+        .collect::<Vec<_>>();
+    let mut buf = Vec::new();
+    trie.decode_into(&tokens, &mut buf);
+    assert_eq!(buf, trie.decode(&tokens));
+    trie.decode_raw_into(&tokens, &mut buf);
+    buf.clear();
+
+    let before = num_allocs();
+    for _ in 0..10 {
+        buf.clear();
+        trie.decode_into(&tokens, &mut buf);
+        buf.clear();
+        trie.decode_raw_into(&tokens, &mut buf);
+    }
+    assert_eq!(num_allocs(), before);
+    assert_eq!(buf, trie.decode_raw(&tokens));
+}
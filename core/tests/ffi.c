@@ -0,0 +1,72 @@
+/* Links toktrie.h against the library; see the "C API" step in .github/workflows/rust.yml. */
+
+#include <stdio.h>
+#include <stdlib.h>
+
+#include "../toktrie.h"
+
+static bool accept_a(void *user_data, uint8_t byte) {
+  size_t *depth = user_data;
+  if (byte == 'a' && *depth == 0) {
+    *depth += 1;
+    return true;
+  }
+  return false;
+}
+
+static void pop_bytes(void *user_data, size_t num) { *(size_t *)user_data -= num; }
+
+static void collapse(void *user_data) { (void)user_data; }
+
+static bool special_allowed(void *user_data, uint32_t special) {
+  (void)user_data;
+  return special == TOKTRIE_SPECIAL_END_OF_SENTENCE;
+}
+
+int main(int argc, char **argv) {
+  if (argc != 2) {
+    fprintf(stderr, "usage: %s trie.bin\n", argv[0]);
+    return 2;
+  }
+  FILE *f = fopen(argv[1], "rb");
+  if (!f) {
+    perror(argv[1]);
+    return 2;
+  }
+  static uint8_t buf[1 << 20];
+  size_t len = fread(buf, 1, sizeof(buf), f);
+  fclose(f);
+
+  TokTrie *trie = toktrie_new_from_bytes(buf, len);
+  if (!trie) {
+    fprintf(stderr, "failed to load %s\n", argv[1]);
+    return 1;
+  }
+  size_t vocab_size = toktrie_vocab_size(trie);
+
+  TokTrieTokenId tokens[16];
+  size_t num_tokens = 0;
+  const uint8_t *text = (const uint8_t *)"ab";
+  if (toktrie_greedy_tokenize(trie, text, 2, tokens, 16, &num_tokens) != TOKTRIE_OK) {
+    fprintf(stderr, "greedy_tokenize failed\n");
+    return 1;
+  }
+
+  size_t depth = 0;
+  TokTrieRecognizer rec = {&depth, accept_a, pop_bytes, collapse, special_allowed, NULL, NULL};
+  size_t mask_words = (vocab_size + 31) / 32;
+  uint32_t *mask = calloc(mask_words, sizeof(uint32_t));
+  if (toktrie_compute_bias(trie, &rec, mask, mask_words) != TOKTRIE_OK) {
+    fprintf(stderr, "compute_bias failed\n");
+    return 1;
+  }
+  size_t num_allowed = 0;
+  for (size_t t = 0; t < vocab_size; t++) {
+    num_allowed += (mask[t / 32] >> (t % 32)) & 1;
+  }
+  printf("vocab_size=%zu tokens(ab)=%zu allowed=%zu\n", vocab_size, num_tokens, num_allowed);
+
+  free(mask);
+  toktrie_free(trie);
+  return 0;
+}
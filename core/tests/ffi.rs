@@ -0,0 +1,222 @@
+//! The C API, called through function pointers of the types declared in `toktrie.h`.
+#![cfg(feature = "cffi")]
+
+use core::ffi::c_void;
+use std::ptr::{null, null_mut};
+
+use toktrie::{ffi::*, TokRxInfo, TokTrie, TokenId};
+
+// the signatures of toktrie.h
+type NewFromBytes = unsafe extern "C" fn(*const u8, usize) -> *mut TokTrie;
+type Free = unsafe extern "C" fn(*mut TokTrie);
+type VocabSize = unsafe extern "C" fn(*const TokTrie) -> usize;
+type Token = unsafe extern "C" fn(*const TokTrie, u32, *mut *const u8, *mut usize) -> i32;
+type GreedyTokenize =
+    unsafe extern "C" fn(*const TokTrie, *const u8, usize, *mut u32, usize, *mut usize) -> i32;
+type ComputeBias =
+    unsafe extern "C" fn(*const TokTrie, *const TokTrieRecognizer, *mut u32, usize) -> i32;
+
+struct Api {
+    new_from_bytes: NewFromBytes,
+    free: Free,
+    vocab_size: VocabSize,
+    token: Token,
+    greedy_tokenize: GreedyTokenize,
+    compute_bias: ComputeBias,
+}
+
+const API: Api = Api {
+    new_from_bytes: toktrie_new_from_bytes,
+    free: toktrie_free,
+    vocab_size: toktrie_vocab_size,
+    token: toktrie_token,
+    greedy_tokenize: toktrie_greedy_tokenize,
+    compute_bias: toktrie_compute_bias,
+};
+
+fn words() -> Vec<Vec<u8>> {
+    [&b"\xff<eos>"[..], b"a", b"b", b"ab", b"ba", b"abc", b"c"]
+        .iter()
+        .map(|w| w.to_vec())
+        .collect()
+}
+
+fn load() -> *mut TokTrie {
+    let words = words();
+    let bytes = TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words).serialize();
+    let trie = unsafe { (API.new_from_bytes)(bytes.as_ptr(), bytes.len()) };
+    assert!(!trie.is_null());
+    trie
+}
+
+// accepts a prefix of `target`, and EOS after all of it
+struct Prefix {
+    target: &'static [u8],
+    len: usize,
+    calls: usize,
+}
+
+unsafe extern "C" fn try_push_byte(data: *mut c_void, byte: u8) -> bool {
+    let p = &mut *(data as *mut Prefix);
+    p.calls += 1;
+    if p.target.get(p.len) == Some(&byte) {
+        p.len += 1;
+        true
+    } else {
+        false
+    }
+}
+
+unsafe extern "C" fn pop_bytes(data: *mut c_void, num: usize) {
+    let p = &mut *(data as *mut Prefix);
+    p.len -= num;
+}
+
+unsafe extern "C" fn collapse(_data: *mut c_void) {}
+
+unsafe extern "C" fn special_allowed(data: *mut c_void, special: u32) -> bool {
+    let p = &*(data as *mut Prefix);
+    special == TOKTRIE_SPECIAL_END_OF_SENTENCE && p.len == p.target.len()
+}
+
+fn recognizer(p: &mut Prefix) -> TokTrieRecognizer {
+    TokTrieRecognizer {
+        user_data: p as *mut Prefix as *mut c_void,
+        try_push_byte: Some(try_push_byte),
+        pop_bytes: Some(pop_bytes),
+        collapse: Some(collapse),
+        special_allowed: Some(special_allowed),
+        trie_started: None,
+        trie_finished: None,
+    }
+}
+
+fn mask_tokens(mask: &[u32]) -> Vec<TokenId> {
+    (0..mask.len() as u32 * 32)
+        .filter(|&t| mask[t as usize / 32] & (1 << (t % 32)) != 0)
+        .collect()
+}
+
+#[test]
+fn load_and_query() {
+    let trie = load();
+    unsafe {
+        assert_eq!((API.vocab_size)(trie), 7);
+        assert_eq!((API.vocab_size)(null()), 0);
+
+        let mut ptr = null();
+        let mut len = 0;
+        assert_eq!((API.token)(trie, 5, &mut ptr, &mut len), TOKTRIE_OK);
+        assert_eq!(std::slice::from_raw_parts(ptr, len), b"abc");
+        assert_eq!(
+            (API.token)(trie, 7, &mut ptr, &mut len),
+            TOKTRIE_ERR_INVALID
+        );
+        assert_eq!((API.token)(trie, 0, null_mut(), &mut len), TOKTRIE_ERR_NULL);
+        assert_eq!((API.token)(null(), 0, &mut ptr, &mut len), TOKTRIE_ERR_NULL);
+
+        let bad = b"not a trie";
+        assert!((API.new_from_bytes)(bad.as_ptr(), bad.len()).is_null());
+        assert!((API.new_from_bytes)(null(), 0).is_null());
+
+        (API.free)(trie);
+        (API.free)(null_mut());
+    }
+}
+
+#[test]
+fn greedy_tokenize() {
+    let trie = load();
+    let text = b"abcbab";
+    let mut out = [0u32; 8];
+    let mut len = 0;
+    unsafe {
+        let r = (API.greedy_tokenize)(
+            trie,
+            text.as_ptr(),
+            text.len(),
+            out.as_mut_ptr(),
+            8,
+            &mut len,
+        );
+        assert_eq!(r, TOKTRIE_OK);
+        assert_eq!(&out[..len], &[5, 4, 2]);
+
+        let r = (API.greedy_tokenize)(
+            trie,
+            text.as_ptr(),
+            text.len(),
+            out.as_mut_ptr(),
+            2,
+            &mut len,
+        );
+        assert_eq!(r, TOKTRIE_ERR_SIZE);
+        assert_eq!(len, 3);
+        let r = (API.greedy_tokenize)(trie, text.as_ptr(), text.len(), null_mut(), 0, &mut len);
+        assert_eq!(r, TOKTRIE_ERR_SIZE);
+        assert_eq!(len, 3);
+
+        let r = (API.greedy_tokenize)(trie, b"xy".as_ptr(), 2, out.as_mut_ptr(), 8, &mut len);
+        assert_eq!(r, TOKTRIE_ERR_INVALID);
+        let r = (API.greedy_tokenize)(trie, null(), 0, out.as_mut_ptr(), 8, &mut len);
+        assert_eq!(r, TOKTRIE_ERR_NULL);
+        (API.free)(trie);
+    }
+}
+
+#[test]
+fn compute_bias() {
+    let trie = load();
+    unsafe {
+        let mut p = Prefix {
+            target: b"ab",
+            len: 0,
+            calls: 0,
+        };
+        let rec = recognizer(&mut p);
+        // the extra word is left unchanged
+        let mut mask = [0u32, 0xdead];
+        assert_eq!(
+            (API.compute_bias)(trie, &rec, mask.as_mut_ptr(), 2),
+            TOKTRIE_OK
+        );
+        assert_eq!(mask_tokens(&mask[..1]), vec![1, 3]);
+        assert_eq!(mask[1], 0xdead);
+        assert!(p.calls > 0);
+        assert_eq!(p.len, 0);
+
+        let mut p = Prefix {
+            target: b"",
+            len: 0,
+            calls: 0,
+        };
+        let rec = recognizer(&mut p);
+        assert_eq!(
+            (API.compute_bias)(trie, &rec, mask.as_mut_ptr(), 1),
+            TOKTRIE_OK
+        );
+        assert_eq!(mask_tokens(&mask[..1]), vec![0]);
+
+        assert_eq!(
+            (API.compute_bias)(trie, &rec, mask.as_mut_ptr(), 0),
+            TOKTRIE_ERR_SIZE
+        );
+        assert_eq!(
+            (API.compute_bias)(trie, &rec, null_mut(), 1),
+            TOKTRIE_ERR_NULL
+        );
+        assert_eq!(
+            (API.compute_bias)(trie, null(), mask.as_mut_ptr(), 1),
+            TOKTRIE_ERR_NULL
+        );
+        let incomplete = TokTrieRecognizer {
+            collapse: None,
+            ..rec
+        };
+        assert_eq!(
+            (API.compute_bias)(trie, &incomplete, mask.as_mut_ptr(), 1),
+            TOKTRIE_ERR_NULL
+        );
+        (API.free)(trie);
+    }
+}
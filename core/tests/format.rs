@@ -0,0 +1,300 @@
+//! The serialized format, against fixtures in `tests/data/`.
+
+use toktrie::{TokRxInfo, TokTrie, TokTrieRef};
+
+// the tokens of the fixtures
+fn fixture_words() -> Vec<Vec<u8>> {
+    [
+        &b"\xff<|endoftext|>"[..],
+        b"a",
+        b"b",
+        b"c",
+        b"d",
+        b"e",
+        b" ",
+        b"\n",
+        b"ab",
+        b"abc",
+        b"abd",
+        b" a",
+        b" ab",
+        b"ba",
+        b"bad",
+        b"\xc3\xa9",
+        b"\xc3",
+        b"\xa9",
+        b"cafe",
+        b"caf\xc3\xa9",
+    ]
+    .iter()
+    .map(|w| w.to_vec())
+    .collect()
+}
+
+fn fixture_trie() -> TokTrie {
+    let words = fixture_words();
+    TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words)
+}
+
+fn u32_at(bytes: &[u8], idx: usize) -> u32 {
+    u32::from_le_bytes(bytes[idx * 4..idx * 4 + 4].try_into().unwrap())
+}
+
+// written by serialize() before token_data_bytes was fixed: it holds trie_bytes
+const OLD_LAYOUT: &[u8] = include_bytes!("data/old_layout.bin");
+
+#[test]
+fn old_layout_loads() {
+    assert_eq!(u32_at(OLD_LAYOUT, 4), u32_at(OLD_LAYOUT, 2));
+    let trie = TokTrie::try_from_bytes(OLD_LAYOUT).unwrap();
+    let expected = fixture_trie();
+    assert_eq!(trie.vocab_size(), expected.vocab_size());
+    for (t, w) in fixture_words().iter().enumerate() {
+        assert_eq!(trie.token(t as u32), &w[..]);
+        assert_eq!(trie.token_id(w), Some(t as u32));
+    }
+    assert_eq!(trie.serialize(), expected.serialize());
+    let again = TokTrie::try_from_bytes(&trie.serialize()).unwrap();
+    assert_eq!(again.serialize(), trie.serialize());
+}
+
+#[test]
+fn token_data_bytes_is_token_data_len() {
+    let trie = fixture_trie();
+    let bytes = trie.serialize();
+    let token_data_len: usize = fixture_words().iter().map(|w| w.len()).sum();
+    assert_eq!(u32_at(&bytes, 4) as usize, token_data_len);
+}
+
+#[test]
+fn round_trip() {
+    for trie in [
+        fixture_trie(),
+        TokTrie::try_from_bytes(OLD_LAYOUT).unwrap(),
+        synthetic_trie(),
+    ] {
+        let bytes = trie.serialize();
+        let loaded = TokTrie::try_from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.serialize(), bytes);
+        assert_eq!(loaded.vocab_size(), trie.vocab_size());
+        assert_eq!(loaded.max_token_len(), trie.max_token_len());
+        for t in 0..trie.vocab_size() as u32 {
+            assert_eq!(loaded.token(t), trie.token(t));
+        }
+    }
+}
+
+#[cfg(feature = "testing")]
+fn synthetic_trie() -> TokTrie {
+    let words = toktrie::testing::synthetic_vocab(5000, 3);
+    TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words)
+}
+
+#[cfg(not(feature = "testing"))]
+fn synthetic_trie() -> TokTrie {
+    fixture_trie()
+}
+
+#[test]
+fn truncated_buffers_fail_cleanly() {
+    for bytes in [OLD_LAYOUT, V4] {
+        // before version 5, the stats section after token data is optional;
+        // the old layout has none, and the wrong token_data_bytes
+        let stats_start = (1..5).map(|i| u32_at(bytes, i) as usize).sum::<usize>();
+        let stats_start = stats_start.min(bytes.len());
+        for len in 0..stats_start {
+            assert!(
+                TokTrie::try_from_bytes(&bytes[..len]).is_err(),
+                "{} of {} bytes loaded",
+                len,
+                bytes.len()
+            );
+        }
+        let trie = TokTrie::try_from_bytes(&bytes[..stats_start]).unwrap();
+        assert_eq!(trie.token(19), b"caf\xc3\xa9");
+    }
+    // now it's required, and has its size
+    for len in 0..GOLDEN.len() {
+        assert!(
+            TokTrie::try_from_bytes(&GOLDEN[..len]).is_err(),
+            "{} of {} bytes loaded",
+            len,
+            GOLDEN.len()
+        );
+    }
+}
+
+// written by the current serialize(), from fixture_words()
+const GOLDEN: &[u8] = include_bytes!("data/trie_v5.bin");
+// the same, written at format version 4, before the stats section had its size
+const V4: &[u8] = include_bytes!("data/trie_v4.bin");
+
+#[test]
+fn v4_loads() {
+    assert_eq!(u32_at(V4, 7), 4);
+    assert_eq!(u32_at(GOLDEN, 7), 5);
+    let trie = TokTrie::try_from_bytes(V4).unwrap();
+    assert_eq!(trie.serialize(), GOLDEN);
+    #[cfg(feature = "std")]
+    {
+        let trie = TokTrie::deserialize_from(&mut &V4[..]).unwrap();
+        assert_eq!(trie.serialize(), GOLDEN);
+        // its stats section spans the rest of the input
+        let more = [V4, b"more"].concat();
+        assert!(TokTrie::deserialize_from(&mut &more[..]).is_err());
+    }
+}
+
+#[test]
+fn golden_serialize() {
+    assert_eq!(fixture_trie().serialize(), GOLDEN);
+    let trie = TokTrie::try_from_bytes(GOLDEN).unwrap();
+    assert_eq!(trie.serialize(), GOLDEN);
+}
+
+#[test]
+fn byte_swapped_loads() {
+    // the header, nodes and token offsets in big-endian, as written on such machines
+    let mut bytes = GOLDEN.to_vec();
+    let offsets_end = (1..4).map(|i| u32_at(GOLDEN, i) as usize).sum::<usize>();
+    for w in bytes[..offsets_end].chunks_exact_mut(4) {
+        w.reverse();
+    }
+    let trie = TokTrie::try_from_bytes(&bytes).unwrap();
+    assert_eq!(trie.serialize(), GOLDEN);
+    let borrowed = TokTrie::from_bytes_borrowed(&bytes);
+    for (t, w) in fixture_words().iter().enumerate() {
+        assert_eq!(borrowed.token(t as u32), &w[..]);
+    }
+}
+
+// loads with all the checking loaders; Err if any of them fails, panics are caught
+fn try_load(bytes: &[u8]) -> std::thread::Result<Result<(), String>> {
+    std::panic::catch_unwind(|| {
+        let owned = TokTrie::try_from_bytes(bytes).map_err(|e| e.to_string());
+        let borrowed = TokTrieRef::try_from_bytes(bytes).map_err(|e| e.to_string());
+        assert_eq!(owned.is_ok(), borrowed.is_ok());
+        owned.map(|t| {
+            // usable as loaded
+            t.serialize();
+        })
+    })
+}
+
+#[test]
+fn flipped_bits_never_panic() {
+    let mut panics = Vec::new();
+    for bit in 0..GOLDEN.len() * 8 {
+        let mut bytes = GOLDEN.to_vec();
+        bytes[bit / 8] ^= 1 << (bit % 8);
+        match try_load(&bytes) {
+            Err(_) => panics.push(bit),
+            // magic, section sizes and version
+            Ok(res) if [0, 1, 2, 3, 4, 7].contains(&(bit / 32)) => {
+                assert!(res.is_err(), "bit {} of the header", bit)
+            }
+            Ok(_) => {}
+        }
+    }
+    assert!(panics.is_empty(), "panicked with bits {:?} flipped", panics);
+}
+
+#[test]
+fn truncated_at_section_boundaries() {
+    for trie in [fixture_trie(), synthetic_trie()] {
+        for bytes in [trie.serialize(), trie.serialize_with_fingerprint(&[7; 32])] {
+            let fp_len = bytes.len() - trie.serialize().len();
+            let hd = &bytes[fp_len..];
+            let hd_size = u32_at(hd, 1) as usize;
+            let mut boundary = fp_len + hd_size;
+            let mut boundaries = vec![fp_len, boundary];
+            for i in 2..5 {
+                boundary += u32_at(hd, i) as usize;
+                boundaries.push(boundary);
+            }
+            // and inside the stats section, after its magic and size
+            boundaries.extend([boundary + 4, boundary + 8, bytes.len() - 1]);
+            for &end in &boundaries {
+                for len in [end.saturating_sub(1), end, end + 1] {
+                    if len >= bytes.len() {
+                        continue;
+                    }
+                    let res = try_load(&bytes[..len]).expect("panicked");
+                    assert!(res.is_err(), "{} of {} bytes loaded", len, bytes.len());
+                }
+            }
+            try_load(&bytes).unwrap().unwrap();
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+mod streams {
+    use std::io::{Cursor, Read};
+
+    use super::*;
+
+    // returns at most 3 bytes per read()
+    struct ShortReads<'a>(&'a [u8]);
+
+    impl Read for ShortReads<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(3);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn serialize_to_matches_serialize() {
+        for trie in [fixture_trie(), synthetic_trie()] {
+            let mut out = Vec::new();
+            trie.serialize_to(&mut out).unwrap();
+            assert_eq!(out, trie.serialize());
+        }
+    }
+
+    #[test]
+    fn deserialize_from_stream() {
+        for trie in [fixture_trie(), synthetic_trie()] {
+            let bytes = trie.serialize();
+            // nothing past the trie is read
+            let mut cursor = Cursor::new([&bytes[..], b"next"].concat());
+            let loaded = TokTrie::deserialize_from(&mut cursor).unwrap();
+            assert_eq!(loaded.serialize(), bytes);
+            assert_eq!(cursor.position() as usize, bytes.len());
+            let mut rest = Vec::new();
+            cursor.read_to_end(&mut rest).unwrap();
+            assert_eq!(rest, b"next");
+            let loaded = TokTrie::deserialize_from(&mut ShortReads(&bytes)).unwrap();
+            assert_eq!(loaded.serialize(), bytes);
+
+            let with_fp = trie.serialize_with_fingerprint(&[7; 32]);
+            let loaded = TokTrie::deserialize_from(&mut ShortReads(&with_fp)).unwrap();
+            assert_eq!(loaded.serialize(), bytes);
+        }
+    }
+
+    #[test]
+    fn truncated_streams_fail_cleanly() {
+        for len in 0..GOLDEN.len() {
+            let err = TokTrie::deserialize_from(&mut ShortReads(&GOLDEN[..len])).unwrap_err();
+            assert!(
+                err.to_string().starts_with("TokTrie: "),
+                "{} bytes: {}",
+                len,
+                err
+            );
+        }
+        let stats_start = (1..5).map(|i| u32_at(GOLDEN, i) as usize).sum::<usize>();
+        let err = TokTrie::deserialize_from(&mut &GOLDEN[..stats_start + 10]).unwrap_err();
+        assert_eq!(err.to_string(), "TokTrie: truncated stats");
+        // a stats size larger than the input
+        let mut bytes = GOLDEN.to_vec();
+        bytes[stats_start + 4..stats_start + 8].copy_from_slice(&u32::MAX.to_le_bytes());
+        let err = TokTrie::deserialize_from(&mut &bytes[..]).unwrap_err();
+        assert_eq!(err.to_string(), "TokTrie: truncated stats");
+        assert!(TokTrie::try_from_bytes(&bytes).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+//! `trie_from_gguf()` on GGUF files built here, with just the metadata.
+#![cfg(feature = "gguf")]
+
+use std::io::Cursor;
+
+use toktrie::{
+    gguf::{token_bytes_from_gguf, trie_from_gguf},
+    TokTrie, TokenId,
+};
+
+enum Value {
+    U32(u32),
+    I32(i32),
+    F32(f32),
+    Str(&'static str),
+    Strings(Vec<&'static str>),
+    I32s(Vec<i32>),
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u64).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn gguf(kvs: &[(&str, Value)]) -> Vec<u8> {
+    let mut out = b"GGUF".to_vec();
+    out.extend_from_slice(&3u32.to_le_bytes());
+    // tensors
+    out.extend_from_slice(&0u64.to_le_bytes());
+    out.extend_from_slice(&(kvs.len() as u64).to_le_bytes());
+    for (key, value) in kvs {
+        write_str(&mut out, key);
+        match value {
+            Value::U32(v) => {
+                out.extend_from_slice(&4u32.to_le_bytes());
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::I32(v) => {
+                out.extend_from_slice(&5u32.to_le_bytes());
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::F32(v) => {
+                out.extend_from_slice(&6u32.to_le_bytes());
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::Str(s) => {
+                out.extend_from_slice(&8u32.to_le_bytes());
+                write_str(&mut out, s);
+            }
+            Value::Strings(v) => {
+                out.extend_from_slice(&9u32.to_le_bytes());
+                out.extend_from_slice(&8u32.to_le_bytes());
+                out.extend_from_slice(&(v.len() as u64).to_le_bytes());
+                for s in v {
+                    write_str(&mut out, s);
+                }
+            }
+            Value::I32s(v) => {
+                out.extend_from_slice(&9u32.to_le_bytes());
+                out.extend_from_slice(&5u32.to_le_bytes());
+                out.extend_from_slice(&(v.len() as u64).to_le_bytes());
+                for x in v {
+                    out.extend_from_slice(&x.to_le_bytes());
+                }
+            }
+        }
+    }
+    out
+}
+
+fn llama() -> Vec<(&'static str, Value)> {
+    vec![
+        ("general.architecture", Value::Str("llama")),
+        ("llama.rope.freq_base", Value::F32(10000.0)),
+        ("tokenizer.ggml.model", Value::Str("llama")),
+        (
+            "tokenizer.ggml.tokens",
+            Value::Strings(vec![
+                "<unk>", "<s>", "</s>", "<0x41>", "<0xE2>", "▁a", "b", "<|user|>", "[UNUSED]",
+            ]),
+        ),
+        (
+            "tokenizer.ggml.token_type",
+            Value::I32s(vec![2, 3, 3, 6, 6, 1, 1, 4, 5]),
+        ),
+        ("tokenizer.ggml.merges", Value::Strings(vec!["▁ a"])),
+        ("tokenizer.ggml.bos_token_id", Value::U32(1)),
+        ("tokenizer.ggml.eos_token_id", Value::U32(2)),
+        ("tokenizer.ggml.unknown_token_id", Value::I32(0)),
+        // no padding_token_id or eot_token_id
+    ]
+}
+
+fn special(name: &str) -> Vec<u8> {
+    let mut res = vec![TokTrie::SPECIAL_TOKEN_PREFIX_BYTE];
+    res.extend_from_slice(name.as_bytes());
+    res
+}
+
+#[test]
+fn llama_tokens() {
+    let bytes = gguf(&llama());
+    let (trie, info) = trie_from_gguf(Cursor::new(&bytes)).unwrap();
+    assert_eq!(info.vocab_size, 9);
+    assert_eq!(info.tok_eos, 2);
+    assert_eq!(info.tok_bos, Some(1));
+    assert_eq!(info.tok_unk, Some(0));
+    assert_eq!(info.tok_pad, None);
+    assert_eq!(info.tok_end_of_turn, None);
+
+    let expected = [
+        special("<unk>"),
+        special("<s>"),
+        special("</s>"),
+        b"A".to_vec(),
+        vec![0xe2],
+        b" a".to_vec(),
+        b"b".to_vec(),
+        b"<|user|>".to_vec(),
+        vec![],
+    ];
+    for (t, bytes) in expected.iter().enumerate() {
+        assert_eq!(trie.token(t as TokenId), &bytes[..], "token {}", t);
+    }
+    let mut specials = trie.get_special_tokens();
+    specials.sort();
+    assert_eq!(specials, vec![0, 1, 2]);
+    assert!(!trie.is_special_token(7));
+    assert_eq!(trie.greedy_tokenize(b" abA"), vec![5, 6, 3]);
+
+    let (info2, words) = token_bytes_from_gguf(Cursor::new(&bytes)).unwrap();
+    assert_eq!(info2, info);
+    assert_eq!(words, expected);
+
+    // reading starts at the current position
+    let mut prefixed = b"junk".to_vec();
+    prefixed.extend_from_slice(&bytes);
+    let mut cursor = Cursor::new(&prefixed);
+    cursor.set_position(4);
+    assert_eq!(trie_from_gguf(cursor).unwrap().0, trie);
+}
+
+#[test]
+fn gpt2_tokens() {
+    let bytes = gguf(&[
+        ("tokenizer.ggml.model", Value::Str("gpt2")),
+        (
+            "tokenizer.ggml.tokens",
+            Value::Strings(vec!["!", "Ġa", "<|endoftext|>", "Ċ"]),
+        ),
+        ("tokenizer.ggml.token_type", Value::I32s(vec![1, 1, 3, 1])),
+        ("tokenizer.ggml.eos_token_id", Value::U32(2)),
+    ]);
+    let (trie, info) = trie_from_gguf(Cursor::new(&bytes)).unwrap();
+    assert_eq!(info.tok_eos, 2);
+    assert_eq!(info.tok_bos, None);
+    assert_eq!(trie.token(1), b" a");
+    assert_eq!(trie.token(2), special("<|endoftext|>"));
+    assert_eq!(trie.token(3), b"\n");
+
+    // without token types or EOS
+    let bytes = gguf(&[
+        ("tokenizer.ggml.model", Value::Str("gpt2")),
+        ("tokenizer.ggml.tokens", Value::Strings(vec!["!", "Ġa"])),
+    ]);
+    let (trie, info) = trie_from_gguf(Cursor::new(&bytes)).unwrap();
+    assert_eq!(info.tok_eos, 0);
+    assert_eq!(trie.token(1), b" a");
+}
+
+fn error(bytes: &[u8]) -> String {
+    trie_from_gguf(Cursor::new(bytes)).unwrap_err().to_string()
+}
+
+#[test]
+fn errors() {
+    let bytes = gguf(&llama());
+    // truncated anywhere
+    for len in 0..bytes.len() {
+        assert!(
+            trie_from_gguf(Cursor::new(&bytes[..len])).is_err(),
+            "{}",
+            len
+        );
+    }
+    assert_eq!(error(b"GGUX\x03\0\0\0"), "GGUF: not a GGUF file");
+    let mut v1 = bytes.clone();
+    v1[4] = 1;
+    assert_eq!(error(&v1), "GGUF: unsupported version 1");
+
+    // an array length that doesn't fit in the file
+    let mut huge = bytes.clone();
+    let pos = huge
+        .windows(b"tokenizer.ggml.tokens".len())
+        .position(|w| w == b"tokenizer.ggml.tokens")
+        .unwrap()
+        + b"tokenizer.ggml.tokens".len()
+        + 8;
+    huge[pos..pos + 8].copy_from_slice(&(u64::MAX / 2).to_le_bytes());
+    assert_eq!(error(&huge), "GGUF: metadata truncated");
+
+    let with = |key: &'static str, value: Value| {
+        let mut kvs = llama();
+        kvs.retain(|(k, _)| *k != key);
+        kvs.push((key, value));
+        gguf(&kvs)
+    };
+    assert_eq!(
+        error(&with("tokenizer.ggml.eos_token_id", Value::U32(9))),
+        "GGUF: EOS token 9 out of range (vocab size 9)"
+    );
+    assert_eq!(
+        error(&with("tokenizer.ggml.bos_token_id", Value::I32(-1))),
+        "GGUF: BOS token -1 out of range (vocab size 9)"
+    );
+    assert_eq!(
+        error(&with("tokenizer.ggml.eos_token_id", Value::Str("2"))),
+        "GGUF: expecting an integer, got value type 8"
+    );
+    assert_eq!(
+        error(&with("tokenizer.ggml.model", Value::Str("bert"))),
+        "GGUF: unsupported tokenizer model \"bert\""
+    );
+    assert_eq!(
+        error(&with("tokenizer.ggml.token_type", Value::I32s(vec![1; 3]))),
+        "GGUF: 3 token types for 9 tokens"
+    );
+    assert_eq!(
+        error(&with(
+            "tokenizer.ggml.token_type",
+            Value::I32s(vec![2, 3, 3, 6, 6, 1, 1, 4, 9])
+        )),
+        "GGUF: invalid type 9 of token \"[UNUSED]\""
+    );
+    assert_eq!(
+        error(&with(
+            "tokenizer.ggml.token_type",
+            Value::I32s(vec![2, 3, 3, 6, 6, 6, 1, 4, 5])
+        )),
+        "GGUF: invalid byte token \"▁a\""
+    );
+    assert_eq!(
+        error(&with("tokenizer.ggml.tokens", Value::Strings(vec![]))),
+        "GGUF: empty vocabulary"
+    );
+    let mut kvs = llama();
+    kvs.retain(|(k, _)| *k != "tokenizer.ggml.tokens");
+    assert_eq!(error(&gguf(&kvs)), "GGUF: tokenizer.ggml.tokens missing");
+}
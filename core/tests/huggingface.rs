@@ -0,0 +1,120 @@
+//! `TokTrie::from_hf_json()` on small tokenizer.json files, in `tests/data/`,
+//! laid out like the ones of the models they are named after.
+#![cfg(feature = "hf")]
+
+use toktrie::{huggingface::token_bytes_from_hf_json, TokTrie, TokenId};
+
+const GPT2: &str = include_str!("data/gpt2_tokenizer.json");
+const LLAMA3: &str = include_str!("data/llama3_tokenizer.json");
+const LLAMA2: &str = include_str!("data/llama2_tokenizer.json");
+
+fn special(name: &str) -> Vec<u8> {
+    let mut res = vec![TokTrie::SPECIAL_TOKEN_PREFIX_BYTE];
+    res.extend_from_slice(name.as_bytes());
+    res
+}
+
+fn check_tokens(trie: &TokTrie, expected: &[Vec<u8>]) {
+    assert_eq!(trie.vocab_size(), expected.len());
+    for (t, bytes) in expected.iter().enumerate() {
+        assert_eq!(trie.token(t as TokenId), &bytes[..], "token {}", t);
+    }
+}
+
+fn check_round_trip(trie: &TokTrie, text: &[u8]) {
+    let tokens = trie.greedy_tokenize(text);
+    assert_eq!(trie.decode_raw(&tokens), text);
+    let loaded = TokTrie::from_bytes(&trie.serialize());
+    assert_eq!(loaded.greedy_tokenize(text), tokens);
+}
+
+#[test]
+fn gpt2() {
+    let (trie, info) = TokTrie::from_hf_json(GPT2).unwrap();
+    let expected = [
+        b"!".to_vec(),
+        b"a".to_vec(),
+        b"b".to_vec(),
+        b" ".to_vec(),
+        b" a".to_vec(),
+        b"ab".to_vec(),
+        b"\n".to_vec(),
+        "é".as_bytes().to_vec(),
+        b"caf".to_vec(),
+        special("<|endoftext|>"),
+    ];
+    check_tokens(&trie, &expected);
+    assert_eq!(info.tok_eos, 9);
+    assert_eq!(info.tok_bos, None);
+    assert!(trie.is_special_token(9));
+    assert!((0..9).all(|t| !trie.is_special_token(t)));
+    assert_eq!(trie.greedy_tokenize(b" ab\n"), vec![4, 2, 6]);
+    check_round_trip(&trie, "caf\u{e9} ab!\n".as_bytes());
+    assert_eq!(token_bytes_from_hf_json(GPT2).unwrap().1, expected);
+}
+
+#[test]
+fn llama3() {
+    let (trie, info) = TokTrie::from_hf_json(LLAMA3).unwrap();
+    let expected = [
+        b"!".to_vec(),
+        b"H".to_vec(),
+        b"i".to_vec(),
+        b" ".to_vec(),
+        b"Hi".to_vec(),
+        b" Hi".to_vec(),
+        b"\n\n".to_vec(),
+        "你".as_bytes().to_vec(),
+        "好".as_bytes().to_vec(),
+        " 你好".as_bytes().to_vec(),
+        special("<|begin_of_text|>"),
+        special("<|end_of_text|>"),
+        special("<|reserved_special_token_0|>"),
+        special("<|eot_id|>"),
+    ];
+    check_tokens(&trie, &expected);
+    assert_eq!(info.tok_bos, Some(10));
+    assert_eq!(info.tok_eos, 11);
+    assert_eq!(info.tok_end_of_turn, Some(13));
+    assert!((10..14).all(|t| trie.is_special_token(t)));
+    assert_eq!(trie.token_id(b"\xff<|eot_id|>"), Some(13));
+    check_round_trip(&trie, "Hi 你好!\n\n".as_bytes());
+}
+
+#[test]
+fn llama2() {
+    let (trie, info) = TokTrie::from_hf_json(LLAMA2).unwrap();
+    let expected = [
+        special("<unk>"),
+        special("<s>"),
+        special("</s>"),
+        b"\n".to_vec(),
+        b"\xe4".to_vec(),
+        b"\xf0".to_vec(),
+        b" ".to_vec(),
+        b" the".to_vec(),
+        b"the".to_vec(),
+        "你".as_bytes().to_vec(),
+    ];
+    check_tokens(&trie, &expected);
+    assert_eq!(info.tok_unk, Some(0));
+    assert_eq!(info.tok_bos, Some(1));
+    assert_eq!(info.tok_eos, 2);
+    check_round_trip(&trie, "the the\n你".as_bytes());
+}
+
+#[test]
+fn bad_ids() {
+    let with_id = |id: &str| GPT2.replace("\"caf\": 8", &format!("\"caf\": {}", id));
+    assert!(TokTrie::from_hf_json(&with_id("8")).is_ok());
+    // ids can have gaps
+    let (trie, _) = TokTrie::from_hf_json(&with_id("12")).unwrap();
+    assert_eq!(trie.vocab_size(), 13);
+    assert_eq!(trie.token(8), b"");
+    assert_eq!(trie.token(12), b"caf");
+    for id in ["1000000", "4294967296", "-1", "8.5", "\"8\""] {
+        assert!(TokTrie::from_hf_json(&with_id(id)).is_err(), "id {}", id);
+    }
+    let added = GPT2.replace("\"id\": 9,", "\"id\": 4294967305,");
+    assert!(TokTrie::from_hf_json(&added).is_err());
+}
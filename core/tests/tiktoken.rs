@@ -0,0 +1,74 @@
+//! `TokTrie::from_sparse()` on the tokens of a small tiktoken file, in `tests/data/`,
+//! with the lines out of order and gaps in the ids.
+#![cfg(feature = "tiktoken")]
+
+use toktrie::{
+    tiktoken::{parse_tiktoken, try_parse_tiktoken},
+    TokRxInfo, TokTrie, TokenId,
+};
+
+const SMALL: &str = include_str!("data/small.tiktoken");
+
+fn expected() -> Vec<&'static [u8]> {
+    vec![b"!", b"a", b"b", b"", b" a", b"ab", b"\xfe", b"", b" ab"]
+}
+
+#[test]
+fn parse() {
+    let entries = parse_tiktoken(SMALL);
+    assert_eq!(entries.len(), 7);
+    assert_eq!(entries[0], (5, b"ab".to_vec()));
+    assert_eq!(entries[5], (6, vec![0xfe]));
+    // empty lines are skipped
+    assert_eq!(parse_tiktoken(&format!("\n{}\n\n", SMALL)), entries);
+
+    for (text, msg) in [
+        ("YQ==", "tiktoken line 1: expecting '<base64> <id>'"),
+        ("YQ== 1\nYQ== x", "tiktoken line 2: invalid token id"),
+        ("YQ== -1", "tiktoken line 1: invalid token id"),
+        ("Y!== 1", "tiktoken line 1: "),
+    ] {
+        let e = try_parse_tiktoken(text).unwrap_err().to_string();
+        assert!(e.starts_with(msg), "{:?}: {}", text, e);
+    }
+}
+
+#[test]
+fn from_sparse() {
+    // the vocab size is the largest id plus one
+    let trie = TokTrie::from_sparse(&TokRxInfo::new(0, 3), parse_tiktoken(SMALL)).unwrap();
+    assert_eq!(trie.vocab_size(), 9);
+    for (t, bytes) in expected().iter().enumerate() {
+        assert_eq!(trie.token(t as TokenId), *bytes, "token {}", t);
+    }
+    assert_eq!(trie.greedy_tokenize(b"! ab\xfeab"), vec![0, 8, 6, 5]);
+    assert_eq!(trie.token_id(b""), None);
+
+    // the same as a dense vocabulary
+    let words = expected().iter().map(|w| w.to_vec()).collect::<Vec<_>>();
+    let dense = TokTrie::from(&TokRxInfo::new(9, 3), &words);
+    assert_eq!(trie, dense);
+
+    // a larger vocab size adds empty tokens at the end
+    let trie = TokTrie::from_sparse(&TokRxInfo::new(12, 3), parse_tiktoken(SMALL)).unwrap();
+    assert_eq!(trie.vocab_size(), 12);
+    assert_eq!(trie.token(11), b"");
+}
+
+#[test]
+fn from_sparse_errors() {
+    let entries = parse_tiktoken(SMALL);
+    let e = TokTrie::from_sparse(&TokRxInfo::new(8, 3), entries.clone()).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "TokTrie: token 8 out of range (vocab size 8)"
+    );
+
+    let mut dup = entries.clone();
+    dup.push((4, b"x".to_vec()));
+    let e = TokTrie::from_sparse(&TokRxInfo::new(0, 3), dup).unwrap_err();
+    assert_eq!(e.to_string(), "TokTrie: token 4 given more than once");
+
+    let e = TokTrie::from_sparse(&TokRxInfo::new(0, 0), vec![(u32::MAX, b"a".to_vec())]);
+    assert!(e.is_err());
+}
@@ -0,0 +1,101 @@
+/*
+ * C API of toktrie, from src/ffi.rs; keep the two in sync.
+ *
+ * Build the library with the cffi feature:
+ *   cargo rustc --release --lib --features cffi --crate-type cdylib,staticlib
+ *
+ * Functions return TOKTRIE_OK or one of the negative TOKTRIE_ERR_* codes;
+ * panics are caught and reported as TOKTRIE_ERR_PANIC.
+ */
+
+#ifndef TOKTRIE_H
+#define TOKTRIE_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define TOKTRIE_OK 0
+/* A required pointer (or callback) was null. */
+#define TOKTRIE_ERR_NULL (-1)
+/* An output buffer is too small; the required size is reported where possible. */
+#define TOKTRIE_ERR_SIZE (-2)
+/* A token id is out of range, or bytes could not be tokenized. */
+#define TOKTRIE_ERR_INVALID (-3)
+#define TOKTRIE_ERR_PANIC (-4)
+
+/* Values passed to TokTrieRecognizer.special_allowed */
+#define TOKTRIE_SPECIAL_UNKNOWN 0
+#define TOKTRIE_SPECIAL_PADDING 1
+#define TOKTRIE_SPECIAL_SEPARATOR 2
+#define TOKTRIE_SPECIAL_BEGINNING_OF_SENTENCE 3
+#define TOKTRIE_SPECIAL_END_OF_SENTENCE 4
+#define TOKTRIE_SPECIAL_END_OF_TURN 5
+
+typedef uint32_t TokTrieTokenId;
+
+/* Opaque; created by toktrie_new_from_bytes(), freed by toktrie_free(). */
+typedef struct TokTrie TokTrie;
+
+/*
+ * A recognizer implemented in C; see the Recognizer trait for the semantics.
+ * trie_started and trie_finished may be null; the other callbacks are required.
+ * Every callback gets user_data as the first argument. Callbacks must not unwind
+ * (throw C++ exceptions or longjmp out).
+ */
+typedef struct TokTrieRecognizer {
+  void *user_data;
+  bool (*try_push_byte)(void *user_data, uint8_t byte);
+  void (*pop_bytes)(void *user_data, size_t num);
+  void (*collapse)(void *user_data);
+  bool (*special_allowed)(void *user_data, uint32_t special);
+  void (*trie_started)(void *user_data);
+  void (*trie_finished)(void *user_data);
+} TokTrieRecognizer;
+
+/*
+ * Load a trie serialized with TokTrie::serialize().
+ * Returns null if ptr is null, or the bytes are not a valid trie.
+ */
+TokTrie *toktrie_new_from_bytes(const uint8_t *ptr, size_t len);
+
+/* Free a trie returned by toktrie_new_from_bytes(); null is ignored. */
+void toktrie_free(TokTrie *trie);
+
+/* Number of tokens in the trie, or 0 if trie is null. */
+size_t toktrie_vocab_size(const TokTrie *trie);
+
+/*
+ * Set *out_ptr and *out_len to the bytes of token id.
+ * The bytes are owned by the trie.
+ */
+int32_t toktrie_token(const TokTrie *trie, TokTrieTokenId id,
+                      const uint8_t **out_ptr, size_t *out_len);
+
+/*
+ * Tokenize len bytes at bytes greedily, writing the tokens to out_tokens and
+ * their number to *out_len. If out_cap is too small, returns TOKTRIE_ERR_SIZE
+ * with the required size in *out_len.
+ */
+int32_t toktrie_greedy_tokenize(const TokTrie *trie, const uint8_t *bytes,
+                                size_t len, TokTrieTokenId *out_tokens,
+                                size_t out_cap, size_t *out_len);
+
+/*
+ * Compute the set of tokens allowed by the recognizer.
+ * Token t is allowed if bit t % 32 of mask_ptr[t / 32] is set.
+ * mask_words must be at least (vocab_size + 31) / 32; extra words are left
+ * unchanged.
+ */
+int32_t toktrie_compute_bias(const TokTrie *trie, const TokTrieRecognizer *rec,
+                             uint32_t *mask_ptr, size_t mask_words);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* TOKTRIE_H */